@@ -0,0 +1,173 @@
+// `market-cli bench`: the compute units of each instruction, measured on a cluster. It
+// runs a whole market with the keypair as its authority, oracle, LP and trader, one
+// instruction per transaction, and reads what each one consumed back from the cluster.
+//
+// To compare two builds of the program, run it against a local validator with the old one
+// deployed and `--save` the results, then deploy the new one and pass them as `--baseline`:
+//
+//   solana-test-validator --reset --bpf-program <PROGRAM_ID> old.so
+//   market-cli bench --usd-mint <MINT> --liquidity 100000000 --save before.json
+//   solana-test-validator --reset --bpf-program <PROGRAM_ID> new.so
+//   market-cli bench --usd-mint <MINT> --liquidity 100000000 --baseline before.json
+//
+// The keypair needs a factory (`init-factory`) and USD in its associated token account.
+// An instruction the deployed program rejects, such as `set_market_status` on a build from
+// before it existed, is reported as skipped and the run goes on. See
+// `docs/compute-units.md` for the whole procedure.
+use std::fs;
+use std::path::Path;
+
+use anchor_lang::prelude::Pubkey;
+use anchor_lang::solana_program::instruction::Instruction;
+use market_client::{instructions, pda, MarketAddresses, OptionalAccounts};
+use serde_json::{json, Value};
+use solana_bet_placing_market::{MarketFactory, MarketStatus};
+use solana_signer::Signer;
+
+use crate::{Cli, Result};
+
+// What one instruction consumed, nothing when the program rejected it
+pub struct Measurement {
+    pub instruction: &'static str,
+    pub compute_units: Option<u64>,
+}
+
+impl Cli {
+    pub fn bench(&self, usd_mint: &Pubkey, liquidity: u64) -> Result<Vec<Measurement>> {
+        let payer = self.payer.pubkey();
+        let factory: MarketFactory = self
+            .rpc
+            .account(&pda::market_factory(&payer))
+            .map_err(|error| format!("{error}, see `init-factory`"))?;
+        let market = MarketAddresses::new(pda::market(&payer, factory.created_markets));
+        // Trades are a tenth of the liquidity. A purchase at even odds gets more shares than
+        // the USD paid, so half of it can always be sold back.
+        let trade = liquidity / 10;
+        let none = OptionalAccounts::default();
+
+        let mut measurements = Vec::new();
+        let mut measure = |instruction: &'static str, program_instruction: Instruction| {
+            let compute_units = match self.rpc.send(&[program_instruction], &[&self.payer]) {
+                Ok(signature) => {
+                    let compute_units = self.rpc.compute_units(&signature)?;
+                    println!("{instruction:<28}{compute_units:>10}");
+                    Some(compute_units)
+                }
+                Err(error) => {
+                    println!("{instruction:<28}{:>10}  {error}", "skipped");
+                    None
+                }
+            };
+            measurements.push(Measurement {
+                instruction,
+                compute_units,
+            });
+            Result::Ok(())
+        };
+
+        measure(
+            "create_new_market",
+            instructions::create_market(&payer, &market, usd_mint, &payer),
+        )?;
+        measure(
+            "initialize_pool",
+            instructions::initialize_pool(&payer, &market),
+        )?;
+        self.rpc
+            .send(&self.create_outcome_accounts(&market), &[&self.payer])?;
+        measure(
+            "add_liquidity",
+            instructions::add_liquidity(&payer, &market, usd_mint, liquidity, &none),
+        )?;
+        measure(
+            "purchase_outcome_shares",
+            instructions::purchase(&payer, &market, usd_mint, &market.yes_mint, trade, &none),
+        )?;
+        measure(
+            "sell_outcome_shares",
+            instructions::sell(
+                &payer,
+                &market,
+                usd_mint,
+                &market.yes_mint,
+                trade / 2,
+                &none,
+            ),
+        )?;
+        measure(
+            "remove_liquidity",
+            instructions::remove_liquidity(&payer, &market, usd_mint, liquidity / 2, &none),
+        )?;
+        measure(
+            "resolve_market",
+            instructions::resolve_market(&payer, &market, 1),
+        )?;
+        // The authority finalizes right away instead of waiting out the dispute window
+        measure(
+            "set_market_status",
+            instructions::set_market_status(&payer, &market, MarketStatus::Finalized),
+        )?;
+        measure(
+            "resolve_user_winnings",
+            instructions::claim_winnings(&payer, &market, usd_mint, &none),
+        )?;
+
+        Ok(measurements)
+    }
+}
+
+pub fn save(path: &Path, measurements: &[Measurement]) -> Result<()> {
+    let measurements: Vec<Value> = measurements
+        .iter()
+        .map(|measurement| {
+            json!({
+                "instruction": measurement.instruction,
+                "compute_units": measurement.compute_units,
+            })
+        })
+        .collect();
+    fs::write(path, serde_json::to_string_pretty(&measurements)?)?;
+    Ok(())
+}
+
+// The table of the measurements, against those `save` stored in `baseline` when given
+pub fn print_table(measurements: &[Measurement], baseline: Option<&Path>) -> Result<()> {
+    let baseline: Vec<Value> = match baseline {
+        Some(path) => serde_json::from_str(&fs::read_to_string(path)?)?,
+        None => Vec::new(),
+    };
+    let baseline_units = |instruction: &str| {
+        baseline
+            .iter()
+            .find(|measurement| measurement["instruction"] == instruction)
+            .and_then(|measurement| measurement["compute_units"].as_u64())
+    };
+    let units = |compute_units: Option<u64>| {
+        compute_units.map_or_else(|| "skipped".to_owned(), |units| units.to_string())
+    };
+
+    println!();
+    println!("| instruction | compute units | baseline | change |");
+    println!("|---|---:|---:|---:|");
+    for measurement in measurements {
+        let before = baseline_units(measurement.instruction);
+        let change = match (measurement.compute_units, before) {
+            (Some(after), Some(before)) => format!(
+                "{:+.1}%",
+                (after as f64 - before as f64) / before as f64 * 100.0
+            ),
+            _ => "-".to_owned(),
+        };
+        let before = if baseline.is_empty() {
+            "-".to_owned()
+        } else {
+            units(before)
+        };
+        println!(
+            "| {} | {} | {before} | {change} |",
+            measurement.instruction,
+            units(measurement.compute_units)
+        );
+    }
+    Ok(())
+}
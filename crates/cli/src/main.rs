@@ -0,0 +1,783 @@
+// Operator CLI for the market program: sets up factories, markets and pools, trades,
+// resolves and claims, and prints the program accounts.
+use std::path::PathBuf;
+
+use anchor_lang::prelude::Pubkey;
+use anchor_lang::solana_program::instruction::Instruction;
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
+use market_client::{instructions, pda, MarketAddresses, OptionalAccounts};
+use serde_json::json;
+use solana_bet_placing_market::math::{self, SCALE};
+use solana_bet_placing_market::{
+    Market, MarketFactory, MarketGroup, MarketPool, MarketPrices, MarketStatus, PurchaseQuote,
+    SaleQuote,
+};
+use solana_keypair::{read_keypair_file, Keypair};
+use solana_signer::Signer;
+
+mod bench;
+mod rpc;
+
+use rpc::RpcClient;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Parser)]
+#[command(about = "Operator CLI for the market program")]
+struct Args {
+    /// RPC endpoint
+    #[arg(long, default_value = "http://127.0.0.1:8899")]
+    url: String,
+
+    /// Keypair signing and paying for the transactions [default: ~/.config/solana/id.json]
+    #[arg(long)]
+    keypair: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+// Amounts are in base units of their token
+#[derive(Subcommand)]
+enum Command {
+    /// Print every address the program derives for a market
+    #[command(group(ArgGroup::new("which").required(true).args(["market", "market_number"])))]
+    Pdas {
+        #[arg(long)]
+        market: Option<Pubkey>,
+        /// Number of the market in the factory of `--authority`
+        #[arg(long)]
+        market_number: Option<u64>,
+        /// Authority of the factory [default: the keypair]
+        #[arg(long)]
+        authority: Option<Pubkey>,
+    },
+    /// Create the market factory of the keypair
+    InitFactory,
+    /// Create the next market of the keypair's factory, with its pool
+    CreateMarket {
+        #[arg(long)]
+        usd_mint: Pubkey,
+        /// Who resolves the market [default: the keypair]
+        #[arg(long)]
+        oracle: Option<Pubkey>,
+        /// Question shown for the market
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long, requires = "name")]
+        category: Option<String>,
+        /// Also store the metadata in this Supabase project, with the key in `SUPABASE_KEY`
+        #[arg(long, requires = "name")]
+        supabase_url: Option<String>,
+        /// USD to add as the first liquidity
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Create the pool of a market created without one, as its authority
+    InitPool {
+        #[arg(long)]
+        market: Pubkey,
+    },
+    /// Deposit USD into the pool for LP shares
+    AddLiquidity {
+        #[arg(long)]
+        market: Pubkey,
+        #[arg(long)]
+        amount: u64,
+    },
+    /// Burn LP shares for their part of the pool
+    RemoveLiquidity {
+        #[arg(long)]
+        market: Pubkey,
+        #[arg(long)]
+        shares: u64,
+    },
+    /// Current prices, or what a trade would get
+    #[command(group(ArgGroup::new("trade").args(["buy", "sell"]).requires("outcome")))]
+    Quote {
+        #[arg(long)]
+        market: Pubkey,
+        #[arg(long, value_enum, requires = "trade")]
+        outcome: Option<Side>,
+        /// USD to spend
+        #[arg(long)]
+        buy: Option<u64>,
+        /// Shares to sell
+        #[arg(long)]
+        sell: Option<u64>,
+    },
+    /// Buy outcome shares for USD
+    Buy {
+        #[arg(long)]
+        market: Pubkey,
+        #[arg(long, value_enum)]
+        outcome: Side,
+        #[arg(long)]
+        amount: u64,
+    },
+    /// Sell outcome shares for USD
+    Sell {
+        #[arg(long)]
+        market: Pubkey,
+        #[arg(long, value_enum)]
+        outcome: Side,
+        #[arg(long)]
+        shares: u64,
+    },
+    /// Resolve a market, the keypair being its oracle
+    Resolve {
+        #[arg(long)]
+        market: Pubkey,
+        #[arg(long, value_enum)]
+        outcome: Side,
+    },
+    /// Void a market, the keypair being its oracle
+    Invalidate {
+        #[arg(long)]
+        market: Pubkey,
+    },
+    /// Pause, reopen or close a market, or dispute or finalize its resolution, the keypair
+    /// being its authority
+    SetStatus {
+        #[arg(long)]
+        market: Pubkey,
+        #[arg(long, value_enum)]
+        status: Status,
+    },
+    /// Finalize the resolution of a market nobody disputed in time, any keypair
+    Finalize {
+        #[arg(long)]
+        market: Pubkey,
+    },
+    /// Upgrade a market and its pool stored in an older layout, the keypair being its
+    /// authority
+    Migrate {
+        #[arg(long)]
+        market: Pubkey,
+    },
+    /// Link markets of the keypair of which exactly one resolves YES, so that they get
+    /// resolved together
+    CreateGroup {
+        #[arg(long)]
+        usd_mint: Pubkey,
+        /// Any number, unique among the keypair's groups
+        #[arg(long)]
+        group_id: u64,
+        /// The members, in the group's order
+        #[arg(long, num_args = 2.., required = true)]
+        markets: Vec<Pubkey>,
+    },
+    /// Resolve every market of a group, the keypair being its oracle
+    ResolveGroup {
+        #[arg(long)]
+        group: Pubkey,
+        /// The member resolved YES
+        #[arg(long)]
+        winner: Pubkey,
+    },
+    /// Burn NO shares of every market of a group for USD
+    ConvertNo {
+        #[arg(long)]
+        group: Pubkey,
+        /// Shares of each market
+        #[arg(long)]
+        amount: u64,
+    },
+    /// Claim the keypair's winnings on a resolved market
+    Claim {
+        #[arg(long)]
+        market: Pubkey,
+    },
+    /// Print the market and its pool
+    Show {
+        #[arg(long)]
+        market: Pubkey,
+    },
+    /// Run a new market through its whole life and print the compute units of each
+    /// instruction, see `bench.rs`
+    Bench {
+        #[arg(long)]
+        usd_mint: Pubkey,
+        /// USD the keypair deposits, a tenth of it is traded
+        #[arg(long)]
+        liquidity: u64,
+        /// Store the measurements as JSON, for a later `--baseline`
+        #[arg(long)]
+        save: Option<PathBuf>,
+        /// Measurements stored by `--save` to compare with
+        #[arg(long)]
+        baseline: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Side {
+    Yes,
+    No,
+}
+
+// The statuses the authority sets by hand
+#[derive(Clone, Copy, ValueEnum)]
+enum Status {
+    Open,
+    Paused,
+    Closed,
+    Disputed,
+    Finalized,
+}
+
+impl From<Status> for MarketStatus {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Open => MarketStatus::Open,
+            Status::Paused => MarketStatus::Paused,
+            Status::Closed => MarketStatus::Closed,
+            Status::Disputed => MarketStatus::Disputed,
+            Status::Finalized => MarketStatus::Finalized,
+        }
+    }
+}
+
+impl Side {
+    fn mint(self, market: &MarketAddresses) -> Pubkey {
+        match self {
+            Side::Yes => market.yes_mint,
+            Side::No => market.no_mint,
+        }
+    }
+
+    // As `resolve_market` takes it
+    fn outcome(self) -> u8 {
+        match self {
+            Side::Yes => 1,
+            Side::No => 0,
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    // Deriving addresses needs neither the keypair nor the cluster
+    if let Command::Pdas {
+        market,
+        market_number,
+        authority,
+    } = args.command
+    {
+        let market = match (market, market_number) {
+            (Some(market), _) => market,
+            (None, Some(number)) => {
+                let authority = match authority {
+                    Some(authority) => authority,
+                    None => read_keypair(args.keypair)?.pubkey(),
+                };
+                pda::market(&authority, number)
+            }
+            (None, None) => unreachable!("enforced by clap"),
+        };
+        for (name, address) in MarketAddresses::new(market).named() {
+            println!("{name:<20}{address}");
+        }
+        return Ok(());
+    }
+
+    let cli = Cli {
+        rpc: RpcClient::new(args.url),
+        payer: read_keypair(args.keypair)?,
+    };
+    cli.run(args.command)
+}
+
+fn read_keypair(path: Option<PathBuf>) -> Result<Keypair> {
+    let path = match path {
+        Some(path) => path,
+        None => PathBuf::from(std::env::var("HOME")?).join(".config/solana/id.json"),
+    };
+    read_keypair_file(&path)
+        .map_err(|error| format!("cannot read {}: {error}", path.display()).into())
+}
+
+struct Cli {
+    rpc: RpcClient,
+    payer: Keypair,
+}
+
+impl Cli {
+    fn run(&self, command: Command) -> Result<()> {
+        let payer = self.payer.pubkey();
+        match command {
+            Command::Pdas { .. } => unreachable!("handled without a keypair"),
+            Command::InitFactory => {
+                self.send(&[instructions::initialize_market_factory(&payer)])?;
+                println!("market factory: {}", pda::market_factory(&payer));
+            }
+            Command::CreateMarket {
+                usd_mint,
+                oracle,
+                name,
+                category,
+                supabase_url,
+                seed,
+            } => {
+                let factory: MarketFactory = self
+                    .rpc
+                    .account(&pda::market_factory(&payer))
+                    .map_err(|error| format!("{error}, see `init-factory`"))?;
+                let market = MarketAddresses::new(pda::market(&payer, factory.created_markets));
+                self.send(&[
+                    instructions::create_market(
+                        &payer,
+                        &market,
+                        &usd_mint,
+                        &oracle.unwrap_or(payer),
+                    ),
+                    instructions::initialize_pool(&payer, &market),
+                ])?;
+                println!("market #{}: {}", factory.created_markets, market.market);
+
+                if let Some(name) = name {
+                    // The row the app keeps in its `market_metadata` table
+                    let metadata = json!({
+                        "market_pubkey": market.market.to_string(),
+                        "market_name": name,
+                        "market_category": category,
+                        "created_by": payer.to_string(),
+                    });
+                    println!("{metadata:#}");
+                    if let Some(supabase_url) = supabase_url {
+                        store_metadata(&supabase_url, &metadata)?;
+                    }
+                }
+                if let Some(usd_amount) = seed {
+                    self.add_liquidity(&market, &usd_mint, usd_amount)?;
+                }
+            }
+            Command::InitPool { market } => {
+                self.send(&[instructions::initialize_pool(
+                    &payer,
+                    &MarketAddresses::new(market),
+                )])?;
+            }
+            Command::AddLiquidity { market, amount } => {
+                let usd_mint = self.market(&market)?.usd_mint;
+                self.add_liquidity(&MarketAddresses::new(market), &usd_mint, amount)?;
+            }
+            Command::RemoveLiquidity { market, shares } => {
+                let usd_mint = self.market(&market)?.usd_mint;
+                let market = MarketAddresses::new(market);
+                let mut instructions = self.create_outcome_accounts(&market);
+                instructions.push(instructions::remove_liquidity(
+                    &payer,
+                    &market,
+                    &usd_mint,
+                    shares,
+                    &OptionalAccounts::default(),
+                ));
+                self.send(&instructions)?;
+            }
+            Command::Quote {
+                market,
+                outcome,
+                buy,
+                sell,
+            } => {
+                let market = MarketAddresses::new(market);
+                match (outcome, buy, sell) {
+                    (Some(outcome), Some(usd_amount), _) => {
+                        let quote: PurchaseQuote = self.rpc.view(
+                            instructions::quote_purchase(
+                                &market,
+                                &outcome.mint(&market),
+                                usd_amount,
+                            ),
+                            &payer,
+                        )?;
+                        print_purchase_quote(&quote);
+                    }
+                    (Some(outcome), _, Some(shares)) => {
+                        let quote: SaleQuote = self.rpc.view(
+                            instructions::quote_sell(&market, &outcome.mint(&market), shares),
+                            &payer,
+                        )?;
+                        print_sale_quote(&quote);
+                    }
+                    _ => {
+                        let prices: MarketPrices =
+                            self.rpc.view(instructions::get_prices(&market), &payer)?;
+                        print_prices(&prices);
+                    }
+                }
+            }
+            Command::Buy {
+                market,
+                outcome,
+                amount,
+            } => {
+                let usd_mint = self.market(&market)?.usd_mint;
+                let market = MarketAddresses::new(market);
+                let outcome_mint = outcome.mint(&market);
+                self.send(&[
+                    instructions::create_token_account(&payer, &payer, &outcome_mint),
+                    instructions::purchase(
+                        &payer,
+                        &market,
+                        &usd_mint,
+                        &outcome_mint,
+                        amount,
+                        &OptionalAccounts::default(),
+                    ),
+                ])?;
+            }
+            Command::Sell {
+                market,
+                outcome,
+                shares,
+            } => {
+                let usd_mint = self.market(&market)?.usd_mint;
+                let market = MarketAddresses::new(market);
+                self.send(&[instructions::sell(
+                    &payer,
+                    &market,
+                    &usd_mint,
+                    &outcome.mint(&market),
+                    shares,
+                    &OptionalAccounts::default(),
+                )])?;
+            }
+            Command::Resolve { market, outcome } => {
+                self.send(&[instructions::resolve_market(
+                    &payer,
+                    &MarketAddresses::new(market),
+                    outcome.outcome(),
+                )])?;
+            }
+            Command::Invalidate { market } => {
+                self.send(&[instructions::invalidate_market(
+                    &payer,
+                    &MarketAddresses::new(market),
+                )])?;
+            }
+            Command::Migrate { market } => {
+                self.send(&[instructions::migrate_market(
+                    &payer,
+                    &MarketAddresses::new(market),
+                )])?;
+            }
+            Command::Finalize { market } => {
+                self.send(&[instructions::finalize_market(
+                    &payer,
+                    &MarketAddresses::new(market),
+                )])?;
+            }
+            Command::SetStatus { market, status } => {
+                self.send(&[instructions::set_market_status(
+                    &payer,
+                    &MarketAddresses::new(market),
+                    status.into(),
+                )])?;
+            }
+            Command::CreateGroup {
+                usd_mint,
+                group_id,
+                markets,
+            } => {
+                self.send(&[instructions::create_market_group(
+                    &payer, group_id, &usd_mint, &markets,
+                )])?;
+                println!("group: {}", pda::market_group(&payer, group_id));
+            }
+            Command::ResolveGroup { group, winner } => {
+                let markets = self.market_group(&group)?.markets;
+                let winner = markets
+                    .iter()
+                    .position(|market| *market == winner)
+                    .ok_or("the winner is not a market of the group")?;
+                self.send(&[instructions::resolve_market_group(
+                    &payer,
+                    &group,
+                    &markets,
+                    winner as u8,
+                )])?;
+            }
+            Command::ConvertNo { group, amount } => {
+                let market_group = self.market_group(&group)?;
+                self.send(&[instructions::convert_no_tokens(
+                    &payer,
+                    &group,
+                    &market_group.markets,
+                    &market_group.usd_mint,
+                    amount,
+                )])?;
+            }
+            Command::Claim { market } => {
+                let usd_mint = self.market(&market)?.usd_mint;
+                let market = MarketAddresses::new(market);
+                let mut instructions = self.create_outcome_accounts(&market);
+                instructions.push(instructions::claim_winnings(
+                    &payer,
+                    &market,
+                    &usd_mint,
+                    &OptionalAccounts::default(),
+                ));
+                self.send(&instructions)?;
+            }
+            Command::Show { market } => {
+                let addresses = MarketAddresses::new(market);
+                print_market(&market, &self.market(&market)?);
+                print_pool(
+                    &addresses.pool,
+                    &self.rpc.zero_copy_account(&addresses.pool)?,
+                );
+            }
+            Command::Bench {
+                usd_mint,
+                liquidity,
+                save,
+                baseline,
+            } => {
+                let measurements = self.bench(&usd_mint, liquidity)?;
+                if let Some(path) = save {
+                    bench::save(&path, &measurements)?;
+                }
+                bench::print_table(&measurements, baseline.as_deref())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&self, instructions: &[Instruction]) -> Result<()> {
+        let signature = self.rpc.send(instructions, &[&self.payer])?;
+        println!("transaction: {signature}");
+        Ok(())
+    }
+
+    fn market(&self, market: &Pubkey) -> Result<Market> {
+        self.rpc.account(market)
+    }
+
+    fn market_group(&self, group: &Pubkey) -> Result<MarketGroup> {
+        self.rpc.account(group)
+    }
+
+    // The keypair's YES, NO and LP share accounts, which the liquidity instructions and
+    // the claim expect to exist
+    fn create_outcome_accounts(&self, market: &MarketAddresses) -> Vec<Instruction> {
+        let payer = self.payer.pubkey();
+        [market.yes_mint, market.no_mint, market.lp_share_mint]
+            .iter()
+            .map(|mint| instructions::create_token_account(&payer, &payer, mint))
+            .collect()
+    }
+
+    fn add_liquidity(
+        &self,
+        market: &MarketAddresses,
+        usd_mint: &Pubkey,
+        usd_amount: u64,
+    ) -> Result<()> {
+        let payer = self.payer.pubkey();
+        let mut instructions = self.create_outcome_accounts(market);
+        instructions.push(instructions::add_liquidity(
+            &payer,
+            market,
+            usd_mint,
+            usd_amount,
+            &OptionalAccounts::default(),
+        ));
+        self.send(&instructions)
+    }
+}
+
+fn store_metadata(supabase_url: &str, metadata: &serde_json::Value) -> Result<()> {
+    let key = std::env::var("SUPABASE_KEY").map_err(|_| "SUPABASE_KEY is not set")?;
+    ureq::post(&format!(
+        "{}/rest/v1/market_metadata",
+        supabase_url.trim_end_matches('/')
+    ))
+    .set("apikey", &key)
+    .set("Authorization", &format!("Bearer {key}"))
+    .set("Prefer", "return=minimal")
+    .send_json(metadata)?;
+    Ok(())
+}
+
+fn price(price: u64) -> String {
+    format!("{:.4}", price as f64 / SCALE as f64)
+}
+
+fn outcome(outcome: Option<u8>) -> &'static str {
+    match outcome {
+        Some(1) => "yes",
+        Some(_) => "no",
+        None => "-",
+    }
+}
+
+fn print_fields(title: &str, fields: &[(&str, String)]) {
+    println!("{title}");
+    for (name, value) in fields {
+        println!("  {name:<24}{value}");
+    }
+}
+
+fn print_market(address: &Pubkey, market: &Market) {
+    let limits = &market.risk_limits;
+    print_fields(
+        &format!("Market {address}"),
+        &[
+            ("market_number", market.market_number.to_string()),
+            ("authority", market.authority.to_string()),
+            ("oracle", market.oracle.to_string()),
+            ("usd_mint", market.usd_mint.to_string()),
+            ("yes_mint", market.yes_mint.to_string()),
+            ("no_mint", market.no_mint.to_string()),
+            ("lp_share_mint", market.lp_share_mint.to_string()),
+            ("vault", market.vault.to_string()),
+            ("market_volume", market.market_volume.to_string()),
+            ("version", market.version.to_string()),
+            ("status", format!("{:?}", market.status)),
+            ("resolved", market.resolved.to_string()),
+            ("outcome", outcome(market.outcome).to_owned()),
+            ("trading_fee_bps", market.trading_fee_bps.to_string()),
+            ("referral_share_bps", market.referral_share_bps.to_string()),
+            ("relay_fee", market.relay_fee.to_string()),
+            (
+                "max_price_impact_bps",
+                limits.max_price_impact_bps.to_string(),
+            ),
+            ("max_trade_size", limits.max_trade_size.to_string()),
+            ("min_pool_reserve", limits.min_pool_reserve.to_string()),
+            ("keeper_reward", market.keeper_reward.to_string()),
+            ("settlement_budget", market.settlement_budget.to_string()),
+            ("event_seq", market.event_seq.to_string()),
+        ],
+    );
+}
+
+fn print_pool(address: &Pubkey, pool: &MarketPool) {
+    // An empty pool has no price
+    let (yes_price, no_price) = match math::prices(pool.yes_liquidity, pool.no_liquidity) {
+        Ok((yes_price, no_price)) => (price(yes_price), price(no_price)),
+        Err(_) => ("-".to_owned(), "-".to_owned()),
+    };
+    print_fields(
+        &format!("Pool {address}"),
+        &[
+            ("yes_price", yes_price),
+            ("no_price", no_price),
+            ("yes_liquidity", pool.yes_liquidity.to_string()),
+            ("no_liquidity", pool.no_liquidity.to_string()),
+            ("liquidity_value", pool.liquidity_value.to_string()),
+            ("liquidity_shares", pool.liquidity_shares.to_string()),
+            ("usd_collateral", pool.usd_collateral.to_string()),
+            ("total_yes_mints", pool.total_yes_mints.to_string()),
+            ("total_no_mints", pool.total_no_mints.to_string()),
+            ("fees_collected", pool.fees_collected.to_string()),
+            ("last_price_update", pool.last_price_update.to_string()),
+        ],
+    );
+}
+
+fn print_prices(prices: &MarketPrices) {
+    print_fields(
+        "Prices",
+        &[
+            ("yes_price", price(prices.yes_price)),
+            ("no_price", price(prices.no_price)),
+            ("yes_liquidity", prices.yes_liquidity.to_string()),
+            ("no_liquidity", prices.no_liquidity.to_string()),
+            ("liquidity_value", prices.liquidity_value.to_string()),
+            ("liquidity_shares", prices.liquidity_shares.to_string()),
+            ("resolved", prices.resolved.to_string()),
+            ("outcome", outcome(prices.outcome).to_owned()),
+        ],
+    );
+}
+
+fn print_purchase_quote(quote: &PurchaseQuote) {
+    print_fields(
+        "Purchase",
+        &[
+            ("usd_amount", quote.usd_amount.to_string()),
+            ("shares_purchased", quote.shares_purchased.to_string()),
+            ("fee", quote.fee.to_string()),
+            (
+                "yes_price",
+                format!(
+                    "{} -> {}",
+                    price(quote.yes_price_before),
+                    price(quote.yes_price_after)
+                ),
+            ),
+            (
+                "no_price",
+                format!(
+                    "{} -> {}",
+                    price(quote.no_price_before),
+                    price(quote.no_price_after)
+                ),
+            ),
+        ],
+    );
+}
+
+fn print_sale_quote(quote: &SaleQuote) {
+    print_fields(
+        "Sale",
+        &[
+            ("shares", quote.shares.to_string()),
+            ("usd_received", quote.usd_received.to_string()),
+            ("fee", quote.fee.to_string()),
+            (
+                "yes_price",
+                format!(
+                    "{} -> {}",
+                    price(quote.yes_price_before),
+                    price(quote.yes_price_after)
+                ),
+            ),
+            (
+                "no_price",
+                format!(
+                    "{} -> {}",
+                    price(quote.no_price_before),
+                    price(quote.no_price_after)
+                ),
+            ),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn arguments_are_consistent() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn a_trade_quote_needs_an_outcome() {
+        let market = Pubkey::new_unique().to_string();
+        assert!(Args::try_parse_from(["market-cli", "quote", "--market", &market]).is_ok());
+        assert!(
+            Args::try_parse_from(["market-cli", "quote", "--market", &market, "--buy", "5"])
+                .is_err()
+        );
+        assert!(Args::try_parse_from([
+            "market-cli",
+            "quote",
+            "--market",
+            &market,
+            "--outcome",
+            "yes",
+            "--buy",
+            "5",
+            "--sell",
+            "5",
+        ])
+        .is_err());
+    }
+}
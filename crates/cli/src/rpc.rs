@@ -0,0 +1,184 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anchor_lang::prelude::Pubkey;
+use anchor_lang::solana_program::hash::Hash;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::{AccountDeserialize, AnchorDeserialize, ZeroCopy};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use market_client::state;
+use serde_json::{json, Value};
+use solana_keypair::Keypair;
+use solana_signer::Signer;
+use solana_transaction::Transaction;
+
+use crate::Result;
+
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
+// More than any view instruction returns
+const RETURN_DATA_PADDING: usize = 1_024;
+
+// The JSON-RPC calls of the CLI, over plain blocking HTTP
+pub struct RpcClient {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl RpcClient {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let mut response: Value = self
+            .agent
+            .post(&self.url)
+            .send_json(json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": method,
+                "params": params,
+            }))?
+            .into_json()?;
+        if let Some(error) = response.get("error") {
+            let mut message = format!("{method} failed: {}", error["message"]);
+            for log in error["data"]["logs"].as_array().into_iter().flatten() {
+                message.push_str(&format!("\n  {}", log.as_str().unwrap_or_default()));
+            }
+            return Err(message.into());
+        }
+        Ok(response["result"].take())
+    }
+
+    pub fn account_data(&self, address: &Pubkey) -> Result<Option<Vec<u8>>> {
+        let result = self.request(
+            "getAccountInfo",
+            json!([address.to_string(), { "encoding": "base64", "commitment": "confirmed" }]),
+        )?;
+        match result["value"]["data"][0].as_str() {
+            Some(data) => Ok(Some(BASE64_STANDARD.decode(data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn account<T: AccountDeserialize>(&self, address: &Pubkey) -> Result<T> {
+        let data = self
+            .account_data(address)?
+            .ok_or_else(|| format!("account {address} not found"))?;
+        Ok(T::try_deserialize(&mut data.as_slice())?)
+    }
+
+    pub fn zero_copy_account<T: ZeroCopy>(&self, address: &Pubkey) -> Result<T> {
+        let data = self
+            .account_data(address)?
+            .ok_or_else(|| format!("account {address} not found"))?;
+        Ok(state::decode_zero_copy(&data)?)
+    }
+
+    fn latest_blockhash(&self) -> Result<Hash> {
+        let result = self.request("getLatestBlockhash", json!([{ "commitment": "confirmed" }]))?;
+        let blockhash = result["value"]["blockhash"]
+            .as_str()
+            .ok_or("getLatestBlockhash: no blockhash")?;
+        Ok(blockhash.parse()?)
+    }
+
+    // Sends the instructions in one transaction paid by the first signer, and waits until
+    // it is confirmed
+    pub fn send(&self, instructions: &[Instruction], signers: &[&Keypair]) -> Result<String> {
+        let transaction = Transaction::new_signed_with_payer(
+            instructions,
+            Some(&signers[0].pubkey()),
+            signers,
+            self.latest_blockhash()?,
+        );
+        let signature = self.request(
+            "sendTransaction",
+            json!([
+                BASE64_STANDARD.encode(bincode::serialize(&transaction)?),
+                { "encoding": "base64", "preflightCommitment": "confirmed" },
+            ]),
+        )?;
+        let signature = signature
+            .as_str()
+            .ok_or("sendTransaction: no signature")?
+            .to_owned();
+
+        let started = Instant::now();
+        while started.elapsed() < CONFIRM_TIMEOUT {
+            let statuses = self.request("getSignatureStatuses", json!([[signature]]))?;
+            let status = &statuses["value"][0];
+            if !status["err"].is_null() {
+                return Err(format!("transaction {signature} failed: {}", status["err"]).into());
+            }
+            if matches!(
+                status["confirmationStatus"].as_str(),
+                Some("confirmed" | "finalized")
+            ) {
+                return Ok(signature);
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+        Err(format!("transaction {signature} not confirmed in time").into())
+    }
+
+    // What a confirmed transaction consumed, all its instructions together
+    pub fn compute_units(&self, signature: &str) -> Result<u64> {
+        let transaction = self.request(
+            "getTransaction",
+            json!([
+                signature,
+                { "encoding": "json", "commitment": "confirmed", "maxSupportedTransactionVersion": 0 },
+            ]),
+        )?;
+        transaction["meta"]["computeUnitsConsumed"]
+            .as_u64()
+            .ok_or_else(|| format!("getTransaction: no compute units for {signature}").into())
+    }
+
+    // Runs a view instruction through `simulateTransaction` and decodes its return data
+    pub fn view<T: AnchorDeserialize>(
+        &self,
+        instruction: Instruction,
+        payer: &Pubkey,
+    ) -> Result<T> {
+        let transaction = Transaction::new_with_payer(&[instruction], Some(payer));
+        let result = self.request(
+            "simulateTransaction",
+            json!([
+                BASE64_STANDARD.encode(bincode::serialize(&transaction)?),
+                {
+                    "encoding": "base64",
+                    "commitment": "confirmed",
+                    "sigVerify": false,
+                    "replaceRecentBlockhash": true,
+                },
+            ]),
+        )?;
+        let value = &result["value"];
+        if !value["err"].is_null() {
+            let logs: Vec<&str> = value["logs"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .collect();
+            return Err(format!(
+                "simulation failed: {}\n  {}",
+                value["err"],
+                logs.join("\n  ")
+            )
+            .into());
+        }
+        let data = value["returnData"]["data"][0]
+            .as_str()
+            .ok_or("the instruction returned nothing")?;
+        // The runtime trims the trailing zeros of the return data
+        let mut data = BASE64_STANDARD.decode(data)?;
+        data.resize(data.len() + RETURN_DATA_PADDING, 0);
+        Ok(T::deserialize(&mut data.as_slice())?)
+    }
+}
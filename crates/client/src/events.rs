@@ -0,0 +1,174 @@
+use ::solana_bet_placing_market::*;
+use anchor_lang::event::EVENT_IX_TAG_LE;
+use anchor_lang::prelude::{AnchorDeserialize, Pubkey};
+use anchor_lang::Discriminator;
+use base64::prelude::{Engine, BASE64_STANDARD};
+
+// One variant per program event, decoded from its discriminator
+macro_rules! events {
+    ($($name:ident),* $(,)?) => {
+        // Named after the program events
+        #[allow(clippy::enum_variant_names)]
+        pub enum Event {
+            $($name($name),)*
+        }
+
+        impl Event {
+            // Trailing bytes are ignored so that fields appended by a newer schema version
+            // do not stop the known ones from being decoded
+            pub fn decode(data: &[u8]) -> Option<Self> {
+                $(
+                    if let Some(mut payload) = data.strip_prefix($name::DISCRIMINATOR) {
+                        return $name::deserialize(&mut payload).ok().map(Event::$name);
+                    }
+                )*
+                None
+            }
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Event::$name(_) => stringify!($name),)*
+                }
+            }
+
+            pub fn header(&self) -> &EventHeader {
+                match self {
+                    $(Event::$name(event) => &event.header,)*
+                }
+            }
+        }
+    };
+}
+
+events!(
+    MarketFactoryInitializedEvent,
+    CreatedMarketsBumpedEvent,
+    FactoryFeesSetEvent,
+    MarketCreatedEvent,
+    PoolInitializedEvent,
+    RiskLimitsSetEvent,
+    PositionOpenedEvent,
+    LiquidityPositionOpenedEvent,
+    ReferrerRegisteredEvent,
+    SessionCreatedEvent,
+    SessionDelegatedEvent,
+    SessionRevokedEvent,
+    SettlementApprovedEvent,
+    SettlementBudgetFundedEvent,
+    SettlementBudgetReclaimedEvent,
+    LiquidityAddedEvent,
+    LiquidityRemovedEvent,
+    PurchasedOutcomeSharesEvent,
+    ReferralFeePaidEvent,
+    RelayFeePaidEvent,
+    SoldOutcomeSharesEvent,
+    MarketStatusChangedEvent,
+    MarketMigratedEvent,
+    MarketResolvedEvent,
+    MarketJoinedGroupEvent,
+    MarketGroupCreatedEvent,
+    MarketGroupResolvedEvent,
+    NoTokensConvertedEvent,
+    ResolveUserWinningsEvent,
+    BatchClaimEvent,
+    HoldersSettledEvent,
+    MarketAuditEvent,
+);
+
+// The event of an inner instruction of the program, when it is a self-CPI emitting one
+pub fn cpi_event(data: &[u8]) -> Option<&[u8]> {
+    data.strip_prefix(EVENT_IX_TAG_LE)
+}
+
+// Only the data logged while the program is the one running counts, a CPI into another
+// program could log anything
+pub fn log_events(logs: &[String], program_id: &Pubkey) -> Vec<Vec<u8>> {
+    let program_id = program_id.to_string();
+    let mut running = Vec::new();
+    let mut events = Vec::new();
+    for log in logs {
+        let Some(log) = log.strip_prefix("Program ") else {
+            continue;
+        };
+        if let Some(data) = log.strip_prefix("data: ") {
+            if running.last() == Some(&program_id.as_str()) {
+                if let Ok(event) = BASE64_STANDARD.decode(data) {
+                    events.push(event);
+                }
+            }
+            continue;
+        }
+        // `log:`, `return:` and the like, never a program id
+        let mut words = log.split(' ');
+        match (words.next(), words.next()) {
+            (Some(program), _) if program.ends_with(':') => {}
+            (Some(program), Some("invoke")) => running.push(program),
+            (Some(_), Some("success" | "failed:")) => {
+                running.pop();
+            }
+            _ => {}
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::Event as _;
+
+    fn resolved_event() -> MarketResolvedEvent {
+        MarketResolvedEvent {
+            header: EventHeader {
+                version: EVENT_SCHEMA_VERSION,
+                event_seq: 3,
+                slot: 7,
+                unix_timestamp: 1_700_000_000,
+            },
+            market: Pubkey::new_unique(),
+            solver: Pubkey::new_unique(),
+            outcome: 1,
+        }
+    }
+
+    #[test]
+    fn only_the_data_logged_by_the_program_is_taken() {
+        let event = resolved_event().data();
+        let other = Pubkey::new_unique();
+        let logs = [
+            format!("Program {} invoke [1]", ID),
+            "Program log: Instruction: ResolveMarket".to_owned(),
+            format!("Program {other} invoke [2]"),
+            "Program data: AAAA".to_owned(),
+            format!("Program {other} success"),
+            format!("Program data: {}", BASE64_STANDARD.encode(&event)),
+            format!("Program {} consumed 5000 of 200000 compute units", ID),
+            format!("Program {} success", ID),
+            "Program data: AAAA".to_owned(),
+        ];
+        assert_eq!(log_events(&logs, &ID), vec![event.clone()]);
+
+        let instruction = [EVENT_IX_TAG_LE, &event].concat();
+        assert_eq!(cpi_event(&instruction), Some(&event[..]));
+        assert_eq!(cpi_event(&event), None);
+    }
+
+    #[test]
+    fn events_decode_from_their_discriminator() {
+        let resolved = resolved_event();
+        let mut data = resolved.data();
+        let Some(Event::MarketResolvedEvent(decoded)) = Event::decode(&data) else {
+            panic!("not decoded as a MarketResolvedEvent");
+        };
+        assert_eq!(decoded.market, resolved.market);
+        assert_eq!(decoded.outcome, 1);
+
+        // A newer schema version with more fields
+        data.extend_from_slice(&[1, 2, 3]);
+        let event = Event::decode(&data).unwrap();
+        assert_eq!(event.name(), "MarketResolvedEvent");
+        assert_eq!(event.header().event_seq, 3);
+
+        assert!(Event::decode(&[0; 16]).is_none());
+    }
+}
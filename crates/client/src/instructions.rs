@@ -0,0 +1,613 @@
+// Builders of the program instructions. The token accounts of the users are always their
+// associated ones.
+use anchor_lang::prelude::Pubkey;
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::sysvar;
+use anchor_lang::{system_program, InstructionData, ToAccountMetas};
+use anchor_spl::associated_token::{self, get_associated_token_address};
+use anchor_spl::token;
+use solana_bet_placing_market::{accounts, event_accounts, instruction, MarketStatus, ID};
+
+use crate::pda::{self, MarketAddresses};
+
+// The optional accounts of the user instructions, all left out by default. An instruction
+// without a slot for one of them ignores it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OptionalAccounts {
+    // Once `open_position` created the user's position on the market
+    pub position: bool,
+    // Once `open_liquidity_position` created it
+    pub lp_position: bool,
+    // Gets the referral share of the trading fee, once registered with `register_referrer`
+    pub referrer: Option<Pubkey>,
+    // Signs for the user, through the session `create_session` opened for it
+    pub session_key: Option<Pubkey>,
+    // Pays for the transaction and gets the relay fee of the market
+    pub relayer: Option<Pubkey>,
+}
+
+impl OptionalAccounts {
+    fn position(&self, user: &Pubkey, market: &MarketAddresses) -> Option<Pubkey> {
+        self.position.then(|| pda::position(&market.market, user))
+    }
+
+    fn lp_position(&self, user: &Pubkey, market: &MarketAddresses) -> Option<Pubkey> {
+        self.lp_position
+            .then(|| pda::lp_position(&market.market, user))
+    }
+
+    fn session(&self, user: &Pubkey) -> Option<Pubkey> {
+        self.session_key
+            .map(|session_key| pda::session(user, &session_key))
+    }
+
+    // Who signs the user instructions
+    fn signer(&self, user: &Pubkey) -> Pubkey {
+        self.session_key.unwrap_or(*user)
+    }
+}
+
+fn program_instruction(accounts: impl ToAccountMetas, data: impl InstructionData) -> Instruction {
+    Instruction {
+        program_id: ID,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+// The associated token account of `owner`, created unless it exists already
+pub fn create_token_account(payer: &Pubkey, owner: &Pubkey, mint: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: associated_token::ID,
+        accounts: vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(get_associated_token_address(owner, mint), false),
+            AccountMeta::new_readonly(*owner, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new_readonly(token::ID, false),
+        ],
+        // `CreateIdempotent`
+        data: vec![1],
+    }
+}
+
+pub fn initialize_market_factory(authority: &Pubkey) -> Instruction {
+    program_instruction(
+        event_accounts!(accounts::InitializeMarketFactory {
+            market_factory: pda::market_factory(authority),
+            authority: *authority,
+            system_program: system_program::ID,
+        }),
+        instruction::InitializeMarketFactory {},
+    )
+}
+
+pub fn create_market(
+    authority: &Pubkey,
+    market: &MarketAddresses,
+    usd_mint: &Pubkey,
+    oracle: &Pubkey,
+) -> Instruction {
+    program_instruction(
+        event_accounts!(accounts::InitializeMarket {
+            market: market.market,
+            yes_mint: market.yes_mint,
+            no_mint: market.no_mint,
+            lp_share_mint: market.lp_share_mint,
+            usd_mint: *usd_mint,
+            market_factory: pda::market_factory(authority),
+            vault: market.vault,
+            authority: *authority,
+            system_program: system_program::ID,
+            token_program: token::ID,
+            rent: sysvar::rent::ID,
+        }),
+        instruction::CreateNewMarket {
+            oracle_key: *oracle,
+        },
+    )
+}
+
+pub fn initialize_pool(authority: &Pubkey, market: &MarketAddresses) -> Instruction {
+    program_instruction(
+        event_accounts!(accounts::InitializePool {
+            pool: market.pool,
+            liquidity_yes_tokens_account: market.yes_liquidity_pool,
+            liquidity_no_tokens_account: market.no_liquidity_pool,
+            price_history: market.price_history,
+            market: market.market,
+            authority: *authority,
+            yes_mint: market.yes_mint,
+            no_mint: market.no_mint,
+            system_program: system_program::ID,
+            token_program: token::ID,
+        }),
+        instruction::InitializePool {},
+    )
+}
+
+pub fn open_position(user: &Pubkey, payer: &Pubkey, market: &MarketAddresses) -> Instruction {
+    program_instruction(
+        event_accounts!(accounts::OpenPosition {
+            position: pda::position(&market.market, user),
+            market: market.market,
+            user: *user,
+            payer: *payer,
+            system_program: system_program::ID,
+        }),
+        instruction::OpenPosition {},
+    )
+}
+
+pub fn open_liquidity_position(
+    user: &Pubkey,
+    payer: &Pubkey,
+    market: &MarketAddresses,
+) -> Instruction {
+    program_instruction(
+        event_accounts!(accounts::OpenLiquidityPosition {
+            lp_position: pda::lp_position(&market.market, user),
+            market: market.market,
+            pool: market.pool,
+            user: *user,
+            payer: *payer,
+            system_program: system_program::ID,
+        }),
+        instruction::OpenLiquidityPosition {},
+    )
+}
+
+// Referral fees are paid to the USD account of `referrer`
+pub fn register_referrer(referrer: &Pubkey, payer: &Pubkey, usd_mint: &Pubkey) -> Instruction {
+    program_instruction(
+        event_accounts!(accounts::RegisterReferrer {
+            referral: pda::referral(referrer),
+            usd_account: get_associated_token_address(referrer, usd_mint),
+            referrer: *referrer,
+            payer: *payer,
+            system_program: system_program::ID,
+        }),
+        instruction::RegisterReferrer {},
+    )
+}
+
+fn pool_liquidity(
+    user: &Pubkey,
+    market: &MarketAddresses,
+    usd_mint: &Pubkey,
+    optional: &OptionalAccounts,
+) -> accounts::PoolLiquidity {
+    event_accounts!(accounts::PoolLiquidity {
+        pool: market.pool,
+        market: market.market,
+        vault: market.vault,
+        yes_mint: market.yes_mint,
+        no_mint: market.no_mint,
+        lp_share_mint: market.lp_share_mint,
+        user_usd_account: get_associated_token_address(user, usd_mint),
+        user_yes_account: get_associated_token_address(user, &market.yes_mint),
+        user_no_account: get_associated_token_address(user, &market.no_mint),
+        user_lp_share_account: get_associated_token_address(user, &market.lp_share_mint),
+        liquidity_yes_tokens_account: market.yes_liquidity_pool,
+        liquidity_no_tokens_account: market.no_liquidity_pool,
+        position: optional.position(user, market),
+        lp_position: optional.lp_position(user, market),
+        user: *user,
+        token_program: token::ID,
+    })
+}
+
+pub fn add_liquidity(
+    user: &Pubkey,
+    market: &MarketAddresses,
+    usd_mint: &Pubkey,
+    usd_amount: u64,
+    optional: &OptionalAccounts,
+) -> Instruction {
+    program_instruction(
+        pool_liquidity(user, market, usd_mint, optional),
+        instruction::AddLiquidity { usd_amount },
+    )
+}
+
+pub fn remove_liquidity(
+    user: &Pubkey,
+    market: &MarketAddresses,
+    usd_mint: &Pubkey,
+    shares: u64,
+    optional: &OptionalAccounts,
+) -> Instruction {
+    program_instruction(
+        pool_liquidity(user, market, usd_mint, optional),
+        instruction::RemoveLiquidity { shares },
+    )
+}
+
+pub fn purchase(
+    user: &Pubkey,
+    market: &MarketAddresses,
+    usd_mint: &Pubkey,
+    outcome_mint: &Pubkey,
+    usd_amount: u64,
+    optional: &OptionalAccounts,
+) -> Instruction {
+    program_instruction(
+        event_accounts!(accounts::PurchaseOutcomeShares {
+            market: market.market,
+            pool: market.pool,
+            vault: market.vault,
+            yes_mint: market.yes_mint,
+            no_mint: market.no_mint,
+            user_usd_account: get_associated_token_address(user, usd_mint),
+            user_outcome_mint_account: get_associated_token_address(user, outcome_mint),
+            liquidity_yes_tokens_account: market.yes_liquidity_pool,
+            liquidity_no_tokens_account: market.no_liquidity_pool,
+            price_history: market.price_history,
+            position: optional.position(user, market),
+            referral: optional.referrer.as_ref().map(pda::referral),
+            referrer_usd_account: optional
+                .referrer
+                .map(|referrer| get_associated_token_address(&referrer, usd_mint)),
+            session: optional.session(user),
+            relayer: optional.relayer,
+            relayer_usd_account: optional
+                .relayer
+                .map(|relayer| get_associated_token_address(&relayer, usd_mint)),
+            user: optional.signer(user),
+            token_program: token::ID,
+        }),
+        instruction::PurchaseOutcomeShares {
+            usd_amount,
+            purchased_outcome_mint_pubkey: *outcome_mint,
+        },
+    )
+}
+
+pub fn sell(
+    user: &Pubkey,
+    market: &MarketAddresses,
+    usd_mint: &Pubkey,
+    outcome_mint: &Pubkey,
+    shares: u64,
+    optional: &OptionalAccounts,
+) -> Instruction {
+    program_instruction(
+        event_accounts!(accounts::SellOutcomeShares {
+            market: market.market,
+            pool: market.pool,
+            vault: market.vault,
+            yes_mint: market.yes_mint,
+            no_mint: market.no_mint,
+            user_usd_account: get_associated_token_address(user, usd_mint),
+            user_outcome_mint_account: get_associated_token_address(user, outcome_mint),
+            liquidity_yes_tokens_account: market.yes_liquidity_pool,
+            liquidity_no_tokens_account: market.no_liquidity_pool,
+            price_history: market.price_history,
+            position: optional.position(user, market),
+            referral: optional.referrer.as_ref().map(pda::referral),
+            referrer_usd_account: optional
+                .referrer
+                .map(|referrer| get_associated_token_address(&referrer, usd_mint)),
+            session: optional.session(user),
+            relayer: optional.relayer,
+            relayer_usd_account: optional
+                .relayer
+                .map(|relayer| get_associated_token_address(&relayer, usd_mint)),
+            user: optional.signer(user),
+            token_program: token::ID,
+        }),
+        instruction::SellOutcomeShares {
+            shares,
+            sold_outcome_mint_pubkey: *outcome_mint,
+        },
+    )
+}
+
+pub fn resolve_market(oracle: &Pubkey, market: &MarketAddresses, outcome: u8) -> Instruction {
+    program_instruction(
+        event_accounts!(accounts::ResolveMarket {
+            market: market.market,
+            pool: market.pool,
+            oracle: *oracle,
+        }),
+        instruction::ResolveMarket { outcome },
+    )
+}
+
+pub fn invalidate_market(oracle: &Pubkey, market: &MarketAddresses) -> Instruction {
+    program_instruction(
+        event_accounts!(accounts::ResolveMarket {
+            market: market.market,
+            pool: market.pool,
+            oracle: *oracle,
+        }),
+        instruction::InvalidateMarket {},
+    )
+}
+
+pub fn migrate_market(authority: &Pubkey, market: &MarketAddresses) -> Instruction {
+    program_instruction(
+        event_accounts!(accounts::MigrateMarket {
+            market: market.market,
+            pool: market.pool,
+            authority: *authority,
+            system_program: system_program::ID,
+        }),
+        instruction::MigrateMarket {},
+    )
+}
+
+pub fn set_market_status(
+    authority: &Pubkey,
+    market: &MarketAddresses,
+    status: MarketStatus,
+) -> Instruction {
+    program_instruction(
+        event_accounts!(accounts::SetMarketStatus {
+            market: market.market,
+            authority: *authority,
+        }),
+        instruction::SetMarketStatus { status },
+    )
+}
+
+// Anyone finalizes the resolution once the dispute window is over
+pub fn finalize_market(payer: &Pubkey, market: &MarketAddresses) -> Instruction {
+    program_instruction(
+        event_accounts!(accounts::FinalizeMarket {
+            market: market.market,
+            payer: *payer,
+        }),
+        instruction::FinalizeMarket {},
+    )
+}
+
+pub fn claim_winnings(
+    user: &Pubkey,
+    market: &MarketAddresses,
+    usd_mint: &Pubkey,
+    optional: &OptionalAccounts,
+) -> Instruction {
+    program_instruction(
+        event_accounts!(accounts::ResolveUserWinnings {
+            market: market.market,
+            pool: market.pool,
+            vault: market.vault,
+            yes_mint: market.yes_mint,
+            no_mint: market.no_mint,
+            lp_share_mint: market.lp_share_mint,
+            user_usd_account: get_associated_token_address(user, usd_mint),
+            user_yes_account: get_associated_token_address(user, &market.yes_mint),
+            user_no_account: get_associated_token_address(user, &market.no_mint),
+            position: optional.position(user, market),
+            session: optional.session(user),
+            user: optional.signer(user),
+            token_program: token::ID,
+        }),
+        instruction::ResolveUserWinnings {},
+    )
+}
+
+// A group instruction with its remaining accounts appended, `members` giving those of
+// each member in the group's order
+fn group_instruction(
+    accounts: impl ToAccountMetas,
+    data: impl InstructionData,
+    members: impl IntoIterator<Item = AccountMeta>,
+) -> Instruction {
+    let mut instruction = program_instruction(accounts, data);
+    instruction.accounts.extend(members);
+    instruction
+}
+
+pub fn create_market_group(
+    authority: &Pubkey,
+    group_id: u64,
+    usd_mint: &Pubkey,
+    markets: &[Pubkey],
+) -> Instruction {
+    let group = pda::market_group(authority, group_id);
+    group_instruction(
+        event_accounts!(accounts::CreateMarketGroup {
+            group,
+            vault: pda::group_vault(&group),
+            usd_mint: *usd_mint,
+            authority: *authority,
+            system_program: system_program::ID,
+            token_program: token::ID,
+        }),
+        instruction::CreateMarketGroup { group_id },
+        markets
+            .iter()
+            .map(|market| AccountMeta::new(*market, false)),
+    )
+}
+
+// `markets` are those of the group, in its order, and `winner` the index of the one
+// resolved YES
+pub fn resolve_market_group(
+    oracle: &Pubkey,
+    group: &Pubkey,
+    markets: &[Pubkey],
+    winner: u8,
+) -> Instruction {
+    group_instruction(
+        event_accounts!(accounts::ResolveMarketGroup {
+            group: *group,
+            vault: pda::group_vault(group),
+            oracle: *oracle,
+            token_program: token::ID,
+        }),
+        instruction::ResolveMarketGroup { winner },
+        markets.iter().flat_map(|market| {
+            let market = MarketAddresses::new(*market);
+            [
+                AccountMeta::new(market.market, false),
+                AccountMeta::new(market.pool, false),
+                AccountMeta::new(market.vault, false),
+            ]
+        }),
+    )
+}
+
+pub fn convert_no_tokens(
+    user: &Pubkey,
+    group: &Pubkey,
+    markets: &[Pubkey],
+    usd_mint: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    group_instruction(
+        event_accounts!(accounts::ConvertNoTokens {
+            group: *group,
+            vault: pda::group_vault(group),
+            user_usd_account: get_associated_token_address(user, usd_mint),
+            user: *user,
+            token_program: token::ID,
+        }),
+        instruction::ConvertNoTokens { amount },
+        markets.iter().flat_map(|market| {
+            let market = MarketAddresses::new(*market);
+            [
+                AccountMeta::new_readonly(market.market, false),
+                AccountMeta::new(market.pool, false),
+                AccountMeta::new(market.vault, false),
+                AccountMeta::new(market.no_mint, false),
+                AccountMeta::new(get_associated_token_address(user, &market.no_mint), false),
+            ]
+        }),
+    )
+}
+
+fn quote_accounts(market: &MarketAddresses) -> accounts::QuoteMarket {
+    accounts::QuoteMarket {
+        market: market.market,
+        pool: market.pool,
+    }
+}
+
+pub fn get_prices(market: &MarketAddresses) -> Instruction {
+    program_instruction(quote_accounts(market), instruction::GetPrices {})
+}
+
+pub fn quote_purchase(
+    market: &MarketAddresses,
+    outcome_mint: &Pubkey,
+    usd_amount: u64,
+) -> Instruction {
+    program_instruction(
+        quote_accounts(market),
+        instruction::QuotePurchase {
+            usd_amount,
+            purchased_outcome_mint_pubkey: *outcome_mint,
+        },
+    )
+}
+
+pub fn quote_sell(market: &MarketAddresses, outcome_mint: &Pubkey, shares: u64) -> Instruction {
+    program_instruction(
+        quote_accounts(market),
+        instruction::QuoteSell {
+            shares,
+            sold_outcome_mint_pubkey: *outcome_mint,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_bet_placing_market::{GROUP_RESOLUTION_LEN, NO_CONVERSION_LEN};
+
+    #[test]
+    fn optional_accounts_fill_the_trade_accounts() {
+        let user = Pubkey::new_unique();
+        let usd_mint = Pubkey::new_unique();
+        let market = MarketAddresses::new(pda::market(&Pubkey::new_unique(), 0));
+
+        let plain = purchase(
+            &user,
+            &market,
+            &usd_mint,
+            &market.yes_mint,
+            10,
+            &OptionalAccounts::default(),
+        );
+        // Left out optional accounts are passed as the program id
+        let signers: Vec<_> = plain
+            .accounts
+            .iter()
+            .filter(|meta| meta.is_signer)
+            .collect();
+        assert_eq!(signers.len(), 1);
+        assert_eq!(signers[0].pubkey, user);
+        assert_eq!(
+            plain
+                .accounts
+                .iter()
+                .filter(|meta| meta.pubkey == ID)
+                .count(),
+            6
+        );
+
+        let session_key = Pubkey::new_unique();
+        let referrer = Pubkey::new_unique();
+        let relayer = Pubkey::new_unique();
+        let optional = OptionalAccounts {
+            position: true,
+            referrer: Some(referrer),
+            session_key: Some(session_key),
+            relayer: Some(relayer),
+            ..OptionalAccounts::default()
+        };
+        let relayed = purchase(&user, &market, &usd_mint, &market.yes_mint, 10, &optional);
+        let address = |index: usize| relayed.accounts[index].pubkey;
+        assert_eq!(address(5), get_associated_token_address(&user, &usd_mint));
+        assert_eq!(address(10), pda::position(&market.market, &user));
+        assert_eq!(address(11), pda::referral(&referrer));
+        assert_eq!(
+            address(12),
+            get_associated_token_address(&referrer, &usd_mint)
+        );
+        assert_eq!(address(13), pda::session(&user, &session_key));
+        assert_eq!(address(14), relayer);
+        assert_eq!(
+            address(15),
+            get_associated_token_address(&relayer, &usd_mint)
+        );
+        let signers: Vec<_> = relayed
+            .accounts
+            .iter()
+            .filter(|meta| meta.is_signer)
+            .map(|meta| meta.pubkey)
+            .collect();
+        assert_eq!(signers, vec![relayer, session_key]);
+    }
+
+    #[test]
+    fn group_instructions_append_the_accounts_of_each_member() {
+        let user = Pubkey::new_unique();
+        let usd_mint = Pubkey::new_unique();
+        let group = pda::market_group(&Pubkey::new_unique(), 7);
+        let markets = [Pubkey::new_unique(), Pubkey::new_unique()];
+
+        let conversion = convert_no_tokens(&user, &group, &markets, &usd_mint, 10);
+        let members = &conversion.accounts[5..];
+        assert_eq!(members.len(), markets.len() * NO_CONVERSION_LEN);
+        let second = MarketAddresses::new(markets[1]);
+        assert_eq!(members[NO_CONVERSION_LEN].pubkey, second.market);
+        assert_eq!(
+            members[NO_CONVERSION_LEN * 2 - 1].pubkey,
+            get_associated_token_address(&user, &second.no_mint)
+        );
+        assert!(members.iter().all(|meta| !meta.is_signer));
+
+        let resolution = resolve_market_group(&user, &group, &markets, 1);
+        assert_eq!(
+            resolution.accounts.len(),
+            4 + markets.len() * GROUP_RESOLUTION_LEN
+        );
+        assert_eq!(resolution.accounts[1].pubkey, pda::group_vault(&group));
+    }
+}
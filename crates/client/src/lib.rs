@@ -0,0 +1,10 @@
+// Everything a client of the market program needs: the addresses it derives, its
+// instructions with their accounts, and the decoding of its accounts and events.
+pub mod events;
+pub mod instructions;
+pub mod pda;
+pub mod state;
+
+pub use instructions::OptionalAccounts;
+pub use pda::MarketAddresses;
+pub use solana_bet_placing_market::ID;
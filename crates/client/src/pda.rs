@@ -0,0 +1,90 @@
+use anchor_lang::prelude::Pubkey;
+use solana_bet_placing_market::ID;
+
+pub fn market_factory(authority: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"market_factory", authority.as_ref()], &ID).0
+}
+
+pub fn market(authority: &Pubkey, market_number: u64) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"market", authority.as_ref(), &market_number.to_le_bytes()],
+        &ID,
+    )
+    .0
+}
+
+pub fn position(market: &Pubkey, user: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"position", market.as_ref(), user.as_ref()], &ID).0
+}
+
+pub fn lp_position(market: &Pubkey, user: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"lp_position", market.as_ref(), user.as_ref()], &ID).0
+}
+
+// Shared by all the markets
+pub fn referral(referrer: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"referral", referrer.as_ref()], &ID).0
+}
+
+pub fn session(owner: &Pubkey, session_key: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"session", owner.as_ref(), session_key.as_ref()], &ID).0
+}
+
+pub fn market_group(authority: &Pubkey, group_id: u64) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"market_group", authority.as_ref(), &group_id.to_le_bytes()],
+        &ID,
+    )
+    .0
+}
+
+pub fn group_vault(group: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"group_vault", group.as_ref()], &ID).0
+}
+
+fn market_seed(seed: &[u8], market: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[seed, market.as_ref()], &ID).0
+}
+
+// Every account the program derives from a market
+pub struct MarketAddresses {
+    pub market: Pubkey,
+    pub yes_mint: Pubkey,
+    pub no_mint: Pubkey,
+    pub lp_share_mint: Pubkey,
+    pub vault: Pubkey,
+    pub pool: Pubkey,
+    pub yes_liquidity_pool: Pubkey,
+    pub no_liquidity_pool: Pubkey,
+    pub price_history: Pubkey,
+}
+
+impl MarketAddresses {
+    pub fn new(market: Pubkey) -> Self {
+        Self {
+            market,
+            yes_mint: market_seed(b"yes_mint", &market),
+            no_mint: market_seed(b"no_mint", &market),
+            lp_share_mint: market_seed(b"lp_share_mint", &market),
+            vault: market_seed(b"vault", &market),
+            pool: market_seed(b"pool", &market),
+            yes_liquidity_pool: market_seed(b"yes_liquidity_pool", &market),
+            no_liquidity_pool: market_seed(b"no_liquidity_pool", &market),
+            price_history: market_seed(b"price_history", &market),
+        }
+    }
+
+    pub fn named(&self) -> [(&'static str, Pubkey); 9] {
+        [
+            ("market", self.market),
+            ("yes_mint", self.yes_mint),
+            ("no_mint", self.no_mint),
+            ("lp_share_mint", self.lp_share_mint),
+            ("vault", self.vault),
+            ("pool", self.pool),
+            ("yes_liquidity_pool", self.yes_liquidity_pool),
+            ("no_liquidity_pool", self.no_liquidity_pool),
+            ("price_history", self.price_history),
+        ]
+    }
+}
@@ -0,0 +1,37 @@
+use anchor_lang::error::ErrorCode;
+use anchor_lang::{error, require_eq, AccountDeserialize, Result, ZeroCopy};
+use solana_bet_placing_market::{Market, MarketFactory, MarketGroup, MarketPool};
+
+// The data of a program account, checked against its discriminator
+pub fn decode<T: AccountDeserialize>(data: &[u8]) -> Result<T> {
+    T::try_deserialize(&mut &data[..])
+}
+
+// The same for a zero-copy account, copied out of the data as it need not be aligned
+pub fn decode_zero_copy<T: ZeroCopy>(data: &[u8]) -> Result<T> {
+    let data = data
+        .strip_prefix(T::DISCRIMINATOR)
+        .ok_or(ErrorCode::AccountDiscriminatorMismatch)?;
+    require_eq!(
+        data.len(),
+        std::mem::size_of::<T>(),
+        ErrorCode::AccountDidNotDeserialize
+    );
+    Ok(bytemuck::pod_read_unaligned(data))
+}
+
+pub fn market_factory(data: &[u8]) -> Result<MarketFactory> {
+    decode(data)
+}
+
+pub fn market(data: &[u8]) -> Result<Market> {
+    decode(data)
+}
+
+pub fn pool(data: &[u8]) -> Result<MarketPool> {
+    decode_zero_copy(data)
+}
+
+pub fn market_group(data: &[u8]) -> Result<MarketGroup> {
+    decode(data)
+}
@@ -0,0 +1,582 @@
+use std::path::Path;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::Result;
+use market_client::events::Event;
+
+// Every event lands in `events`, the ones analytics care about are also normalized into
+// their own table. Amounts are raw token amounts, prices are scaled by the program `SCALE`.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sync_state (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    last_slot INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS transactions (
+    signature TEXT PRIMARY KEY,
+    slot INTEGER NOT NULL,
+    block_time INTEGER,
+    failed INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS events (
+    signature TEXT NOT NULL REFERENCES transactions (signature),
+    event_index INTEGER NOT NULL,
+    slot INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    event_seq INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (signature, event_index)
+);
+
+CREATE TABLE IF NOT EXISTS markets (
+    address TEXT PRIMARY KEY,
+    market_factory TEXT NOT NULL,
+    authority TEXT NOT NULL,
+    oracle TEXT NOT NULL,
+    market_number INTEGER NOT NULL,
+    usd_mint TEXT NOT NULL,
+    yes_mint TEXT NOT NULL,
+    no_mint TEXT NOT NULL,
+    lp_share_mint TEXT NOT NULL,
+    vault TEXT NOT NULL,
+    trading_fee_bps INTEGER NOT NULL,
+    referral_share_bps INTEGER NOT NULL,
+    relay_fee INTEGER NOT NULL,
+    created_slot INTEGER NOT NULL,
+    created_at INTEGER,
+    signature TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS trades (
+    signature TEXT NOT NULL,
+    event_index INTEGER NOT NULL,
+    market TEXT NOT NULL,
+    user TEXT NOT NULL,
+    side TEXT NOT NULL CHECK (side IN ('buy', 'sell')),
+    outcome_mint TEXT NOT NULL,
+    usd_amount INTEGER NOT NULL,
+    shares INTEGER NOT NULL,
+    fee INTEGER NOT NULL,
+    yes_price_before INTEGER NOT NULL,
+    no_price_before INTEGER NOT NULL,
+    yes_price_after INTEGER NOT NULL,
+    no_price_after INTEGER NOT NULL,
+    event_seq INTEGER NOT NULL,
+    slot INTEGER NOT NULL,
+    block_time INTEGER,
+    PRIMARY KEY (signature, event_index)
+);
+CREATE INDEX IF NOT EXISTS trades_market ON trades (market, event_seq);
+
+CREATE TABLE IF NOT EXISTS liquidity (
+    signature TEXT NOT NULL,
+    event_index INTEGER NOT NULL,
+    market TEXT NOT NULL,
+    user TEXT NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('add', 'remove')),
+    usd_amount INTEGER NOT NULL,
+    lp_shares INTEGER NOT NULL,
+    pool_lp_shares INTEGER NOT NULL,
+    event_seq INTEGER NOT NULL,
+    slot INTEGER NOT NULL,
+    block_time INTEGER,
+    PRIMARY KEY (signature, event_index)
+);
+CREATE INDEX IF NOT EXISTS liquidity_market ON liquidity (market, event_seq);
+
+CREATE TABLE IF NOT EXISTS resolutions (
+    market TEXT PRIMARY KEY,
+    solver TEXT NOT NULL,
+    outcome INTEGER NOT NULL,
+    slot INTEGER NOT NULL,
+    block_time INTEGER,
+    signature TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS status_changes (
+    signature TEXT NOT NULL,
+    event_index INTEGER NOT NULL,
+    market TEXT NOT NULL,
+    previous_status TEXT NOT NULL,
+    status TEXT NOT NULL,
+    changed_by TEXT NOT NULL,
+    event_seq INTEGER NOT NULL,
+    slot INTEGER NOT NULL,
+    block_time INTEGER,
+    PRIMARY KEY (signature, event_index)
+);
+CREATE INDEX IF NOT EXISTS status_changes_market ON status_changes (market, event_seq);
+
+CREATE TABLE IF NOT EXISTS claims (
+    signature TEXT NOT NULL,
+    event_index INTEGER NOT NULL,
+    market TEXT NOT NULL,
+    user TEXT NOT NULL,
+    yes_tokens INTEGER NOT NULL,
+    no_tokens INTEGER NOT NULL,
+    winning_amount INTEGER NOT NULL,
+    event_seq INTEGER NOT NULL,
+    slot INTEGER NOT NULL,
+    block_time INTEGER,
+    PRIMARY KEY (signature, event_index)
+);
+CREATE INDEX IF NOT EXISTS claims_market ON claims (market);
+
+CREATE TABLE IF NOT EXISTS group_members (
+    market TEXT PRIMARY KEY,
+    market_group TEXT NOT NULL,
+    event_seq INTEGER NOT NULL,
+    slot INTEGER NOT NULL,
+    block_time INTEGER,
+    signature TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS group_members_group ON group_members (market_group);
+";
+
+pub struct Db {
+    conn: Connection,
+}
+
+// Where an event comes from
+struct EventSource<'a> {
+    signature: &'a str,
+    event_index: usize,
+    slot: u64,
+    block_time: Option<i64>,
+}
+
+impl Db {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::with_schema(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self> {
+        Self::with_schema(Connection::open_in_memory()?)
+    }
+
+    fn with_schema(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    // The slot to resume from, the transactions of that slot already indexed get skipped
+    pub fn last_slot(&self) -> Result<Option<u64>> {
+        Ok(self
+            .conn
+            .query_row("SELECT last_slot FROM sync_state WHERE id = 0", [], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    pub fn is_indexed(&self, signature: &str) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT 1 FROM transactions WHERE signature = ?1",
+                [signature],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    // Records a transaction with its events and moves the cursor, all or nothing. `events`
+    // holds the raw data of every event next to its decoded form.
+    pub fn index_transaction(
+        &mut self,
+        signature: &str,
+        slot: u64,
+        block_time: Option<i64>,
+        failed: bool,
+        events: &[(Vec<u8>, Event)],
+    ) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT OR IGNORE INTO transactions (signature, slot, block_time, failed)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![signature, slot, block_time, failed],
+        )?;
+        for (event_index, (data, event)) in events.iter().enumerate() {
+            let source = EventSource {
+                signature,
+                event_index,
+                slot,
+                block_time,
+            };
+            insert_event(&tx, &source, data, event)?;
+        }
+        tx.execute(
+            "INSERT INTO sync_state (id, last_slot) VALUES (0, ?1)
+             ON CONFLICT (id) DO UPDATE SET last_slot = MAX(last_slot, excluded.last_slot)",
+            [slot],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+fn insert_event(
+    conn: &Connection,
+    source: &EventSource,
+    data: &[u8],
+    event: &Event,
+) -> rusqlite::Result<()> {
+    let EventSource {
+        signature,
+        event_index,
+        slot,
+        block_time,
+    } = *source;
+    conn.execute(
+        "INSERT OR IGNORE INTO events
+            (signature, event_index, slot, name, version, event_seq, data)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            signature,
+            event_index,
+            slot,
+            event.name(),
+            event.header().version,
+            event.header().event_seq,
+            data,
+        ],
+    )?;
+
+    match event {
+        Event::MarketCreatedEvent(event) => {
+            conn.execute(
+                "INSERT OR IGNORE INTO markets (address, market_factory, authority, oracle,
+                    market_number, usd_mint, yes_mint, no_mint, lp_share_mint, vault,
+                    trading_fee_bps, referral_share_bps, relay_fee, created_slot, created_at,
+                    signature)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+                params![
+                    event.market.to_string(),
+                    event.market_factory.to_string(),
+                    event.authority.to_string(),
+                    event.oracle.to_string(),
+                    event.market_number,
+                    event.usd_mint.to_string(),
+                    event.yes_mint.to_string(),
+                    event.no_mint.to_string(),
+                    event.lp_share_mint.to_string(),
+                    event.vault.to_string(),
+                    event.trading_fee_bps,
+                    event.referral_share_bps,
+                    event.relay_fee,
+                    slot,
+                    block_time,
+                    signature,
+                ],
+            )?;
+        }
+        Event::PurchasedOutcomeSharesEvent(event) => {
+            insert_trade(
+                conn,
+                source,
+                event.header.event_seq,
+                TradeRow {
+                    market: event.market.to_string(),
+                    user: event.user.to_string(),
+                    side: "buy",
+                    outcome_mint: event.wanted_shares_purchased_mint.to_string(),
+                    usd_amount: event.amount,
+                    shares: event.wanted_shares_purchased,
+                    fee: event.fee,
+                    prices: [
+                        event.yes_price_before_purchase,
+                        event.no_price_before_purchase,
+                        event.yes_price_after_purchase,
+                        event.no_price_after_purchase,
+                    ],
+                },
+            )?;
+        }
+        Event::SoldOutcomeSharesEvent(event) => {
+            insert_trade(
+                conn,
+                source,
+                event.header.event_seq,
+                TradeRow {
+                    market: event.market.to_string(),
+                    user: event.user.to_string(),
+                    side: "sell",
+                    outcome_mint: event.sold_shares_mint.to_string(),
+                    usd_amount: event.usd_received,
+                    shares: event.shares_sold,
+                    fee: event.fee,
+                    prices: [
+                        event.yes_price_before_sale,
+                        event.no_price_before_sale,
+                        event.yes_price_after_sale,
+                        event.no_price_after_sale,
+                    ],
+                },
+            )?;
+        }
+        Event::LiquidityAddedEvent(event) => {
+            conn.execute(
+                "INSERT OR IGNORE INTO liquidity (signature, event_index, market, user, kind,
+                    usd_amount, lp_shares, pool_lp_shares, event_seq, slot, block_time)
+                 VALUES (?1, ?2, ?3, ?4, 'add', ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    signature,
+                    event_index,
+                    event.market.to_string(),
+                    event.user.to_string(),
+                    event.amount,
+                    event.liquidity_shares_gained,
+                    event.pool_total_liquidity_shares,
+                    event.header.event_seq,
+                    slot,
+                    block_time,
+                ],
+            )?;
+        }
+        Event::LiquidityRemovedEvent(event) => {
+            conn.execute(
+                "INSERT OR IGNORE INTO liquidity (signature, event_index, market, user, kind,
+                    usd_amount, lp_shares, pool_lp_shares, event_seq, slot, block_time)
+                 VALUES (?1, ?2, ?3, ?4, 'remove', ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    signature,
+                    event_index,
+                    event.market.to_string(),
+                    event.user.to_string(),
+                    event.equivalent_usd,
+                    event.burnt_lp_shares,
+                    event.pool_remaining_liquidity_shares,
+                    event.header.event_seq,
+                    slot,
+                    block_time,
+                ],
+            )?;
+        }
+        Event::MarketStatusChangedEvent(event) => {
+            conn.execute(
+                "INSERT OR IGNORE INTO status_changes (signature, event_index, market,
+                    previous_status, status, changed_by, event_seq, slot, block_time)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    signature,
+                    event_index,
+                    event.market.to_string(),
+                    format!("{:?}", event.previous_status),
+                    format!("{:?}", event.status),
+                    event.changed_by.to_string(),
+                    event.header.event_seq,
+                    slot,
+                    block_time,
+                ],
+            )?;
+        }
+        // A disputed market is resolved again, the latest resolution stands
+        Event::MarketResolvedEvent(event) => {
+            conn.execute(
+                "INSERT INTO resolutions (market, solver, outcome, slot, block_time, signature)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT (market) DO UPDATE SET solver = excluded.solver,
+                    outcome = excluded.outcome, slot = excluded.slot,
+                    block_time = excluded.block_time, signature = excluded.signature
+                 WHERE excluded.slot >= resolutions.slot",
+                params![
+                    event.market.to_string(),
+                    event.solver.to_string(),
+                    event.outcome,
+                    slot,
+                    block_time,
+                    signature,
+                ],
+            )?;
+        }
+        Event::MarketJoinedGroupEvent(event) => {
+            conn.execute(
+                "INSERT OR IGNORE INTO group_members (market, market_group, event_seq, slot,
+                    block_time, signature)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    event.market.to_string(),
+                    event.group.to_string(),
+                    event.header.event_seq,
+                    slot,
+                    block_time,
+                    signature,
+                ],
+            )?;
+        }
+        Event::ResolveUserWinningsEvent(event) => {
+            conn.execute(
+                "INSERT OR IGNORE INTO claims (signature, event_index, market, user, yes_tokens,
+                    no_tokens, winning_amount, event_seq, slot, block_time)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    signature,
+                    event_index,
+                    event.market.to_string(),
+                    event.user.to_string(),
+                    event.user_yes_tokens,
+                    event.user_no_tokens,
+                    event.winning_amount,
+                    event.header.event_seq,
+                    slot,
+                    block_time,
+                ],
+            )?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+struct TradeRow {
+    market: String,
+    user: String,
+    side: &'static str,
+    outcome_mint: String,
+    usd_amount: u64,
+    shares: u64,
+    fee: u64,
+    // YES and NO before, then YES and NO after
+    prices: [u64; 4],
+}
+
+fn insert_trade(
+    conn: &Connection,
+    source: &EventSource,
+    event_seq: u64,
+    trade: TradeRow,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO trades (signature, event_index, market, user, side, outcome_mint,
+            usd_amount, shares, fee, yes_price_before, no_price_before, yes_price_after,
+            no_price_after, event_seq, slot, block_time)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+        params![
+            source.signature,
+            source.event_index,
+            trade.market,
+            trade.user,
+            trade.side,
+            trade.outcome_mint,
+            trade.usd_amount,
+            trade.shares,
+            trade.fee,
+            trade.prices[0],
+            trade.prices[1],
+            trade.prices[2],
+            trade.prices[3],
+            event_seq,
+            source.slot,
+            source.block_time,
+        ],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::prelude::Pubkey;
+    use anchor_lang::Event as _;
+    use solana_bet_placing_market::{
+        EventHeader, MarketJoinedGroupEvent, PurchasedOutcomeSharesEvent, EVENT_SCHEMA_VERSION,
+    };
+
+    fn header(event_seq: u64) -> EventHeader {
+        EventHeader {
+            version: EVENT_SCHEMA_VERSION,
+            event_seq,
+            slot: 10,
+            unix_timestamp: 1_700_000_000,
+        }
+    }
+
+    fn purchase(event_seq: u64) -> (Vec<u8>, Event) {
+        let event = PurchasedOutcomeSharesEvent {
+            header: header(event_seq),
+            market: Pubkey::new_unique(),
+            user: Pubkey::new_unique(),
+            amount: 100,
+            wanted_shares_purchased: 180,
+            wanted_shares_purchased_mint: Pubkey::new_unique(),
+            fee: 1,
+            yes_price_before_purchase: 500_000,
+            no_price_before_purchase: 500_000,
+            yes_price_after_purchase: 640_000,
+            no_price_after_purchase: 360_000,
+            pool_remaining_yes_tokens: 20,
+            pool_remaining_no_tokens: 200,
+        };
+        let data = event.data();
+        (data, Event::PurchasedOutcomeSharesEvent(event))
+    }
+
+    fn count(db: &Db, table: &str) -> u64 {
+        db.conn
+            .query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn indexing_is_idempotent_and_moves_the_cursor() {
+        let mut db = Db::open_in_memory().unwrap();
+        assert_eq!(db.last_slot().unwrap(), None);
+
+        let events = [purchase(4), purchase(5)];
+        db.index_transaction("sig", 10, Some(1_700_000_000), false, &events)
+            .unwrap();
+        db.index_transaction("sig", 10, Some(1_700_000_000), false, &events)
+            .unwrap();
+        assert!(db.is_indexed("sig").unwrap());
+        assert_eq!(count(&db, "events"), 2);
+        assert_eq!(count(&db, "trades"), 2);
+        let (side, fee): (String, u64) = db
+            .conn
+            .query_row(
+                "SELECT side, fee FROM trades WHERE event_seq = 5",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((side.as_str(), fee), ("buy", 1));
+
+        // The cursor never goes back
+        db.index_transaction("failed", 8, None, true, &[]).unwrap();
+        assert_eq!(db.last_slot().unwrap(), Some(10));
+    }
+
+    #[test]
+    fn group_members_are_recorded_as_they_join() {
+        let mut db = Db::open_in_memory().unwrap();
+        let group = Pubkey::new_unique();
+        let events: Vec<_> = (0..2)
+            .map(|_| {
+                let event = MarketJoinedGroupEvent {
+                    header: header(4),
+                    market: Pubkey::new_unique(),
+                    group,
+                };
+                (event.data(), Event::MarketJoinedGroupEvent(event))
+            })
+            .collect();
+        db.index_transaction("sig", 10, None, false, &events)
+            .unwrap();
+
+        let members: u64 = db
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM group_members WHERE market_group = ?1",
+                [group.to_string()],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(members, 2);
+    }
+}
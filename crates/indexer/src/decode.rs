@@ -0,0 +1,73 @@
+use anchor_lang::prelude::Pubkey;
+use market_client::events::{cpi_event, log_events};
+
+use crate::rpc::Transaction;
+
+// The raw event data of a transaction, in emission order. Programs built with `event-cpi`
+// emit through self-CPIs, the others through `Program data:` logs of the program itself.
+pub fn transaction_events(transaction: &Transaction, program_id: &Pubkey) -> Vec<Vec<u8>> {
+    let mut events = log_events(&transaction.log_messages, program_id);
+    for (instruction_program, data) in &transaction.inner_instructions {
+        if instruction_program == program_id {
+            if let Some(event) = cpi_event(data) {
+                events.push(event.to_vec());
+            }
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::event::EVENT_IX_TAG_LE;
+    use anchor_lang::Event as _;
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use solana_bet_placing_market::{EventHeader, MarketResolvedEvent, EVENT_SCHEMA_VERSION, ID};
+
+    fn header(event_seq: u64) -> EventHeader {
+        EventHeader {
+            version: EVENT_SCHEMA_VERSION,
+            event_seq,
+            slot: 7,
+            unix_timestamp: 1_700_000_000,
+        }
+    }
+
+    fn resolved_event() -> MarketResolvedEvent {
+        MarketResolvedEvent {
+            header: header(3),
+            market: Pubkey::new_unique(),
+            solver: Pubkey::new_unique(),
+            outcome: 1,
+        }
+    }
+
+    #[test]
+    fn only_the_data_logged_by_the_program_is_taken() {
+        let event = resolved_event().data();
+        let other = Pubkey::new_unique();
+        let logs = [
+            format!("Program {} invoke [1]", ID),
+            "Program log: Instruction: ResolveMarket".to_owned(),
+            format!("Program {other} invoke [2]"),
+            "Program data: AAAA".to_owned(),
+            format!("Program {other} success"),
+            format!("Program data: {}", BASE64_STANDARD.encode(&event)),
+            format!("Program {} consumed 5000 of 200000 compute units", ID),
+            format!("Program {} success", ID),
+            "Program data: AAAA".to_owned(),
+        ];
+        let transaction = Transaction {
+            block_time: None,
+            log_messages: logs.to_vec(),
+            inner_instructions: vec![
+                (other, [EVENT_IX_TAG_LE, &event].concat()),
+                (ID, [EVENT_IX_TAG_LE, &event].concat()),
+            ],
+        };
+
+        let events = transaction_events(&transaction, &ID);
+        assert_eq!(events, vec![event.clone(), event]);
+    }
+}
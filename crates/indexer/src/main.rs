@@ -0,0 +1,134 @@
+// Reads the transactions of the market program from an RPC endpoint and writes their events
+// into SQLite. Every run resumes from the last indexed slot, `--from-slot` backfills from an
+// earlier one.
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use anchor_lang::prelude::Pubkey;
+use clap::Parser;
+
+mod db;
+mod decode;
+mod rpc;
+
+use db::Db;
+use market_client::events::Event;
+use rpc::{RpcClient, SignatureInfo};
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// The most `getSignaturesForAddress` returns at once
+const SIGNATURES_PAGE: usize = 1_000;
+
+#[derive(Parser)]
+#[command(about = "Indexes the market program events into SQLite")]
+struct Args {
+    /// RPC endpoint to read the transactions from
+    #[arg(long, default_value = "http://127.0.0.1:8899")]
+    rpc_url: String,
+
+    /// SQLite database, created when missing
+    #[arg(long, default_value = "market-events.sqlite")]
+    db: PathBuf,
+
+    /// The market program to index
+    #[arg(long, default_value_t = solana_bet_placing_market::ID)]
+    program_id: Pubkey,
+
+    /// Index from this slot instead of the last indexed one, 0 for a full backfill
+    #[arg(long)]
+    from_slot: Option<u64>,
+
+    /// Commitment of the indexed transactions
+    #[arg(long, default_value = "finalized")]
+    commitment: String,
+
+    /// Keep indexing new transactions, polling every this many seconds
+    #[arg(long)]
+    poll: Option<u64>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let rpc = RpcClient::new(args.rpc_url, args.commitment);
+    let mut db = Db::open(&args.db)?;
+
+    let mut from_slot = args.from_slot;
+    loop {
+        let start = match from_slot.take() {
+            Some(slot) => slot,
+            None => db.last_slot()?.unwrap_or(0),
+        };
+        let indexed = sync(&rpc, &mut db, &args.program_id, start)?;
+        eprintln!(
+            "indexed {} transactions and {} events from slot {start}",
+            indexed.transactions, indexed.events
+        );
+
+        match args.poll {
+            Some(seconds) => thread::sleep(Duration::from_secs(seconds)),
+            None => return Ok(()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Indexed {
+    transactions: usize,
+    events: usize,
+}
+
+// Indexes the program transactions from `from_slot` on, oldest first so that the cursor
+// only moves forward and an interrupted run resumes where it stopped
+fn sync(rpc: &RpcClient, db: &mut Db, program_id: &Pubkey, from_slot: u64) -> Result<Indexed> {
+    let pending = pending_signatures(rpc, db, program_id, from_slot)?;
+
+    let mut indexed = Indexed::default();
+    for info in pending.iter().rev() {
+        let mut events = Vec::new();
+        let mut block_time = None;
+        if !info.failed {
+            // Pruned by the node, the slot is still recorded so that it is not asked again
+            if let Some(transaction) = rpc.transaction(&info.signature)? {
+                block_time = transaction.block_time;
+                for data in decode::transaction_events(&transaction, program_id) {
+                    if let Some(event) = Event::decode(&data) {
+                        events.push((data, event));
+                    }
+                }
+            }
+        }
+        db.index_transaction(&info.signature, info.slot, block_time, info.failed, &events)?;
+        indexed.transactions += 1;
+        indexed.events += events.len();
+    }
+    Ok(indexed)
+}
+
+// The signatures not indexed yet down to `from_slot`, newest first
+fn pending_signatures(
+    rpc: &RpcClient,
+    db: &Db,
+    program_id: &Pubkey,
+    from_slot: u64,
+) -> Result<Vec<SignatureInfo>> {
+    let mut pending = Vec::new();
+    let mut before: Option<String> = None;
+    loop {
+        let page = rpc.signatures_for_address(program_id, before.as_deref(), SIGNATURES_PAGE)?;
+        let last_page = page.len() < SIGNATURES_PAGE;
+        before = page.last().map(|info| info.signature.clone());
+        for info in page {
+            if info.slot < from_slot {
+                return Ok(pending);
+            }
+            if !db.is_indexed(&info.signature)? {
+                pending.push(info);
+            }
+        }
+        if last_page {
+            return Ok(pending);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use anchor_lang::prelude::Pubkey;
+use serde_json::{json, Value};
+
+use crate::Result;
+
+// The JSON-RPC calls of the indexer, over plain blocking HTTP
+pub struct RpcClient {
+    url: String,
+    commitment: String,
+    agent: ureq::Agent,
+}
+
+pub struct SignatureInfo {
+    pub signature: String,
+    pub slot: u64,
+    pub failed: bool,
+}
+
+pub struct Transaction {
+    pub block_time: Option<i64>,
+    pub log_messages: Vec<String>,
+    // Program id and data of every inner instruction, in execution order
+    pub inner_instructions: Vec<(Pubkey, Vec<u8>)>,
+}
+
+impl RpcClient {
+    pub fn new(url: String, commitment: String) -> Self {
+        Self {
+            url,
+            commitment,
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let mut response: Value = self
+            .agent
+            .post(&self.url)
+            .send_json(json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": method,
+                "params": params,
+            }))?
+            .into_json()?;
+        if let Some(error) = response.get("error") {
+            return Err(format!("{method} failed: {error}").into());
+        }
+        Ok(response["result"].take())
+    }
+
+    // Newest first, older than `before` when given
+    pub fn signatures_for_address(
+        &self,
+        address: &Pubkey,
+        before: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<SignatureInfo>> {
+        let mut config = json!({ "limit": limit, "commitment": self.commitment });
+        if let Some(before) = before {
+            config["before"] = json!(before);
+        }
+        let result = self.request(
+            "getSignaturesForAddress",
+            json!([address.to_string(), config]),
+        )?;
+
+        let mut signatures = Vec::new();
+        for entry in result
+            .as_array()
+            .ok_or("getSignaturesForAddress: not an array")?
+        {
+            signatures.push(SignatureInfo {
+                signature: string(entry, "signature")?.to_owned(),
+                slot: entry["slot"]
+                    .as_u64()
+                    .ok_or("getSignaturesForAddress: no slot")?,
+                failed: !entry["err"].is_null(),
+            });
+        }
+        Ok(signatures)
+    }
+
+    // `None` when the node does not have the transaction (anymore)
+    pub fn transaction(&self, signature: &str) -> Result<Option<Transaction>> {
+        let result = self.request(
+            "getTransaction",
+            json!([signature, {
+                "encoding": "json",
+                "commitment": self.commitment,
+                "maxSupportedTransactionVersion": 0,
+            }]),
+        )?;
+        if result.is_null() {
+            return Ok(None);
+        }
+
+        let meta = &result["meta"];
+        let log_messages = meta["logMessages"]
+            .as_array()
+            .map(|logs| {
+                logs.iter()
+                    .filter_map(|log| log.as_str().map(str::to_owned))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Versioned transactions index into the loaded addresses after the static keys
+        let mut account_keys = Vec::new();
+        for keys in [
+            &result["transaction"]["message"]["accountKeys"],
+            &meta["loadedAddresses"]["writable"],
+            &meta["loadedAddresses"]["readonly"],
+        ] {
+            for key in keys.as_array().into_iter().flatten() {
+                let key = key.as_str().ok_or("getTransaction: invalid account key")?;
+                account_keys.push(key.parse::<Pubkey>()?);
+            }
+        }
+
+        let mut inner_instructions = Vec::new();
+        for group in meta["innerInstructions"].as_array().into_iter().flatten() {
+            for instruction in group["instructions"].as_array().into_iter().flatten() {
+                let program_id = instruction["programIdIndex"]
+                    .as_u64()
+                    .and_then(|index| account_keys.get(index as usize))
+                    .ok_or("getTransaction: invalid program id index")?;
+                let data = bs58::decode(string(instruction, "data")?).into_vec()?;
+                inner_instructions.push((*program_id, data));
+            }
+        }
+
+        Ok(Some(Transaction {
+            block_time: result["blockTime"].as_i64(),
+            log_messages,
+            inner_instructions,
+        }))
+    }
+}
+
+fn string<'a>(value: &'a Value, field: &str) -> Result<&'a str> {
+    value[field]
+        .as_str()
+        .ok_or_else(|| format!("missing `{field}` in the RPC response").into())
+}
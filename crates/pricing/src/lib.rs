@@ -0,0 +1,269 @@
+// The pricing of the market program for the web app, built with
+// `yarn build:pricing` into `src/blockchain/pricing`. The math is the very file the program
+// compiles, so the quotes shown are the amounts the instructions end up moving.
+//
+// Amounts are in base units and prices are scaled by `SCALE`, both as `bigint` on the
+// JavaScript side. Outcomes use the `Market.outcome` encoding: 0 for NO, 1 for YES.
+use wasm_bindgen::prelude::*;
+
+// Only the quoting part of it is exported
+#[allow(dead_code)]
+#[path = "../../../programs/solana-bet-placing-market/src/math.rs"]
+mod math;
+
+use math::{MathError, MathResult, Outcome, PoolState};
+
+// What a price of 1 USD is
+#[wasm_bindgen]
+pub fn scale() -> u64 {
+    math::SCALE as u64
+}
+
+fn outcome(outcome: u8) -> Result<Outcome, JsError> {
+    Outcome::from_u8(outcome).ok_or_else(|| JsError::new("the outcome is either 0 or 1"))
+}
+
+fn quote<T>(result: MathResult<T>) -> Result<T, JsError> {
+    result.map_err(|error| {
+        JsError::new(match error {
+            MathError::Overflow => "the amounts overflow",
+            MathError::EmptyPool => "the pool has no liquidity",
+            MathError::InvalidWindow => "invalid averaging window",
+        })
+    })
+}
+
+// A snapshot of a `MarketPool`, with the fees of its `Market`
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct Pool {
+    state: PoolState,
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct Prices {
+    pub yes_price: u64,
+    pub no_price: u64,
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct BuyQuote {
+    pub shares_purchased: u64,
+    pub shares_from_pool: u64,
+    pub fee: u64,
+    pub referral_fee: u64,
+    pub yes_price_before: u64,
+    pub no_price_before: u64,
+    pub yes_price_after: u64,
+    pub no_price_after: u64,
+    pub yes_liquidity_after: u64,
+    pub no_liquidity_after: u64,
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct SellQuote {
+    pub usd_received: u64,
+    pub fee: u64,
+    pub referral_fee: u64,
+    pub yes_price_before: u64,
+    pub no_price_before: u64,
+    pub yes_price_after: u64,
+    pub no_price_after: u64,
+    pub yes_liquidity_after: u64,
+    pub no_liquidity_after: u64,
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct AddLiquidityQuote {
+    pub liquidity_shares_gained: u64,
+    pub yes_to_user: u64,
+    pub no_to_user: u64,
+    pub yes_to_pool: u64,
+    pub no_to_pool: u64,
+    pub liquidity_value_after: u64,
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct RemoveLiquidityQuote {
+    pub usd_received: u64,
+    // The tokens of `outcome_given` handed back to keep the odds, none once resolved
+    pub outcome_tokens_received: u64,
+    pub outcome_given: Option<u8>,
+    pub yes_liquidity_after: u64,
+    pub no_liquidity_after: u64,
+}
+
+#[wasm_bindgen]
+impl Pool {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        yes_liquidity: u64,
+        no_liquidity: u64,
+        liquidity_value: u64,
+        liquidity_shares: u64,
+        trading_fee_bps: u16,
+    ) -> Pool {
+        Pool {
+            state: PoolState {
+                yes_liquidity,
+                no_liquidity,
+                liquidity_value,
+                liquidity_shares,
+                fee_bps: trading_fee_bps,
+                referral_share_bps: 0,
+            },
+        }
+    }
+
+    // The same pool for a referred trade, which pays part of the fee to the referrer
+    pub fn referred(&self, referral_share_bps: u16) -> Pool {
+        Pool {
+            state: PoolState {
+                referral_share_bps,
+                ..self.state
+            },
+        }
+    }
+
+    pub fn prices(&self) -> Result<Prices, JsError> {
+        let (yes_price, no_price) = quote(math::prices(
+            self.state.yes_liquidity,
+            self.state.no_liquidity,
+        ))?;
+        Ok(Prices {
+            yes_price,
+            no_price,
+        })
+    }
+
+    pub fn buy(&self, usd_amount: u64, purchased_outcome: u8) -> Result<BuyQuote, JsError> {
+        let purchase = quote(math::buy(
+            &self.state,
+            usd_amount,
+            outcome(purchased_outcome)?,
+        ))?;
+        let (yes_price_after, no_price_after) = quote(math::prices(
+            purchase.yes_liquidity_after,
+            purchase.no_liquidity_after,
+        ))?;
+        Ok(BuyQuote {
+            shares_purchased: purchase.shares_purchased,
+            shares_from_pool: purchase.shares_from_pool,
+            fee: purchase.fee,
+            referral_fee: purchase.referral_fee,
+            yes_price_before: purchase.yes_price_before,
+            no_price_before: purchase.no_price_before,
+            yes_price_after,
+            no_price_after,
+            yes_liquidity_after: purchase.yes_liquidity_after,
+            no_liquidity_after: purchase.no_liquidity_after,
+        })
+    }
+
+    pub fn sell(&self, shares: u64, sold_outcome: u8) -> Result<SellQuote, JsError> {
+        let sale = quote(math::sell(&self.state, shares, outcome(sold_outcome)?))?;
+        let (yes_price_after, no_price_after) = quote(math::prices(
+            sale.yes_liquidity_after,
+            sale.no_liquidity_after,
+        ))?;
+        Ok(SellQuote {
+            usd_received: sale.usd_returned,
+            fee: sale.fee,
+            referral_fee: sale.referral_fee,
+            yes_price_before: sale.yes_price_before,
+            no_price_before: sale.no_price_before,
+            yes_price_after,
+            no_price_after,
+            yes_liquidity_after: sale.yes_liquidity_after,
+            no_liquidity_after: sale.no_liquidity_after,
+        })
+    }
+
+    pub fn add_liquidity(&self, usd_amount: u64) -> Result<AddLiquidityQuote, JsError> {
+        let added = quote(math::add_liquidity(&self.state, usd_amount))?;
+        Ok(AddLiquidityQuote {
+            liquidity_shares_gained: added.liquidity_shares_gained,
+            yes_to_user: added.yes_to_user,
+            no_to_user: added.no_to_user,
+            yes_to_pool: added.yes_to_pool,
+            no_to_pool: added.no_to_pool,
+            liquidity_value_after: added.liquidity_value_after,
+        })
+    }
+
+    // `resolved_outcome` is the outcome of the market once resolved
+    pub fn remove_liquidity(
+        &self,
+        shares: u64,
+        resolved_outcome: Option<u8>,
+    ) -> Result<RemoveLiquidityQuote, JsError> {
+        let resolved_outcome = resolved_outcome.map(outcome).transpose()?;
+        let removed = quote(math::remove_liquidity(
+            &self.state,
+            shares,
+            resolved_outcome,
+        ))?;
+        Ok(RemoveLiquidityQuote {
+            usd_received: removed.usd_to_user,
+            outcome_tokens_received: removed.outcome_tokens_to_user,
+            outcome_given: removed.outcome_given.map(|outcome| outcome as u8),
+            yes_liquidity_after: removed.yes_liquidity_after,
+            no_liquidity_after: removed.no_liquidity_after,
+        })
+    }
+}
+
+// What claiming pays for these tokens once the market resolved to `outcome`
+#[wasm_bindgen]
+pub fn settlement_value(yes_tokens: u64, no_tokens: u64, outcome: u8) -> Result<u64, JsError> {
+    Ok(math::settlement_value(
+        yes_tokens,
+        no_tokens,
+        self::outcome(outcome)?,
+    ))
+}
+
+#[cfg(test)]
+mod quotes {
+    use super::*;
+
+    const USD: u64 = 1_000_000_000;
+
+    #[test]
+    fn quotes_are_the_program_math() {
+        // 100 USD of liquidity leaning towards NO, with a 1% fee
+        let pool = Pool::new(125 * USD, 80 * USD, 100 * USD, 100 * USD, 100);
+
+        let prices = pool.prices().unwrap();
+        assert_eq!(
+            (prices.yes_price, prices.no_price),
+            math::prices(125 * USD, 80 * USD).unwrap()
+        );
+
+        let referred = pool.referred(2_000);
+        let purchase = referred.buy(10 * USD, 1).unwrap();
+        let expected = math::buy(&referred.state, 10 * USD, Outcome::Yes).unwrap();
+        assert_eq!(purchase.shares_purchased, expected.shares_purchased);
+        assert_eq!(purchase.fee, expected.fee);
+        assert_eq!(purchase.referral_fee, expected.referral_fee);
+        assert!(purchase.yes_price_after > purchase.yes_price_before);
+
+        let sale = pool.sell(purchase.shares_purchased, 1).unwrap();
+        let expected = math::sell(&pool.state, purchase.shares_purchased, Outcome::Yes).unwrap();
+        assert_eq!(sale.usd_received, expected.usd_returned);
+        assert_eq!(sale.referral_fee, 0);
+
+        let removed = pool.remove_liquidity(10 * USD, None).unwrap();
+        assert_eq!(removed.outcome_given, Some(1));
+        let removed = pool.remove_liquidity(10 * USD, Some(0)).unwrap();
+        assert_eq!(removed.usd_received, 8 * USD);
+        assert_eq!(removed.outcome_given, None);
+
+        assert_eq!(settlement_value(3 * USD, 2 * USD, 1).unwrap(), 3 * USD);
+    }
+}
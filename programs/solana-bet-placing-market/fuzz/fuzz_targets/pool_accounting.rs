@@ -0,0 +1,395 @@
+// Random sequences of add/remove/buy/sell/resolve/claim against the pure AMM math, with a
+// random trading fee and referral share.
+//
+// The model keeps the same books as the program: the vault, the pool, the outcome token
+// supplies and every user's balances. An action the program would reject is skipped.
+// After every accepted action it checks that:
+// - the vault still covers every outstanding claim on it;
+// - nobody gets more than what backs the payout: claims pay exactly the winning tokens
+//   burnt, and an LP never gets more than its share of the pool.
+// Once the sequence is over, everybody claims and exits, and all the payouts together,
+// referral fees included, may not exceed what was deposited.
+//
+// Run it with `cargo fuzz run pool_accounting` from this directory.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use solana_bet_placing_market::math::{self, Outcome, PoolState, SCALE};
+use solana_bet_placing_market::MarketFactory;
+
+const USERS: usize = 4;
+// Up to 10k USD per action, small enough to never overflow the pool
+const MAX_AMOUNT: u64 = 10_000 * SCALE as u64;
+// The pool rounds in several places, each of them worth at most a few base units
+const ROUNDING: u64 = 16;
+
+#[derive(Arbitrary, Debug, Clone, Copy)]
+enum Action {
+    AddLiquidity {
+        user: u8,
+        usd_amount: u64,
+    },
+    RemoveLiquidity {
+        user: u8,
+        percent: u8,
+    },
+    Buy {
+        user: u8,
+        usd_amount: u64,
+        yes: bool,
+        referred: bool,
+    },
+    Sell {
+        user: u8,
+        percent: u8,
+        yes: bool,
+        referred: bool,
+    },
+    Resolve {
+        yes: bool,
+    },
+    Claim {
+        user: u8,
+    },
+}
+
+#[derive(Default, Debug)]
+struct User {
+    deposited: u64,
+    withdrawn: u64,
+    yes: u64,
+    no: u64,
+    lp: u64,
+}
+
+#[derive(Default, Debug)]
+struct Model {
+    pool: PoolState,
+    vault: u64,
+    yes_supply: u64,
+    no_supply: u64,
+    resolved: Option<Outcome>,
+    users: [User; USERS],
+    // The market's referral share, and what the referrers got out of it
+    referral_share_bps: u16,
+    referrals_paid: u64,
+}
+
+fn outcome(yes: bool) -> Outcome {
+    if yes {
+        Outcome::Yes
+    } else {
+        Outcome::No
+    }
+}
+
+// `part / whole` of `amount`, rounded up
+fn share_of(amount: u64, part: u64, whole: u64) -> u64 {
+    (amount as u128 * part as u128).div_ceil(whole as u128) as u64
+}
+
+impl Model {
+    fn apply(&mut self, action: Action) {
+        match action {
+            Action::AddLiquidity { user, usd_amount } => {
+                self.add_liquidity(user as usize % USERS, usd_amount % MAX_AMOUNT)
+            }
+            Action::RemoveLiquidity { user, percent } => {
+                let user = user as usize % USERS;
+                let shares = share_of(self.users[user].lp, (percent % 100) as u64 + 1, 100);
+                self.remove_liquidity(user, shares)
+            }
+            Action::Buy {
+                user,
+                usd_amount,
+                yes,
+                referred,
+            } => self.buy(
+                user as usize % USERS,
+                usd_amount % MAX_AMOUNT,
+                outcome(yes),
+                referred,
+            ),
+            Action::Sell {
+                user,
+                percent,
+                yes,
+                referred,
+            } => {
+                let user = user as usize % USERS;
+                let held = match outcome(yes) {
+                    Outcome::Yes => self.users[user].yes,
+                    Outcome::No => self.users[user].no,
+                };
+                let shares = share_of(held, (percent % 100) as u64 + 1, 100);
+                self.sell(user, shares, outcome(yes), referred)
+            }
+            Action::Resolve { yes } => self.resolve(outcome(yes)),
+            Action::Claim { user } => self.claim(user as usize % USERS),
+        }
+        self.check_solvency();
+    }
+
+    fn add_liquidity(&mut self, user: usize, usd_amount: u64) {
+        if usd_amount == 0 || self.resolved.is_some() {
+            return;
+        }
+        let Ok(added) = math::add_liquidity(&self.pool, usd_amount) else {
+            return;
+        };
+
+        let user = &mut self.users[user];
+        user.deposited += usd_amount;
+        user.yes += added.yes_to_user;
+        user.no += added.no_to_user;
+        user.lp += added.liquidity_shares_gained;
+        self.vault += usd_amount;
+        self.yes_supply += usd_amount;
+        self.no_supply += usd_amount;
+        self.pool.yes_liquidity += added.yes_to_pool;
+        self.pool.no_liquidity += added.no_to_pool;
+        self.pool.liquidity_value = added.liquidity_value_after;
+        self.pool.liquidity_shares = added.liquidity_shares_after;
+    }
+
+    fn remove_liquidity(&mut self, user: usize, shares: u64) {
+        if shares == 0 || shares > self.users[user].lp {
+            return;
+        }
+        let Ok(removed) = math::remove_liquidity(&self.pool, shares, self.resolved) else {
+            return;
+        };
+        let (yes_given, no_given) = match removed.outcome_given {
+            Some(Outcome::Yes) => (removed.outcome_tokens_to_user, 0),
+            Some(Outcome::No) => (0, removed.outcome_tokens_to_user),
+            None => (0, 0),
+        };
+        // The token program would refuse to move more than the pool holds
+        if removed.yes_burnt + yes_given > self.pool.yes_liquidity
+            || removed.no_burnt + no_given > self.pool.no_liquidity
+        {
+            return;
+        }
+
+        // Every USD paid out comes from a complete set burnt out of the pool, and the LP
+        // gets at most its share of each side of the pool.
+        let total_shares = self.pool.liquidity_shares;
+        match self.resolved {
+            None => {
+                assert!(
+                    removed.usd_to_user <= removed.yes_burnt.min(removed.no_burnt),
+                    "{removed:?} pays more than the complete sets it burns"
+                );
+                assert!(
+                    removed.usd_to_user + yes_given
+                        <= share_of(self.pool.yes_liquidity, shares, total_shares) + ROUNDING,
+                    "{shares} of {total_shares} shares take too many YES: {removed:?} from {:?}",
+                    self.pool
+                );
+                assert!(
+                    removed.usd_to_user + no_given
+                        <= share_of(self.pool.no_liquidity, shares, total_shares) + ROUNDING,
+                    "{shares} of {total_shares} shares take too many NO: {removed:?} from {:?}",
+                    self.pool
+                );
+            }
+            Some(resolved) => {
+                let winning_liquidity = math::resolved_liquidity_value(&self.pool, resolved);
+                assert!(
+                    removed.usd_to_user <= share_of(winning_liquidity, shares, total_shares),
+                    "{shares} of {total_shares} shares take too much: {removed:?} from {:?}",
+                    self.pool
+                );
+            }
+        }
+        self.pay(user, removed.usd_to_user);
+
+        let user = &mut self.users[user];
+        user.lp -= shares;
+        user.yes += yes_given;
+        user.no += no_given;
+        self.yes_supply -= removed.yes_burnt;
+        self.no_supply -= removed.no_burnt;
+        self.pool.yes_liquidity = removed.yes_liquidity_after;
+        self.pool.no_liquidity = removed.no_liquidity_after;
+        self.pool.liquidity_value = removed.liquidity_value_after;
+        self.pool.liquidity_shares = removed.liquidity_shares_after;
+    }
+
+    // The pool as a trade sees it, with the referral share only when there is a referrer
+    fn trading_state(&self, referred: bool) -> PoolState {
+        PoolState {
+            referral_share_bps: if referred {
+                self.referral_share_bps
+            } else {
+                0
+            },
+            ..self.pool
+        }
+    }
+
+    fn buy(&mut self, user: usize, usd_amount: u64, outcome: Outcome, referred: bool) {
+        if usd_amount == 0 || self.resolved.is_some() {
+            return;
+        }
+        let Ok(purchase) = math::buy(&self.trading_state(referred), usd_amount, outcome) else {
+            return;
+        };
+        assert_eq!(
+            purchase.shares_purchased,
+            usd_amount - purchase.fee + purchase.shares_from_pool
+        );
+
+        let user = &mut self.users[user];
+        user.deposited += usd_amount;
+        match outcome {
+            Outcome::Yes => user.yes += purchase.shares_purchased,
+            Outcome::No => user.no += purchase.shares_purchased,
+        }
+        // The referral fee goes from the user to the referrer, never through the vault
+        let minted = usd_amount - purchase.referral_fee;
+        self.referrals_paid += purchase.referral_fee;
+        self.vault += minted;
+        self.yes_supply += minted;
+        self.no_supply += minted;
+        self.pool.yes_liquidity = purchase.yes_liquidity_after;
+        self.pool.no_liquidity = purchase.no_liquidity_after;
+        self.pool.liquidity_value = purchase.liquidity_value_after;
+    }
+
+    fn sell(&mut self, user: usize, shares: u64, outcome: Outcome, referred: bool) {
+        if shares == 0 || self.resolved.is_some() {
+            return;
+        }
+        let Ok(sale) = math::sell(&self.trading_state(referred), shares, outcome) else {
+            return;
+        };
+        if sale.usd_returned == 0 {
+            return;
+        }
+        // Selling can only ever pay less than the shares would redeem for
+        assert!(sale.usd_returned <= shares, "{shares} shares sold for {sale:?}");
+        let product = sale.yes_liquidity_after as u128 * sale.no_liquidity_after as u128;
+        assert!(
+            product >= (self.pool.liquidity_value as u128).pow(2),
+            "{sale:?} erodes the constant product of {:?}",
+            self.pool
+        );
+        self.pay(user, sale.usd_returned);
+        assert!(sale.referral_fee <= self.vault);
+        self.vault -= sale.referral_fee;
+        self.referrals_paid += sale.referral_fee;
+
+        let user = &mut self.users[user];
+        match outcome {
+            Outcome::Yes => user.yes -= shares,
+            Outcome::No => user.no -= shares,
+        }
+        let burnt = sale.usd_returned + sale.referral_fee;
+        self.yes_supply -= burnt;
+        self.no_supply -= burnt;
+        self.pool.yes_liquidity = sale.yes_liquidity_after;
+        self.pool.no_liquidity = sale.no_liquidity_after;
+        self.pool.liquidity_value = sale.liquidity_value_after;
+    }
+
+    fn resolve(&mut self, outcome: Outcome) {
+        if self.resolved.is_some() {
+            return;
+        }
+        self.resolved = Some(outcome);
+        self.pool.liquidity_value = math::resolved_liquidity_value(&self.pool, outcome);
+    }
+
+    fn claim(&mut self, user: usize) {
+        let Some(resolved) = self.resolved else {
+            return;
+        };
+        let (yes, no) = (self.users[user].yes, self.users[user].no);
+        if yes == 0 && no == 0 {
+            return;
+        }
+
+        let payout = math::settlement_value(yes, no, resolved);
+        let winning_tokens = match resolved {
+            Outcome::Yes => yes,
+            Outcome::No => no,
+        };
+        assert_eq!(
+            payout, winning_tokens,
+            "a claim must pay 1 USD per winning token"
+        );
+        self.pay(user, payout);
+
+        let user = &mut self.users[user];
+        user.yes = 0;
+        user.no = 0;
+        self.yes_supply -= yes;
+        self.no_supply -= no;
+    }
+
+    fn pay(&mut self, user: usize, usd_amount: u64) {
+        assert!(
+            usd_amount <= self.vault,
+            "paying {usd_amount} out of a vault of {}",
+            self.vault
+        );
+        self.vault -= usd_amount;
+        self.users[user].withdrawn += usd_amount;
+    }
+
+    // Every outstanding winning token is a 1 USD claim on the vault, including the ones
+    // still held by the pool on behalf of the LPs
+    fn check_solvency(&self) {
+        let liabilities = match self.resolved {
+            None => self.yes_supply.max(self.no_supply),
+            Some(Outcome::Yes) => self.yes_supply,
+            Some(Outcome::No) => self.no_supply,
+        };
+        assert!(
+            self.vault >= liabilities,
+            "vault {} below the liabilities {liabilities}: {self:?}",
+            self.vault
+        );
+
+        let users_yes: u64 = self.users.iter().map(|user| user.yes).sum();
+        let users_no: u64 = self.users.iter().map(|user| user.no).sum();
+        assert_eq!(users_yes + self.pool.yes_liquidity, self.yes_supply);
+        assert_eq!(users_no + self.pool.no_liquidity, self.no_supply);
+    }
+
+    // Everybody claims and every LP exits
+    fn settle(&mut self, outcome: Outcome) {
+        self.resolve(outcome);
+        for user in 0..USERS {
+            self.claim(user);
+            self.check_solvency();
+        }
+        for user in 0..USERS {
+            let shares = self.users[user].lp;
+            self.remove_liquidity(user, shares);
+            self.check_solvency();
+        }
+
+        let deposited: u64 = self.users.iter().map(|user| user.deposited).sum();
+        let withdrawn: u64 = self.users.iter().map(|user| user.withdrawn).sum::<u64>()
+            + self.referrals_paid;
+        assert!(
+            withdrawn <= deposited,
+            "{withdrawn} withdrawn out of {deposited} deposited"
+        );
+        assert_eq!(self.vault, deposited - withdrawn);
+    }
+}
+
+fuzz_target!(|input: (Vec<Action>, bool, u16, u16)| {
+    let (actions, outcome_yes, fee_bps, referral_share_bps) = input;
+    let mut model = Model::default();
+    model.pool.fee_bps = fee_bps % (MarketFactory::MAX_TRADING_FEE_BPS + 1);
+    model.referral_share_bps = referral_share_bps % (MarketFactory::MAX_REFERRAL_SHARE_BPS + 1);
+    for action in actions {
+        model.apply(action);
+    }
+    model.settle(outcome(outcome_yes));
+});
@@ -0,0 +1,196 @@
+// The older account layouts, as `migrate_market` reads them. Version 0 has no `version`
+// and no `reserved` room, so every new field changed its size. Version 1 of the pool is
+// the last one serialized with Borsh, the market is still read as a `Market` from then on.
+use anchor_lang::prelude::*;
+
+use crate::{Market, MarketPool, MarketStatus, RiskLimits};
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MarketV0 {
+    pub usd_mint: Pubkey,
+    pub yes_mint: Pubkey,
+    pub no_mint: Pubkey,
+    pub lp_share_mint: Pubkey,
+    pub vault: Pubkey,
+    pub authority: Pubkey,
+    pub oracle: Pubkey,
+    pub market_number: u64,
+    pub market_volume: u64,
+    pub resolved: bool,
+    pub outcome: Option<u8>,
+    pub bump: u8,
+    pub trading_fee_bps: u16,
+    pub referral_share_bps: u16,
+    pub risk_limits: RiskLimits,
+    pub keeper_reward: u64,
+    pub settlement_budget: u64,
+    pub relay_fee: u64,
+    pub event_seq: u64,
+    pub status: MarketStatus,
+}
+
+impl MarketV0 {
+    // What `Market::LEN` was, the accounts being allocated with `8 + LEN` bytes
+    pub const LEN: usize = 8 + 32 * 7 + 8 * 2 + 1 + 2 + 1 + 2 + 2 + RiskLimits::LEN + 8 * 4 + 1;
+}
+
+impl From<MarketV0> for Market {
+    fn from(market: MarketV0) -> Self {
+        Market {
+            usd_mint: market.usd_mint,
+            yes_mint: market.yes_mint,
+            no_mint: market.no_mint,
+            lp_share_mint: market.lp_share_mint,
+            vault: market.vault,
+            authority: market.authority,
+            oracle: market.oracle,
+            market_number: market.market_number,
+            market_volume: market.market_volume,
+            resolved: market.resolved,
+            outcome: market.outcome,
+            bump: market.bump,
+            trading_fee_bps: market.trading_fee_bps,
+            referral_share_bps: market.referral_share_bps,
+            risk_limits: market.risk_limits,
+            keeper_reward: market.keeper_reward,
+            settlement_budget: market.settlement_budget,
+            relay_fee: market.relay_fee,
+            event_seq: market.event_seq,
+            status: market.status,
+            version: 0,
+            vault_bump: 0,
+            yes_mint_bump: 0,
+            no_mint_bump: 0,
+            lp_share_mint_bump: 0,
+            group: Pubkey::default(),
+            resolved_at: 0,
+            settlement_started: false,
+            reserved: [0; Market::RESERVED],
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MarketPoolV0 {
+    pub liquidity_yes_tokens_account: Pubkey,
+    pub liquidity_no_tokens_account: Pubkey,
+    pub yes_liquidity: u64,
+    pub no_liquidity: u64,
+    pub liquidity_value: u64,
+    pub liquidity_shares: u64,
+    pub usd_collateral: u64,
+    pub total_yes_mints: u64,
+    pub total_no_mints: u64,
+    pub bump: u8,
+    pub yes_price_cumulative: u128,
+    pub last_price_update: i64,
+    pub fees_collected: u64,
+    pub fee_per_share: u128,
+}
+
+impl MarketPoolV0 {
+    // What `MarketPool::LEN` was
+    pub const LEN: usize = 8 + 32 * 3 + 8 * 7 + 1 + 16 + 8 + 8 + 16;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MarketPoolV1 {
+    pub liquidity_yes_tokens_account: Pubkey,
+    pub liquidity_no_tokens_account: Pubkey,
+    pub yes_liquidity: u64,
+    pub no_liquidity: u64,
+    pub liquidity_value: u64,
+    pub liquidity_shares: u64,
+    pub usd_collateral: u64,
+    pub total_yes_mints: u64,
+    pub total_no_mints: u64,
+    pub bump: u8,
+    pub yes_price_cumulative: u128,
+    pub last_price_update: i64,
+    pub fees_collected: u64,
+    pub fee_per_share: u128,
+    pub version: u8,
+    pub reserved: [u8; 64],
+}
+
+impl MarketPoolV1 {
+    pub const LEN: usize = MarketPoolV0::LEN + 1 + 64;
+
+    // The zero-copy pool of `market`, which also keeps the bumps of its token accounts
+    pub fn migrate(self, market: Pubkey) -> MarketPool {
+        let bump =
+            |seed: &[u8]| Pubkey::find_program_address(&[seed, market.as_ref()], &crate::ID).1;
+        MarketPool {
+            market,
+            liquidity_yes_tokens_account: self.liquidity_yes_tokens_account,
+            liquidity_no_tokens_account: self.liquidity_no_tokens_account,
+            yes_liquidity: self.yes_liquidity,
+            no_liquidity: self.no_liquidity,
+            liquidity_value: self.liquidity_value,
+            liquidity_shares: self.liquidity_shares,
+            usd_collateral: self.usd_collateral,
+            total_yes_mints: self.total_yes_mints,
+            total_no_mints: self.total_no_mints,
+            yes_price_cumulative: self.yes_price_cumulative.into(),
+            last_price_update: self.last_price_update,
+            fees_collected: self.fees_collected,
+            fee_per_share: self.fee_per_share.into(),
+            bump: self.bump,
+            liquidity_yes_tokens_bump: bump(b"yes_liquidity_pool"),
+            liquidity_no_tokens_bump: bump(b"no_liquidity_pool"),
+            version: MarketPool::VERSION,
+            padding: [0; 4],
+            group_collateral: 0,
+            reserved: [0; MarketPool::RESERVED],
+        }
+    }
+}
+
+impl From<MarketPoolV0> for MarketPoolV1 {
+    fn from(pool: MarketPoolV0) -> Self {
+        MarketPoolV1 {
+            liquidity_yes_tokens_account: pool.liquidity_yes_tokens_account,
+            liquidity_no_tokens_account: pool.liquidity_no_tokens_account,
+            yes_liquidity: pool.yes_liquidity,
+            no_liquidity: pool.no_liquidity,
+            liquidity_value: pool.liquidity_value,
+            liquidity_shares: pool.liquidity_shares,
+            usd_collateral: pool.usd_collateral,
+            total_yes_mints: pool.total_yes_mints,
+            total_no_mints: pool.total_no_mints,
+            bump: pool.bump,
+            yes_price_cumulative: pool.yes_price_cumulative,
+            last_price_update: pool.last_price_update,
+            fees_collected: pool.fees_collected,
+            fee_per_share: pool.fee_per_share,
+            version: 1,
+            reserved: [0; 64],
+        }
+    }
+}
+
+// The account in the layout of `len`, `None` once it has been migrated
+pub fn read<T: AnchorDeserialize>(
+    account: &AccountInfo,
+    discriminator: &[u8],
+    len: usize,
+) -> Result<Option<T>> {
+    let data = account.try_borrow_data()?;
+    if data.len() != 8 + len {
+        return Ok(None);
+    }
+    require!(
+        data.starts_with(discriminator),
+        ErrorCode::AccountDiscriminatorMismatch
+    );
+    Ok(Some(T::deserialize(&mut &data[8..])?))
+}
+
+// A pool in either Borsh layout, `None` once it is zero-copy
+pub fn read_pool(account: &AccountInfo) -> Result<Option<MarketPoolV1>> {
+    if let Some(pool) = read::<MarketPoolV0>(account, MarketPool::DISCRIMINATOR, MarketPoolV0::LEN)?
+    {
+        return Ok(Some(pool.into()));
+    }
+    read(account, MarketPool::DISCRIMINATOR, MarketPoolV1::LEN)
+}
@@ -1,11 +1,51 @@
 use anchor_lang::error_code;
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program_option::COption;
+use anchor_spl::associated_token::get_associated_token_address;
 use anchor_spl::token;
 use anchor_spl::token::{Mint, Token, TokenAccount};
 
+pub mod legacy;
+pub mod math;
+
+use math::{MathError, Outcome, PoolState, SCALE};
+
 declare_id!("3waVbK9Pps4X1ZwS5GbwDQKmX5syrwe6guwnyN3YJfRc");
 
-const SCALE: u128 = 1_000_000_000; // 9 decimals
+// With the `event-cpi` feature events go through a self-CPI and end up in the inner
+// instructions instead of the logs, which get truncated. This needs every emitting
+// instruction context to be `#[event_cpi]` and the handlers to name their context `ctx`.
+#[cfg(not(feature = "event-cpi"))]
+use anchor_lang::prelude::emit as emit_event;
+#[cfg(feature = "event-cpi")]
+use anchor_lang::prelude::emit_cpi as emit_event;
+
+// The PDA signing the event instructions of `emit_cpi!`
+pub fn event_authority() -> Pubkey {
+    Pubkey::find_program_address(&[b"__event_authority"], &ID).0
+}
+
+// The client side of the above: builds the accounts of an emitting instruction, adding the
+// event authority and the program itself when the `event-cpi` feature asks for them
+#[cfg(not(feature = "event-cpi"))]
+#[macro_export]
+macro_rules! event_accounts {
+    ($($accounts:ident)::+ { $($field:ident $(: $value:expr)?),* $(,)? }) => {
+        $($accounts)::+ { $($field $(: $value)?),* }
+    };
+}
+
+#[cfg(feature = "event-cpi")]
+#[macro_export]
+macro_rules! event_accounts {
+    ($($accounts:ident)::+ { $($field:ident $(: $value:expr)?),* $(,)? }) => {
+        $($accounts)::+ {
+            $($field $(: $value)?,)*
+            event_authority: $crate::event_authority(),
+            program: $crate::ID,
+        }
+    };
+}
 
 #[program]
 pub mod solana_bet_placing_market {
@@ -17,12 +57,65 @@ pub mod solana_bet_placing_market {
 
         // Store the number of created markets, initially to 0
         market_factory.created_markets = 0;
+        market_factory.event_seq = 0;
+
+        emit_event!(MarketFactoryInitializedEvent {
+            header: market_factory.next_event()?,
+            market_factory: market_factory.key(),
+            authority: ctx.accounts.authority.key(),
+        });
 
         Ok(())
     }
 
     pub fn bump_created_markets(ctx: Context<BumpCreatedMarkets>, new_value: u64) -> Result<()> {
-        ctx.accounts.market_factory.created_markets = new_value;
+        let market_factory = &mut ctx.accounts.market_factory;
+        market_factory.created_markets = new_value;
+
+        emit_event!(CreatedMarketsBumpedEvent {
+            header: market_factory.next_event()?,
+            market_factory: market_factory.key(),
+            created_markets: new_value,
+        });
+        Ok(())
+    }
+
+    // The fee taken on every trade of the markets created from now on. It stays in the
+    // pool and goes to its LPs.
+    pub fn set_trading_fee(ctx: Context<SetTradingFee>, trading_fee_bps: u16) -> Result<()> {
+        require!(
+            trading_fee_bps <= MarketFactory::MAX_TRADING_FEE_BPS,
+            MarketError::FeeTooHigh
+        );
+        ctx.accounts.market_factory.trading_fee_bps = trading_fee_bps;
+        emit_event!(factory_fees_event(&mut ctx.accounts.market_factory)?);
+        Ok(())
+    }
+
+    // The part of the trading fee paid to the referrer of a trade instead of the LPs, for
+    // the markets created from now on
+    pub fn set_referral_share(
+        ctx: Context<SetReferralShare>,
+        referral_share_bps: u16,
+    ) -> Result<()> {
+        require!(
+            referral_share_bps <= MarketFactory::MAX_REFERRAL_SHARE_BPS,
+            MarketError::ReferralShareTooHigh
+        );
+        ctx.accounts.market_factory.referral_share_bps = referral_share_bps;
+        emit_event!(factory_fees_event(&mut ctx.accounts.market_factory)?);
+        Ok(())
+    }
+
+    // The USD a relayer paying for a trade gets out of it, on the markets created from now on.
+    // Capped, since the user signing a relayed trade does not sign for the fee.
+    pub fn set_relay_fee(ctx: Context<SetRelayFee>, relay_fee: u64) -> Result<()> {
+        require!(
+            relay_fee <= MarketFactory::MAX_RELAY_FEE,
+            MarketError::RelayFeeTooHigh
+        );
+        ctx.accounts.market_factory.relay_fee = relay_fee;
+        emit_event!(factory_fees_event(&mut ctx.accounts.market_factory)?);
         Ok(())
     }
 
@@ -44,43 +137,374 @@ pub mod solana_bet_placing_market {
         market.bump = ctx.bumps.market;
         market.outcome = None;
         market.resolved = false;
+        market.status = MarketStatus::Draft;
+        market.version = Market::VERSION;
+        market.vault_bump = ctx.bumps.vault;
+        market.yes_mint_bump = ctx.bumps.yes_mint;
+        market.no_mint_bump = ctx.bumps.no_mint;
+        market.lp_share_mint_bump = ctx.bumps.lp_share_mint;
+        market.trading_fee_bps = market_factory.trading_fee_bps;
+        market.referral_share_bps = market_factory.referral_share_bps;
+        market.risk_limits = RiskLimits::default();
+        market.keeper_reward = 0;
+        market.settlement_budget = 0;
+        market.relay_fee = market_factory.relay_fee;
+        market.event_seq = 0;
 
         // Increase the number of created markets
         market_factory.created_markets += 1;
 
+        emit_event!(MarketCreatedEvent {
+            header: market.next_event()?,
+            market: market.key(),
+            market_factory: market_factory.key(),
+            authority: market.authority,
+            oracle: market.oracle,
+            market_number: market.market_number,
+            usd_mint: market.usd_mint,
+            yes_mint: market.yes_mint,
+            no_mint: market.no_mint,
+            lp_share_mint: market.lp_share_mint,
+            vault: market.vault,
+            trading_fee_bps: market.trading_fee_bps,
+            referral_share_bps: market.referral_share_bps,
+            relay_fee: market.relay_fee,
+        });
+
         Ok(())
     }
 
     pub fn initialize_pool(ctx: Context<InitializePool>) -> Result<()> {
-        let pool = &mut ctx.accounts.pool;
-
-        // Store the details for the freshly initialized pool
-        pool.yes_liquidity = 0;
-        pool.no_liquidity = 0;
-        pool.usd_collateral = 0;
-        pool.total_yes_mints = 0;
-        pool.total_no_mints = 0;
-        pool.liquidity_value = 0;
-        pool.liquidity_shares = 0;
+        // A fresh zero-copy account is all zeros, only the non-zero fields are set
+        let mut pool = ctx.accounts.pool.load_init()?;
+        pool.market = ctx.accounts.market.key();
         pool.bump = ctx.bumps.pool;
+        pool.last_price_update = Clock::get()?.unix_timestamp;
+        pool.version = MarketPool::VERSION;
 
         // Store the liquidity pool token accounts
         pool.liquidity_yes_tokens_account = ctx.accounts.liquidity_yes_tokens_account.key();
         pool.liquidity_no_tokens_account = ctx.accounts.liquidity_no_tokens_account.key();
+        pool.liquidity_yes_tokens_bump = ctx.bumps.liquidity_yes_tokens_account;
+        pool.liquidity_no_tokens_bump = ctx.bumps.liquidity_no_tokens_account;
+
+        // The price chart starts empty, the first trade opens its first candle
+        let mut price_history = ctx.accounts.price_history.load_init()?;
+        price_history.market = ctx.accounts.market.key();
+        price_history.interval = PriceHistory::INTERVAL;
+
+        emit_event!(PoolInitializedEvent {
+            header: ctx.accounts.market.next_event()?,
+            market: ctx.accounts.market.key(),
+            pool: ctx.accounts.pool.key(),
+            liquidity_yes_tokens_account: ctx.accounts.liquidity_yes_tokens_account.key(),
+            liquidity_no_tokens_account: ctx.accounts.liquidity_no_tokens_account.key(),
+            price_history: ctx.accounts.price_history.key(),
+        });
+
+        // Trading opens with the pool
+        let authority = ctx.accounts.authority.key();
+        emit_event!(status_changed_event(
+            &mut ctx.accounts.market,
+            MarketStatus::Open,
+            authority
+        )?);
 
         Ok(())
     }
 
-    #[inline(never)]
-    pub fn add_liquidity(ctx: Context<PoolLiquidity>, usd_amount: u64) -> Result<()> {
-        require!(usd_amount > 0, MarketError::Zero);
+    // Bounds on every trade, tunable by the market authority until resolution
+    pub fn set_risk_limits(ctx: Context<SetRiskLimits>, risk_limits: RiskLimits) -> Result<()> {
+        ctx.accounts
+            .market
+            .require_status(MarketStatus::CONFIGURABLE)?;
+        let market = &mut ctx.accounts.market;
+        market.risk_limits = risk_limits;
+
+        emit_event!(RiskLimitsSetEvent {
+            header: market.next_event()?,
+            market: market.key(),
+            risk_limits,
+        });
+        Ok(())
+    }
+
+    // Upgrades a market and its pool from an older layout to the current one, resizing
+    // them. The authority pays the rent of any extra bytes.
+    pub fn migrate_market(ctx: Context<MigrateMarket>) -> Result<()> {
+        let market_info = ctx.accounts.market.to_account_info();
+        let mut market = match legacy::read::<legacy::MarketV0>(
+            &market_info,
+            Market::DISCRIMINATOR,
+            legacy::MarketV0::LEN,
+        )? {
+            Some(market) => market.into(),
+            None => Market::try_deserialize(&mut &market_info.try_borrow_data()?[..])?,
+        };
         require!(
-            ctx.accounts.market.resolved == false,
-            MarketError::MarketResolved
+            market.version < Market::VERSION,
+            MarketError::AccountAlreadyMigrated
+        );
+        require_keys_eq!(
+            market.authority,
+            ctx.accounts.authority.key(),
+            ErrorCode::ConstraintHasOne
+        );
+        market.find_bumps(&market_info.key());
+        market.version = Market::VERSION;
+
+        // A draft market has no pool yet
+        let pool_info = ctx.accounts.pool.to_account_info();
+        if !pool_info.data_is_empty() {
+            require_keys_eq!(*pool_info.owner, crate::ID, ErrorCode::ConstraintOwner);
+            let pool = legacy::read_pool(&pool_info)?.ok_or(MarketError::AccountAlreadyMigrated)?;
+            let pool = pool.migrate(market_info.key());
+            let mut data = MarketPool::DISCRIMINATOR.to_vec();
+            data.extend_from_slice(bytemuck::bytes_of(&pool));
+            write_migrated(
+                &pool_info,
+                &data,
+                MarketPool::LEN,
+                &ctx.accounts.authority,
+                &ctx.accounts.system_program,
+            )?;
+        }
+
+        let event = MarketMigratedEvent {
+            header: market.next_event()?,
+            market: market_info.key(),
+            version: market.version,
+        };
+        let mut data = Vec::new();
+        market.try_serialize(&mut data)?;
+        write_migrated(
+            &market_info,
+            &data,
+            8 + Market::LEN,
+            &ctx.accounts.authority,
+            &ctx.accounts.system_program,
+        )?;
+        emit_event!(event);
+
+        Ok(())
+    }
+
+    // The transitions the market authority makes by hand: pausing and reopening trading,
+    // closing it ahead of the resolution, then disputing the resolution within
+    // `Market::DISPUTE_WINDOW` or finalizing it early. Resolving and invalidating are left
+    // to the oracle.
+    pub fn set_market_status(ctx: Context<SetMarketStatus>, status: MarketStatus) -> Result<()> {
+        let market = &ctx.accounts.market;
+        require!(
+            status.authority_transitions_from().contains(&market.status),
+            MarketError::InvalidStatusTransition
+        );
+        if status == MarketStatus::Disputed {
+            require!(
+                !market.dispute_window_elapsed()?,
+                MarketError::DisputeWindowElapsed
+            );
+        }
+
+        let authority = ctx.accounts.authority.key();
+        emit_event!(status_changed_event(
+            &mut ctx.accounts.market,
+            status,
+            authority
+        )?);
+        Ok(())
+    }
+
+    // Anyone finalizes a resolution the authority has not disputed within
+    // `Market::DISPUTE_WINDOW`, so that the winnings do not wait on the authority
+    pub fn finalize_market(ctx: Context<FinalizeMarket>) -> Result<()> {
+        let market = &ctx.accounts.market;
+        require!(
+            market.status == MarketStatus::Resolved,
+            MarketError::InvalidStatusTransition
+        );
+        require!(
+            market.dispute_window_elapsed()?,
+            MarketError::ResolutionNotFinal
+        );
+
+        let finalized_by = ctx.accounts.payer.key();
+        emit_event!(status_changed_event(
+            &mut ctx.accounts.market,
+            MarketStatus::Finalized,
+            finalized_by
+        )?);
+        Ok(())
+    }
+
+    // Opt-in record of what a user traded on a market. Once it exists, pass it to the
+    // trade, liquidity and claim instructions to keep it up to date.
+    pub fn open_position(ctx: Context<OpenPosition>) -> Result<()> {
+        let position = &mut ctx.accounts.position;
+        position.market = ctx.accounts.market.key();
+        position.owner = ctx.accounts.user.key();
+        position.bump = ctx.bumps.position;
+
+        emit_event!(PositionOpenedEvent {
+            header: ctx.accounts.market.next_event()?,
+            market: position.market,
+            owner: position.owner,
+            position: position.key(),
+        });
+
+        Ok(())
+    }
+
+    // A partner frontend signs up once to get a share of the fees of the trades it brings
+    // in, paid to `usd_account`. The stats are shared by all the markets.
+    pub fn register_referrer(ctx: Context<RegisterReferrer>) -> Result<()> {
+        let referral = &mut ctx.accounts.referral;
+        referral.referrer = ctx.accounts.referrer.key();
+        referral.usd_account = ctx.accounts.usd_account.key();
+        referral.bump = ctx.bumps.referral;
+
+        emit_event!(ReferrerRegisteredEvent {
+            header: EventHeader::unsequenced()?,
+            referrer: referral.referrer,
+            referral: referral.key(),
+            usd_account: referral.usd_account,
+        });
+
+        Ok(())
+    }
+
+    // Lets `session_key` trade for the owner until `expires_at`, spending up to `spend_cap`
+    // USD, on `market` only unless it is the default key. The owner's USD account is
+    // delegated to the session account for the cap, see `delegate_to_session` for the rest.
+    // A token account has a single delegate, so a USD account backs one session at a time:
+    // the previous one has to be revoked first.
+    pub fn create_session(
+        ctx: Context<CreateSession>,
+        session_key: Pubkey,
+        market: Pubkey,
+        expires_at: i64,
+        spend_cap: u64,
+    ) -> Result<()> {
+        require!(
+            expires_at > Clock::get()?.unix_timestamp,
+            MarketError::SessionExpired
+        );
+
+        let session = &mut ctx.accounts.session;
+        session.owner = ctx.accounts.owner.key();
+        session.session_key = session_key;
+        session.market = market;
+        session.expires_at = expires_at;
+        session.spend_cap = spend_cap;
+        session.spent = 0;
+        session.bump = ctx.bumps.session;
+
+        require_undelegated(&ctx.accounts.owner_usd_account, &session.key())?;
+        let cpi_context = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token::Approve {
+                to: ctx.accounts.owner_usd_account.to_account_info(),
+                delegate: ctx.accounts.session.to_account_info(),
+                authority: ctx.accounts.owner.to_account_info(),
+            },
         );
+        token::approve(cpi_context, spend_cap)?;
+
+        emit_event!(SessionCreatedEvent {
+            header: EventHeader::unsequenced()?,
+            session: ctx.accounts.session.key(),
+            owner: ctx.accounts.owner.key(),
+            session_key,
+            market,
+            expires_at,
+            spend_cap,
+        });
+        Ok(())
+    }
+
+    // Delegates an outcome token account to the session, so it can sell and claim them.
+    // Not over an account approved with `approve_settlement`, or delegated to another session.
+    pub fn delegate_to_session(ctx: Context<DelegateToSession>) -> Result<()> {
+        require_undelegated(&ctx.accounts.token_account, &ctx.accounts.session.key())?;
+        let cpi_context = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token::Approve {
+                to: ctx.accounts.token_account.to_account_info(),
+                delegate: ctx.accounts.session.to_account_info(),
+                authority: ctx.accounts.owner.to_account_info(),
+            },
+        );
+        token::approve(cpi_context, u64::MAX)?;
+
+        emit_event!(SessionDelegatedEvent {
+            header: EventHeader::unsequenced()?,
+            session: ctx.accounts.session.key(),
+            token_account: ctx.accounts.token_account.key(),
+        });
+        Ok(())
+    }
+
+    // Closes the session and revokes its delegation of the USD account, and of the outcome
+    // token accounts passed as remaining accounts.
+    pub fn revoke_session<'info>(
+        ctx: Context<'_, '_, 'info, 'info, RevokeSession<'info>>,
+    ) -> Result<()> {
+        let session = ctx.accounts.session.key();
+        let owner = ctx.accounts.owner.key();
+        // The USD account may back a later approval already, which is left alone
+        if ctx.accounts.owner_usd_account.delegate == COption::Some(session) {
+            revoke_delegation(
+                ctx.accounts.owner_usd_account.to_account_info(),
+                &ctx.accounts.owner,
+                &ctx.accounts.token_program,
+            )?;
+        }
+        for token_account in ctx.remaining_accounts {
+            let delegated = Account::<TokenAccount>::try_from(token_account)?;
+            require!(
+                delegated.owner == owner && delegated.delegate == COption::Some(session),
+                MarketError::InvalidSession
+            );
+            revoke_delegation(
+                token_account.clone(),
+                &ctx.accounts.owner,
+                &ctx.accounts.token_program,
+            )?;
+        }
+
+        emit_event!(SessionRevokedEvent {
+            header: EventHeader::unsequenced()?,
+            session: ctx.accounts.session.key(),
+            owner: ctx.accounts.owner.key(),
+        });
+        Ok(())
+    }
+
+    // The same for liquidity providers, passed to `add_liquidity` and `remove_liquidity`
+    pub fn open_liquidity_position(ctx: Context<OpenLiquidityPosition>) -> Result<()> {
+        let lp_position = &mut ctx.accounts.lp_position;
+        lp_position.market = ctx.accounts.market.key();
+        lp_position.owner = ctx.accounts.user.key();
+        lp_position.fee_per_share_checkpoint = ctx.accounts.pool.load()?.fee_per_share.get();
+        lp_position.bump = ctx.bumps.lp_position;
+
+        emit_event!(LiquidityPositionOpenedEvent {
+            header: ctx.accounts.market.next_event()?,
+            market: lp_position.market,
+            owner: lp_position.owner,
+            lp_position: lp_position.key(),
+            fee_per_share_checkpoint: lp_position.fee_per_share_checkpoint,
+        });
 
-        // let pool = &mut ctx.accounts.pool;
-        // let market = &ctx.accounts.market;
+        Ok(())
+    }
+
+    #[inline(never)]
+    pub fn add_liquidity(ctx: Context<PoolLiquidity>, usd_amount: u64) -> Result<()> {
+        require!(usd_amount > 0, MarketError::Zero);
+        ctx.accounts.market.require_status(MarketStatus::TRADING)?;
+        let mut pool = ctx.accounts.pool.load_mut()?;
+        pool.accumulate_price(None)?;
 
         // Transfer the usd to the market vault
         {
@@ -94,48 +518,44 @@ pub mod solana_bet_placing_market {
             token::transfer(cpi_ctx, usd_amount)?;
         }
 
-        // 1. Minting the equal number of YES and NO tokens in case
-        // the pool has equal chances for both outcomes.
-        if ctx.accounts.pool.yes_liquidity == ctx.accounts.pool.no_liquidity {
-            add_liquidity_equal_outcomes(ctx.accounts, usd_amount)?;
-        } else {
-            let total_tokens = ctx.accounts.pool.yes_liquidity + ctx.accounts.pool.no_liquidity;
-            let yes_token_price =
-                ((ctx.accounts.pool.no_liquidity as u128) * SCALE / (total_tokens as u128)) as u64;
-            let no_token_price =
-                ((ctx.accounts.pool.yes_liquidity as u128) * SCALE / (total_tokens as u128)) as u64;
-
-            msg!(
-                "Yes token price: {}, No token price: {}",
-                yes_token_price,
-                no_token_price
-            );
-            msg!(
-                "Pool No Liquidity: {}, Pool Yes Liquidity: {}",
-                ctx.accounts.pool.no_liquidity,
-                ctx.accounts.pool.yes_liquidity
-            );
-
-            // Otherwise, we have to decide how to share the liquidity
-            if ctx.accounts.pool.no_liquidity > ctx.accounts.pool.yes_liquidity {
-                add_unequal_outcomes_with_more_no(
-                    ctx.accounts,
-                    yes_token_price,
-                    no_token_price,
-                    usd_amount,
-                )?;
-            } else {
-                add_unequal_outcomes_with_more_yes(
-                    ctx.accounts,
-                    yes_token_price,
-                    no_token_price,
-                    usd_amount,
-                )?;
-            }
+        // 1. Deciding how to share the minted YES and NO tokens between the pool and the
+        // user. On equal chances everything goes into the pool, otherwise the user gets
+        // back the more probable outcome so that the pool keeps its odds.
+        let added = math::add_liquidity(&pool.state(), usd_amount)?;
+        if let Some(position) = &mut ctx.accounts.position {
+            let yes_price = math::yes_price(&pool.state(), None)?;
+            position.record_received(Outcome::Yes, added.yes_to_user, yes_price);
+            position.record_received(Outcome::No, added.no_to_user, SCALE as u64 - yes_price);
         }
+        mint_added_liquidity(ctx.accounts, &added)?;
+        if let Some(lp_position) = &mut ctx.accounts.lp_position {
+            lp_position.record_deposit(&pool, usd_amount, &added)?;
+        }
+
+        emit_event!(LiquidityAddedEvent {
+            header: ctx.accounts.market.next_event()?,
+            market: ctx.accounts.market.key(),
+            user: ctx.accounts.user.key(),
+            amount: usd_amount,
+            liquidity_shares_gained: added.liquidity_shares_gained,
+            pool_total_liquidity_shares: added.liquidity_shares_after,
+            usd_added_to_pool: added.liquidity_shares_gained,
+            yes_added_to_pool: added.yes_to_pool,
+            no_added_to_pool: added.no_to_pool,
+            yes_given_to_user: added.yes_to_user,
+            no_given_to_user: added.no_to_user,
+            yes_minted: usd_amount,
+            no_minted: usd_amount,
+        });
 
         // 2. Updating the pool with the new values
-        ctx.accounts.pool.usd_collateral += usd_amount;
+        pool.yes_liquidity += added.yes_to_pool;
+        pool.no_liquidity += added.no_to_pool;
+        pool.total_yes_mints += usd_amount;
+        pool.total_no_mints += usd_amount;
+        pool.liquidity_value = added.liquidity_value_after;
+        pool.liquidity_shares = added.liquidity_shares_after;
+        pool.usd_collateral += usd_amount;
 
         Ok(())
     }
@@ -146,6 +566,12 @@ pub mod solana_bet_placing_market {
             MarketError::InsufficientFunds
         );
         require!(shares > 0, MarketError::Zero);
+        ctx.accounts
+            .market
+            .require_status(MarketStatus::WITHDRAWABLE)?;
+        let resolved_outcome = ctx.accounts.market.resolved_outcome();
+        let mut pool = ctx.accounts.pool.load_mut()?;
+        pool.accumulate_price(resolved_outcome)?;
 
         // The first thing we are going to do is to burn the user's shares
         // and remove them from the pool
@@ -163,272 +589,104 @@ pub mod solana_bet_placing_market {
             token::burn(cpi_context, shares)?;
         }
 
-        if ctx.accounts.pool.liquidity_value == 0 {
-            return Err(MarketError::MarketNotInitialized.into());
-        }
-
-        if ctx.accounts.market.resolved == false {
-            // Then we are going to calculate what share is the least probable outcome
-            let (
-                mut highest_liquidity,
-                mut lowest_liquidity,
-                liquidity_outcome_token_account,
-                user_outcome_token_account,
-                lowest_outcome_mint,
-            ) = if ctx.accounts.pool.yes_liquidity < ctx.accounts.pool.no_liquidity {
-                (
-                    ctx.accounts.pool.no_liquidity,
-                    ctx.accounts.pool.yes_liquidity,
-                    &ctx.accounts.liquidity_no_tokens_account,
-                    &ctx.accounts.user_no_account,
-                    &ctx.accounts.no_mint,
-                )
-            } else {
-                (
-                    ctx.accounts.pool.yes_liquidity,
-                    ctx.accounts.pool.no_liquidity,
-                    &ctx.accounts.liquidity_yes_tokens_account,
-                    &ctx.accounts.user_yes_account,
-                    &ctx.accounts.yes_mint,
-                )
-            };
-
-            // Compute the price aswell
-            let total_price = highest_liquidity as u128 + lowest_liquidity as u128;
-            let lowest_price = (lowest_liquidity as u128 * SCALE / total_price) as u64;
-            let highest_price = (highest_liquidity as u128 * SCALE / total_price) as u64;
-
-            let liquidity_shares_value = ((ctx.accounts.pool.liquidity_value as u128
-                * shares as u128)
-                / highest_liquidity as u128) as u64;
-
-            // We are transferring OUT from the vault, the shares value
-            transfer_outcome(
-                &ctx.accounts.vault,
-                &ctx.accounts.user_usd_account,
-                &ctx.accounts.market,
-                &ctx.accounts.token_program,
-                liquidity_shares_value,
-                &[&[
-                    b"market",
-                    ctx.accounts.market.authority.as_ref(),
-                    &ctx.accounts.market.market_number.to_le_bytes(),
-                    &ctx.accounts.market.bump.to_le_bytes(),
-                ]],
-            )?;
-
-            // Burn the yes tokens that are not backed by collateral anymore
-            // Sometimes, due to rounding errors, the yes liquidity can be less than the
-            // liquidity shares value, so we need to always burn the existent quantity.
-            let diff = ctx.accounts.pool.yes_liquidity as i128 - liquidity_shares_value as i128;
-            let yes_tokens_to_burn = if diff < 0 && diff.abs() < SCALE as i128 {
-                ctx.accounts.pool.yes_liquidity
-            } else {
-                liquidity_shares_value
-            };
-            burn_mint_tokens(
-                &ctx.accounts.yes_mint,
-                &ctx.accounts.liquidity_yes_tokens_account,
-                &ctx.accounts.market,
-                &ctx.accounts.token_program,
-                yes_tokens_to_burn,
-                &[&[
-                    b"market",
-                    ctx.accounts.market.authority.as_ref(),
-                    &ctx.accounts.market.market_number.to_le_bytes(),
-                    &ctx.accounts.market.bump.to_le_bytes(),
-                ]],
-            )?;
-
-            // Burn the no tokens that are not backed by collateral anymore
-            let diff = ctx.accounts.pool.no_liquidity as i128 - liquidity_shares_value as i128;
-            let no_tokens_to_burn = if diff < 0 && diff.abs() < SCALE as i128 {
-                ctx.accounts.pool.no_liquidity
-            } else {
-                liquidity_shares_value
-            };
-            burn_mint_tokens(
-                &ctx.accounts.no_mint,
-                &ctx.accounts.liquidity_no_tokens_account,
-                &ctx.accounts.market,
-                &ctx.accounts.token_program,
-                no_tokens_to_burn,
-                &[&[
-                    b"market",
-                    ctx.accounts.market.authority.as_ref(),
-                    &ctx.accounts.market.market_number.to_le_bytes(),
-                    &ctx.accounts.market.bump.to_le_bytes(),
-                ]],
-            )?;
+        // Before resolution, the user gets the USD value of the shares plus the part of the
+        // least probable outcome that would unbalance the pool. After resolution, the user
+        // gets his part of the remaining winning tokens.
+        let removed = math::remove_liquidity(&pool.state(), shares, resolved_outcome)?;
+        // We are transferring OUT from the vault, the shares value
+        transfer_outcome(
+            &ctx.accounts.vault,
+            &ctx.accounts.user_usd_account,
+            &ctx.accounts.market,
+            &ctx.accounts.token_program,
+            removed.usd_to_user,
+        )?;
 
-            // Then we are removing the shares from our representation of the pool
-            ctx.accounts.pool.usd_collateral -= liquidity_shares_value;
-            ctx.accounts.pool.liquidity_value -= liquidity_shares_value;
-            ctx.accounts.pool.liquidity_shares -= shares;
-            ctx.accounts.pool.yes_liquidity -= yes_tokens_to_burn;
-            ctx.accounts.pool.no_liquidity -= no_tokens_to_burn;
-            ctx.accounts.pool.total_yes_mints -= yes_tokens_to_burn;
-            ctx.accounts.pool.total_no_mints -= no_tokens_to_burn;
-
-            highest_liquidity = highest_liquidity.saturating_sub(liquidity_shares_value);
-            lowest_liquidity = lowest_liquidity.saturating_sub(liquidity_shares_value);
-
-            let remaining_highest_liquidity =
-                ((lowest_liquidity as u128 * highest_price as u128) / lowest_price as u128) as u64;
-            msg!(
-                "Highest liquidity: {}, Lowest liquidity: {}, Remaining highest liquidity: {}",
-                highest_liquidity,
-                lowest_liquidity,
-                remaining_highest_liquidity
-            );
-            let user_belonging_lowest_outcome = highest_liquidity - remaining_highest_liquidity;
+        // Burn the yes tokens that are not backed by collateral anymore
+        burn_mint_tokens(
+            &ctx.accounts.yes_mint,
+            &ctx.accounts.liquidity_yes_tokens_account,
+            &ctx.accounts.market,
+            &ctx.accounts.token_program,
+            removed.yes_burnt,
+        )?;
 
-            // Now transfer from the liquidity pool to the user's account
-            transfer_outcome(
-                liquidity_outcome_token_account,
-                user_outcome_token_account,
-                &ctx.accounts.market,
-                &ctx.accounts.token_program,
-                user_belonging_lowest_outcome,
-                &[&[
-                    b"market",
-                    ctx.accounts.market.authority.as_ref(),
-                    &ctx.accounts.market.market_number.to_le_bytes(),
-                    &ctx.accounts.market.bump.to_le_bytes(),
-                ]],
-            )?;
+        // Burn the no tokens that are not backed by collateral anymore
+        burn_mint_tokens(
+            &ctx.accounts.no_mint,
+            &ctx.accounts.liquidity_no_tokens_account,
+            &ctx.accounts.market,
+            &ctx.accounts.token_program,
+            removed.no_burnt,
+        )?;
 
-            // We reduce the pools liquidity
-            if ctx.accounts.pool.yes_liquidity < ctx.accounts.pool.no_liquidity {
-                ctx.accounts.pool.no_liquidity = remaining_highest_liquidity;
-            } else {
-                ctx.accounts.pool.yes_liquidity = remaining_highest_liquidity;
+        // Now transfer the rebalancing outcome tokens from the liquidity pool to the user's account
+        let received_lowest_outcome_mint = match removed.outcome_given {
+            Some(outcome) => {
+                let (liquidity_outcome_token_account, user_outcome_token_account, mint) =
+                    match outcome {
+                        Outcome::Yes => (
+                            &ctx.accounts.liquidity_yes_tokens_account,
+                            &ctx.accounts.user_yes_account,
+                            ctx.accounts.yes_mint.key(),
+                        ),
+                        Outcome::No => (
+                            &ctx.accounts.liquidity_no_tokens_account,
+                            &ctx.accounts.user_no_account,
+                            ctx.accounts.no_mint.key(),
+                        ),
+                    };
+                transfer_outcome(
+                    liquidity_outcome_token_account,
+                    user_outcome_token_account,
+                    &ctx.accounts.market,
+                    &ctx.accounts.token_program,
+                    removed.outcome_tokens_to_user,
+                )?;
+                mint
             }
+            None => Pubkey::default(),
+        };
 
-            // And we also modify the new liquidity value to be the root value of the multiplication
-            let liquidity_value_squared =
-                ctx.accounts.pool.yes_liquidity as u128 * ctx.accounts.pool.no_liquidity as u128;
-            ctx.accounts.pool.liquidity_value = sqrt_u128(liquidity_value_squared) as u64;
-
-            // Now we are emitting the event
-            emit!(LiquidityRemovedEvent {
-                market: ctx.accounts.market.key(),
-                user: ctx.accounts.user.key(),
-                burnt_lp_shares: shares,
-                pool_remaining_liquidity_shares: ctx.accounts.pool.liquidity_shares,
-                equivalent_usd: liquidity_shares_value,
-                received_lowest_outcome_tokens: user_belonging_lowest_outcome,
-                received_lowest_outcome_mint: lowest_outcome_mint.key(),
-                remaining_yes_tokens: ctx.accounts.pool.yes_liquidity,
-                remaining_no_tokens: ctx.accounts.pool.no_liquidity,
-            });
-            msg!(
-                "User removed liquidity, shares: {}, received: {} USD, remaining yes: {}, remaining no: {}, received lowest outcome: {}, received lowest outcome mint: {}",
-                shares,
-                liquidity_shares_value,
-                ctx.accounts.pool.yes_liquidity,
-                ctx.accounts.pool.no_liquidity,
-                user_belonging_lowest_outcome,
-                lowest_outcome_mint.key()
-            );
-
-            Ok(())
-        } else {
-            // If the market is resolved, we first calculate to see how much
-            // he owns from the remaining winning tokens
-            let remaining_winning_shares = if ctx.accounts.market.outcome == Some(0) {
-                ctx.accounts.pool.no_liquidity
-            } else {
-                ctx.accounts.pool.yes_liquidity
+        // The rebalancing tokens enter the position at the price the pool quoted
+        if let (Some(position), Some(outcome)) = (&mut ctx.accounts.position, removed.outcome_given)
+        {
+            let yes_price = math::yes_price(&pool.state(), None)?;
+            let price = match outcome {
+                Outcome::Yes => yes_price,
+                Outcome::No => SCALE as u64 - yes_price,
             };
+            position.record_received(outcome, removed.outcome_tokens_to_user, price);
+        }
 
-            let liquidity_share_price = ((remaining_winning_shares as u128 * SCALE)
-                / ctx.accounts.pool.liquidity_shares as u128)
-                as u64;
-            let user_belonging_money =
-                ((shares as u128) * liquidity_share_price as u128 / SCALE) as u64;
-            // We are transferring now out from the vault the shares value
-            transfer_outcome(
-                &ctx.accounts.vault,
-                &ctx.accounts.user_usd_account,
-                &ctx.accounts.market,
-                &ctx.accounts.token_program,
-                user_belonging_money,
-                &[&[
-                    b"market",
-                    ctx.accounts.market.authority.as_ref(),
-                    &ctx.accounts.market.market_number.to_le_bytes(),
-                    &ctx.accounts.market.bump.to_le_bytes(),
-                ]],
-            )?;
-
-            // Now we are going to burn the shares
-            let yes_tokens_to_burn = if user_belonging_money > ctx.accounts.pool.yes_liquidity {
-                ctx.accounts.pool.yes_liquidity
-            } else {
-                user_belonging_money
-            };
-            burn_mint_tokens(
-                &ctx.accounts.yes_mint,
-                &ctx.accounts.liquidity_yes_tokens_account,
-                &ctx.accounts.market,
-                &ctx.accounts.token_program,
-                yes_tokens_to_burn,
-                &[&[
-                    b"market",
-                    ctx.accounts.market.authority.as_ref(),
-                    &ctx.accounts.market.market_number.to_le_bytes(),
-                    &ctx.accounts.market.bump.to_le_bytes(),
-                ]],
-            )?;
+        if let Some(lp_position) = &mut ctx.accounts.lp_position {
+            lp_position.record_withdrawal(&pool, shares, &removed)?;
+        }
 
-            let no_tokens_to_burn = if user_belonging_money > ctx.accounts.pool.no_liquidity {
-                ctx.accounts.pool.no_liquidity
-            } else {
-                user_belonging_money
-            };
-            burn_mint_tokens(
-                &ctx.accounts.no_mint,
-                &ctx.accounts.liquidity_no_tokens_account,
-                &ctx.accounts.market,
-                &ctx.accounts.token_program,
-                no_tokens_to_burn,
-                &[&[
-                    b"market",
-                    ctx.accounts.market.authority.as_ref(),
-                    &ctx.accounts.market.market_number.to_le_bytes(),
-                    &ctx.accounts.market.bump.to_le_bytes(),
-                ]],
-            )?;
+        // Then we are removing the shares from our representation of the pool
+        pool.usd_collateral -= removed.usd_to_user;
+        pool.liquidity_value = removed.liquidity_value_after;
+        pool.liquidity_shares = removed.liquidity_shares_after;
+        pool.yes_liquidity = removed.yes_liquidity_after;
+        pool.no_liquidity = removed.no_liquidity_after;
+        pool.total_yes_mints -= removed.yes_burnt;
+        pool.total_no_mints -= removed.no_burnt;
 
-            msg!(
-                "Pool liquidity Value: {}, User Belonging Money: {}",
-                ctx.accounts.pool.liquidity_value,
-                user_belonging_money
-            );
-            // Then we are removing the shares from our representation of the pool
-            ctx.accounts.pool.usd_collateral -= user_belonging_money;
-            ctx.accounts.pool.liquidity_value -= user_belonging_money;
-            ctx.accounts.pool.liquidity_shares -= shares;
-            ctx.accounts.pool.yes_liquidity -= yes_tokens_to_burn;
-            ctx.accounts.pool.no_liquidity -= no_tokens_to_burn;
-            ctx.accounts.pool.total_yes_mints -= yes_tokens_to_burn;
-            ctx.accounts.pool.total_no_mints -= no_tokens_to_burn;
-
-            emit!(LiquidityRemovedEvent {
-                market: ctx.accounts.market.key(),
-                user: ctx.accounts.user.key(),
-                burnt_lp_shares: shares,
-                pool_remaining_liquidity_shares: ctx.accounts.pool.liquidity_shares,
-                equivalent_usd: user_belonging_money,
-                received_lowest_outcome_tokens: 0,
-                received_lowest_outcome_mint: Pubkey::default(),
-                remaining_yes_tokens: ctx.accounts.pool.yes_liquidity,
-                remaining_no_tokens: ctx.accounts.pool.no_liquidity,
-            });
+        // Now we are emitting the event
+        emit_event!(LiquidityRemovedEvent {
+            header: ctx.accounts.market.next_event()?,
+            market: ctx.accounts.market.key(),
+            user: ctx.accounts.user.key(),
+            burnt_lp_shares: shares,
+            pool_remaining_liquidity_shares: pool.liquidity_shares,
+            equivalent_usd: removed.usd_to_user,
+            received_lowest_outcome_tokens: removed.outcome_tokens_to_user,
+            received_lowest_outcome_mint,
+            remaining_yes_tokens: pool.yes_liquidity,
+            remaining_no_tokens: pool.no_liquidity,
+        });
 
-            Ok(())
-        }
+        Ok(())
     }
 
     pub fn purchase_outcome_shares(
@@ -438,100 +696,118 @@ pub mod solana_bet_placing_market {
     ) -> Result<()> {
         // First and foremost, we need the amount to be bigger than 0
         require!(usd_amount > 0, MarketError::Zero);
-        require!(
-            ctx.accounts.market.resolved == false,
-            MarketError::MarketResolved
-        );
-
-        // Transfer the usd to the market vault
-        {
-            let cpi_accounts = token::Transfer {
-                from: ctx.accounts.user_usd_account.to_account_info(),
-                to: ctx.accounts.vault.to_account_info(),
-                authority: ctx.accounts.user.to_account_info(),
-            };
-            let cpi_ctx =
-                CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
-
-            token::transfer(cpi_ctx, usd_amount)?;
-        }
-        // Increase the market volume
-        ctx.accounts.market.market_volume += usd_amount;
-
-        let wanted_token_account;
-        let other_token_account;
-        let wanted_mint;
-        let other_mint;
-        let wanted_from_liquidity;
+        ctx.accounts.market.require_status(MarketStatus::TRADING)?;
+        let mut pool = ctx.accounts.pool.load_mut()?;
+        pool.accumulate_price(None)?;
 
-        let total_shares =
-            ctx.accounts.pool.yes_liquidity as u128 + ctx.accounts.pool.no_liquidity as u128;
-        let yes_token_price =
-            ((ctx.accounts.pool.no_liquidity as u128 * SCALE) / total_shares) as u64;
-        let no_token_price =
-            ((ctx.accounts.pool.yes_liquidity as u128 * SCALE) / total_shares) as u64;
+        // A relayer paying for the transaction gets its fee first, the rest is traded
+        let relay_fee = relay_fee(
+            &ctx.accounts.market,
+            &ctx.accounts.relayer,
+            &ctx.accounts.relayer_usd_account,
+        )?;
+        require!(usd_amount > relay_fee, MarketError::RelayFeeNotCovered);
+        let usd_amount = usd_amount - relay_fee;
 
         // Now we figure out whether the user wants
         // a YES or a NO token
-        if purchased_outcome_mint_pubkey == ctx.accounts.yes_mint.key() {
-            // YES token
-            wanted_token_account = &ctx.accounts.liquidity_yes_tokens_account;
-            other_token_account = &ctx.accounts.liquidity_no_tokens_account;
-            wanted_mint = &ctx.accounts.yes_mint;
-            other_mint = &ctx.accounts.no_mint;
-
-            // Calculating what is the difference between the initial and the new outcome
-            let liquidity_value = ctx.accounts.pool.liquidity_value as u128;
-            let no_liquidity = ctx.accounts.pool.no_liquidity as u128;
-            let usd = usd_amount as u128;
-            let new_yes_liquidity = (liquidity_value.pow(2) / (no_liquidity + usd)) as u64;
-            wanted_from_liquidity = ctx.accounts.pool.yes_liquidity - new_yes_liquidity;
-
-            // Then we modify the pool values
-            let pool = &mut ctx.accounts.pool;
-            pool.yes_liquidity = new_yes_liquidity;
-            pool.no_liquidity += usd_amount;
-            pool.usd_collateral += usd_amount;
-            pool.total_yes_mints += usd_amount;
-            pool.total_no_mints += usd_amount;
-        } else if purchased_outcome_mint_pubkey == ctx.accounts.no_mint.key() {
-            // NO token
-            wanted_token_account = &ctx.accounts.liquidity_no_tokens_account;
-            other_token_account = &ctx.accounts.liquidity_yes_tokens_account;
-            wanted_mint = &ctx.accounts.no_mint;
-            other_mint = &ctx.accounts.yes_mint;
-
-            // Calculating what is the difference between the initial and the new outcome
-            let liquidity_value = ctx.accounts.pool.liquidity_value as u128;
-            let yes_liquidity = ctx.accounts.pool.yes_liquidity as u128;
-            let usd = usd_amount as u128;
-            let new_no_liquidity = (liquidity_value.pow(2) / (yes_liquidity + usd)) as u64;
-            wanted_from_liquidity = ctx.accounts.pool.no_liquidity - new_no_liquidity;
-
-            // Then we modify the pool values
-            let pool = &mut ctx.accounts.pool;
-            pool.no_liquidity = new_no_liquidity;
-            pool.yes_liquidity += usd_amount;
-            pool.usd_collateral += usd_amount;
-            pool.total_yes_mints += usd_amount;
-            pool.total_no_mints += usd_amount;
-        } else {
-            return Err(MarketError::MintNotAllowed.into());
-        }
+        let outcome = ctx
+            .accounts
+            .market
+            .outcome_of_mint(&purchased_outcome_mint_pubkey)
+            .ok_or(MarketError::MintNotAllowed)?;
+        let (wanted_token_account, other_token_account, wanted_mint, other_mint) = match outcome {
+            Outcome::Yes => (
+                &ctx.accounts.liquidity_yes_tokens_account,
+                &ctx.accounts.liquidity_no_tokens_account,
+                &ctx.accounts.yes_mint,
+                &ctx.accounts.no_mint,
+            ),
+            Outcome::No => (
+                &ctx.accounts.liquidity_no_tokens_account,
+                &ctx.accounts.liquidity_yes_tokens_account,
+                &ctx.accounts.no_mint,
+                &ctx.accounts.yes_mint,
+            ),
+        };
 
-        // Now we need to first mint the wanted tokens into user's account
+        // Calculating what is the difference between the initial and the new outcome
+        let referred = referred(&ctx.accounts.referral, &ctx.accounts.referrer_usd_account)?;
+        let purchase = math::buy(
+            &pool.trading_state(&ctx.accounts.market, referred),
+            usd_amount,
+            outcome,
+        )?;
+        let traded_amount = usd_amount - purchase.fee;
+        require!(traded_amount > 0, MarketError::Zero);
+        let trader = trader(&ctx.accounts.user, &ctx.accounts.session);
+        if let Some(session) = &mut ctx.accounts.session {
+            session.authorize(
+                &ctx.accounts.market.key(),
+                usd_amount + relay_fee,
+                Clock::get()?.unix_timestamp,
+            )?;
+            require_keys_eq!(
+                ctx.accounts.user_outcome_mint_account.owner,
+                trader,
+                MarketError::InvalidSession
+            );
+        }
+        ctx.accounts.market.risk_limits.check_trade(
+            outcome,
+            usd_amount,
+            match outcome {
+                Outcome::Yes => purchase.yes_price_before,
+                Outcome::No => purchase.no_price_before,
+            },
+            purchase.yes_liquidity_after,
+            purchase.no_liquidity_after,
+        )?;
+        // The referral fee goes straight to the referrer, the rest mints complete sets
+        let minted_amount = usd_amount - purchase.referral_fee;
+        let pool_fee = purchase.fee - purchase.referral_fee;
+
+        // Transfer the usd to the market vault
+        transfer_from_trader(
+            &ctx.accounts.user_usd_account,
+            &ctx.accounts.vault,
+            &ctx.accounts.user,
+            &ctx.accounts.session,
+            &ctx.accounts.token_program,
+            minted_amount,
+        )?;
+        if let Some(referrer_usd_account) = &ctx.accounts.referrer_usd_account {
+            if purchase.referral_fee > 0 {
+                transfer_from_trader(
+                    &ctx.accounts.user_usd_account,
+                    referrer_usd_account,
+                    &ctx.accounts.user,
+                    &ctx.accounts.session,
+                    &ctx.accounts.token_program,
+                    purchase.referral_fee,
+                )?;
+            }
+        }
+        if let Some(relayer_usd_account) = &ctx.accounts.relayer_usd_account {
+            if relay_fee > 0 {
+                transfer_from_trader(
+                    &ctx.accounts.user_usd_account,
+                    relayer_usd_account,
+                    &ctx.accounts.user,
+                    &ctx.accounts.session,
+                    &ctx.accounts.token_program,
+                    relay_fee,
+                )?;
+            }
+        }
+
+        // Now we need to first mint the wanted tokens into user's account
         mint_outcome(
             wanted_mint,
             &ctx.accounts.user_outcome_mint_account,
             &ctx.accounts.market,
             &ctx.accounts.token_program,
-            usd_amount,
-            &[&[
-                b"market",
-                ctx.accounts.market.authority.as_ref(),
-                &ctx.accounts.market.market_number.to_le_bytes(),
-                &ctx.accounts.market.bump.to_le_bytes(),
-            ]],
+            traded_amount,
         )?;
 
         // Then we mint the other tokens to the liquidity pool
@@ -540,119 +816,680 @@ pub mod solana_bet_placing_market {
             other_token_account,
             &ctx.accounts.market,
             &ctx.accounts.token_program,
-            usd_amount,
-            &[&[
-                b"market",
-                ctx.accounts.market.authority.as_ref(),
-                &ctx.accounts.market.market_number.to_le_bytes(),
-                &ctx.accounts.market.bump.to_le_bytes(),
-            ]],
+            minted_amount,
         )?;
 
+        // The fee stays in the pool as complete sets, its other half was minted just above
+        if pool_fee > 0 {
+            mint_outcome(
+                wanted_mint,
+                wanted_token_account,
+                &ctx.accounts.market,
+                &ctx.accounts.token_program,
+                pool_fee,
+            )?;
+        }
+
         // Then we transfer the purchased tokens from the pool to the user
         transfer_outcome(
             wanted_token_account,
             &ctx.accounts.user_outcome_mint_account,
             &ctx.accounts.market,
             &ctx.accounts.token_program,
-            wanted_from_liquidity,
-            &[&[
-                b"market",
-                ctx.accounts.market.authority.as_ref(),
-                &ctx.accounts.market.market_number.to_le_bytes(),
-                &ctx.accounts.market.bump.to_le_bytes(),
-            ]],
+            purchase.shares_from_pool,
         )?;
 
+        // Increase the market volume
+        ctx.accounts.market.market_volume += usd_amount;
+
+        // Then we modify the pool values
+        pool.yes_liquidity = purchase.yes_liquidity_after;
+        pool.no_liquidity = purchase.no_liquidity_after;
+        pool.liquidity_value = purchase.liquidity_value_after;
+        pool.collect_fee(pool_fee)?;
+        pool.usd_collateral += minted_amount;
+        pool.total_yes_mints += minted_amount;
+        pool.total_no_mints += minted_amount;
+
+        // Record the trade in the price chart
+        let yes_price_after = math::yes_price(&pool.state(), None)?;
+        ctx.accounts.price_history.load_mut()?.record_trade(
+            Clock::get()?.unix_timestamp,
+            purchase.yes_price_before,
+            yes_price_after,
+            usd_amount,
+        );
+
+        if let Some(position) = &mut ctx.accounts.position {
+            position.record_purchase(outcome, purchase.shares_purchased, usd_amount);
+        }
+
         // Now we are emitting the event
-        emit!(PurchasedOutcomeSharesEvent {
+        emit_event!(PurchasedOutcomeSharesEvent {
+            header: ctx.accounts.market.next_event()?,
             market: ctx.accounts.market.key(),
-            user: ctx.accounts.user.key(),
+            user: trader,
             amount: usd_amount,
-            wanted_shares_purchased: usd_amount + wanted_from_liquidity,
+            wanted_shares_purchased: purchase.shares_purchased,
             wanted_shares_purchased_mint: purchased_outcome_mint_pubkey,
-            yes_price_before_purchase: yes_token_price,
-            no_price_before_purchase: no_token_price,
-            pool_remaining_yes_tokens: ctx.accounts.pool.yes_liquidity,
-            pool_remaining_no_tokens: ctx.accounts.pool.no_liquidity,
+            fee: purchase.fee,
+            yes_price_before_purchase: purchase.yes_price_before,
+            no_price_before_purchase: purchase.no_price_before,
+            yes_price_after_purchase: yes_price_after,
+            no_price_after_purchase: SCALE as u64 - yes_price_after,
+            pool_remaining_yes_tokens: pool.yes_liquidity,
+            pool_remaining_no_tokens: pool.no_liquidity,
         });
 
+        if let Some(referral) = &mut ctx.accounts.referral {
+            referral.record_trade(usd_amount, purchase.referral_fee);
+            emit_event!(ReferralFeePaidEvent {
+                header: ctx.accounts.market.next_event()?,
+                market: ctx.accounts.market.key(),
+                referrer: referral.referrer,
+                user: trader,
+                volume: usd_amount,
+                referral_fee: purchase.referral_fee,
+                total_volume: referral.volume,
+                total_earnings: referral.earnings,
+            });
+        }
+
+        if let Some(relayer) = &ctx.accounts.relayer {
+            emit_event!(RelayFeePaidEvent {
+                header: ctx.accounts.market.next_event()?,
+                market: ctx.accounts.market.key(),
+                relayer: relayer.key(),
+                user: trader,
+                relay_fee,
+            });
+        }
+
         Ok(())
     }
 
-    pub fn resolve_market(ctx: Context<ResolveMarket>, outcome: u8) -> Result<()> {
+    // The reverse of a purchase: the user gives `shares` outcome tokens back to the pool,
+    // which burns complete sets out of its liquidity and pays them from the vault
+    pub fn sell_outcome_shares(
+        ctx: Context<SellOutcomeShares>,
+        shares: u64,
+        sold_outcome_mint_pubkey: Pubkey,
+    ) -> Result<()> {
+        require!(shares > 0, MarketError::Zero);
+        ctx.accounts.market.require_status(MarketStatus::TRADING)?;
         require!(
-            ctx.accounts.market.resolved == false,
-            MarketError::MarketResolved
+            shares <= ctx.accounts.user_outcome_mint_account.amount,
+            MarketError::InsufficientFunds
+        );
+        let mut pool = ctx.accounts.pool.load_mut()?;
+        pool.accumulate_price(None)?;
+
+        let outcome = ctx
+            .accounts
+            .market
+            .outcome_of_mint(&sold_outcome_mint_pubkey)
+            .ok_or(MarketError::MintNotAllowed)?;
+        let sold_token_account = match outcome {
+            Outcome::Yes => &ctx.accounts.liquidity_yes_tokens_account,
+            Outcome::No => &ctx.accounts.liquidity_no_tokens_account,
+        };
+
+        // Too few shares are worth less than the smallest USD unit
+        let referred = referred(&ctx.accounts.referral, &ctx.accounts.referrer_usd_account)?;
+        let sale = math::sell(
+            &pool.trading_state(&ctx.accounts.market, referred),
+            shares,
+            outcome,
+        )?;
+        require!(sale.usd_returned > 0, MarketError::Zero);
+        // The relayer's fee comes out of what the user gets
+        let relay_fee = relay_fee(
+            &ctx.accounts.market,
+            &ctx.accounts.relayer,
+            &ctx.accounts.relayer_usd_account,
+        )?;
+        require!(
+            sale.usd_returned > relay_fee,
+            MarketError::RelayFeeNotCovered
+        );
+        let usd_received = sale.usd_returned - relay_fee;
+        ctx.accounts.market.risk_limits.check_trade(
+            outcome,
+            sale.usd_returned + sale.fee,
+            match outcome {
+                Outcome::Yes => sale.yes_price_before,
+                Outcome::No => sale.no_price_before,
+            },
+            sale.yes_liquidity_after,
+            sale.no_liquidity_after,
+        )?;
+        let trader = trader(&ctx.accounts.user, &ctx.accounts.session);
+        if let Some(session) = &mut ctx.accounts.session {
+            session.authorize(&ctx.accounts.market.key(), 0, Clock::get()?.unix_timestamp)?;
+            require_keys_eq!(
+                ctx.accounts.user_usd_account.owner,
+                trader,
+                MarketError::InvalidSession
+            );
+        }
+        // The sets paid out, to the user and to the referrer
+        let burnt_amount = sale.usd_returned + sale.referral_fee;
+
+        // The sold tokens go into the pool first
+        transfer_from_trader(
+            &ctx.accounts.user_outcome_mint_account,
+            sold_token_account,
+            &ctx.accounts.user,
+            &ctx.accounts.session,
+            &ctx.accounts.token_program,
+            shares,
+        )?;
+
+        // Then the pool burns the complete sets backing the payout
+        burn_mint_tokens(
+            &ctx.accounts.yes_mint,
+            &ctx.accounts.liquidity_yes_tokens_account,
+            &ctx.accounts.market,
+            &ctx.accounts.token_program,
+            burnt_amount,
+        )?;
+        burn_mint_tokens(
+            &ctx.accounts.no_mint,
+            &ctx.accounts.liquidity_no_tokens_account,
+            &ctx.accounts.market,
+            &ctx.accounts.token_program,
+            burnt_amount,
+        )?;
+
+        // And the vault pays them out
+        transfer_outcome(
+            &ctx.accounts.vault,
+            &ctx.accounts.user_usd_account,
+            &ctx.accounts.market,
+            &ctx.accounts.token_program,
+            usd_received,
+        )?;
+        if let Some(referrer_usd_account) = &ctx.accounts.referrer_usd_account {
+            if sale.referral_fee > 0 {
+                transfer_outcome(
+                    &ctx.accounts.vault,
+                    referrer_usd_account,
+                    &ctx.accounts.market,
+                    &ctx.accounts.token_program,
+                    sale.referral_fee,
+                )?;
+            }
+        }
+        if let Some(relayer_usd_account) = &ctx.accounts.relayer_usd_account {
+            if relay_fee > 0 {
+                transfer_outcome(
+                    &ctx.accounts.vault,
+                    relayer_usd_account,
+                    &ctx.accounts.market,
+                    &ctx.accounts.token_program,
+                    relay_fee,
+                )?;
+            }
+        }
+
+        ctx.accounts.market.market_volume += sale.usd_returned;
+
+        pool.yes_liquidity = sale.yes_liquidity_after;
+        pool.no_liquidity = sale.no_liquidity_after;
+        pool.liquidity_value = sale.liquidity_value_after;
+        pool.collect_fee(sale.fee - sale.referral_fee)?;
+        pool.usd_collateral -= burnt_amount;
+        pool.total_yes_mints -= burnt_amount;
+        pool.total_no_mints -= burnt_amount;
+
+        // Record the trade in the price chart
+        let yes_price_after = math::yes_price(&pool.state(), None)?;
+        ctx.accounts.price_history.load_mut()?.record_trade(
+            Clock::get()?.unix_timestamp,
+            sale.yes_price_before,
+            yes_price_after,
+            sale.usd_returned,
         );
+
+        if let Some(position) = &mut ctx.accounts.position {
+            position.record_sale(outcome, shares, usd_received);
+        }
+
+        emit_event!(SoldOutcomeSharesEvent {
+            header: ctx.accounts.market.next_event()?,
+            market: ctx.accounts.market.key(),
+            user: trader,
+            shares_sold: shares,
+            sold_shares_mint: sold_outcome_mint_pubkey,
+            usd_received,
+            fee: sale.fee,
+            yes_price_before_sale: sale.yes_price_before,
+            no_price_before_sale: sale.no_price_before,
+            yes_price_after_sale: yes_price_after,
+            no_price_after_sale: SCALE as u64 - yes_price_after,
+            pool_remaining_yes_tokens: pool.yes_liquidity,
+            pool_remaining_no_tokens: pool.no_liquidity,
+        });
+
+        if let Some(referral) = &mut ctx.accounts.referral {
+            referral.record_trade(sale.usd_returned, sale.referral_fee);
+            emit_event!(ReferralFeePaidEvent {
+                header: ctx.accounts.market.next_event()?,
+                market: ctx.accounts.market.key(),
+                referrer: referral.referrer,
+                user: trader,
+                volume: sale.usd_returned,
+                referral_fee: sale.referral_fee,
+                total_volume: referral.volume,
+                total_earnings: referral.earnings,
+            });
+        }
+
+        if let Some(relayer) = &ctx.accounts.relayer {
+            emit_event!(RelayFeePaidEvent {
+                header: ctx.accounts.market.next_event()?,
+                market: ctx.accounts.market.key(),
+                relayer: relayer.key(),
+                user: trader,
+                relay_fee,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Also settles a disputed resolution, with the same outcome or the other one
+    pub fn resolve_market(ctx: Context<ResolveMarket>, outcome: u8) -> Result<()> {
+        ctx.accounts
+            .market
+            .require_status(MarketStatus::RESOLVABLE)?;
         require!(outcome == 0 || outcome == 1, MarketError::InvalidOutcome);
+        // The pool price counts up to now, the resolved one from now on
+        let resolved_outcome = ctx.accounts.market.resolved_outcome();
+        let mut pool = ctx.accounts.pool.load_mut()?;
+        pool.accumulate_price(resolved_outcome)?;
 
         // Set the outcome and mark the market as resolved
         ctx.accounts.market.outcome = Some(outcome);
         ctx.accounts.market.resolved = true;
+        ctx.accounts.market.resolved_at = Clock::get()?.unix_timestamp;
 
         // Then we will update the pool liquidity value by transforming
         // the desired outcome into 1 usd value
-        ctx.accounts.pool.liquidity_value = if outcome == 0 {
-            ctx.accounts.pool.no_liquidity
-        } else {
-            ctx.accounts.pool.yes_liquidity
-        };
+        pool.liquidity_value = math::resolved_liquidity_value(
+            &pool.state(),
+            Outcome::from_u8(outcome).ok_or(MarketError::InvalidOutcome)?,
+        );
 
-        emit!(MarketResolvedEvent {
+        let oracle = ctx.accounts.oracle.key();
+        emit_event!(status_changed_event(
+            &mut ctx.accounts.market,
+            MarketStatus::Resolved,
+            oracle
+        )?);
+        emit_event!(MarketResolvedEvent {
+            header: ctx.accounts.market.next_event()?,
             market: ctx.accounts.market.key(),
-            solver: ctx.accounts.oracle.key(),
+            solver: oracle,
             outcome,
         });
 
         Ok(())
     }
 
-    pub fn resolve_user_winnings(ctx: Context<ResolveUserWinnings>) -> Result<()> {
+    // Voids the market instead of resolving it. Every outcome token is then redeemed for
+    // half a USD, and the LPs withdraw the pool as it stood before the resolution.
+    pub fn invalidate_market(ctx: Context<ResolveMarket>) -> Result<()> {
+        ctx.accounts
+            .market
+            .require_status(MarketStatus::RESOLVABLE)?;
+        let resolved_outcome = ctx.accounts.market.resolved_outcome();
+        let mut pool = ctx.accounts.pool.load_mut()?;
+        pool.accumulate_price(resolved_outcome)?;
+
+        // A disputed resolution valued the pool at its winning tokens only
+        if resolved_outcome.is_some() {
+            pool.liquidity_value =
+                math::sqrt_u128(pool.yes_liquidity as u128 * pool.no_liquidity as u128) as u64;
+        }
+        ctx.accounts.market.outcome = None;
+        ctx.accounts.market.resolved = true;
+
+        let oracle = ctx.accounts.oracle.key();
+        emit_event!(status_changed_event(
+            &mut ctx.accounts.market,
+            MarketStatus::Invalid,
+            oracle
+        )?);
+
+        Ok(())
+    }
+
+    // Links markets of which exactly one resolves YES. The remaining accounts are the
+    // members: markets of the signer sharing an oracle and a USD mint, none of them in a
+    // group or resolved yet. From then on only `resolve_market_group` resolves them, and
+    // `convert_no_tokens` turns NO tokens of every member into USD.
+    pub fn create_market_group<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CreateMarketGroup<'info>>,
+        group_id: u64,
+    ) -> Result<()> {
+        let members = ctx.remaining_accounts;
         require!(
-            ctx.accounts.market.resolved == true,
-            MarketError::MarketNotResolved
+            (2..=MarketGroup::MAX_MARKETS).contains(&members.len()),
+            MarketError::InvalidGroup
         );
-        let user_yes_amount = ctx.accounts.user_yes_account.amount;
-        let user_no_amount = ctx.accounts.user_no_account.amount;
+        let group_key = ctx.accounts.group.key();
+        let mut markets: Vec<Pubkey> = Vec::with_capacity(members.len());
+        let mut oracle = None;
+        for member in members {
+            let mut market = Account::<Market>::try_from(member)?;
+            require!(
+                market.authority == ctx.accounts.authority.key()
+                    && market.usd_mint == ctx.accounts.usd_mint.key()
+                    && *oracle.get_or_insert(market.oracle) == market.oracle
+                    && !markets.contains(&market.key()),
+                MarketError::InvalidGroup
+            );
+            require!(
+                market.group == Pubkey::default(),
+                MarketError::MarketInGroup
+            );
+            market.require_status(MarketStatus::CONFIGURABLE)?;
+
+            market.group = group_key;
+            emit_event!(MarketJoinedGroupEvent {
+                header: market.next_event()?,
+                market: market.key(),
+                group: group_key,
+            });
+            market.exit(&crate::ID)?;
+            markets.push(market.key());
+        }
+
+        let group = &mut ctx.accounts.group;
+        group.authority = ctx.accounts.authority.key();
+        group.oracle = oracle.unwrap_or_default();
+        group.usd_mint = ctx.accounts.usd_mint.key();
+        group.vault = ctx.accounts.vault.key();
+        group.group_id = group_id;
+        group.markets = markets;
+        group.bump = ctx.bumps.group;
+        group.vault_bump = ctx.bumps.vault;
+
+        emit_event!(MarketGroupCreatedEvent {
+            header: group.next_event()?,
+            group: group.key(),
+            authority: group.authority,
+            oracle: group.oracle,
+            markets: group.markets.clone(),
+        });
+
+        Ok(())
+    }
+
+    // Resolves every member of the group: the one at `winner` in `markets` YES, the others
+    // NO. A disputed resolution is settled the same way, once the authority disputed any
+    // member. Grouped markets cannot be invalidated. The remaining accounts come in groups
+    // of `GROUP_RESOLUTION_LEN`, one per member in the group's order.
+    pub fn resolve_market_group<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ResolveMarketGroup<'info>>,
+        winner: u8,
+    ) -> Result<()> {
+        let group = &ctx.accounts.group;
+        let members = ctx.remaining_accounts;
         require!(
-            user_yes_amount > 0 || user_no_amount > 0,
-            MarketError::InsufficientFunds
+            (winner as usize) < group.markets.len(),
+            MarketError::InvalidOutcome
         );
+        require!(
+            members.len() == group.markets.len() * GROUP_RESOLUTION_LEN,
+            MarketError::InvalidBatch
+        );
+        let winner = winner as usize;
+        let previous_winner = group.winner.map(usize::from);
+        let converted = group.converted;
+        let oracle = ctx.accounts.oracle.key();
+
+        // The vaults the USD of the converted NO tokens moves between, see below
+        let mut winner_vault = None;
+        let mut previous_winner_accounts = None;
+        // A resolved group is resolved again, all its members together, once the authority
+        // of one of them disputes it. A member finalized meanwhile keeps its outcome, and
+        // with it the winner.
+        let mut disputed = false;
+        for (index, member) in members.chunks_exact(GROUP_RESOLUTION_LEN).enumerate() {
+            let mut market = Account::<Market>::try_from(&member[0])?;
+            let pool = AccountLoader::<MarketPool>::try_from(&member[1])?;
+            let vault = Account::<TokenAccount>::try_from(&member[2])?;
+            require!(
+                market.key() == group.markets[index]
+                    && pool.load()?.market == market.key()
+                    && vault.key() == market.vault,
+                MarketError::InvalidBatch
+            );
+            if previous_winner.is_none() {
+                market.require_status(MarketStatus::RESOLVABLE)?;
+            } else if market.status == MarketStatus::Finalized {
+                require!(previous_winner == Some(winner), MarketError::MarketResolved);
+                if index == winner {
+                    winner_vault = Some(vault);
+                }
+                continue;
+            } else {
+                market.require_status(&[MarketStatus::Resolved, MarketStatus::Disputed])?;
+                disputed |= market.status == MarketStatus::Disputed;
+            }
+
+            // The same steps as `resolve_market`
+            let outcome = if index == winner {
+                Outcome::Yes
+            } else {
+                Outcome::No
+            };
+            {
+                let mut pool = pool.load_mut()?;
+                pool.accumulate_price(market.resolved_outcome())?;
+                pool.liquidity_value = math::resolved_liquidity_value(&pool.state(), outcome);
+                // The winner's YES tokens are paid from its vault alone
+                if index == winner && previous_winner != Some(winner) {
+                    pool.usd_collateral += converted;
+                    pool.group_collateral = 0;
+                } else if index != winner && previous_winner == Some(index) {
+                    pool.usd_collateral -= converted;
+                    pool.group_collateral = converted;
+                }
+            }
+            market.outcome = Some(outcome as u8);
+            market.resolved = true;
+            market.resolved_at = Clock::get()?.unix_timestamp;
+
+            emit_event!(status_changed_event(
+                &mut market,
+                MarketStatus::Resolved,
+                oracle
+            )?);
+            emit_event!(MarketResolvedEvent {
+                header: market.next_event()?,
+                market: market.key(),
+                solver: oracle,
+                outcome: outcome as u8,
+            });
+            market.exit(&crate::ID)?;
+
+            if index == winner {
+                winner_vault = Some(vault);
+            } else if previous_winner == Some(index) {
+                previous_winner_accounts = Some((market, vault));
+            }
+        }
+
+        require!(
+            previous_winner.is_none() || disputed,
+            MarketError::MarketResolved
+        );
+        let winner_vault = winner_vault.ok_or(MarketError::InvalidBatch)?;
+        if converted > 0 {
+            if let Some((previous_market, previous_vault)) = &previous_winner_accounts {
+                transfer_outcome(
+                    previous_vault,
+                    &winner_vault,
+                    previous_market,
+                    &ctx.accounts.token_program,
+                    converted,
+                )?;
+            } else if previous_winner.is_none() {
+                group.with_signer(|signer| {
+                    let cpi_context = CpiContext::new_with_signer(
+                        ctx.accounts.token_program.to_account_info(),
+                        token::Transfer {
+                            from: ctx.accounts.vault.to_account_info(),
+                            to: winner_vault.to_account_info(),
+                            authority: group.to_account_info(),
+                        },
+                        signer,
+                    );
+
+                    token::transfer(cpi_context, converted)
+                })?;
+            }
+        }
+
+        let group = &mut ctx.accounts.group;
+        group.winner = Some(winner as u8);
+        emit_event!(MarketGroupResolvedEvent {
+            header: group.next_event()?,
+            group: group.key(),
+            solver: oracle,
+            winner: group.markets[winner],
+            converted,
+        });
+
+        Ok(())
+    }
+
+    // Burns `amount` NO tokens of every member of the group for `amount` USD times the
+    // members but one: exactly one member resolves YES, so that is what they pay out
+    // together whatever the outcome. Arbitrageurs converting whenever the NO tokens are
+    // cheaper keep the prices of the members consistent. Each vault releases the USD of
+    // its burnt tokens, the group vault keeps `amount` of it for the YES tokens left
+    // without their NO until `resolve_market_group`. The remaining accounts come in groups
+    // of `NO_CONVERSION_LEN`, one per member in the group's order.
+    pub fn convert_no_tokens<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ConvertNoTokens<'info>>,
+        amount: u64,
+    ) -> Result<()> {
+        require!(amount > 0, MarketError::Zero);
+        let group = &ctx.accounts.group;
+        let members = ctx.remaining_accounts;
+        require!(group.winner.is_none(), MarketError::MarketResolved);
+        require!(
+            members.len() == group.markets.len() * NO_CONVERSION_LEN,
+            MarketError::InvalidBatch
+        );
+
+        for (index, member) in members.chunks_exact(NO_CONVERSION_LEN).enumerate() {
+            let market = Account::<Market>::try_from(&member[0])?;
+            let pool = AccountLoader::<MarketPool>::try_from(&member[1])?;
+            let vault = Account::<TokenAccount>::try_from(&member[2])?;
+            let no_mint = Account::<Mint>::try_from(&member[3])?;
+            let user_no_account = Account::<TokenAccount>::try_from(&member[4])?;
+            require!(
+                market.key() == group.markets[index]
+                    && pool.load()?.market == market.key()
+                    && vault.key() == market.vault
+                    && no_mint.key() == market.no_mint
+                    && user_no_account.mint == market.no_mint,
+                MarketError::InvalidBatch
+            );
+            market.require_status(MarketStatus::TRADING)?;
 
-        // The first thing we are going to do is to burn the user's YES and NO tokens
-        if user_yes_amount > 0 {
             let cpi_context = CpiContext::new(
                 ctx.accounts.token_program.to_account_info(),
                 token::Burn {
-                    mint: ctx.accounts.yes_mint.to_account_info(),
-                    from: ctx.accounts.user_yes_account.to_account_info(),
-                    authority: ctx.accounts.user.to_account_info(), // the user must sign
+                    mint: no_mint.to_account_info(),
+                    from: user_no_account.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
                 },
             );
+            token::burn(cpi_context, amount)?;
+
+            // The first vault funds the group, the others pay the user
+            let to = if index == 0 {
+                &ctx.accounts.vault
+            } else {
+                &ctx.accounts.user_usd_account
+            };
+            transfer_outcome(&vault, to, &market, &ctx.accounts.token_program, amount)?;
 
-            token::burn(cpi_context, user_yes_amount)?;
+            let mut pool = pool.load_mut()?;
+            pool.total_no_mints -= amount;
+            pool.usd_collateral -= amount;
+            pool.group_collateral += amount;
         }
 
-        if user_no_amount > 0 {
-            let cpi_context = CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                token::Burn {
-                    mint: ctx.accounts.no_mint.to_account_info(),
-                    from: ctx.accounts.user_no_account.to_account_info(),
-                    authority: ctx.accounts.user.to_account_info(), // the user must sign
-                },
+        let usd_received = amount
+            .checked_mul(members.len() as u64 / NO_CONVERSION_LEN as u64 - 1)
+            .ok_or(MarketError::MathOverflow)?;
+        let group = &mut ctx.accounts.group;
+        group.converted += amount;
+
+        emit_event!(NoTokensConvertedEvent {
+            header: group.next_event()?,
+            group: group.key(),
+            user: ctx.accounts.user.key(),
+            amount,
+            usd_received,
+        });
+
+        Ok(())
+    }
+
+    pub fn resolve_user_winnings(ctx: Context<ResolveUserWinnings>) -> Result<()> {
+        ctx.accounts.market.require_status(MarketStatus::SETTLED)?;
+        let user_yes_amount = ctx.accounts.user_yes_account.amount;
+        let user_no_amount = ctx.accounts.user_no_account.amount;
+        require!(
+            user_yes_amount > 0 || user_no_amount > 0,
+            MarketError::InsufficientFunds
+        );
+        let trader = trader(&ctx.accounts.user, &ctx.accounts.session);
+        if let Some(session) = &mut ctx.accounts.session {
+            session.authorize(&ctx.accounts.market.key(), 0, Clock::get()?.unix_timestamp)?;
+            require_keys_eq!(
+                ctx.accounts.user_usd_account.owner,
+                trader,
+                MarketError::InvalidSession
             );
+        }
+
+        // The first thing we are going to do is to burn the user's YES and NO tokens
+        if user_yes_amount > 0 {
+            burn_from_trader(
+                &ctx.accounts.yes_mint,
+                &ctx.accounts.user_yes_account,
+                &ctx.accounts.user,
+                &ctx.accounts.session,
+                &ctx.accounts.token_program,
+                user_yes_amount,
+            )?;
+        }
 
-            token::burn(cpi_context, user_no_amount)?;
+        if user_no_amount > 0 {
+            burn_from_trader(
+                &ctx.accounts.no_mint,
+                &ctx.accounts.user_no_account,
+                &ctx.accounts.user,
+                &ctx.accounts.session,
+                &ctx.accounts.token_program,
+                user_no_amount,
+            )?;
         }
 
         // No we are going to compute how much money the winnings are worth
-        let winning_amount = if ctx.accounts.market.outcome == Some(1) {
-            user_yes_amount
-        } else {
-            user_no_amount
-        };
+        let winning_amount = ctx
+            .accounts
+            .market
+            .settlement_value(user_yes_amount, user_no_amount)?;
 
         // Now we're gonna transfer funds from the vault to the user
         transfer_outcome(
@@ -661,23 +1498,22 @@ pub mod solana_bet_placing_market {
             &ctx.accounts.market,
             &ctx.accounts.token_program,
             winning_amount,
-            &[&[
-                b"market",
-                ctx.accounts.market.authority.as_ref(),
-                &ctx.accounts.market.market_number.to_le_bytes(),
-                &ctx.accounts.market.bump.to_le_bytes(),
-            ]],
         )?;
 
         // And we are going to edit the pool
-        let pool = &mut ctx.accounts.pool;
+        let mut pool = ctx.accounts.pool.load_mut()?;
         pool.total_yes_mints -= user_yes_amount;
         pool.total_no_mints -= user_no_amount;
         pool.usd_collateral -= winning_amount;
 
-        emit!(ResolveUserWinningsEvent {
+        if let Some(position) = &mut ctx.accounts.position {
+            position.record_settlement(winning_amount);
+        }
+
+        emit_event!(ResolveUserWinningsEvent {
+            header: ctx.accounts.market.next_event()?,
             market: ctx.accounts.market.key(),
-            user: ctx.accounts.user.key(),
+            user: trader,
             user_yes_tokens: user_yes_amount,
             user_no_tokens: user_no_amount,
             winning_amount,
@@ -685,807 +1521,3198 @@ pub mod solana_bet_placing_market {
 
         Ok(())
     }
-}
 
-#[inline(never)]
-pub fn mint_outcome<'info>(
-    mint: &Account<'info, Mint>,
-    to_account: &Account<'info, TokenAccount>,
-    market: &Account<'info, Market>,
-    token_program: &Program<'info, Token>,
-    amount: u64,
-    signer: &[&[&[u8]]],
-) -> Result<()> {
-    // Creating the context useful for the minting
-    let cpi_context = CpiContext::new_with_signer(
-        token_program.to_account_info(),
-        token::MintTo {
-            mint: mint.to_account_info(),
-            to: to_account.to_account_info(),
-            authority: market.to_account_info(),
-        },
-        signer,
-    );
+    // `resolve_user_winnings` over many markets at once. The remaining accounts come in
+    // groups of `BATCH_CLAIM_GROUP_LEN`, see `claim_batch_group`. Markets not resolved yet
+    // or without anything to claim are skipped, any other mismatch fails the whole batch.
+    // User positions are not updated, claim those markets one by one to keep them.
+    pub fn batch_claim_winnings<'info>(
+        ctx: Context<'_, '_, 'info, 'info, BatchClaimWinnings<'info>>,
+    ) -> Result<()> {
+        let groups = ctx.remaining_accounts;
+        require!(
+            !groups.is_empty()
+                && groups
+                    .chunks_exact(BATCH_CLAIM_GROUP_LEN)
+                    .remainder()
+                    .is_empty(),
+            MarketError::InvalidBatch
+        );
 
-    // Once created the context, then mint
-    token::mint_to(cpi_context, amount)
-}
+        let mut claimed_markets = Vec::new();
+        let mut skipped_markets = Vec::new();
+        let mut total_winnings: u64 = 0;
+        for group in groups.chunks(BATCH_CLAIM_GROUP_LEN) {
+            match claim_batch_group(group, &ctx.accounts.user, &ctx.accounts.token_program)? {
+                Some(event) => {
+                    claimed_markets.push(group[0].key());
+                    total_winnings = total_winnings
+                        .checked_add(event.winning_amount)
+                        .ok_or(MarketError::MathOverflow)?;
+                    emit_event!(event);
+                }
+                None => skipped_markets.push(group[0].key()),
+            }
+        }
 
-pub fn transfer_outcome<'info>(
-    from_mint_account: &Account<'info, TokenAccount>,
-    to_mint_account: &Account<'info, TokenAccount>,
-    market: &Account<'info, Market>,
-    token_program: &Program<'info, Token>,
-    amount: u64,
-    signer: &[&[&[u8]]],
-) -> Result<()> {
-    let cpi_context = CpiContext::new_with_signer(
-        token_program.to_account_info(),
-        token::Transfer {
-            from: from_mint_account.to_account_info(),
-            to: to_mint_account.to_account_info(),
-            authority: market.to_account_info(),
-        },
-        signer,
-    );
+        emit_event!(BatchClaimEvent {
+            header: EventHeader::unsequenced()?,
+            user: ctx.accounts.user.key(),
+            claimed_markets,
+            skipped_markets,
+            total_winnings,
+        });
 
-    token::transfer(cpi_context, amount)
-}
+        Ok(())
+    }
 
-fn burn_mint_tokens<'info>(
-    mint: &Account<'info, Mint>,
-    from_account: &Account<'info, TokenAccount>,
-    market: &Account<'info, Market>,
-    token_program: &Program<'info, Token>,
-    amount: u64,
-    signer: &[&[&[u8]]],
-) -> Result<()> {
-    let cpi_context = CpiContext::new_with_signer(
-        token_program.to_account_info(),
-        token::Burn {
-            mint: mint.to_account_info(),
-            from: from_account.to_account_info(),
-            authority: market.to_account_info(),
-        },
-        signer,
-    );
+    // Lets `settle_holders` burn the user's outcome tokens once the market is resolved, so
+    // that the winnings get paid without the user coming back
+    pub fn approve_settlement(ctx: Context<ApproveSettlement>) -> Result<()> {
+        for account in [
+            &ctx.accounts.user_yes_account,
+            &ctx.accounts.user_no_account,
+        ] {
+            // Not over the delegation of a session, which `revoke_session` lifts
+            require_undelegated(account, &ctx.accounts.market.key())?;
+            let cpi_context = CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Approve {
+                    to: account.to_account_info(),
+                    delegate: ctx.accounts.market.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            );
 
-    token::burn(cpi_context, amount)
-}
+            token::approve(cpi_context, u64::MAX)?;
+        }
 
-#[inline(never)]
-fn add_liquidity_equal_outcomes(add_liquidity: &mut PoolLiquidity, usd_amount: u64) -> Result<()> {
-    let market = &add_liquidity.market;
-    let pool = &mut add_liquidity.pool;
+        emit_event!(SettlementApprovedEvent {
+            header: ctx.accounts.market.next_event()?,
+            market: ctx.accounts.market.key(),
+            user: ctx.accounts.user.key(),
+            user_yes_account: ctx.accounts.user_yes_account.key(),
+            user_no_account: ctx.accounts.user_no_account.key(),
+        });
 
-    // mint first the YES tokens
-    mint_outcome(
-        &add_liquidity.yes_mint,
-        &add_liquidity.liquidity_yes_tokens_account,
-        market,
-        &add_liquidity.token_program,
-        usd_amount,
-        &[&[
-            b"market",
-            market.authority.as_ref(),
-            &market.market_number.to_le_bytes(),
-            &market.bump.to_le_bytes(),
-        ]],
-    )?;
+        Ok(())
+    }
 
-    // mint the same number of NO tokens
-    mint_outcome(
-        &add_liquidity.no_mint,
-        &add_liquidity.liquidity_no_tokens_account,
-        market,
-        &add_liquidity.token_program,
-        usd_amount,
-        &[&[
-            b"market",
-            market.authority.as_ref(),
-            &market.market_number.to_le_bytes(),
-            &market.bump.to_le_bytes(),
-        ]],
-    )?;
+    // USD the authority puts in the vault to reward the keepers running `settle_holders`,
+    // `keeper_reward` for every holder they pay out at least
+    // `Market::MIN_PAYOUT_PER_KEEPER_REWARD` times that. The reward no longer changes once
+    // the keepers have started on it.
+    pub fn fund_settlement_budget(
+        ctx: Context<FundSettlementBudget>,
+        budget: u64,
+        keeper_reward: u64,
+    ) -> Result<()> {
+        let market = &ctx.accounts.market;
+        market.require_status(MarketStatus::FUNDABLE)?;
+        require!(
+            !market.settlement_started || keeper_reward == market.keeper_reward,
+            MarketError::SettlementStarted
+        );
+        {
+            let cpi_accounts = token::Transfer {
+                from: ctx.accounts.authority_usd_account.to_account_info(),
+                to: ctx.accounts.vault.to_account_info(),
+                authority: ctx.accounts.authority.to_account_info(),
+            };
+            let cpi_ctx =
+                CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
 
-    // Now we also mint the lp shares
-    mint_outcome(
-        &add_liquidity.lp_share_mint,
-        &add_liquidity.user_lp_share_account,
-        market,
-        &add_liquidity.token_program,
-        usd_amount,
-        &[&[
-            b"market",
-            market.authority.as_ref(),
-            &market.market_number.to_le_bytes(),
-            &market.bump.to_le_bytes(),
-        ]],
-    )?;
+            token::transfer(cpi_ctx, budget)?;
+        }
 
-    // Once added, we should also update the pool yes mints with the new values
-    pool.yes_liquidity += usd_amount;
-    pool.no_liquidity += usd_amount;
-    pool.total_yes_mints += usd_amount;
-    pool.total_no_mints += usd_amount;
+        let market = &mut ctx.accounts.market;
+        market.settlement_budget = market
+            .settlement_budget
+            .checked_add(budget)
+            .ok_or(MarketError::MathOverflow)?;
+        market.keeper_reward = keeper_reward;
+
+        emit_event!(SettlementBudgetFundedEvent {
+            header: market.next_event()?,
+            market: market.key(),
+            budget,
+            keeper_reward,
+            settlement_budget: market.settlement_budget,
+        });
 
-    // And also update the liquidity values & shares
-    pool.liquidity_value += usd_amount;
-    pool.liquidity_shares += usd_amount;
+        Ok(())
+    }
 
-    emit!(LiquidityAddedEvent {
-        market: market.key(),
-        user: add_liquidity.user.key(),
-        amount: usd_amount,
-        liquidity_shares_gained: usd_amount,
-        pool_total_liquidity_shares: pool.liquidity_shares,
-        usd_added_to_pool: usd_amount,
-        yes_added_to_pool: usd_amount,
-        no_added_to_pool: usd_amount,
-        yes_given_to_user: 0,
-        no_given_to_user: 0,
-        yes_minted: usd_amount,
-        no_minted: usd_amount,
-    });
+    // Permissionless crank paying the holders of a settled market. The remaining accounts
+    // are pairs of a holder's outcome token account, delegated to the market through
+    // `approve_settlement`, and the holder's USD associated token account. Other token
+    // accounts are skipped. The keeper gets `keeper_reward` per holder paid enough, see
+    // `Market::MIN_PAYOUT_PER_KEEPER_REWARD`, as long as the settlement budget lasts.
+    pub fn settle_holders<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SettleHolders<'info>>,
+    ) -> Result<()> {
+        ctx.accounts.market.require_status(MarketStatus::SETTLED)?;
+        let pairs = ctx.remaining_accounts;
+        require!(
+            !pairs.is_empty() && pairs.chunks_exact(2).remainder().is_empty(),
+            MarketError::InvalidBatch
+        );
+        let market = &ctx.accounts.market;
+        let mut pool = ctx.accounts.pool.load_mut()?;
+
+        // Counted here while `market` is borrowed, stored back after the loop
+        let mut event_seq = market.event_seq;
+        let mut settled_holders: u32 = 0;
+        let mut rewarded_holders: u64 = 0;
+        let mut paid_out: u64 = 0;
+        let min_rewarded_payout = market
+            .keeper_reward
+            .saturating_mul(Market::MIN_PAYOUT_PER_KEEPER_REWARD);
+        let mut skipped_accounts = Vec::new();
+        for pair in pairs.chunks_exact(2) {
+            let holder_account = Account::<TokenAccount>::try_from(&pair[0])?;
+            let amount = holder_account.amount.min(holder_account.delegated_amount);
+            // Both outcomes are paid once the market is invalidated, the winning one otherwise
+            let (mint, outcome) = match market.outcome_of_mint(&holder_account.mint) {
+                Some(Outcome::Yes) => (&ctx.accounts.yes_mint, Outcome::Yes),
+                _ => (&ctx.accounts.no_mint, Outcome::No),
+            };
+            let winning_amount = match outcome {
+                Outcome::Yes => market.settlement_value(amount, 0)?,
+                Outcome::No => market.settlement_value(0, amount)?,
+            };
+            if holder_account.mint != mint.key()
+                || holder_account.delegate != COption::Some(market.key())
+                || winning_amount == 0
+            {
+                skipped_accounts.push(holder_account.key());
+                continue;
+            }
+            let holder_usd_account = Account::<TokenAccount>::try_from(&pair[1])?;
+            require!(
+                holder_usd_account.key()
+                    == get_associated_token_address(&holder_account.owner, &market.usd_mint),
+                MarketError::InvalidHolderAccount
+            );
 
-    Ok(())
-}
+            burn_mint_tokens(
+                mint,
+                &holder_account,
+                market,
+                &ctx.accounts.token_program,
+                amount,
+            )?;
+            transfer_outcome(
+                &ctx.accounts.vault,
+                &holder_usd_account,
+                market,
+                &ctx.accounts.token_program,
+                winning_amount,
+            )?;
 
-#[inline(never)]
-fn add_unequal_outcomes_with_more_no(
-    add_liquidity: &mut PoolLiquidity,
-    yes_token_price: u64,
-    no_token_price: u64,
-    usd_amount: u64,
-) -> Result<()> {
-    let pool = &mut add_liquidity.pool;
-    let market = &add_liquidity.market;
+            match outcome {
+                Outcome::Yes => pool.total_yes_mints -= amount,
+                Outcome::No => pool.total_no_mints -= amount,
+            }
+            pool.usd_collateral -= winning_amount;
+
+            event_seq += 1;
+            emit_event!(ResolveUserWinningsEvent {
+                header: EventHeader::new(event_seq)?,
+                market: market.key(),
+                user: holder_account.owner,
+                user_yes_tokens: if outcome == Outcome::Yes { amount } else { 0 },
+                user_no_tokens: if outcome == Outcome::No { amount } else { 0 },
+                winning_amount,
+            });
+            settled_holders += 1;
+            paid_out += winning_amount;
+            if winning_amount >= min_rewarded_payout {
+                rewarded_holders += 1;
+            }
+        }
 
-    // Total Minted tokens
-    let new_no_minted_tokens = pool.no_liquidity + usd_amount;
-    let new_yes_minted_tokens = pool.yes_liquidity + usd_amount;
+        let keeper_reward = market
+            .keeper_reward
+            .saturating_mul(rewarded_holders)
+            .min(market.settlement_budget);
+        if keeper_reward > 0 {
+            transfer_outcome(
+                &ctx.accounts.vault,
+                &ctx.accounts.keeper_usd_account,
+                market,
+                &ctx.accounts.token_program,
+                keeper_reward,
+            )?;
+        }
+        ctx.accounts.market.settlement_budget -= keeper_reward;
+        ctx.accounts.market.event_seq = event_seq;
+        if settled_holders > 0 {
+            ctx.accounts.market.settlement_started = true;
+        }
 
-    // if there is more liquidity in the NO pool, it means it is less likely to win
-    // therefore, we are going to give back to the user the more probable outcome
+        emit_event!(HoldersSettledEvent {
+            header: ctx.accounts.market.next_event()?,
+            market: ctx.accounts.market.key(),
+            keeper: ctx.accounts.keeper.key(),
+            settled_holders,
+            paid_out,
+            keeper_reward,
+            skipped_accounts,
+        });
 
-    // Pool minted tokens
-    let new_lp_no_minted_tokens = new_no_minted_tokens; // It is the same being the less probable chance
-    let new_lp_yes_minted_tokens =
-        ((no_token_price as u128 * new_no_minted_tokens as u128) / yes_token_price as u128) as u64;
-    let liquidity_squared = new_lp_no_minted_tokens as u128 * new_lp_yes_minted_tokens as u128;
-    let new_liquidity_value = sqrt_u128(liquidity_squared) as u64;
+        Ok(())
+    }
 
-    // Now, we calculate what we have to give to the user
-    let user_belonging_yes_tokens = new_yes_minted_tokens - new_lp_yes_minted_tokens;
-    let user_belonging_liquidity_shares = new_liquidity_value - pool.liquidity_shares;
+    // The authority takes back what is left of the settlement budget once the market has no
+    // holder left to pay, the tokens still in the pool being the LPs' to withdraw
+    pub fn reclaim_settlement_budget(ctx: Context<ReclaimSettlementBudget>) -> Result<()> {
+        let market = &ctx.accounts.market;
+        market.require_status(MarketStatus::SETTLED)?;
+        let budget = market.settlement_budget;
+        require!(budget > 0, MarketError::Zero);
+        {
+            let pool = ctx.accounts.pool.load()?;
+            let held_yes_tokens = pool.total_yes_mints - pool.yes_liquidity;
+            let held_no_tokens = pool.total_no_mints - pool.no_liquidity;
+            require!(
+                market.settlement_value(held_yes_tokens, held_no_tokens)? == 0,
+                MarketError::HoldersNotSettled
+            );
+        }
 
-    // Now, we first mint the NO tokens in the liquidity pool
-    mint_outcome(
-        &add_liquidity.no_mint,
-        &add_liquidity.liquidity_no_tokens_account,
-        market,
-        &add_liquidity.token_program,
-        usd_amount,
-        &[&[
-            b"market",
-            market.authority.as_ref(),
-            &market.market_number.to_le_bytes(),
-            &market.bump.to_le_bytes(),
-        ]],
-    )?;
+        transfer_outcome(
+            &ctx.accounts.vault,
+            &ctx.accounts.authority_usd_account,
+            market,
+            &ctx.accounts.token_program,
+            budget,
+        )?;
 
-    // Then mint the YES tokens to the liquidity pool
-    let yes_lp_minted = new_lp_yes_minted_tokens - pool.yes_liquidity;
-    mint_outcome(
-        &add_liquidity.yes_mint,
-        &add_liquidity.liquidity_yes_tokens_account,
-        market,
-        &add_liquidity.token_program,
-        yes_lp_minted,
-        &[&[
-            b"market",
-            market.authority.as_ref(),
-            &market.market_number.to_le_bytes(),
-            &market.bump.to_le_bytes(),
-        ]],
-    )?;
+        let market = &mut ctx.accounts.market;
+        market.settlement_budget = 0;
+        emit_event!(SettlementBudgetReclaimedEvent {
+            header: market.next_event()?,
+            market: market.key(),
+            budget,
+        });
 
-    // Then mint what belongs to the user
-    // 1. The remaining YES tokens
-    mint_outcome(
-        &add_liquidity.yes_mint,
-        &add_liquidity.user_yes_account,
-        market,
-        &add_liquidity.token_program,
-        user_belonging_yes_tokens,
-        &[&[
-            b"market",
-            market.authority.as_ref(),
-            &market.market_number.to_le_bytes(),
-            &market.bump.to_le_bytes(),
-        ]],
-    )?;
+        Ok(())
+    }
 
-    // 2. The LP shares
-    mint_outcome(
-        &add_liquidity.lp_share_mint,
-        &add_liquidity.user_lp_share_account,
-        market,
-        &add_liquidity.token_program,
-        user_belonging_liquidity_shares,
-        &[&[
-            b"market",
-            market.authority.as_ref(),
-            &market.market_number.to_le_bytes(),
-            &market.bump.to_le_bytes(),
-        ]],
-    )?;
+    pub fn audit_market(ctx: Context<AuditMarket>) -> Result<()> {
+        let pool = ctx.accounts.pool.load()?;
+        let vault_balance = ctx.accounts.vault.amount;
+        let yes_supply = ctx.accounts.yes_mint.supply;
+        let no_supply = ctx.accounts.no_mint.supply;
+
+        // Every outstanding outcome token can be redeemed for 1 USD if its side wins,
+        // so before resolution the vault has to cover the bigger of the two supplies.
+        // Once resolved, only the winning tokens are still worth something, and once
+        // invalidated every token is worth half a USD. The group of the market, if any,
+        // pays part of the YES tokens instead of the vault.
+        let market = &ctx.accounts.market;
+        let yes_payout = yes_supply.saturating_sub(pool.group_collateral);
+        let worst_case_payout = match market.outcome {
+            _ if market.status == MarketStatus::Invalid => {
+                math::invalid_settlement_value(yes_supply, no_supply)
+            }
+            Some(0) => no_supply,
+            Some(_) => yes_payout,
+            None => yes_payout.max(no_supply),
+        };
 
-    // Send the event
-    emit!(LiquidityAddedEvent {
-        market: market.key(),
-        user: add_liquidity.user.key(),
-        amount: usd_amount,
-        liquidity_shares_gained: user_belonging_liquidity_shares,
-        pool_total_liquidity_shares: new_liquidity_value,
-        usd_added_to_pool: user_belonging_liquidity_shares,
-        yes_added_to_pool: yes_lp_minted,
-        no_added_to_pool: usd_amount,
-        yes_given_to_user: user_belonging_yes_tokens,
-        no_given_to_user: 0,
-        yes_minted: usd_amount,
-        no_minted: usd_amount,
-    });
-
-    // Now we update the pool
-    pool.yes_liquidity += yes_lp_minted;
-    pool.no_liquidity += usd_amount;
-    pool.total_yes_mints += usd_amount;
-    pool.total_no_mints += usd_amount;
-
-    // The shares for now don't differ from the value
-    pool.liquidity_value = new_liquidity_value;
-    pool.liquidity_shares = new_liquidity_value;
+        // The pool's own representation has to match what the token program holds
+        let supplies_match = yes_supply == pool.total_yes_mints
+            && no_supply == pool.total_no_mints
+            && ctx.accounts.lp_share_mint.supply == pool.liquidity_shares;
+        let liquidity_matches = ctx.accounts.liquidity_yes_tokens_account.amount
+            == pool.yes_liquidity
+            && ctx.accounts.liquidity_no_tokens_account.amount == pool.no_liquidity;
 
-    Ok(())
-}
+        emit_event!(MarketAuditEvent {
+            header: EventHeader::unsequenced()?,
+            market: ctx.accounts.market.key(),
+            vault_balance,
+            usd_collateral: pool.usd_collateral,
+            yes_supply,
+            total_yes_mints: pool.total_yes_mints,
+            no_supply,
+            total_no_mints: pool.total_no_mints,
+            lp_share_supply: ctx.accounts.lp_share_mint.supply,
+            liquidity_shares: pool.liquidity_shares,
+            worst_case_payout,
+            vault_surplus: vault_balance as i128 - worst_case_payout as i128,
+            collateral_covered: vault_balance >= pool.usd_collateral,
+            supplies_match,
+            liquidity_matches,
+        });
 
-#[inline(never)]
-fn add_unequal_outcomes_with_more_yes(
-    add_liquidity: &mut PoolLiquidity,
-    yes_token_price: u64,
-    no_token_price: u64,
-    usd_amount: u64,
-) -> Result<()> {
-    let pool = &mut add_liquidity.pool;
-    let market = &add_liquidity.market;
+        // The only hard failure is a vault that cannot pay everybody out
+        require!(
+            vault_balance >= worst_case_payout,
+            MarketError::VaultInsolvent
+        );
 
-    // Total Minted tokens
-    let new_no_minted_tokens = pool.no_liquidity + usd_amount;
-    let new_yes_minted_tokens = pool.yes_liquidity + usd_amount;
+        Ok(())
+    }
 
-    // if there is more liquidity in the YES pool, it means it is less likely to win
-    // therefore, we are going to give back to the user the more probable outcome: NO
-    // Pool minted tokens
-    let new_lp_yes_minted_tokens = new_yes_minted_tokens; // It is the same being the less probable chance
-    let new_lp_no_minted_tokens =
-        ((yes_token_price as u128 * new_yes_minted_tokens as u128) / no_token_price as u128) as u64;
-    let liquidity_squared = new_lp_no_minted_tokens as u128 * new_lp_yes_minted_tokens as u128;
-    let new_liquidity_value = sqrt_u128(liquidity_squared) as u64;
+    // The quote instructions below never mutate any account. They are meant to be
+    // called through `simulateTransaction`, the result being sent back as return data.
+    pub fn quote_purchase(
+        ctx: Context<QuoteMarket>,
+        usd_amount: u64,
+        purchased_outcome_mint_pubkey: Pubkey,
+    ) -> Result<PurchaseQuote> {
+        require!(usd_amount > 0, MarketError::Zero);
+        ctx.accounts.market.require_status(MarketStatus::TRADING)?;
+
+        let outcome = ctx
+            .accounts
+            .market
+            .outcome_of_mint(&purchased_outcome_mint_pubkey)
+            .ok_or(MarketError::MintNotAllowed)?;
+        let purchase = math::buy(
+            &ctx.accounts
+                .pool
+                .load()?
+                .trading_state(&ctx.accounts.market, false),
+            usd_amount,
+            outcome,
+        )?;
+        let (yes_price_after, no_price_after) =
+            math::prices(purchase.yes_liquidity_after, purchase.no_liquidity_after)?;
 
-    // Now, we calculate what we have to give to the user
-    let user_belonging_no_tokens = new_no_minted_tokens - new_lp_no_minted_tokens;
-    let user_belonging_liquidity_shares = new_liquidity_value - pool.liquidity_shares;
+        Ok(PurchaseQuote {
+            usd_amount,
+            shares_purchased: purchase.shares_purchased,
+            shares_from_pool: purchase.shares_from_pool,
+            fee: purchase.fee,
+            yes_price_before: purchase.yes_price_before,
+            no_price_before: purchase.no_price_before,
+            yes_price_after,
+            no_price_after,
+            pool_yes_liquidity_after: purchase.yes_liquidity_after,
+            pool_no_liquidity_after: purchase.no_liquidity_after,
+        })
+    }
 
-    // Now, we first mint the YES tokens in the liquidity pool
-    mint_outcome(
-        &add_liquidity.yes_mint,
-        &add_liquidity.liquidity_yes_tokens_account,
-        market,
-        &add_liquidity.token_program,
-        usd_amount,
-        &[&[
-            b"market",
-            market.authority.as_ref(),
-            &market.market_number.to_le_bytes(),
-            &market.bump.to_le_bytes(),
-        ]],
-    )?;
+    pub fn quote_sell(
+        ctx: Context<QuoteMarket>,
+        shares: u64,
+        sold_outcome_mint_pubkey: Pubkey,
+    ) -> Result<SaleQuote> {
+        require!(shares > 0, MarketError::Zero);
+        ctx.accounts.market.require_status(MarketStatus::TRADING)?;
+
+        let outcome = ctx
+            .accounts
+            .market
+            .outcome_of_mint(&sold_outcome_mint_pubkey)
+            .ok_or(MarketError::MintNotAllowed)?;
+        let sale = math::sell(
+            &ctx.accounts
+                .pool
+                .load()?
+                .trading_state(&ctx.accounts.market, false),
+            shares,
+            outcome,
+        )?;
+        let (yes_price_after, no_price_after) =
+            math::prices(sale.yes_liquidity_after, sale.no_liquidity_after)?;
+
+        Ok(SaleQuote {
+            shares,
+            usd_received: sale.usd_returned,
+            fee: sale.fee,
+            yes_price_before: sale.yes_price_before,
+            no_price_before: sale.no_price_before,
+            yes_price_after,
+            no_price_after,
+            pool_yes_liquidity_after: sale.yes_liquidity_after,
+            pool_no_liquidity_after: sale.no_liquidity_after,
+        })
+    }
 
-    // Then mint the NO tokens to the liquidity pool
-    let no_lp_minted = new_lp_no_minted_tokens - pool.no_liquidity;
-    mint_outcome(
-        &add_liquidity.no_mint,
-        &add_liquidity.liquidity_no_tokens_account,
-        market,
-        &add_liquidity.token_program,
-        no_lp_minted,
-        &[&[
-            b"market",
-            market.authority.as_ref(),
-            &market.market_number.to_le_bytes(),
-            &market.bump.to_le_bytes(),
-        ]],
-    )?;
+    pub fn quote_add_liquidity(
+        ctx: Context<QuoteMarket>,
+        usd_amount: u64,
+    ) -> Result<AddLiquidityQuote> {
+        require!(usd_amount > 0, MarketError::Zero);
+        ctx.accounts.market.require_status(MarketStatus::TRADING)?;
 
-    // Then mint what belongs to the user
-    // 1. The remaining NO tokens
-    mint_outcome(
-        &add_liquidity.no_mint,
-        &add_liquidity.user_no_account,
-        market,
-        &add_liquidity.token_program,
-        user_belonging_no_tokens,
-        &[&[
-            b"market",
-            market.authority.as_ref(),
-            &market.market_number.to_le_bytes(),
-            &market.bump.to_le_bytes(),
-        ]],
-    )?;
+        let added = math::add_liquidity(&ctx.accounts.pool.load()?.state(), usd_amount)?;
 
-    // 2. The LP shares
-    mint_outcome(
-        &add_liquidity.lp_share_mint,
-        &add_liquidity.user_lp_share_account,
-        market,
-        &add_liquidity.token_program,
-        user_belonging_liquidity_shares,
-        &[&[
-            b"market",
-            market.authority.as_ref(),
-            &market.market_number.to_le_bytes(),
-            &market.bump.to_le_bytes(),
-        ]],
-    )?;
+        Ok(AddLiquidityQuote {
+            usd_amount,
+            liquidity_shares_gained: added.liquidity_shares_gained,
+            yes_given_to_user: added.yes_to_user,
+            no_given_to_user: added.no_to_user,
+            yes_added_to_pool: added.yes_to_pool,
+            no_added_to_pool: added.no_to_pool,
+            pool_liquidity_value_after: added.liquidity_value_after,
+        })
+    }
 
-    // Send the event
-    emit!(LiquidityAddedEvent {
-        market: market.key(),
-        user: add_liquidity.user.key(),
-        amount: usd_amount,
-        liquidity_shares_gained: user_belonging_liquidity_shares,
-        pool_total_liquidity_shares: new_liquidity_value,
-        usd_added_to_pool: user_belonging_liquidity_shares,
-        yes_added_to_pool: usd_amount,
-        no_added_to_pool: no_lp_minted,
-        yes_given_to_user: 0,
-        no_given_to_user: user_belonging_no_tokens,
-        yes_minted: usd_amount,
-        no_minted: usd_amount,
-    });
-
-    // Now we update the pool
-    pool.yes_liquidity += usd_amount;
-    pool.no_liquidity += no_lp_minted;
-    pool.total_yes_mints += usd_amount;
-    pool.total_no_mints += usd_amount;
-
-    // The shares for now don't differ from the value
-    pool.liquidity_value = new_liquidity_value;
-    pool.liquidity_shares = new_liquidity_value;
+    pub fn quote_remove_liquidity(
+        ctx: Context<QuoteMarket>,
+        shares: u64,
+    ) -> Result<RemoveLiquidityQuote> {
+        require!(shares > 0, MarketError::Zero);
+        ctx.accounts
+            .market
+            .require_status(MarketStatus::WITHDRAWABLE)?;
+
+        let market = &ctx.accounts.market;
+        let removed = math::remove_liquidity(
+            &ctx.accounts.pool.load()?.state(),
+            shares,
+            market.resolved_outcome(),
+        )?;
 
-    Ok(())
-}
+        Ok(RemoveLiquidityQuote {
+            shares,
+            usd_received: removed.usd_to_user,
+            received_lowest_outcome_tokens: removed.outcome_tokens_to_user,
+            received_lowest_outcome_mint: match removed.outcome_given {
+                Some(Outcome::Yes) => market.yes_mint,
+                Some(Outcome::No) => market.no_mint,
+                None => Pubkey::default(),
+            },
+            pool_yes_liquidity_after: removed.yes_liquidity_after,
+            pool_no_liquidity_after: removed.no_liquidity_after,
+        })
+    }
+
+    pub fn get_prices(ctx: Context<QuoteMarket>) -> Result<MarketPrices> {
+        let market = &ctx.accounts.market;
+        let pool = ctx.accounts.pool.load()?;
+
+        // Once resolved, the winning outcome is worth exactly 1 USD and the other nothing
+        let (yes_price, no_price) = match market.resolved_outcome() {
+            _ if market.status == MarketStatus::Invalid => (SCALE as u64 / 2, SCALE as u64 / 2),
+            Some(Outcome::No) => (0, SCALE as u64),
+            Some(Outcome::Yes) => (SCALE as u64, 0),
+            None => math::prices(pool.yes_liquidity, pool.no_liquidity)?,
+        };
+
+        Ok(MarketPrices {
+            yes_price,
+            no_price,
+            yes_liquidity: pool.yes_liquidity,
+            no_liquidity: pool.no_liquidity,
+            liquidity_value: pool.liquidity_value,
+            liquidity_shares: pool.liquidity_shares,
+            resolved: market.resolved,
+            outcome: market.outcome,
+            status: market.status,
+        })
+    }
 
-// Babylonian method (Heron's method) for unsigned integers
-pub fn sqrt_u128(input: u128) -> u128 {
-    if input == 0 {
-        return 0;
+    // The current value of the running YES price sum. Keep it and pass it to `get_twap`
+    // later on to get the average price in between.
+    pub fn observe_price(ctx: Context<QuoteMarket>) -> Result<PriceObservation> {
+        ctx.accounts.pool.load()?.price_observation(
+            ctx.accounts.market.resolved_outcome(),
+            Clock::get()?.unix_timestamp,
+        )
     }
 
-    let mut guess = (input + 1) / 2;
-    let mut result = input;
+    // The time weighted average price since `since`. A trade only weighs as much as the
+    // time its price stood, so a single-block trade barely moves it.
+    pub fn get_twap(ctx: Context<QuoteMarket>, since: PriceObservation) -> Result<MarketTwap> {
+        let now = ctx.accounts.pool.load()?.price_observation(
+            ctx.accounts.market.resolved_outcome(),
+            Clock::get()?.unix_timestamp,
+        )?;
+        let yes_price = math::twap(
+            since.yes_price_cumulative,
+            since.timestamp,
+            now.yes_price_cumulative,
+            now.timestamp,
+        )?;
 
-    while guess < result {
-        result = guess;
-        guess = (input / guess + guess) / 2;
+        Ok(MarketTwap {
+            yes_price,
+            no_price: SCALE as u64 - yes_price,
+            start: since.timestamp,
+            end: now.timestamp,
+        })
     }
 
-    result
+    // What an LP position is worth now: the shares valued at the current prices, next to
+    // everything that went in and out of it
+    pub fn get_liquidity_nav(ctx: Context<QuoteLiquidityPosition>) -> Result<LiquidityNav> {
+        let market = &ctx.accounts.market;
+        let pool = ctx.accounts.pool.load()?;
+        let lp_position = &ctx.accounts.lp_position;
+        let resolved_outcome = market.resolved_outcome();
+        let yes_price = if market.status == MarketStatus::Invalid {
+            SCALE / 2
+        } else {
+            math::yes_price(&pool.state(), resolved_outcome)? as u128
+        };
+        let no_price = SCALE - yes_price;
+        let value = |yes: u64, no: u64| (yes as u128 * yes_price + no as u128 * no_price) / SCALE;
+
+        let shares = lp_position.lp_shares.min(pool.liquidity_shares);
+        let (redeemable_usd, redeemable_outcome_tokens, redeemable_outcome_mint, token_value) =
+            if shares == 0 {
+                (0, 0, Pubkey::default(), 0)
+            } else {
+                let removed = math::remove_liquidity(&pool.state(), shares, resolved_outcome)?;
+                let tokens = removed.outcome_tokens_to_user;
+                match removed.outcome_given {
+                    Some(Outcome::Yes) => (
+                        removed.usd_to_user,
+                        tokens,
+                        market.yes_mint,
+                        value(tokens, 0),
+                    ),
+                    Some(Outcome::No) => (
+                        removed.usd_to_user,
+                        tokens,
+                        market.no_mint,
+                        value(0, tokens),
+                    ),
+                    None => (removed.usd_to_user, 0, Pubkey::default(), 0),
+                }
+            };
+        let net_asset_value = redeemable_usd as u128 + token_value;
+        let received_tokens_value = value(lp_position.yes_received, lp_position.no_received);
+        let accrued_fees = lp_position
+            .accrued_fees
+            .checked_add(math::fees_earned(
+                lp_position.lp_shares,
+                pool.fee_per_share.get(),
+                lp_position.fee_per_share_checkpoint,
+            )?)
+            .ok_or(MarketError::MathOverflow)?;
+        let profit_and_loss =
+            (net_asset_value + lp_position.withdrawn_usd as u128 + received_tokens_value) as i128
+                - lp_position.deposited_usd as i128;
+        let impermanent_loss = profit_and_loss - accrued_fees as i128;
+        let liquidity_value_share = if pool.liquidity_shares == 0 {
+            0
+        } else {
+            pool.liquidity_value as u128 * shares as u128 / pool.liquidity_shares as u128
+        };
+
+        Ok(LiquidityNav {
+            lp_shares: lp_position.lp_shares,
+            liquidity_value_share: u64::try_from(liquidity_value_share)
+                .map_err(|_| MarketError::MathOverflow)?,
+            redeemable_usd,
+            redeemable_outcome_tokens,
+            redeemable_outcome_mint,
+            net_asset_value: u64::try_from(net_asset_value)
+                .map_err(|_| MarketError::MathOverflow)?,
+            deposited_usd: lp_position.deposited_usd,
+            withdrawn_usd: lp_position.withdrawn_usd,
+            received_tokens_value: u64::try_from(received_tokens_value)
+                .map_err(|_| MarketError::MathOverflow)?,
+            accrued_fees,
+            profit_and_loss: i64::try_from(profit_and_loss)
+                .map_err(|_| MarketError::MathOverflow)?,
+            impermanent_loss: i64::try_from(impermanent_loss)
+                .map_err(|_| MarketError::MathOverflow)?,
+        })
+    }
 }
 
-#[account]
-pub struct MarketFactory {
-    pub created_markets: u64,
+// A trade is referred when it comes with both the referral stats and the account they pay
+// to, the constraints on `referrer_usd_account` check they go together
+fn referred(
+    referral: &Option<Account<ReferralStats>>,
+    referrer_usd_account: &Option<Account<TokenAccount>>,
+) -> Result<bool> {
+    require!(
+        referral.is_some() == referrer_usd_account.is_some(),
+        MarketError::InvalidReferrer
+    );
+    Ok(referral.is_some())
 }
 
-#[account]
-pub struct Market {
-    pub usd_mint: Pubkey,
-    pub yes_mint: Pubkey,
-    pub no_mint: Pubkey,
-    pub lp_share_mint: Pubkey, // lp mint that is used to represent total shares on a given pool
-    pub vault: Pubkey,
-    pub authority: Pubkey, // Who can create and do operations on the market
-    pub oracle: Pubkey,    // Who can resolve markets and set outcomes
-    pub market_number: u64,
-    pub market_volume: u64, // How much volume has been traded on the market
-    pub resolved: bool,
-    pub outcome: Option<u8>, // 0 = No, 1 = Yes,
-    pub bump: u8,
+// Resizes an account to `space`, topping its rent up from `payer`, then writes `data` over it
+fn write_migrated<'info>(
+    account: &AccountInfo<'info>,
+    data: &[u8],
+    space: usize,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+) -> Result<()> {
+    let missing_rent = Rent::get()?
+        .minimum_balance(space)
+        .saturating_sub(account.lamports());
+    if missing_rent > 0 {
+        let cpi_context = CpiContext::new(
+            system_program.to_account_info(),
+            anchor_lang::system_program::Transfer {
+                from: payer.to_account_info(),
+                to: account.clone(),
+            },
+        );
+        anchor_lang::system_program::transfer(cpi_context, missing_rent)?;
+    }
+    account.realloc(space, true)?;
+    account.try_borrow_mut_data()?[..data.len()].copy_from_slice(data);
+    Ok(())
 }
 
-#[account]
-pub struct MarketPool {
-    pub liquidity_yes_tokens_account: Pubkey,
-    pub liquidity_no_tokens_account: Pubkey,
-    pub yes_liquidity: u64,
-    pub no_liquidity: u64,
-    pub liquidity_value: u64,
-    pub liquidity_shares: u64,
-    pub usd_collateral: u64,
-    pub total_yes_mints: u64,
-    pub total_no_mints: u64,
-    pub bump: u8,
+// Moves the market to `status`, for the handler to emit the change
+fn status_changed_event(
+    market: &mut Account<Market>,
+    status: MarketStatus,
+    changed_by: Pubkey,
+) -> Result<MarketStatusChangedEvent> {
+    let previous_status = market.status;
+    market.status = status;
+    Ok(MarketStatusChangedEvent {
+        header: market.next_event()?,
+        market: market.key(),
+        previous_status,
+        status,
+        changed_by,
+    })
 }
 
-#[event]
-pub struct LiquidityAddedEvent {
-    pub market: Pubkey,
-    pub user: Pubkey,
-    pub amount: u64,
-    pub liquidity_shares_gained: u64,
-    pub pool_total_liquidity_shares: u64,
-    pub usd_added_to_pool: u64,
-    pub yes_added_to_pool: u64,
-    pub no_added_to_pool: u64,
-    pub yes_given_to_user: u64,
-    pub no_given_to_user: u64,
-    pub yes_minted: u64,
-    pub no_minted: u64,
+fn factory_fees_event(market_factory: &mut Account<MarketFactory>) -> Result<FactoryFeesSetEvent> {
+    Ok(FactoryFeesSetEvent {
+        header: market_factory.next_event()?,
+        market_factory: market_factory.key(),
+        trading_fee_bps: market_factory.trading_fee_bps,
+        referral_share_bps: market_factory.referral_share_bps,
+        relay_fee: market_factory.relay_fee,
+    })
 }
 
-#[event]
-pub struct LiquidityRemovedEvent {
+// The fee owed to the relayer of a trade, which comes with the account it is paid to
+fn relay_fee(
+    market: &Market,
+    relayer: &Option<Signer>,
+    relayer_usd_account: &Option<Account<TokenAccount>>,
+) -> Result<u64> {
+    require!(
+        relayer.is_some() == relayer_usd_account.is_some(),
+        MarketError::InvalidRelayer
+    );
+    Ok(if relayer.is_some() {
+        market.relay_fee
+    } else {
+        0
+    })
+}
+
+// Whom a trade is for: the owner of the session when `user` signs with one
+fn trader(user: &Signer, session: &Option<Account<SessionKey>>) -> Pubkey {
+    session.as_ref().map_or(user.key(), |session| session.owner)
+}
+
+// Moves the trader's tokens: the user signs for their own accounts, a session signs for
+// the accounts delegated to it
+#[inline(never)]
+fn transfer_from_trader<'info>(
+    from_account: &Account<'info, TokenAccount>,
+    to_account: &Account<'info, TokenAccount>,
+    user: &Signer<'info>,
+    session: &Option<Account<'info, SessionKey>>,
+    token_program: &Program<'info, Token>,
+    amount: u64,
+) -> Result<()> {
+    let Some(session) = session else {
+        let cpi_context = CpiContext::new(
+            token_program.to_account_info(),
+            token::Transfer {
+                from: from_account.to_account_info(),
+                to: to_account.to_account_info(),
+                authority: user.to_account_info(),
+            },
+        );
+        return token::transfer(cpi_context, amount);
+    };
+
+    let bump = [session.bump];
+    let signer: &[&[&[u8]]] = &[&[
+        b"session",
+        session.owner.as_ref(),
+        session.session_key.as_ref(),
+        &bump,
+    ]];
+    let cpi_context = CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        token::Transfer {
+            from: from_account.to_account_info(),
+            to: to_account.to_account_info(),
+            authority: session.to_account_info(),
+        },
+        signer,
+    );
+    token::transfer(cpi_context, amount)
+}
+
+// The same for burning them
+#[inline(never)]
+fn burn_from_trader<'info>(
+    mint: &Account<'info, Mint>,
+    from_account: &Account<'info, TokenAccount>,
+    user: &Signer<'info>,
+    session: &Option<Account<'info, SessionKey>>,
+    token_program: &Program<'info, Token>,
+    amount: u64,
+) -> Result<()> {
+    let Some(session) = session else {
+        let cpi_context = CpiContext::new(
+            token_program.to_account_info(),
+            token::Burn {
+                mint: mint.to_account_info(),
+                from: from_account.to_account_info(),
+                authority: user.to_account_info(), // the user must sign
+            },
+        );
+        return token::burn(cpi_context, amount);
+    };
+
+    let bump = [session.bump];
+    let signer: &[&[&[u8]]] = &[&[
+        b"session",
+        session.owner.as_ref(),
+        session.session_key.as_ref(),
+        &bump,
+    ]];
+    let cpi_context = CpiContext::new_with_signer(
+        token_program.to_account_info(),
+        token::Burn {
+            mint: mint.to_account_info(),
+            from: from_account.to_account_info(),
+            authority: session.to_account_info(),
+        },
+        signer,
+    );
+    token::burn(cpi_context, amount)
+}
+
+// A token account has a single delegate: approving `delegate` must not take the account
+// over from another one that still holds an allowance
+fn require_undelegated(account: &TokenAccount, delegate: &Pubkey) -> Result<()> {
+    match account.delegate {
+        COption::Some(current) if current != *delegate && account.delegated_amount > 0 => {
+            err!(MarketError::AlreadyDelegated)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn revoke_delegation<'info>(
+    account: AccountInfo<'info>,
+    owner: &Signer<'info>,
+    token_program: &Program<'info, Token>,
+) -> Result<()> {
+    let cpi_context = CpiContext::new(
+        token_program.to_account_info(),
+        token::Revoke {
+            source: account,
+            authority: owner.to_account_info(),
+        },
+    );
+    token::revoke(cpi_context)
+}
+
+#[inline(never)]
+pub fn mint_outcome<'info>(
+    mint: &impl ToAccountInfo<'info>,
+    to_account: &Account<'info, TokenAccount>,
+    market: &Account<'info, Market>,
+    token_program: &Program<'info, Token>,
+    amount: u64,
+) -> Result<()> {
+    market.with_signer(|signer| {
+        // Creating the context useful for the minting
+        let cpi_context = CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            token::MintTo {
+                mint: mint.to_account_info(),
+                to: to_account.to_account_info(),
+                authority: market.to_account_info(),
+            },
+            signer,
+        );
+
+        // Once created the context, then mint
+        token::mint_to(cpi_context, amount)
+    })
+}
+
+pub fn transfer_outcome<'info>(
+    from_mint_account: &Account<'info, TokenAccount>,
+    to_mint_account: &Account<'info, TokenAccount>,
+    market: &Account<'info, Market>,
+    token_program: &Program<'info, Token>,
+    amount: u64,
+) -> Result<()> {
+    market.with_signer(|signer| {
+        let cpi_context = CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            token::Transfer {
+                from: from_mint_account.to_account_info(),
+                to: to_mint_account.to_account_info(),
+                authority: market.to_account_info(),
+            },
+            signer,
+        );
+
+        token::transfer(cpi_context, amount)
+    })
+}
+
+fn burn_mint_tokens<'info>(
+    mint: &impl ToAccountInfo<'info>,
+    from_account: &Account<'info, TokenAccount>,
+    market: &Account<'info, Market>,
+    token_program: &Program<'info, Token>,
+    amount: u64,
+) -> Result<()> {
+    market.with_signer(|signer| {
+        let cpi_context = CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            token::Burn {
+                mint: mint.to_account_info(),
+                from: from_account.to_account_info(),
+                authority: market.to_account_info(),
+            },
+            signer,
+        );
+
+        token::burn(cpi_context, amount)
+    })
+}
+
+// One market of `batch_claim_winnings`: market, pool, vault, YES mint, NO mint, then the
+// user's USD, YES and NO accounts. Returns the claim event to emit, or `None` when skipped.
+#[inline(never)]
+fn claim_batch_group<'info>(
+    group: &'info [AccountInfo<'info>],
+    user: &Signer<'info>,
+    token_program: &Program<'info, Token>,
+) -> Result<Option<ResolveUserWinningsEvent>> {
+    let mut market = Account::<Market>::try_from(&group[0])?;
+    let pool = AccountLoader::<MarketPool>::try_from(&group[1])?;
+    let vault = Account::<TokenAccount>::try_from(&group[2])?;
+    let yes_mint = Account::<Mint>::try_from(&group[3])?;
+    let no_mint = Account::<Mint>::try_from(&group[4])?;
+    let user_usd_account = Account::<TokenAccount>::try_from(&group[5])?;
+    let user_yes_account = Account::<TokenAccount>::try_from(&group[6])?;
+    let user_no_account = Account::<TokenAccount>::try_from(&group[7])?;
+
+    require!(
+        pool.load()?.market == market.key()
+            && vault.key() == market.vault
+            && yes_mint.key() == market.yes_mint
+            && no_mint.key() == market.no_mint
+            && user_usd_account.mint == market.usd_mint
+            && user_yes_account.mint == market.yes_mint
+            && user_no_account.mint == market.no_mint,
+        MarketError::InvalidBatch
+    );
+
+    let user_yes_amount = user_yes_account.amount;
+    let user_no_amount = user_no_account.amount;
+    if !MarketStatus::SETTLED.contains(&market.status) {
+        return Ok(None);
+    }
+    if user_yes_amount == 0 && user_no_amount == 0 {
+        return Ok(None);
+    }
+
+    // The same steps as `resolve_user_winnings`
+    for (mint, from, amount) in [
+        (&yes_mint, &user_yes_account, user_yes_amount),
+        (&no_mint, &user_no_account, user_no_amount),
+    ] {
+        if amount > 0 {
+            let cpi_context = CpiContext::new(
+                token_program.to_account_info(),
+                token::Burn {
+                    mint: mint.to_account_info(),
+                    from: from.to_account_info(),
+                    authority: user.to_account_info(),
+                },
+            );
+
+            token::burn(cpi_context, amount)?;
+        }
+    }
+
+    let winning_amount = market.settlement_value(user_yes_amount, user_no_amount)?;
+    transfer_outcome(
+        &vault,
+        &user_usd_account,
+        &market,
+        token_program,
+        winning_amount,
+    )?;
+
+    let mut pool = pool.load_mut()?;
+    pool.total_yes_mints -= user_yes_amount;
+    pool.total_no_mints -= user_no_amount;
+    pool.usd_collateral -= winning_amount;
+
+    let event = ResolveUserWinningsEvent {
+        header: market.next_event()?,
+        market: market.key(),
+        user: user.key(),
+        user_yes_tokens: user_yes_amount,
+        user_no_tokens: user_no_amount,
+        winning_amount,
+    };
+    market.exit(&crate::ID)?;
+
+    Ok(Some(event))
+}
+
+#[inline(never)]
+fn mint_added_liquidity(
+    add_liquidity: &PoolLiquidity,
+    added: &math::AddLiquidityResult,
+) -> Result<()> {
+    let market = &add_liquidity.market;
+
+    // mint first the YES tokens that go into the liquidity pool
+    mint_outcome(
+        &add_liquidity.yes_mint,
+        &add_liquidity.liquidity_yes_tokens_account,
+        market,
+        &add_liquidity.token_program,
+        added.yes_to_pool,
+    )?;
+
+    // then the NO tokens that go into the liquidity pool
+    mint_outcome(
+        &add_liquidity.no_mint,
+        &add_liquidity.liquidity_no_tokens_account,
+        market,
+        &add_liquidity.token_program,
+        added.no_to_pool,
+    )?;
+
+    // Then mint what belongs to the user
+    // 1. The remaining outcome tokens, if the pool was not balanced
+    if added.yes_to_user > 0 {
+        mint_outcome(
+            &add_liquidity.yes_mint,
+            &add_liquidity.user_yes_account,
+            market,
+            &add_liquidity.token_program,
+            added.yes_to_user,
+        )?;
+    }
+    if added.no_to_user > 0 {
+        mint_outcome(
+            &add_liquidity.no_mint,
+            &add_liquidity.user_no_account,
+            market,
+            &add_liquidity.token_program,
+            added.no_to_user,
+        )?;
+    }
+
+    // 2. The LP shares
+    mint_outcome(
+        &add_liquidity.lp_share_mint,
+        &add_liquidity.user_lp_share_account,
+        market,
+        &add_liquidity.token_program,
+        added.liquidity_shares_gained,
+    )?;
+
+    Ok(())
+}
+
+#[account]
+pub struct MarketFactory {
+    pub created_markets: u64,
+    pub trading_fee_bps: u16,
+    pub referral_share_bps: u16,
+    pub relay_fee: u64,
+    pub event_seq: u64, // The last factory event emitted, see `EventHeader`
+}
+
+#[account]
+pub struct Market {
+    pub usd_mint: Pubkey,
+    pub yes_mint: Pubkey,
+    pub no_mint: Pubkey,
+    pub lp_share_mint: Pubkey, // lp mint that is used to represent total shares on a given pool
+    pub vault: Pubkey,
+    pub authority: Pubkey, // Who can create and do operations on the market
+    pub oracle: Pubkey,    // Who can resolve markets and set outcomes
+    pub market_number: u64,
+    pub market_volume: u64, // How much volume has been traded on the market
+    pub resolved: bool,
+    pub outcome: Option<u8>, // 0 = No, 1 = Yes,
+    pub bump: u8,
+    pub trading_fee_bps: u16, // Set by the factory when the market is created
+    pub referral_share_bps: u16, // The same, in basis points of the trading fee
+    pub risk_limits: RiskLimits,
+    pub keeper_reward: u64, // Paid per holder settled by `settle_holders`, if paid enough
+    pub settlement_budget: u64, // What is left in the vault for those rewards
+    pub relay_fee: u64,     // Set by the factory, in USD per relayed trade
+    pub event_seq: u64,     // The last market event emitted, see `EventHeader`
+    pub status: MarketStatus,
+    // The layout the account is in, see `migrate_market`
+    pub version: u8,
+    // The bumps of the PDAs the market owns, so that they never have to be searched for
+    pub vault_bump: u8,
+    pub yes_mint_bump: u8,
+    pub no_mint_bump: u8,
+    pub lp_share_mint_bump: u8,
+    // The `MarketGroup` the market belongs to, the default key when it stands alone
+    pub group: Pubkey,
+    // When the oracle last resolved the market, which opens `Market::DISPUTE_WINDOW`
+    pub resolved_at: i64,
+    // Set by the first `settle_holders` that pays a holder, which fixes `keeper_reward`
+    pub settlement_started: bool,
+    // Room for the fields to come: they take their bytes from here, so that the account
+    // keeps its size
+    pub reserved: [u8; Market::RESERVED],
+}
+
+// Markets of the same authority of which exactly one resolves YES, such as the candidates
+// of an election. See `create_market_group`.
+#[account]
+pub struct MarketGroup {
+    pub authority: Pubkey,
+    pub oracle: Pubkey, // The oracle of every member, which resolves them together
+    pub usd_mint: Pubkey,
+    pub vault: Pubkey, // Holds the USD of the converted NO tokens until the resolution
+    pub group_id: u64, // Chosen by the authority, seeds the group PDA
+    pub markets: Vec<Pubkey>,
+    pub winner: Option<u8>, // Index in `markets` of the member resolved YES
+    pub converted: u64,     // NO tokens of each member converted by `convert_no_tokens`
+    pub bump: u8,
+    pub vault_bump: u8,
+    pub event_seq: u64, // The last group event emitted, see `EventHeader`
+}
+
+// Where the market is in its life. `resolved` and `outcome` keep describing the resolution,
+// the status tells what can be done with the market.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarketStatus {
+    Draft,     // Created, its pool is not initialized yet
+    Open,      // Trading
+    Paused,    // Trading stopped for a while by the authority
+    Closed,    // Trading over, waiting for the oracle
+    Resolved,  // Resolved by the oracle, the authority may still dispute it
+    Disputed,  // Waiting for the oracle to resolve it again or invalidate it
+    Invalid,   // Voided by the oracle, every outcome token is worth half a USD
+    Finalized, // The resolution stands, winnings are paid
+}
+
+// Per-trade bounds, so that a single trade cannot swing a thin pool. A zero turns the limit
+// off, which is how markets start.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RiskLimits {
+    // How far a trade may move the price of the traded outcome, relative to where it was
+    pub max_price_impact_bps: u16,
+    // The USD paid for a purchase, or the value of the shares sold before the fee
+    pub max_trade_size: u64,
+    // What the pool has to keep of each outcome token after a trade
+    pub min_pool_reserve: u64,
+}
+
+// Zero-copy, as nearly every instruction reads and writes it: `AccountLoader` casts it in
+// place instead of deserializing it and serializing it back.
+#[account(zero_copy)]
+pub struct MarketPool {
     pub market: Pubkey,
-    pub user: Pubkey,
-    pub burnt_lp_shares: u64,
-    pub pool_remaining_liquidity_shares: u64,
-    pub equivalent_usd: u64,
-    pub received_lowest_outcome_tokens: u64,
-    pub received_lowest_outcome_mint: Pubkey,
-    pub remaining_yes_tokens: u64,
-    pub remaining_no_tokens: u64,
+    pub liquidity_yes_tokens_account: Pubkey,
+    pub liquidity_no_tokens_account: Pubkey,
+    pub yes_liquidity: u64,
+    pub no_liquidity: u64,
+    pub liquidity_value: u64,
+    pub liquidity_shares: u64,
+    pub usd_collateral: u64,
+    pub total_yes_mints: u64,
+    pub total_no_mints: u64,
+    // Running sum of the YES price (scaled by `SCALE`) times the seconds it was quoted for,
+    // updated before every instruction that moves the price. See `observe_price`.
+    pub yes_price_cumulative: PodU128,
+    pub last_price_update: i64,
+    // All the trading fees left in the pool, and the same per LP share, scaled by `SCALE`
+    pub fees_collected: u64,
+    pub fee_per_share: PodU128,
+    pub bump: u8,
+    pub liquidity_yes_tokens_bump: u8,
+    pub liquidity_no_tokens_bump: u8,
+    // The same as on `Market`
+    pub version: u8,
+    pub padding: [u8; 4],
+    // USD backing YES tokens of this market that its group holds instead of the vault, see
+    // `convert_no_tokens`
+    pub group_collateral: u64,
+    pub reserved: [u8; MarketPool::RESERVED],
 }
 
-#[event]
-pub struct PurchasedOutcomeSharesEvent {
+// A `u128` stored as its little-endian bytes. `u128` is 16-byte aligned on the host but
+// not on-chain, which would give zero-copy accounts a different layout on each side.
+#[zero_copy]
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct PodU128 {
+    pub bytes: [u8; 16],
+}
+
+// What an LP put into and took out of a pool, for the LP dashboards. Like `UserPosition`,
+// only the shares minted and burnt through it are tracked.
+#[account]
+pub struct LiquidityPosition {
     pub market: Pubkey,
-    pub user: Pubkey,
-    pub amount: u64,
-    pub wanted_shares_purchased: u64,
-    pub wanted_shares_purchased_mint: Pubkey,
-    pub yes_price_before_purchase: u64,
-    pub no_price_before_purchase: u64,
-    pub pool_remaining_yes_tokens: u64,
-    pub pool_remaining_no_tokens: u64,
+    pub owner: Pubkey,
+    pub lp_shares: u64,
+    pub deposited_usd: u64,
+    pub withdrawn_usd: u64,
+    // Outcome tokens handed back by `add_liquidity` and `remove_liquidity`
+    pub yes_received: u64,
+    pub no_received: u64,
+    // Trading fees earned by `lp_shares` up to the checkpoint. They are part of the pool,
+    // so they are paid out with the shares.
+    pub accrued_fees: u64,
+    pub fee_per_share_checkpoint: u128,
+    pub bump: u8,
 }
 
-#[event]
-pub struct MarketResolvedEvent {
+// Registered by a partner frontend, then passed with its USD account to the trades it
+// brings in. Shared by all the markets.
+//
+// Anyone can register, so a trader can refer their own trades from a second wallet: the
+// only check is that the referrer is not the trading wallet itself. This is accepted: such
+// a trader only gets the referral share of their own fee back, a fee rebate the authority
+// sets with `set_referral_share` knowing anyone can claim it.
+#[account]
+pub struct ReferralStats {
+    pub referrer: Pubkey,
+    pub usd_account: Pubkey, // Where the referral fees are paid
+    pub trades: u64,
+    pub volume: u64,
+    pub earnings: u64,
+    pub bump: u8,
+}
+
+// What a user traded on a market through the program, so portfolio pages have a single
+// account to read. Tokens moved with plain token transfers are not tracked.
+#[account]
+pub struct UserPosition {
     pub market: Pubkey,
-    pub solver: Pubkey,
-    pub outcome: u8,
+    pub owner: Pubkey,
+    pub yes_shares: u64,
+    pub no_shares: u64,
+    // What the shares still held cost, see `UserPosition::average_entry_price`
+    pub yes_cost_basis: u64,
+    pub no_cost_basis: u64,
+    // All the USD ever spent on purchases
+    pub total_spent: u64,
+    pub realized_pnl: i64,
+    pub bump: u8,
 }
 
-#[event]
-pub struct ResolveUserWinningsEvent {
+// An ephemeral key a wallet lets trade for it, see `create_session`. The owner's token
+// accounts are delegated to this account, which signs for them once the key is checked.
+#[account]
+pub struct SessionKey {
+    pub owner: Pubkey,
+    pub session_key: Pubkey,
+    pub market: Pubkey, // The default key for all the markets
+    pub expires_at: i64,
+    pub spend_cap: u64,
+    pub spent: u64,
+    pub bump: u8,
+}
+
+pub const PRICE_HISTORY_CANDLES: usize = 200;
+
+// Accounts per market in `batch_claim_winnings`
+pub const BATCH_CLAIM_GROUP_LEN: usize = 8;
+
+// Accounts per member in `resolve_market_group`: its market, pool and vault
+pub const GROUP_RESOLUTION_LEN: usize = 3;
+
+// Accounts per member in `convert_no_tokens`: its market, pool, vault and NO mint, then the
+// user's NO account
+pub const NO_CONVERSION_LEN: usize = 5;
+
+// Bumped whenever the fields of an event change
+pub const EVENT_SCHEMA_VERSION: u8 = 1;
+
+// The YES price chart of a market: the last `PRICE_HISTORY_CANDLES` candles of
+// `interval` seconds each, in a ring buffer. Intervals without any trade have no candle.
+#[account(zero_copy)]
+pub struct PriceHistory {
     pub market: Pubkey,
-    pub user: Pubkey,
-    pub user_yes_tokens: u64,
-    pub user_no_tokens: u64,
-    pub winning_amount: u64,
+    pub interval: i64,
+    // Index of the latest candle
+    pub head: u32,
+    // How many candles are in use, up to `PRICE_HISTORY_CANDLES`
+    pub count: u32,
+    pub candles: [Candle; PRICE_HISTORY_CANDLES],
+}
+
+#[zero_copy]
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct Candle {
+    // Start of the interval, a multiple of `PriceHistory.interval`
+    pub start: i64,
+    pub open: u64,
+    pub high: u64,
+    pub low: u64,
+    pub close: u64,
+    // USD traded during the interval
+    pub volume: u64,
+}
+
+// Leads every event. `event_seq` numbers the events of a market, or of a factory or a group
+// for their own ones, from 1 and without gaps. It is 0 for the events of neither: referrers,
+// sessions, batch claims and audits.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventHeader {
+    pub version: u8,
+    pub event_seq: u64,
+    pub slot: u64,
+    pub unix_timestamp: i64,
+}
+
+impl EventHeader {
+    pub fn new(event_seq: u64) -> Result<Self> {
+        let clock = Clock::get()?;
+        Ok(Self {
+            version: EVENT_SCHEMA_VERSION,
+            event_seq,
+            slot: clock.slot,
+            unix_timestamp: clock.unix_timestamp,
+        })
+    }
+
+    pub fn unsequenced() -> Result<Self> {
+        Self::new(0)
+    }
 }
 
-impl Market {
-    // Calculate the required space. Remember: 8 bytes for the discriminator.
-    pub const LEN: usize = 8 + 32 * 7 + 8 * 2 + 1 + 2 + 1;
-}
+#[event]
+pub struct MarketFactoryInitializedEvent {
+    pub header: EventHeader,
+    pub market_factory: Pubkey,
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct CreatedMarketsBumpedEvent {
+    pub header: EventHeader,
+    pub market_factory: Pubkey,
+    pub created_markets: u64,
+}
+
+// After any of the factory fee setters, with all of them
+#[event]
+pub struct FactoryFeesSetEvent {
+    pub header: EventHeader,
+    pub market_factory: Pubkey,
+    pub trading_fee_bps: u16,
+    pub referral_share_bps: u16,
+    pub relay_fee: u64,
+}
+
+#[event]
+pub struct MarketCreatedEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub market_factory: Pubkey,
+    pub authority: Pubkey,
+    pub oracle: Pubkey,
+    pub market_number: u64,
+    pub usd_mint: Pubkey,
+    pub yes_mint: Pubkey,
+    pub no_mint: Pubkey,
+    pub lp_share_mint: Pubkey,
+    pub vault: Pubkey,
+    pub trading_fee_bps: u16,
+    pub referral_share_bps: u16,
+    pub relay_fee: u64,
+}
+
+#[event]
+pub struct PoolInitializedEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub pool: Pubkey,
+    pub liquidity_yes_tokens_account: Pubkey,
+    pub liquidity_no_tokens_account: Pubkey,
+    pub price_history: Pubkey,
+}
+
+#[event]
+pub struct RiskLimitsSetEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub risk_limits: RiskLimits,
+}
+
+#[event]
+pub struct PositionOpenedEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub position: Pubkey,
+}
+
+#[event]
+pub struct LiquidityPositionOpenedEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub lp_position: Pubkey,
+    pub fee_per_share_checkpoint: u128,
+}
+
+#[event]
+pub struct ReferrerRegisteredEvent {
+    pub header: EventHeader,
+    pub referrer: Pubkey,
+    pub referral: Pubkey,
+    pub usd_account: Pubkey,
+}
+
+#[event]
+pub struct SessionCreatedEvent {
+    pub header: EventHeader,
+    pub session: Pubkey,
+    pub owner: Pubkey,
+    pub session_key: Pubkey,
+    pub market: Pubkey,
+    pub expires_at: i64,
+    pub spend_cap: u64,
+}
+
+#[event]
+pub struct SessionDelegatedEvent {
+    pub header: EventHeader,
+    pub session: Pubkey,
+    pub token_account: Pubkey,
+}
+
+#[event]
+pub struct SessionRevokedEvent {
+    pub header: EventHeader,
+    pub session: Pubkey,
+    pub owner: Pubkey,
+}
+
+#[event]
+pub struct SettlementApprovedEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub user_yes_account: Pubkey,
+    pub user_no_account: Pubkey,
+}
+
+#[event]
+pub struct SettlementBudgetFundedEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub budget: u64,
+    pub keeper_reward: u64,
+    pub settlement_budget: u64,
+}
+
+#[event]
+pub struct SettlementBudgetReclaimedEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub budget: u64,
+}
+
+#[event]
+pub struct LiquidityAddedEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub amount: u64,
+    pub liquidity_shares_gained: u64,
+    pub pool_total_liquidity_shares: u64,
+    pub usd_added_to_pool: u64,
+    pub yes_added_to_pool: u64,
+    pub no_added_to_pool: u64,
+    pub yes_given_to_user: u64,
+    pub no_given_to_user: u64,
+    pub yes_minted: u64,
+    pub no_minted: u64,
+}
+
+#[event]
+pub struct LiquidityRemovedEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub burnt_lp_shares: u64,
+    pub pool_remaining_liquidity_shares: u64,
+    pub equivalent_usd: u64,
+    pub received_lowest_outcome_tokens: u64,
+    pub received_lowest_outcome_mint: Pubkey,
+    pub remaining_yes_tokens: u64,
+    pub remaining_no_tokens: u64,
+}
+
+#[event]
+pub struct PurchasedOutcomeSharesEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub amount: u64,
+    pub wanted_shares_purchased: u64,
+    pub wanted_shares_purchased_mint: Pubkey,
+    pub fee: u64,
+    pub yes_price_before_purchase: u64,
+    pub no_price_before_purchase: u64,
+    pub yes_price_after_purchase: u64,
+    pub no_price_after_purchase: u64,
+    pub pool_remaining_yes_tokens: u64,
+    pub pool_remaining_no_tokens: u64,
+}
+
+#[event]
+pub struct ReferralFeePaidEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub referrer: Pubkey,
+    pub user: Pubkey,
+    pub volume: u64,
+    pub referral_fee: u64,
+    pub total_volume: u64,
+    pub total_earnings: u64,
+}
+
+#[event]
+pub struct RelayFeePaidEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub relayer: Pubkey,
+    pub user: Pubkey,
+    pub relay_fee: u64,
+}
+
+#[event]
+pub struct SoldOutcomeSharesEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub shares_sold: u64,
+    pub sold_shares_mint: Pubkey,
+    pub usd_received: u64,
+    pub fee: u64,
+    pub yes_price_before_sale: u64,
+    pub no_price_before_sale: u64,
+    pub yes_price_after_sale: u64,
+    pub no_price_after_sale: u64,
+    pub pool_remaining_yes_tokens: u64,
+    pub pool_remaining_no_tokens: u64,
+}
+
+#[event]
+pub struct MarketStatusChangedEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub previous_status: MarketStatus,
+    pub status: MarketStatus,
+    pub changed_by: Pubkey,
+}
+
+#[event]
+pub struct MarketMigratedEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub version: u8,
+}
+
+#[event]
+pub struct MarketResolvedEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub solver: Pubkey,
+    pub outcome: u8,
+}
+
+#[event]
+pub struct MarketJoinedGroupEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub group: Pubkey,
+}
+
+#[event]
+pub struct MarketGroupCreatedEvent {
+    pub header: EventHeader,
+    pub group: Pubkey,
+    pub authority: Pubkey,
+    pub oracle: Pubkey,
+    pub markets: Vec<Pubkey>,
+}
+
+#[event]
+pub struct MarketGroupResolvedEvent {
+    pub header: EventHeader,
+    pub group: Pubkey,
+    pub solver: Pubkey,
+    pub winner: Pubkey,
+    // Moved to the winner's vault, from the group's or from the previous winner's
+    pub converted: u64,
+}
+
+#[event]
+pub struct NoTokensConvertedEvent {
+    pub header: EventHeader,
+    pub group: Pubkey,
+    pub user: Pubkey,
+    pub amount: u64, // Of each member
+    pub usd_received: u64,
+}
+
+#[event]
+pub struct ResolveUserWinningsEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub user_yes_tokens: u64,
+    pub user_no_tokens: u64,
+    pub winning_amount: u64,
+}
+
+#[event]
+pub struct BatchClaimEvent {
+    pub header: EventHeader,
+    pub user: Pubkey,
+    pub claimed_markets: Vec<Pubkey>,
+    // Not resolved yet, or nothing to claim
+    pub skipped_markets: Vec<Pubkey>,
+    pub total_winnings: u64,
+}
+
+#[event]
+pub struct HoldersSettledEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub keeper: Pubkey,
+    pub settled_holders: u32,
+    pub paid_out: u64,
+    pub keeper_reward: u64,
+    // Losing or not delegated token accounts
+    pub skipped_accounts: Vec<Pubkey>,
+}
+
+#[event]
+pub struct MarketAuditEvent {
+    pub header: EventHeader,
+    pub market: Pubkey,
+    pub vault_balance: u64,
+    pub usd_collateral: u64,
+    pub yes_supply: u64,
+    pub total_yes_mints: u64,
+    pub no_supply: u64,
+    pub total_no_mints: u64,
+    pub lp_share_supply: u64,
+    pub liquidity_shares: u64,
+    pub worst_case_payout: u64,
+    pub vault_surplus: i128,
+    pub collateral_covered: bool,
+    pub supplies_match: bool,
+    pub liquidity_matches: bool,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PurchaseQuote {
+    pub usd_amount: u64,
+    pub shares_purchased: u64,
+    pub shares_from_pool: u64,
+    pub fee: u64,
+    pub yes_price_before: u64,
+    pub no_price_before: u64,
+    pub yes_price_after: u64,
+    pub no_price_after: u64,
+    pub pool_yes_liquidity_after: u64,
+    pub pool_no_liquidity_after: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SaleQuote {
+    pub shares: u64,
+    pub usd_received: u64,
+    pub fee: u64,
+    pub yes_price_before: u64,
+    pub no_price_before: u64,
+    pub yes_price_after: u64,
+    pub no_price_after: u64,
+    pub pool_yes_liquidity_after: u64,
+    pub pool_no_liquidity_after: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AddLiquidityQuote {
+    pub usd_amount: u64,
+    pub liquidity_shares_gained: u64,
+    pub yes_given_to_user: u64,
+    pub no_given_to_user: u64,
+    pub yes_added_to_pool: u64,
+    pub no_added_to_pool: u64,
+    pub pool_liquidity_value_after: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RemoveLiquidityQuote {
+    pub shares: u64,
+    pub usd_received: u64,
+    pub received_lowest_outcome_tokens: u64,
+    pub received_lowest_outcome_mint: Pubkey,
+    pub pool_yes_liquidity_after: u64,
+    pub pool_no_liquidity_after: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MarketPrices {
+    pub yes_price: u64,
+    pub no_price: u64,
+    pub yes_liquidity: u64,
+    pub no_liquidity: u64,
+    pub liquidity_value: u64,
+    pub liquidity_shares: u64,
+    pub resolved: bool,
+    pub outcome: Option<u8>,
+    pub status: MarketStatus,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LiquidityNav {
+    pub lp_shares: u64,
+    // The shares' part of the pool's `liquidity_value`
+    pub liquidity_value_share: u64,
+    // What removing the shares would pay right now
+    pub redeemable_usd: u64,
+    pub redeemable_outcome_tokens: u64,
+    pub redeemable_outcome_mint: Pubkey,
+    // The above at the current prices
+    pub net_asset_value: u64,
+    pub deposited_usd: u64,
+    pub withdrawn_usd: u64,
+    // The outcome tokens received so far, at the current prices
+    pub received_tokens_value: u64,
+    pub accrued_fees: u64,
+    // NAV + withdrawn + received tokens - deposited
+    pub profit_and_loss: i64,
+    // The same without the fees: what the pool lost (or won) against the traders
+    pub impermanent_loss: i64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PriceObservation {
+    pub yes_price_cumulative: u128,
+    pub timestamp: i64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MarketTwap {
+    pub yes_price: u64,
+    pub no_price: u64,
+    pub start: i64,
+    pub end: i64,
+}
+
+impl MarketFactory {
+    pub const LEN: usize = 8 + 2 + 2 + 8 + 8;
+    pub const MAX_TRADING_FEE_BPS: u16 = 1_000;
+    // The whole fee at most
+    pub const MAX_REFERRAL_SHARE_BPS: u16 = 10_000;
+    // One USD, with the 9 decimals of the market's tokens
+    pub const MAX_RELAY_FEE: u64 = 1_000_000_000;
+
+    pub fn next_event(&mut self) -> Result<EventHeader> {
+        self.event_seq += 1;
+        EventHeader::new(self.event_seq)
+    }
+}
+
+impl MarketStatus {
+    pub const TRADING: &'static [MarketStatus] = &[MarketStatus::Open];
+    pub const WITHDRAWABLE: &'static [MarketStatus] = &[
+        MarketStatus::Open,
+        MarketStatus::Closed,
+        MarketStatus::Finalized,
+        MarketStatus::Invalid,
+    ];
+    pub const CONFIGURABLE: &'static [MarketStatus] = &[
+        MarketStatus::Draft,
+        MarketStatus::Open,
+        MarketStatus::Paused,
+        MarketStatus::Closed,
+    ];
+    pub const RESOLVABLE: &'static [MarketStatus] = &[
+        MarketStatus::Open,
+        MarketStatus::Paused,
+        MarketStatus::Closed,
+        MarketStatus::Disputed,
+    ];
+    // The settlement budget is funded, from the opening of the market on
+    pub const FUNDABLE: &'static [MarketStatus] = &[
+        MarketStatus::Open,
+        MarketStatus::Paused,
+        MarketStatus::Closed,
+        MarketStatus::Resolved,
+        MarketStatus::Disputed,
+        MarketStatus::Finalized,
+        MarketStatus::Invalid,
+    ];
+    // Winnings are paid
+    pub const SETTLED: &'static [MarketStatus] = &[MarketStatus::Finalized, MarketStatus::Invalid];
+
+    // The statuses the authority may move the market to this one from, see
+    // `set_market_status`
+    pub fn authority_transitions_from(self) -> &'static [MarketStatus] {
+        match self {
+            MarketStatus::Open => &[MarketStatus::Paused],
+            MarketStatus::Paused => &[MarketStatus::Open],
+            MarketStatus::Closed => &[MarketStatus::Open, MarketStatus::Paused],
+            MarketStatus::Disputed | MarketStatus::Finalized => &[MarketStatus::Resolved],
+            _ => &[],
+        }
+    }
+
+    // Whether the oracle has had its say
+    pub fn is_settled(&self) -> bool {
+        matches!(
+            self,
+            MarketStatus::Resolved
+                | MarketStatus::Disputed
+                | MarketStatus::Finalized
+                | MarketStatus::Invalid
+        )
+    }
+}
+
+impl Market {
+    // Calculate the required space. Remember: 8 bytes for the discriminator.
+    pub const LEN: usize = 8
+        + 32 * 7
+        + 8 * 2
+        + 1
+        + 2
+        + 1
+        + 2
+        + 2
+        + RiskLimits::LEN
+        + 8 * 4
+        + 1
+        + 1
+        + 4
+        + 32
+        + 8
+        + 1
+        + Self::RESERVED;
+    pub const VERSION: u8 = 2;
+    pub const RESERVED: usize = 19;
+    // How long the authority has to dispute a resolution, after which anyone finalizes it
+    pub const DISPUTE_WINDOW: i64 = 24 * 60 * 60;
+    // A holder only earns the keeper its reward when paid at least this many rewards, so
+    // settling dust accounts cannot drain the budget
+    pub const MIN_PAYOUT_PER_KEEPER_REWARD: u64 = 20;
+
+    // Runs `f` with the seeds the market PDA signs its CPIs with
+    pub fn with_signer<R>(&self, f: impl FnOnce(&[&[&[u8]]]) -> R) -> R {
+        let market_number = self.market_number.to_le_bytes();
+        f(&[&[
+            b"market",
+            self.authority.as_ref(),
+            &market_number,
+            &[self.bump],
+        ]])
+    }
+
+    // Finds the bumps of a market from before they were stored, see `migrate_market`
+    pub fn find_bumps(&mut self, market: &Pubkey) {
+        let bump =
+            |seed: &[u8]| Pubkey::find_program_address(&[seed, market.as_ref()], &crate::ID).1;
+        self.vault_bump = bump(b"vault");
+        self.yes_mint_bump = bump(b"yes_mint");
+        self.no_mint_bump = bump(b"no_mint");
+        self.lp_share_mint_bump = bump(b"lp_share_mint");
+    }
+
+    // Markets resolved before `resolved_at` was recorded have it at zero, long elapsed
+    pub fn dispute_window_elapsed(&self) -> Result<bool> {
+        let closes_at = self.resolved_at.saturating_add(Self::DISPUTE_WINDOW);
+        Ok(Clock::get()?.unix_timestamp >= closes_at)
+    }
+
+    pub fn resolved_outcome(&self) -> Option<Outcome> {
+        self.outcome.and_then(Outcome::from_u8)
+    }
+
+    // Fails unless the market is in one of the `allowed` statuses, with the error telling
+    // why it is not
+    pub fn require_status(&self, allowed: &[MarketStatus]) -> Result<()> {
+        if allowed.contains(&self.status) {
+            return Ok(());
+        }
+        let waits_for_settlement = allowed.iter().all(MarketStatus::is_settled);
+        let error = match self.status {
+            _ if !self.status.is_settled() && waits_for_settlement => {
+                MarketError::MarketNotResolved
+            }
+            MarketStatus::Draft => MarketError::MarketNotInitialized,
+            MarketStatus::Open => MarketError::MarketOpen,
+            MarketStatus::Paused => MarketError::MarketPaused,
+            MarketStatus::Closed => MarketError::MarketClosed,
+            MarketStatus::Resolved if allowed.contains(&MarketStatus::Finalized) => {
+                MarketError::ResolutionNotFinal
+            }
+            MarketStatus::Resolved | MarketStatus::Finalized => MarketError::MarketResolved,
+            MarketStatus::Disputed => MarketError::MarketDisputed,
+            MarketStatus::Invalid => MarketError::MarketInvalid,
+        };
+        Err(error.into())
+    }
+
+    // What outcome tokens are redeemed for once the market is settled
+    pub fn settlement_value(&self, yes_tokens: u64, no_tokens: u64) -> Result<u64> {
+        if self.status == MarketStatus::Invalid {
+            return Ok(math::invalid_settlement_value(yes_tokens, no_tokens));
+        }
+        let outcome = self
+            .resolved_outcome()
+            .ok_or(MarketError::MarketNotResolved)?;
+        Ok(math::settlement_value(yes_tokens, no_tokens, outcome))
+    }
+
+    // Whether the given mint is the YES or the NO mint of this market
+    pub fn outcome_of_mint(&self, mint: &Pubkey) -> Option<Outcome> {
+        if *mint == self.yes_mint {
+            Some(Outcome::Yes)
+        } else if *mint == self.no_mint {
+            Some(Outcome::No)
+        } else {
+            None
+        }
+    }
+
+    pub fn next_event(&mut self) -> Result<EventHeader> {
+        self.event_seq += 1;
+        EventHeader::new(self.event_seq)
+    }
+}
+
+impl MarketGroup {
+    pub const MAX_MARKETS: usize = 8;
+    pub const LEN: usize = 8 + 32 * 4 + 8 + 4 + 32 * Self::MAX_MARKETS + 2 + 8 + 1 + 1 + 8;
+
+    // The same as `Market::with_signer`, for the group PDA that owns the group vault
+    pub fn with_signer<R>(&self, f: impl FnOnce(&[&[&[u8]]]) -> R) -> R {
+        let group_id = self.group_id.to_le_bytes();
+        f(&[&[
+            b"market_group",
+            self.authority.as_ref(),
+            &group_id,
+            &[self.bump],
+        ]])
+    }
+
+    pub fn next_event(&mut self) -> Result<EventHeader> {
+        self.event_seq += 1;
+        EventHeader::new(self.event_seq)
+    }
+}
+
+impl RiskLimits {
+    pub const LEN: usize = 2 + 8 + 8;
+
+    pub fn check_trade(
+        &self,
+        outcome: Outcome,
+        usd_amount: u64,
+        price_before: u64,
+        yes_liquidity_after: u64,
+        no_liquidity_after: u64,
+    ) -> Result<()> {
+        require!(
+            self.max_trade_size == 0 || usd_amount <= self.max_trade_size,
+            MarketError::TradeTooLarge
+        );
+        require!(
+            yes_liquidity_after.min(no_liquidity_after) >= self.min_pool_reserve,
+            MarketError::PoolReserveTooLow
+        );
+        if self.max_price_impact_bps > 0 {
+            let (yes_price_after, no_price_after) =
+                math::prices(yes_liquidity_after, no_liquidity_after)?;
+            let price_after = match outcome {
+                Outcome::Yes => yes_price_after,
+                Outcome::No => no_price_after,
+            };
+            require!(
+                math::price_impact_bps(price_before, price_after)?
+                    <= self.max_price_impact_bps as u64,
+                MarketError::PriceImpactTooHigh
+            );
+        }
+        Ok(())
+    }
+}
+
+impl MarketPool {
+    // Calculate the required space. Remember: 8 bytes for the discriminator.
+    pub const LEN: usize = 8 + std::mem::size_of::<MarketPool>();
+    pub const VERSION: u8 = 2;
+    pub const RESERVED: usize = 56;
+
+    pub fn state(&self) -> PoolState {
+        PoolState {
+            yes_liquidity: self.yes_liquidity,
+            no_liquidity: self.no_liquidity,
+            liquidity_value: self.liquidity_value,
+            liquidity_shares: self.liquidity_shares,
+            fee_bps: 0,
+            referral_share_bps: 0,
+        }
+    }
+
+    // The state trades are priced with, including the market fee and, for referred trades,
+    // the referrer's share of it
+    pub fn trading_state(&self, market: &Market, referred: bool) -> PoolState {
+        PoolState {
+            fee_bps: market.trading_fee_bps,
+            referral_share_bps: if referred {
+                market.referral_share_bps
+            } else {
+                0
+            },
+            ..self.state()
+        }
+    }
+
+    pub fn collect_fee(&mut self, fee: u64) -> Result<()> {
+        self.fees_collected += fee;
+        self.fee_per_share =
+            math::accrue_fee_per_share(self.fee_per_share.get(), fee, self.liquidity_shares)?
+                .into();
+        Ok(())
+    }
+
+    // The running price sum as it would be at `now`, without touching the pool
+    pub fn price_observation(
+        &self,
+        resolved_outcome: Option<Outcome>,
+        now: i64,
+    ) -> Result<PriceObservation> {
+        let yes_price = math::yes_price(&self.state(), resolved_outcome)?;
+        Ok(PriceObservation {
+            yes_price_cumulative: math::accumulate_price(
+                self.yes_price_cumulative.get(),
+                self.last_price_update,
+                yes_price,
+                now,
+            )?,
+            timestamp: now.max(self.last_price_update),
+        })
+    }
+
+    // Must run before the pool changes, so that the old price is the one accumulated
+    pub fn accumulate_price(&mut self, resolved_outcome: Option<Outcome>) -> Result<()> {
+        let observation = self.price_observation(resolved_outcome, Clock::get()?.unix_timestamp)?;
+        self.yes_price_cumulative = observation.yes_price_cumulative.into();
+        self.last_price_update = observation.timestamp;
+        Ok(())
+    }
+}
+
+impl PodU128 {
+    pub fn get(self) -> u128 {
+        u128::from_le_bytes(self.bytes)
+    }
+}
+
+impl From<u128> for PodU128 {
+    fn from(value: u128) -> Self {
+        PodU128 {
+            bytes: value.to_le_bytes(),
+        }
+    }
+}
+
+impl LiquidityPosition {
+    // Calculate the required space. Remember: 8 bytes for the discriminator.
+    pub const LEN: usize = 8 + 32 * 2 + 8 * 6 + 16 + 1;
+
+    // Accrues the fees earned by the shares held since the last checkpoint
+    fn settle_fees(&mut self, pool: &MarketPool) -> Result<()> {
+        let fees = math::fees_earned(
+            self.lp_shares,
+            pool.fee_per_share.get(),
+            self.fee_per_share_checkpoint,
+        )?;
+        self.accrued_fees = self
+            .accrued_fees
+            .checked_add(fees)
+            .ok_or(MarketError::MathOverflow)?;
+        self.fee_per_share_checkpoint = pool.fee_per_share.get();
+        Ok(())
+    }
+
+    pub fn record_deposit(
+        &mut self,
+        pool: &MarketPool,
+        usd_amount: u64,
+        added: &math::AddLiquidityResult,
+    ) -> Result<()> {
+        self.settle_fees(pool)?;
+        self.lp_shares += added.liquidity_shares_gained;
+        self.deposited_usd += usd_amount;
+        self.yes_received += added.yes_to_user;
+        self.no_received += added.no_to_user;
+        Ok(())
+    }
+
+    pub fn record_withdrawal(
+        &mut self,
+        pool: &MarketPool,
+        shares: u64,
+        removed: &math::RemoveLiquidityResult,
+    ) -> Result<()> {
+        self.settle_fees(pool)?;
+        self.lp_shares = self.lp_shares.saturating_sub(shares);
+        self.withdrawn_usd += removed.usd_to_user;
+        match removed.outcome_given {
+            Some(Outcome::Yes) => self.yes_received += removed.outcome_tokens_to_user,
+            Some(Outcome::No) => self.no_received += removed.outcome_tokens_to_user,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+impl ReferralStats {
+    // Calculate the required space. Remember: 8 bytes for the discriminator.
+    pub const LEN: usize = 8 + 32 * 2 + 8 * 3 + 1;
+
+    pub fn record_trade(&mut self, volume: u64, referral_fee: u64) {
+        self.trades += 1;
+        self.volume += volume;
+        self.earnings += referral_fee;
+    }
+}
+
+impl SessionKey {
+    // Calculate the required space. Remember: 8 bytes for the discriminator.
+    pub const LEN: usize = 8 + 32 * 3 + 8 * 3 + 1;
+
+    // Checks the session can act on `market` at `now` and spend `usd_amount` more
+    pub fn authorize(&mut self, market: &Pubkey, usd_amount: u64, now: i64) -> Result<()> {
+        require!(now < self.expires_at, MarketError::SessionExpired);
+        require!(
+            self.market == Pubkey::default() || self.market == *market,
+            MarketError::SessionMarketNotAllowed
+        );
+        let spent = self
+            .spent
+            .checked_add(usd_amount)
+            .ok_or(MarketError::MathOverflow)?;
+        require!(
+            spent <= self.spend_cap,
+            MarketError::SessionSpendCapExceeded
+        );
+        self.spent = spent;
+        Ok(())
+    }
+}
+
+impl UserPosition {
+    // Calculate the required space. Remember: 8 bytes for the discriminator.
+    pub const LEN: usize = 8 + 32 * 2 + 8 * 6 + 1;
+
+    fn side(&mut self, outcome: Outcome) -> (&mut u64, &mut u64) {
+        match outcome {
+            Outcome::Yes => (&mut self.yes_shares, &mut self.yes_cost_basis),
+            Outcome::No => (&mut self.no_shares, &mut self.no_cost_basis),
+        }
+    }
+
+    // The USD paid per share held, scaled by `SCALE`
+    pub fn average_entry_price(&self, outcome: Outcome) -> u64 {
+        let (shares, cost_basis) = match outcome {
+            Outcome::Yes => (self.yes_shares, self.yes_cost_basis),
+            Outcome::No => (self.no_shares, self.no_cost_basis),
+        };
+        if shares == 0 {
+            return 0;
+        }
+        (cost_basis as u128 * SCALE / shares as u128) as u64
+    }
+
+    pub fn record_purchase(&mut self, outcome: Outcome, shares: u64, usd_amount: u64) {
+        let (held, cost_basis) = self.side(outcome);
+        *held += shares;
+        *cost_basis += usd_amount;
+        self.total_spent += usd_amount;
+    }
+
+    // Tokens handed out by the pool, valued at `price`
+    pub fn record_received(&mut self, outcome: Outcome, shares: u64, price: u64) {
+        let (held, cost_basis) = self.side(outcome);
+        *held += shares;
+        *cost_basis += (shares as u128 * price as u128 / SCALE) as u64;
+    }
+
+    // The sold shares take their part of the cost basis with them. Shares that came from
+    // outside of the position have no cost.
+    pub fn record_sale(&mut self, outcome: Outcome, shares: u64, usd_received: u64) {
+        let (held, cost_basis) = self.side(outcome);
+        let sold = shares.min(*held);
+        let cost = if sold == 0 {
+            0
+        } else {
+            (*cost_basis as u128 * sold as u128 / *held as u128) as u64
+        };
+        *held -= sold;
+        *cost_basis -= cost;
+        self.realized_pnl += usd_received as i64 - cost as i64;
+    }
+
+    // A claim burns every outcome token, so the whole position gets realized
+    pub fn record_settlement(&mut self, payout: u64) {
+        let cost = self.yes_cost_basis + self.no_cost_basis;
+        self.realized_pnl += payout as i64 - cost as i64;
+        self.yes_shares = 0;
+        self.no_shares = 0;
+        self.yes_cost_basis = 0;
+        self.no_cost_basis = 0;
+    }
+}
+
+impl PriceHistory {
+    // Calculate the required space. Remember: 8 bytes for the discriminator.
+    pub const LEN: usize = 8 + std::mem::size_of::<PriceHistory>();
+    // Hourly candles, so the chart covers a bit more than a week of trading
+    pub const INTERVAL: i64 = 3600;
+
+    // Moves the chart from `price_before` to `price_after`, opening a new candle if the
+    // trade is the first one of its interval
+    pub fn record_trade(&mut self, now: i64, price_before: u64, price_after: u64, volume: u64) {
+        let start = now - now.rem_euclid(self.interval);
+        if self.count == 0 || self.candles[self.head as usize].start < start {
+            if self.count > 0 {
+                self.head = (self.head + 1) % PRICE_HISTORY_CANDLES as u32;
+            }
+            self.count = (self.count + 1).min(PRICE_HISTORY_CANDLES as u32);
+            self.candles[self.head as usize] = Candle {
+                start,
+                open: price_before,
+                high: price_before,
+                low: price_before,
+                close: price_before,
+                volume: 0,
+            };
+        }
+
+        let candle = &mut self.candles[self.head as usize];
+        candle.high = candle.high.max(price_after);
+        candle.low = candle.low.min(price_after);
+        candle.close = price_after;
+        candle.volume += volume;
+    }
+
+    // The candles in use, oldest first
+    pub fn candles(&self) -> impl Iterator<Item = &Candle> {
+        let oldest = (self.head as usize + PRICE_HISTORY_CANDLES + 1 - self.count as usize)
+            % PRICE_HISTORY_CANDLES;
+        (0..self.count as usize).map(move |i| &self.candles[(oldest + i) % PRICE_HISTORY_CANDLES])
+    }
+}
+
+impl From<MathError> for anchor_lang::error::Error {
+    fn from(error: MathError) -> Self {
+        match error {
+            MathError::Overflow => MarketError::MathOverflow.into(),
+            MathError::EmptyPool => MarketError::MarketNotInitialized.into(),
+            MathError::InvalidWindow => MarketError::InvalidTwapWindow.into(),
+        }
+    }
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct InitializeMarketFactory<'info> {
+    #[account(
+        init,
+        seeds = [b"market_factory", authority.key().as_ref()],
+        bump,
+        payer = authority,
+        space = 8 + MarketFactory::LEN
+    )]
+    pub market_factory: Account<'info, MarketFactory>,
+
+    /// The account that pays for the initialization.
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    /// Programs and sysvars.
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SetTradingFee<'info> {
+    #[account(mut, seeds = [b"market_factory", authority.key().as_ref()], bump)]
+    pub market_factory: Account<'info, MarketFactory>,
+    pub authority: Signer<'info>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SetReferralShare<'info> {
+    #[account(mut, seeds = [b"market_factory", authority.key().as_ref()], bump)]
+    pub market_factory: Account<'info, MarketFactory>,
+    pub authority: Signer<'info>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SetRelayFee<'info> {
+    #[account(mut, seeds = [b"market_factory", authority.key().as_ref()], bump)]
+    pub market_factory: Account<'info, MarketFactory>,
+    pub authority: Signer<'info>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct BumpCreatedMarkets<'info> {
+    #[account(mut)]
+    pub market_factory: Account<'info, MarketFactory>,
+    pub authority: Signer<'info>,
+
+    /// Programs and sysvars.
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction()]
+pub struct InitializeMarket<'info> {
+    #[account(
+        init,
+        seeds = [b"market", authority.key().as_ref(), market_factory.created_markets.to_le_bytes().as_ref()],
+        bump,
+        payer = authority,
+        space = 8 + Market::LEN
+    )]
+    pub market: Account<'info, Market>,
+
+    // YES Mint
+    #[account(
+        init,
+        seeds = [b"yes_mint", market.key().as_ref()],
+        bump,
+        payer = authority,
+        mint::decimals = 9,
+        mint::authority = market,
+        mint::freeze_authority = market,
+    )]
+    pub yes_mint: Account<'info, Mint>,
+
+    // NO Mint
+    #[account(
+        init,
+        seeds = [b"no_mint", market.key().as_ref()],
+        bump,
+        payer = authority,
+        mint::decimals = 9,
+        mint::authority = market,
+        mint::freeze_authority = market,
+    )]
+    pub no_mint: Account<'info, Mint>,
+
+    // LP Share Mint
+    #[account(
+        init,
+        seeds = [b"lp_share_mint", market.key().as_ref()],
+        bump,
+        payer = authority,
+        mint::decimals = 9,
+        mint::authority = market,
+        mint::freeze_authority = market,
+    )]
+    pub lp_share_mint: Account<'info, Mint>,
+
+    // USD Token (your stable token)
+    pub usd_mint: Account<'info, Mint>,
+
+    /// The factory for the market.
+    #[account(mut)]
+    pub market_factory: Account<'info, MarketFactory>,
+
+    /// The vault account where the USD tokens are escrowed.
+    /// Its authority is also set to the market PDA.
+    #[account(
+        init,
+        seeds = [b"vault", market.key().as_ref()],
+        bump,
+        payer = authority,
+        token::mint = usd_mint,
+        token::authority = market
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    /// The account that pays for the initialization.
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    /// Programs and sysvars.
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct InitializePool<'info> {
+    #[account(
+        init,
+        seeds = [b"pool", market.key().as_ref()],
+        bump,
+        payer = authority,
+        space = MarketPool::LEN
+    )]
+    pub pool: AccountLoader<'info, MarketPool>,
+
+    /// The liquidity pool yes tokens account.
+    #[account(
+        init,
+        seeds = [b"yes_liquidity_pool", market.key().as_ref()],
+        bump,
+        payer = authority,
+        token::mint = yes_mint,
+        token::authority = market
+    )]
+    pub liquidity_yes_tokens_account: Account<'info, TokenAccount>,
+
+    /// The liquidity pool no tokens account.
+    #[account(
+        init,
+        seeds = [b"no_liquidity_pool", market.key().as_ref()],
+        bump,
+        payer = authority,
+        token::mint = no_mint,
+        token::authority = market
+    )]
+    pub liquidity_no_tokens_account: Account<'info, TokenAccount>,
+
+    /// The price chart of the market.
+    #[account(
+        init,
+        seeds = [b"price_history", market.key().as_ref()],
+        bump,
+        payer = authority,
+        space = PriceHistory::LEN
+    )]
+    pub price_history: AccountLoader<'info, PriceHistory>,
+
+    /// The market account.
+    #[account(mut, has_one = authority, has_one = yes_mint, has_one = no_mint)]
+    pub market: Account<'info, Market>,
+
+    /// The market authority, which opens the market and pays for the initialization.
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    /// The YES mint
+    pub yes_mint: Account<'info, Mint>,
+
+    /// The NO mint
+    pub no_mint: Account<'info, Mint>,
+
+    /// Programs and sysvars.
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Not relayed like the trades: a relay fee would come out of the deposit or the withdrawal
+// and skew the LP share accounting, for the few providers that cannot pay their own fees.
+// Anyone can still be the fee payer of the transaction, without a fee in return.
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct PoolLiquidity<'info> {
+    #[account(mut, has_one = market)]
+    pub pool: AccountLoader<'info, MarketPool>,
+
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"vault", market.key().as_ref()],
+        bump = market.vault_bump
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    // The mints are only handed to the token program, which reads them itself
+    /// CHECK: the market's YES mint
+    #[account(
+        mut,
+        seeds = [b"yes_mint", market.key().as_ref()],
+        bump = market.yes_mint_bump
+    )]
+    pub yes_mint: UncheckedAccount<'info>,
+
+    /// CHECK: the market's NO mint
+    #[account(
+        mut,
+        seeds = [b"no_mint", market.key().as_ref()],
+        bump = market.no_mint_bump
+    )]
+    pub no_mint: UncheckedAccount<'info>,
+
+    /// CHECK: the market's LP share mint
+    #[account(
+        mut,
+        seeds = [b"lp_share_mint", market.key().as_ref()],
+        bump = market.lp_share_mint_bump
+    )]
+    pub lp_share_mint: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub user_usd_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_yes_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_no_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_lp_share_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"yes_liquidity_pool", market.key().as_ref()],
+        bump = pool.load()?.liquidity_yes_tokens_bump
+    )]
+    pub liquidity_yes_tokens_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"no_liquidity_pool", market.key().as_ref()],
+        bump = pool.load()?.liquidity_no_tokens_bump
+    )]
+    pub liquidity_no_tokens_account: Account<'info, TokenAccount>,
+
+    /// The user's position, only updated when given.
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), user.key().as_ref()],
+        bump = position.bump
+    )]
+    pub position: Option<Account<'info, UserPosition>>,
+
+    /// The LP's position, only updated when given.
+    #[account(
+        mut,
+        seeds = [b"lp_position", market.key().as_ref(), user.key().as_ref()],
+        bump = lp_position.bump
+    )]
+    pub lp_position: Option<Account<'info, LiquidityPosition>>,
+
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(usd_amount: u64, purchased_outcome_mint_pubkey: Pubkey)]
+pub struct PurchaseOutcomeShares<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub pool: AccountLoader<'info, MarketPool>,
+
+    #[account(
+        mut,
+        seeds = [b"vault", market.key().as_ref()],
+        bump = market.vault_bump
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"yes_mint", market.key().as_ref()],
+        bump = market.yes_mint_bump
+    )]
+    pub yes_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"no_mint", market.key().as_ref()],
+        bump = market.no_mint_bump
+    )]
+    pub no_mint: Account<'info, Mint>,
+
+    #[account(mut)]
+    pub user_usd_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = user_outcome_mint_account.mint == purchased_outcome_mint_pubkey
+    )]
+    pub user_outcome_mint_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"yes_liquidity_pool", market.key().as_ref()],
+        bump = pool.load()?.liquidity_yes_tokens_bump
+    )]
+    pub liquidity_yes_tokens_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"no_liquidity_pool", market.key().as_ref()],
+        bump = pool.load()?.liquidity_no_tokens_bump
+    )]
+    pub liquidity_no_tokens_account: Account<'info, TokenAccount>,
+
+    #[account(mut, has_one = market)]
+    pub price_history: AccountLoader<'info, PriceHistory>,
+
+    /// The user's position, only updated when given.
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), trader(&user, &session).as_ref()],
+        bump = position.bump
+    )]
+    pub position: Option<Account<'info, UserPosition>>,
+
+    /// The referrer of the trade, if any, with the account its share of the fee is paid to.
+    /// Self-referral through another wallet is not caught, see `ReferralStats`.
+    #[account(
+        mut,
+        constraint = referral.referrer != trader(&user, &session) @ MarketError::InvalidReferrer
+    )]
+    pub referral: Option<Account<'info, ReferralStats>>,
+
+    #[account(
+        mut,
+        constraint = referral
+            .as_ref()
+            .is_some_and(|referral| referral.usd_account == referrer_usd_account.key())
+            @ MarketError::InvalidReferrer
+    )]
+    pub referrer_usd_account: Option<Account<'info, TokenAccount>>,
+
+    /// The session `user` signs with when trading for its owner.
+    #[account(mut, constraint = session.session_key == user.key() @ MarketError::InvalidSession)]
+    pub session: Option<Account<'info, SessionKey>>,
+
+    /// The relayer paying for the transaction, if any, with the account its fee is paid to.
+    pub relayer: Option<Signer<'info>>,
+
+    #[account(
+        mut,
+        constraint = relayer
+            .as_ref()
+            .is_some_and(|relayer| relayer_usd_account.owner == relayer.key())
+            @ MarketError::InvalidRelayer,
+        constraint = relayer_usd_account.mint == market.usd_mint @ MarketError::InvalidRelayer
+    )]
+    pub relayer_usd_account: Option<Account<'info, TokenAccount>>,
+
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(shares: u64, sold_outcome_mint_pubkey: Pubkey)]
+pub struct SellOutcomeShares<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub pool: AccountLoader<'info, MarketPool>,
+
+    #[account(
+        mut,
+        seeds = [b"vault", market.key().as_ref()],
+        bump = market.vault_bump
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"yes_mint", market.key().as_ref()],
+        bump = market.yes_mint_bump
+    )]
+    pub yes_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"no_mint", market.key().as_ref()],
+        bump = market.no_mint_bump
+    )]
+    pub no_mint: Account<'info, Mint>,
+
+    #[account(mut)]
+    pub user_usd_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = user_outcome_mint_account.mint == sold_outcome_mint_pubkey
+    )]
+    pub user_outcome_mint_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"yes_liquidity_pool", market.key().as_ref()],
+        bump = pool.load()?.liquidity_yes_tokens_bump
+    )]
+    pub liquidity_yes_tokens_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"no_liquidity_pool", market.key().as_ref()],
+        bump = pool.load()?.liquidity_no_tokens_bump
+    )]
+    pub liquidity_no_tokens_account: Account<'info, TokenAccount>,
+
+    #[account(mut, has_one = market)]
+    pub price_history: AccountLoader<'info, PriceHistory>,
+
+    /// The user's position, only updated when given.
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), trader(&user, &session).as_ref()],
+        bump = position.bump
+    )]
+    pub position: Option<Account<'info, UserPosition>>,
+
+    /// The referrer of the trade, if any, with the account its share of the fee is paid to.
+    /// Self-referral through another wallet is not caught, see `ReferralStats`.
+    #[account(
+        mut,
+        constraint = referral.referrer != trader(&user, &session) @ MarketError::InvalidReferrer
+    )]
+    pub referral: Option<Account<'info, ReferralStats>>,
+
+    #[account(
+        mut,
+        constraint = referral
+            .as_ref()
+            .is_some_and(|referral| referral.usd_account == referrer_usd_account.key())
+            @ MarketError::InvalidReferrer
+    )]
+    pub referrer_usd_account: Option<Account<'info, TokenAccount>>,
+
+    /// The session `user` signs with when trading for its owner.
+    #[account(mut, constraint = session.session_key == user.key() @ MarketError::InvalidSession)]
+    pub session: Option<Account<'info, SessionKey>>,
+
+    /// The relayer paying for the transaction, if any, with the account its fee is paid to.
+    pub relayer: Option<Signer<'info>>,
+
+    #[account(
+        mut,
+        constraint = relayer
+            .as_ref()
+            .is_some_and(|relayer| relayer_usd_account.owner == relayer.key())
+            @ MarketError::InvalidRelayer,
+        constraint = relayer_usd_account.mint == market.usd_mint @ MarketError::InvalidRelayer
+    )]
+    pub relayer_usd_account: Option<Account<'info, TokenAccount>>,
+
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SetRiskLimits<'info> {
+    #[account(mut, has_one = authority)]
+    pub market: Account<'info, Market>,
+
+    pub authority: Signer<'info>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct MigrateMarket<'info> {
+    /// CHECK: a market in an older layout, which `Account` cannot read. `migrate_market`
+    /// checks its discriminator and authority.
+    #[account(mut, owner = crate::ID)]
+    pub market: UncheckedAccount<'info>,
+
+    /// CHECK: its pool, the same. Still empty while the market is a draft.
+    #[account(mut, seeds = [b"pool", market.key().as_ref()], bump)]
+    pub pool: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct SetMarketStatus<'info> {
+    #[account(mut, has_one = authority)]
+    pub market: Account<'info, Market>,
+
+    pub authority: Signer<'info>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct FinalizeMarket<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    /// Whoever sends the transaction, recorded in the event.
+    pub payer: Signer<'info>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ResolveMarket<'info> {
+    #[account(
+        mut,
+        has_one = oracle,
+        constraint = market.group == Pubkey::default() @ MarketError::MarketInGroup
+    )]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub pool: AccountLoader<'info, MarketPool>,
+
+    /// CHECK: must match `market.oracle`, enforced by `has_one`
+    pub oracle: Signer<'info>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+#[instruction(group_id: u64)]
+pub struct CreateMarketGroup<'info> {
+    #[account(
+        init,
+        seeds = [b"market_group", authority.key().as_ref(), group_id.to_le_bytes().as_ref()],
+        bump,
+        payer = authority,
+        space = 8 + MarketGroup::LEN
+    )]
+    pub group: Account<'info, MarketGroup>,
+
+    #[account(
+        init,
+        seeds = [b"group_vault", group.key().as_ref()],
+        bump,
+        payer = authority,
+        token::mint = usd_mint,
+        token::authority = group
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    pub usd_mint: Account<'info, Mint>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ResolveMarketGroup<'info> {
+    #[account(mut, has_one = oracle)]
+    pub group: Account<'info, MarketGroup>,
+
+    #[account(mut, seeds = [b"group_vault", group.key().as_ref()], bump = group.vault_bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    pub oracle: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ConvertNoTokens<'info> {
+    #[account(mut)]
+    pub group: Account<'info, MarketGroup>,
+
+    #[account(mut, seeds = [b"group_vault", group.key().as_ref()], bump = group.vault_bump)]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(mut, token::mint = group.usd_mint)]
+    pub user_usd_account: Account<'info, TokenAccount>,
+
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ResolveUserWinnings<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub pool: AccountLoader<'info, MarketPool>,
+
+    #[account(
+        mut,
+        seeds = [b"vault", market.key().as_ref()],
+        bump = market.vault_bump
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"yes_mint", market.key().as_ref()],
+        bump = market.yes_mint_bump
+    )]
+    pub yes_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"no_mint", market.key().as_ref()],
+        bump = market.no_mint_bump
+    )]
+    pub no_mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"lp_share_mint", market.key().as_ref()],
+        bump = market.lp_share_mint_bump
+    )]
+    pub lp_share_mint: Account<'info, Mint>,
+
+    #[account(mut, token::mint = market.usd_mint)]
+    pub user_usd_account: Account<'info, TokenAccount>,
+
+    #[account(mut, token::mint = yes_mint)]
+    pub user_yes_account: Account<'info, TokenAccount>,
+
+    #[account(mut, token::mint = no_mint)]
+    pub user_no_account: Account<'info, TokenAccount>,
+
+    /// The user's position, only updated when given.
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), trader(&user, &session).as_ref()],
+        bump = position.bump
+    )]
+    pub position: Option<Account<'info, UserPosition>>,
+
+    /// The session `user` signs with when claiming for its owner.
+    #[account(mut, constraint = session.session_key == user.key() @ MarketError::InvalidSession)]
+    pub session: Option<Account<'info, SessionKey>>,
+
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct ApproveSettlement<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, constraint = user_yes_account.mint == market.yes_mint)]
+    pub user_yes_account: Account<'info, TokenAccount>,
+
+    #[account(mut, constraint = user_no_account.mint == market.no_mint)]
+    pub user_no_account: Account<'info, TokenAccount>,
 
-impl MarketPool {
-    // Calculate the required space. Remember: 8 bytes for the discriminator.
-    pub const LEN: usize = 8 + 32 * 3 + 8 * 7 + 1;
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
 }
 
+#[cfg_attr(feature = "event-cpi", event_cpi)]
 #[derive(Accounts)]
-pub struct InitializeMarketFactory<'info> {
+pub struct FundSettlementBudget<'info> {
+    #[account(mut, has_one = authority)]
+    pub market: Account<'info, Market>,
+
     #[account(
-        init,
-        seeds = [b"market_factory", authority.key().as_ref()],
-        bump,
-        payer = authority,
-        space = 8 + 8
+        mut,
+        seeds = [b"vault", market.key().as_ref()],
+        bump = market.vault_bump
     )]
-    pub market_factory: Account<'info, MarketFactory>,
+    pub vault: Account<'info, TokenAccount>,
 
-    /// The account that pays for the initialization.
     #[account(mut)]
+    pub authority_usd_account: Account<'info, TokenAccount>,
+
     pub authority: Signer<'info>,
 
-    /// Programs and sysvars.
-    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
 }
 
+#[cfg_attr(feature = "event-cpi", event_cpi)]
 #[derive(Accounts)]
-pub struct BumpCreatedMarkets<'info> {
-    #[account(mut)]
-    pub market_factory: Account<'info, MarketFactory>,
+pub struct ReclaimSettlementBudget<'info> {
+    #[account(mut, has_one = authority)]
+    pub market: Account<'info, Market>,
+
+    #[account(has_one = market)]
+    pub pool: AccountLoader<'info, MarketPool>,
+
+    #[account(
+        mut,
+        seeds = [b"vault", market.key().as_ref()],
+        bump = market.vault_bump
+    )]
+    pub vault: Account<'info, TokenAccount>,
+
+    #[account(mut, token::mint = market.usd_mint)]
+    pub authority_usd_account: Account<'info, TokenAccount>,
+
     pub authority: Signer<'info>,
 
-    /// Programs and sysvars.
-    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
 }
 
+#[cfg_attr(feature = "event-cpi", event_cpi)]
 #[derive(Accounts)]
-#[instruction()]
-pub struct InitializeMarket<'info> {
+pub struct SettleHolders<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market)]
+    pub pool: AccountLoader<'info, MarketPool>,
+
     #[account(
-        init,
-        seeds = [b"market", authority.key().as_ref(), market_factory.created_markets.to_le_bytes().as_ref()],
-        bump,
-        payer = authority,
-        space = 8 + Market::LEN
+        mut,
+        seeds = [b"vault", market.key().as_ref()],
+        bump = market.vault_bump
     )]
-    pub market: Account<'info, Market>,
+    pub vault: Account<'info, TokenAccount>,
 
-    // YES Mint
     #[account(
-        init,
+        mut,
         seeds = [b"yes_mint", market.key().as_ref()],
-        bump,
-        payer = authority,
-        mint::decimals = 9,
-        mint::authority = market,
-        mint::freeze_authority = market,
+        bump = market.yes_mint_bump
     )]
     pub yes_mint: Account<'info, Mint>,
 
-    // NO Mint
     #[account(
-        init,
+        mut,
         seeds = [b"no_mint", market.key().as_ref()],
-        bump,
-        payer = authority,
-        mint::decimals = 9,
-        mint::authority = market,
-        mint::freeze_authority = market,
+        bump = market.no_mint_bump
     )]
     pub no_mint: Account<'info, Mint>,
 
-    // LP Share Mint
-    #[account(
-        init,
-        seeds = [b"lp_share_mint", market.key().as_ref()],
-        bump,
-        payer = authority,
-        mint::decimals = 9,
-        mint::authority = market,
-        mint::freeze_authority = market,
-    )]
-    pub lp_share_mint: Account<'info, Mint>,
+    #[account(mut, constraint = keeper_usd_account.mint == market.usd_mint)]
+    pub keeper_usd_account: Account<'info, TokenAccount>,
 
-    // USD Token (your stable token)
-    pub usd_mint: Account<'info, Mint>,
+    pub keeper: Signer<'info>,
 
-    /// The factory for the market.
-    #[account(mut)]
-    pub market_factory: Account<'info, MarketFactory>,
+    pub token_program: Program<'info, Token>,
+}
 
-    /// The vault account where the USD tokens are escrowed.
-    /// Its authority is also set to the market PDA.
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct BatchClaimWinnings<'info> {
+    pub user: Signer<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct OpenPosition<'info> {
     #[account(
         init,
-        seeds = [b"vault", market.key().as_ref()],
+        seeds = [b"position", market.key().as_ref(), user.key().as_ref()],
         bump,
-        payer = authority,
-        token::mint = usd_mint,
-        token::authority = market
+        payer = payer,
+        space = 8 + UserPosition::LEN
     )]
-    pub vault: Account<'info, TokenAccount>,
+    pub position: Account<'info, UserPosition>,
 
-    /// The account that pays for the initialization.
     #[account(mut)]
-    pub authority: Signer<'info>,
+    pub market: Account<'info, Market>,
+
+    pub user: Signer<'info>,
+
+    /// Pays the rent, the user itself or a relayer.
+    #[account(mut)]
+    pub payer: Signer<'info>,
 
-    /// Programs and sysvars.
     pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
-    pub rent: Sysvar<'info, Rent>,
 }
 
+#[cfg_attr(feature = "event-cpi", event_cpi)]
 #[derive(Accounts)]
-pub struct InitializePool<'info> {
-    #[account(
-        init,
-        seeds = [b"pool", market.key().as_ref()],
-        bump,
-        payer = authority,
-        space = 8 + MarketPool::LEN
-    )]
-    pub pool: Account<'info, MarketPool>,
-
-    /// The liquidity pool yes tokens account.
+#[instruction(session_key: Pubkey)]
+pub struct CreateSession<'info> {
     #[account(
         init,
-        seeds = [b"yes_liquidity_pool", market.key().as_ref()],
+        seeds = [b"session", owner.key().as_ref(), session_key.as_ref()],
         bump,
-        payer = authority,
-        token::mint = yes_mint,
-        token::authority = market
+        payer = payer,
+        space = 8 + SessionKey::LEN
     )]
-    pub liquidity_yes_tokens_account: Account<'info, TokenAccount>,
+    pub session: Account<'info, SessionKey>,
 
-    /// The liquidity pool no tokens account.
-    #[account(
-        init,
-        seeds = [b"no_liquidity_pool", market.key().as_ref()],
-        bump,
-        payer = authority,
-        token::mint = no_mint,
-        token::authority = market
-    )]
-    pub liquidity_no_tokens_account: Account<'info, TokenAccount>,
+    #[account(mut, constraint = owner_usd_account.owner == owner.key())]
+    pub owner_usd_account: Account<'info, TokenAccount>,
 
-    /// The market account.
-    #[account(mut)]
-    pub market: Account<'info, Market>,
+    pub owner: Signer<'info>,
 
-    /// The account that pays for the initialization.
+    /// Pays the rent, the owner itself or a relayer.
     #[account(mut)]
-    pub authority: Signer<'info>,
+    pub payer: Signer<'info>,
 
-    /// The YES mint
-    pub yes_mint: Account<'info, Mint>,
-
-    /// The NO mint
-    pub no_mint: Account<'info, Mint>,
-
-    /// Programs and sysvars.
     pub system_program: Program<'info, System>,
     pub token_program: Program<'info, Token>,
 }
 
+#[cfg_attr(feature = "event-cpi", event_cpi)]
 #[derive(Accounts)]
-pub struct PoolLiquidity<'info> {
-    #[account(mut)]
-    pub pool: Account<'info, MarketPool>,
+pub struct DelegateToSession<'info> {
+    #[account(has_one = owner)]
+    pub session: Account<'info, SessionKey>,
 
-    #[account(mut, has_one=vault)]
-    pub market: Account<'info, Market>,
+    #[account(mut, constraint = token_account.owner == owner.key())]
+    pub token_account: Account<'info, TokenAccount>,
 
-    #[account(mut)]
-    pub vault: Account<'info, TokenAccount>,
+    pub owner: Signer<'info>,
 
-    #[account(mut)]
-    pub yes_mint: Account<'info, Mint>,
+    pub token_program: Program<'info, Token>,
+}
 
-    #[account(mut)]
-    pub no_mint: Account<'info, Mint>,
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct RevokeSession<'info> {
+    #[account(mut, has_one = owner, close = owner)]
+    pub session: Account<'info, SessionKey>,
 
-    #[account(mut)]
-    pub lp_share_mint: Account<'info, Mint>,
+    #[account(mut, constraint = owner_usd_account.owner == owner.key())]
+    pub owner_usd_account: Account<'info, TokenAccount>,
 
     #[account(mut)]
-    pub user_usd_account: Account<'info, TokenAccount>,
+    pub owner: Signer<'info>,
 
-    #[account(mut)]
-    pub user_yes_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
 
-    #[account(mut)]
-    pub user_no_account: Account<'info, TokenAccount>,
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct RegisterReferrer<'info> {
+    #[account(
+        init,
+        seeds = [b"referral", referrer.key().as_ref()],
+        bump,
+        payer = payer,
+        space = 8 + ReferralStats::LEN
+    )]
+    pub referral: Account<'info, ReferralStats>,
+
+    pub usd_account: Account<'info, TokenAccount>,
 
+    pub referrer: Signer<'info>,
+
+    /// Pays the rent, the referrer itself or a relayer.
     #[account(mut)]
-    pub user_lp_share_account: Account<'info, TokenAccount>,
+    pub payer: Signer<'info>,
 
-    #[account(
-        mut,
-        constraint = pool.liquidity_yes_tokens_account == liquidity_yes_tokens_account.key()
-    )]
-    pub liquidity_yes_tokens_account: Account<'info, TokenAccount>,
+    pub system_program: Program<'info, System>,
+}
 
+#[cfg_attr(feature = "event-cpi", event_cpi)]
+#[derive(Accounts)]
+pub struct OpenLiquidityPosition<'info> {
     #[account(
-        mut,
-        constraint = pool.liquidity_no_tokens_account == liquidity_no_tokens_account.key()
+        init,
+        seeds = [b"lp_position", market.key().as_ref(), user.key().as_ref()],
+        bump,
+        payer = payer,
+        space = 8 + LiquidityPosition::LEN
     )]
-    pub liquidity_no_tokens_account: Account<'info, TokenAccount>,
+    pub lp_position: Account<'info, LiquidityPosition>,
 
     #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(has_one = market)]
+    pub pool: AccountLoader<'info, MarketPool>,
+
     pub user: Signer<'info>,
 
-    pub token_program: Program<'info, Token>,
+    /// Pays the rent, the user itself or a relayer.
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
 }
 
+#[cfg_attr(feature = "event-cpi", event_cpi)]
 #[derive(Accounts)]
-#[instruction(usd_amount: u64, purchased_outcome_mint_pubkey: Pubkey)]
-pub struct PurchaseOutcomeShares<'info> {
-    #[account(mut)]
+pub struct AuditMarket<'info> {
     pub market: Account<'info, Market>,
 
-    #[account(mut)]
-    pub pool: Account<'info, MarketPool>,
+    #[account(has_one = market)]
+    pub pool: AccountLoader<'info, MarketPool>,
 
-    #[account(mut)]
+    #[account(seeds = [b"vault", market.key().as_ref()], bump = market.vault_bump)]
     pub vault: Account<'info, TokenAccount>,
 
-    #[account(mut)]
+    #[account(seeds = [b"yes_mint", market.key().as_ref()], bump = market.yes_mint_bump)]
     pub yes_mint: Account<'info, Mint>,
 
-    #[account(mut)]
+    #[account(seeds = [b"no_mint", market.key().as_ref()], bump = market.no_mint_bump)]
     pub no_mint: Account<'info, Mint>,
 
-    #[account(mut)]
-    pub user_usd_account: Account<'info, TokenAccount>,
-
     #[account(
-        mut,
-        constraint = user_outcome_mint_account.mint == purchased_outcome_mint_pubkey
+        seeds = [b"lp_share_mint", market.key().as_ref()],
+        bump = market.lp_share_mint_bump
     )]
-    pub user_outcome_mint_account: Account<'info, TokenAccount>,
+    pub lp_share_mint: Account<'info, Mint>,
 
     #[account(
-        mut,
-        constraint = pool.liquidity_yes_tokens_account == liquidity_yes_tokens_account.key()
+        seeds = [b"yes_liquidity_pool", market.key().as_ref()],
+        bump = pool.load()?.liquidity_yes_tokens_bump
     )]
     pub liquidity_yes_tokens_account: Account<'info, TokenAccount>,
 
     #[account(
-        mut,
-        constraint = pool.liquidity_no_tokens_account == liquidity_no_tokens_account.key()
+        seeds = [b"no_liquidity_pool", market.key().as_ref()],
+        bump = pool.load()?.liquidity_no_tokens_bump
     )]
     pub liquidity_no_tokens_account: Account<'info, TokenAccount>,
-
-    #[account(mut)]
-    pub user: Signer<'info>,
-
-    pub token_program: Program<'info, Token>,
 }
 
 #[derive(Accounts)]
-pub struct ResolveMarket<'info> {
-    #[account(mut, has_one = oracle)]
+pub struct QuoteMarket<'info> {
     pub market: Account<'info, Market>,
 
-    #[account(mut)]
-    pub pool: Account<'info, MarketPool>,
-
-    /// CHECK: must match `market.oracle`, enforced by `has_one`
-    pub oracle: Signer<'info>,
+    #[account(has_one = market)]
+    pub pool: AccountLoader<'info, MarketPool>,
 }
 
 #[derive(Accounts)]
-pub struct ResolveUserWinnings<'info> {
-    #[account(mut)]
+pub struct QuoteLiquidityPosition<'info> {
     pub market: Account<'info, Market>,
 
-    #[account(mut)]
-    pub pool: Account<'info, MarketPool>,
-
-    #[account(mut)]
-    pub vault: Account<'info, TokenAccount>,
-
-    #[account(mut)]
-    pub yes_mint: Account<'info, Mint>,
-
-    #[account(mut)]
-    pub no_mint: Account<'info, Mint>,
-
-    #[account(mut)]
-    pub lp_share_mint: Account<'info, Mint>,
-
-    #[account(mut)]
-    pub user_usd_account: Account<'info, TokenAccount>,
-
-    #[account(mut)]
-    pub user_yes_account: Account<'info, TokenAccount>,
-
-    #[account(mut)]
-    pub user_no_account: Account<'info, TokenAccount>,
-
-    #[account(mut)]
-    pub user: Signer<'info>,
+    #[account(has_one = market)]
+    pub pool: AccountLoader<'info, MarketPool>,
 
-    pub token_program: Program<'info, Token>,
+    #[account(has_one = market)]
+    pub lp_position: Account<'info, LiquidityPosition>,
 }
 
 #[error_code]
@@ -1506,4 +4733,68 @@ pub enum MarketError {
     MarketNotInitialized,
     #[msg("The outcome is invalid.")]
     InvalidOutcome,
+    #[msg("The vault cannot cover the worst-case payout.")]
+    VaultInsolvent,
+    #[msg("The computation overflowed.")]
+    MathOverflow,
+    #[msg("The TWAP window must start before it ends.")]
+    InvalidTwapWindow,
+    #[msg("The trading fee is above the maximum.")]
+    FeeTooHigh,
+    #[msg("The referral share is above the whole fee.")]
+    ReferralShareTooHigh,
+    #[msg("The referral stats and the referrer account do not match.")]
+    InvalidReferrer,
+    #[msg("The trade moves the price more than the market allows.")]
+    PriceImpactTooHigh,
+    #[msg("The trade is bigger than the market allows.")]
+    TradeTooLarge,
+    #[msg("The trade leaves the pool below its minimum reserve.")]
+    PoolReserveTooLow,
+    #[msg("The remaining accounts do not form whole groups.")]
+    InvalidBatch,
+    #[msg("Winnings are only paid to the holder's USD associated token account.")]
+    InvalidHolderAccount,
+    #[msg("The session does not belong to the signer or the accounts to its owner.")]
+    InvalidSession,
+    #[msg("The session has expired.")]
+    SessionExpired,
+    #[msg("The session is scoped to another market.")]
+    SessionMarketNotAllowed,
+    #[msg("The session has spent its USD cap.")]
+    SessionSpendCapExceeded,
+    #[msg("The relayer and its USD account do not match.")]
+    InvalidRelayer,
+    #[msg("The trade does not cover the relay fee.")]
+    RelayFeeNotCovered,
+    #[msg("The market is paused.")]
+    MarketPaused,
+    #[msg("The market is closed, waiting for its resolution.")]
+    MarketClosed,
+    #[msg("The resolution of the market is disputed.")]
+    MarketDisputed,
+    #[msg("The market was invalidated.")]
+    MarketInvalid,
+    #[msg("The market is already open.")]
+    MarketOpen,
+    #[msg("The resolution is not final yet.")]
+    ResolutionNotFinal,
+    #[msg("The market status cannot be set to this one.")]
+    InvalidStatusTransition,
+    #[msg("The account is already in its current layout.")]
+    AccountAlreadyMigrated,
+    #[msg("The markets cannot form a group.")]
+    InvalidGroup,
+    #[msg("The market belongs to a group, which resolves it.")]
+    MarketInGroup,
+    #[msg("The token account is delegated to another account, revoke it first.")]
+    AlreadyDelegated,
+    #[msg("The relay fee is above the maximum.")]
+    RelayFeeTooHigh,
+    #[msg("The resolution can no longer be disputed.")]
+    DisputeWindowElapsed,
+    #[msg("The keeper reward is fixed once the settlement has started.")]
+    SettlementStarted,
+    #[msg("Holders of the market are still to be paid.")]
+    HoldersNotSettled,
 }
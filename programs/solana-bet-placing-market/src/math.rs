@@ -0,0 +1,539 @@
+// Pure AMM math used by the market instructions.
+//
+// Nothing in here knows about Anchor, accounts or CPIs: every function takes plain
+// numbers describing the pool and returns what the instruction has to mint, burn,
+// transfer and store. All the amounts use the same 9 decimals as the tokens, and all
+// the prices are scaled by `SCALE`.
+
+pub const SCALE: u128 = 1_000_000_000; // 9 decimals
+pub const BPS: u128 = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MathError {
+    // Some intermediate value does not fit in its integer type
+    Overflow,
+    // The pool has no liquidity, so there is no price to work with
+    EmptyPool,
+    // The averaging window is empty or runs backwards
+    InvalidWindow,
+}
+
+pub type MathResult<T> = Result<T, MathError>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    No,
+    Yes,
+}
+
+impl Outcome {
+    // 0 = No, 1 = Yes, the same encoding `Market.outcome` uses
+    pub fn from_u8(outcome: u8) -> Option<Self> {
+        match outcome {
+            0 => Some(Outcome::No),
+            1 => Some(Outcome::Yes),
+            _ => None,
+        }
+    }
+}
+
+// The part of the pool the pricing depends on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolState {
+    pub yes_liquidity: u64,
+    pub no_liquidity: u64,
+    pub liquidity_value: u64,
+    pub liquidity_shares: u64,
+    // Taken on every trade, in basis points
+    pub fee_bps: u16,
+    // The part of that fee paid out to the referrer of the trade, in basis points of the fee
+    pub referral_share_bps: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuyResult {
+    // Everything the user ends up with: the freshly minted tokens plus the ones from the pool
+    pub shares_purchased: u64,
+    pub shares_from_pool: u64,
+    // Part of the USD paid, left in the pool as complete sets except for the referral fee
+    pub fee: u64,
+    // Part of the fee paid to the referrer, which never enters the vault
+    pub referral_fee: u64,
+    pub yes_price_before: u64,
+    pub no_price_before: u64,
+    pub yes_liquidity_after: u64,
+    pub no_liquidity_after: u64,
+    pub liquidity_value_after: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SellResult {
+    // Paid to the user. The pool burns as many complete sets (1 YES + 1 NO), plus the
+    // referral fee.
+    pub usd_returned: u64,
+    // Part of the sale value, left in the pool as complete sets except for the referral fee
+    pub fee: u64,
+    // Part of the fee paid to the referrer out of the vault, on top of `usd_returned`
+    pub referral_fee: u64,
+    pub yes_price_before: u64,
+    pub no_price_before: u64,
+    pub yes_liquidity_after: u64,
+    pub no_liquidity_after: u64,
+    pub liquidity_value_after: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AddLiquidityResult {
+    pub liquidity_shares_gained: u64,
+    pub yes_to_user: u64,
+    pub no_to_user: u64,
+    pub yes_to_pool: u64,
+    pub no_to_pool: u64,
+    pub liquidity_value_after: u64,
+    pub liquidity_shares_after: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RemoveLiquidityResult {
+    pub usd_to_user: u64,
+    // The outcome tokens handed back to rebalance the pool, only before resolution
+    pub outcome_tokens_to_user: u64,
+    pub outcome_given: Option<Outcome>,
+    pub yes_burnt: u64,
+    pub no_burnt: u64,
+    pub yes_liquidity_after: u64,
+    pub no_liquidity_after: u64,
+    pub liquidity_value_after: u64,
+    pub liquidity_shares_after: u64,
+}
+
+fn to_u64(value: u128) -> MathResult<u64> {
+    u64::try_from(value).map_err(|_| MathError::Overflow)
+}
+
+fn sub(a: u64, b: u64) -> MathResult<u64> {
+    a.checked_sub(b).ok_or(MathError::Overflow)
+}
+
+fn add(a: u64, b: u64) -> MathResult<u64> {
+    a.checked_add(b).ok_or(MathError::Overflow)
+}
+
+// Babylonian method (Heron's method) for unsigned integers
+pub fn sqrt_u128(input: u128) -> u128 {
+    if input == 0 {
+        return 0;
+    }
+
+    let mut guess = input.div_ceil(2);
+    let mut result = input;
+
+    while guess < result {
+        result = guess;
+        guess = (input / guess + guess) / 2;
+    }
+
+    result
+}
+
+// Returns the (YES, NO) prices of the pool, scaled by `SCALE`
+pub fn prices(yes_liquidity: u64, no_liquidity: u64) -> MathResult<(u64, u64)> {
+    let total_shares = yes_liquidity as u128 + no_liquidity as u128;
+    if total_shares == 0 {
+        return Err(MathError::EmptyPool);
+    }
+
+    let yes_token_price = to_u64((no_liquidity as u128 * SCALE) / total_shares)?;
+    let no_token_price = to_u64((yes_liquidity as u128 * SCALE) / total_shares)?;
+
+    Ok((yes_token_price, no_token_price))
+}
+
+// The part of `amount` taken as a trading fee, rounded up in favour of the pool
+pub fn trading_fee(amount: u64, fee_bps: u16) -> MathResult<u64> {
+    to_u64((amount as u128 * fee_bps as u128).div_ceil(BPS))
+}
+
+// How far a trade moved a price, in basis points of where it was
+pub fn price_impact_bps(price_before: u64, price_after: u64) -> MathResult<u64> {
+    if price_before == 0 {
+        return Err(MathError::EmptyPool);
+    }
+    to_u64(price_before.abs_diff(price_after) as u128 * BPS / price_before as u128)
+}
+
+// The referrer's cut of `fee`, rounded down in favour of the pool
+pub fn referral_fee(fee: u64, referral_share_bps: u16) -> MathResult<u64> {
+    to_u64(fee as u128 * referral_share_bps as u128 / BPS)
+}
+
+// The fee is left in the pool as complete sets, which grows its constant product and so
+// the value of every LP share
+fn keep_fee(
+    yes_liquidity: u64,
+    no_liquidity: u64,
+    liquidity_value: u64,
+    fee: u64,
+) -> MathResult<(u64, u64, u64)> {
+    if fee == 0 {
+        return Ok((yes_liquidity, no_liquidity, liquidity_value));
+    }
+    let yes_liquidity = add(yes_liquidity, fee)?;
+    let no_liquidity = add(no_liquidity, fee)?;
+    let liquidity_value = to_u64(sqrt_u128(yes_liquidity as u128 * no_liquidity as u128))?;
+    Ok((yes_liquidity, no_liquidity, liquidity_value))
+}
+
+// The fee collected on a trade, spread over the LP shares: the running sum of the fees
+// earned per share, scaled by `SCALE`
+pub fn accrue_fee_per_share(
+    fee_per_share: u128,
+    fee: u64,
+    liquidity_shares: u64,
+) -> MathResult<u128> {
+    if liquidity_shares == 0 {
+        return Ok(fee_per_share);
+    }
+    fee_per_share
+        .checked_add(fee as u128 * SCALE / liquidity_shares as u128)
+        .ok_or(MathError::Overflow)
+}
+
+// What `shares` earned since the running sum was at `checkpoint`
+pub fn fees_earned(shares: u64, fee_per_share: u128, checkpoint: u128) -> MathResult<u64> {
+    to_u64(shares as u128 * fee_per_share.saturating_sub(checkpoint) / SCALE)
+}
+
+// The user pays `usd_amount`, which mints as many complete sets. After the fee, the wanted
+// tokens go straight to the user, the other ones go into the pool, and the pool hands out
+// enough wanted tokens to keep `liquidity_value^2` as its constant product.
+pub fn buy(pool: &PoolState, usd_amount: u64, outcome: Outcome) -> MathResult<BuyResult> {
+    let (yes_price_before, no_price_before) = prices(pool.yes_liquidity, pool.no_liquidity)?;
+
+    let (wanted_liquidity, other_liquidity) = match outcome {
+        Outcome::Yes => (pool.yes_liquidity, pool.no_liquidity),
+        Outcome::No => (pool.no_liquidity, pool.yes_liquidity),
+    };
+    let fee = trading_fee(usd_amount, pool.fee_bps)?;
+    let referral_fee = referral_fee(fee, pool.referral_share_bps)?;
+    let usd_amount = sub(usd_amount, fee)?;
+
+    // Rounded up, so that the pool never ends up below its constant product
+    let new_other_liquidity = add(other_liquidity, usd_amount)?;
+    let new_wanted_liquidity = to_u64(
+        (pool.liquidity_value as u128)
+            .pow(2)
+            .div_ceil(new_other_liquidity as u128),
+    )?;
+    let shares_from_pool = sub(wanted_liquidity, new_wanted_liquidity)?;
+
+    let (yes_liquidity_after, no_liquidity_after) = match outcome {
+        Outcome::Yes => (new_wanted_liquidity, new_other_liquidity),
+        Outcome::No => (new_other_liquidity, new_wanted_liquidity),
+    };
+    let (yes_liquidity_after, no_liquidity_after, liquidity_value_after) = keep_fee(
+        yes_liquidity_after,
+        no_liquidity_after,
+        pool.liquidity_value,
+        sub(fee, referral_fee)?,
+    )?;
+
+    Ok(BuyResult {
+        shares_purchased: add(usd_amount, shares_from_pool)?,
+        shares_from_pool,
+        fee,
+        referral_fee,
+        yes_price_before,
+        no_price_before,
+        yes_liquidity_after,
+        no_liquidity_after,
+        liquidity_value_after,
+    })
+}
+
+// The user gives `shares` outcome tokens back to the pool, which then burns `x` complete
+// sets and pays `x` USD out of the vault. `x` is the smaller root of
+// (wanted + shares - x) * (other - x) = liquidity_value^2, rounded in favour of the pool,
+// minus the fee which the pool keeps instead of burning, apart from the referrer's cut.
+pub fn sell(pool: &PoolState, shares: u64, outcome: Outcome) -> MathResult<SellResult> {
+    let (yes_price_before, no_price_before) = prices(pool.yes_liquidity, pool.no_liquidity)?;
+
+    let (wanted_liquidity, other_liquidity) = match outcome {
+        Outcome::Yes => (pool.yes_liquidity, pool.no_liquidity),
+        Outcome::No => (pool.no_liquidity, pool.yes_liquidity),
+    };
+
+    let invariant = (pool.liquidity_value as u128).pow(2);
+    let new_wanted_liquidity = add(wanted_liquidity, shares)? as u128;
+    let other_liquidity_u128 = other_liquidity as u128;
+
+    let product = new_wanted_liquidity
+        .checked_mul(other_liquidity_u128)
+        .ok_or(MathError::Overflow)?;
+    let usd_returned = if product <= invariant {
+        0
+    } else {
+        let b = new_wanted_liquidity + other_liquidity_u128;
+        let c = product - invariant;
+        let discriminant = b
+            .checked_mul(b)
+            .and_then(|b_squared| b_squared.checked_sub(4 * c))
+            .ok_or(MathError::Overflow)?;
+
+        // Rounding the root up makes the payout round down
+        let mut root = sqrt_u128(discriminant);
+        if root * root < discriminant {
+            root += 1;
+        }
+        to_u64((b - root) / 2)?
+    };
+
+    let new_wanted_liquidity = sub(to_u64(new_wanted_liquidity)?, usd_returned)?;
+    let new_other_liquidity = sub(other_liquidity, usd_returned)?;
+    let (yes_liquidity_after, no_liquidity_after) = match outcome {
+        Outcome::Yes => (new_wanted_liquidity, new_other_liquidity),
+        Outcome::No => (new_other_liquidity, new_wanted_liquidity),
+    };
+    let fee = trading_fee(usd_returned, pool.fee_bps)?;
+    let referral_fee = referral_fee(fee, pool.referral_share_bps)?;
+    let (yes_liquidity_after, no_liquidity_after, liquidity_value_after) = keep_fee(
+        yes_liquidity_after,
+        no_liquidity_after,
+        pool.liquidity_value,
+        sub(fee, referral_fee)?,
+    )?;
+
+    Ok(SellResult {
+        usd_returned: sub(usd_returned, fee)?,
+        fee,
+        referral_fee,
+        yes_price_before,
+        no_price_before,
+        yes_liquidity_after,
+        no_liquidity_after,
+        liquidity_value_after,
+    })
+}
+
+// The deposit mints `usd_amount` complete sets. The pool keeps all the tokens of the less
+// probable outcome and only the amount of the more probable one that preserves the current
+// odds, the rest going to the user. On a balanced pool everything goes into the pool.
+pub fn add_liquidity(pool: &PoolState, usd_amount: u64) -> MathResult<AddLiquidityResult> {
+    // The less probable outcome is the one the pool holds the most of
+    let (lowest_liquidity, highest_liquidity) = if pool.yes_liquidity < pool.no_liquidity {
+        (pool.yes_liquidity, pool.no_liquidity)
+    } else {
+        (pool.no_liquidity, pool.yes_liquidity)
+    };
+    if lowest_liquidity == 0 && highest_liquidity > 0 {
+        return Err(MathError::EmptyPool);
+    }
+
+    let new_highest_liquidity = add(highest_liquidity, usd_amount)?;
+    let new_lowest_liquidity = if highest_liquidity == 0 {
+        usd_amount
+    } else {
+        // Rounded up, the pool keeps the odds and the user gets the rounding
+        to_u64(
+            (new_highest_liquidity as u128 * lowest_liquidity as u128)
+                .div_ceil(highest_liquidity as u128),
+        )?
+    };
+    let lowest_to_pool = sub(new_lowest_liquidity, lowest_liquidity)?;
+    let lowest_to_user = sub(usd_amount, lowest_to_pool)?;
+    let liquidity_value_after = to_u64(sqrt_u128(
+        new_highest_liquidity as u128 * new_lowest_liquidity as u128,
+    ))?;
+
+    // The shares grow as much as the pool does, so the depositor does not get any part of
+    // what the pool already holds
+    let liquidity_shares_gained = if pool.liquidity_shares == 0 {
+        liquidity_value_after
+    } else {
+        to_u64(pool.liquidity_shares as u128 * usd_amount as u128 / highest_liquidity as u128)?
+    };
+
+    let (yes_to_pool, no_to_pool, yes_to_user, no_to_user) =
+        if pool.yes_liquidity < pool.no_liquidity {
+            // More NO liquidity means NO is less likely, so the user gets back YES tokens
+            (lowest_to_pool, usd_amount, lowest_to_user, 0)
+        } else {
+            // More YES liquidity means YES is less likely, so the user gets back NO tokens
+            (usd_amount, lowest_to_pool, 0, lowest_to_user)
+        };
+
+    Ok(AddLiquidityResult {
+        liquidity_shares_gained,
+        yes_to_user,
+        no_to_user,
+        yes_to_pool,
+        no_to_pool,
+        liquidity_value_after,
+        liquidity_shares_after: add(pool.liquidity_shares, liquidity_shares_gained)?,
+    })
+}
+
+// Before resolution, the burnt shares are paid in USD by burning complete sets, and the
+// excess of the cheaper outcome is handed back so the pool keeps its odds. After resolution,
+// the shares are paid out of the remaining winning tokens.
+pub fn remove_liquidity(
+    pool: &PoolState,
+    shares: u64,
+    resolved_outcome: Option<Outcome>,
+) -> MathResult<RemoveLiquidityResult> {
+    if pool.liquidity_value == 0 || pool.liquidity_shares == 0 {
+        return Err(MathError::EmptyPool);
+    }
+    let liquidity_shares_after = sub(pool.liquidity_shares, shares)?;
+
+    if let Some(outcome) = resolved_outcome {
+        let remaining_winning_shares = match outcome {
+            Outcome::No => pool.no_liquidity,
+            Outcome::Yes => pool.yes_liquidity,
+        };
+        let liquidity_share_price =
+            to_u64((remaining_winning_shares as u128 * SCALE) / pool.liquidity_shares as u128)?;
+        let usd_to_user = to_u64((shares as u128) * liquidity_share_price as u128 / SCALE)?;
+        let yes_burnt = usd_to_user.min(pool.yes_liquidity);
+        let no_burnt = usd_to_user.min(pool.no_liquidity);
+
+        return Ok(RemoveLiquidityResult {
+            usd_to_user,
+            outcome_tokens_to_user: 0,
+            outcome_given: None,
+            yes_burnt,
+            no_burnt,
+            yes_liquidity_after: pool.yes_liquidity - yes_burnt,
+            no_liquidity_after: pool.no_liquidity - no_burnt,
+            liquidity_value_after: sub(pool.liquidity_value, usd_to_user)?,
+            liquidity_shares_after,
+        });
+    }
+
+    let (highest_liquidity, lowest_liquidity, outcome_given) =
+        if pool.yes_liquidity < pool.no_liquidity {
+            (pool.no_liquidity, pool.yes_liquidity, Outcome::No)
+        } else {
+            (pool.yes_liquidity, pool.no_liquidity, Outcome::Yes)
+        };
+
+    if lowest_liquidity == 0 {
+        return Err(MathError::EmptyPool);
+    }
+
+    // The share of the pool the LP owns, paid in complete sets out of the lowest side. Fees
+    // grow the pool but not the shares, so they go to the LPs here.
+    let usd_to_user =
+        to_u64((lowest_liquidity as u128 * shares as u128) / pool.liquidity_shares as u128)?;
+
+    // Sometimes, due to rounding errors, the liquidity can be less than the
+    // liquidity shares value, so we need to always burn the existent quantity.
+    let burnt = |liquidity: u64| {
+        let diff = liquidity as i128 - usd_to_user as i128;
+        if diff < 0 && diff.abs() < SCALE as i128 {
+            liquidity
+        } else {
+            usd_to_user
+        }
+    };
+    let yes_burnt = burnt(pool.yes_liquidity);
+    let no_burnt = burnt(pool.no_liquidity);
+    let mut yes_liquidity_after = sub(pool.yes_liquidity, yes_burnt)?;
+    let mut no_liquidity_after = sub(pool.no_liquidity, no_burnt)?;
+
+    // The pool keeps its odds. The ratio is taken from the liquidity itself rather than
+    // from the truncated prices and rounded up, so the leaving LP never takes more than
+    // its share of the highest side.
+    let remaining_lowest_liquidity = lowest_liquidity.saturating_sub(usd_to_user);
+    let remaining_highest_liquidity = to_u64(
+        (remaining_lowest_liquidity as u128 * highest_liquidity as u128)
+            .div_ceil(lowest_liquidity as u128),
+    )?;
+    let outcome_tokens_to_user = sub(
+        highest_liquidity.saturating_sub(usd_to_user),
+        remaining_highest_liquidity,
+    )?;
+
+    if yes_liquidity_after < no_liquidity_after {
+        no_liquidity_after = remaining_highest_liquidity;
+    } else {
+        yes_liquidity_after = remaining_highest_liquidity;
+    }
+
+    Ok(RemoveLiquidityResult {
+        usd_to_user,
+        outcome_tokens_to_user,
+        outcome_given: Some(outcome_given),
+        yes_burnt,
+        no_burnt,
+        yes_liquidity_after,
+        no_liquidity_after,
+        liquidity_value_after: to_u64(sqrt_u128(
+            yes_liquidity_after as u128 * no_liquidity_after as u128,
+        ))?,
+        liquidity_shares_after,
+    })
+}
+
+// Once resolved, the pool is worth exactly its remaining winning tokens
+pub fn resolved_liquidity_value(pool: &PoolState, outcome: Outcome) -> u64 {
+    match outcome {
+        Outcome::No => pool.no_liquidity,
+        Outcome::Yes => pool.yes_liquidity,
+    }
+}
+
+// Each winning token is redeemed for 1 USD, the losing ones are worth nothing
+pub fn settlement_value(yes_tokens: u64, no_tokens: u64, outcome: Outcome) -> u64 {
+    match outcome {
+        Outcome::No => no_tokens,
+        Outcome::Yes => yes_tokens,
+    }
+}
+
+// An invalidated market redeems every token for half a USD, rounded down per side
+pub fn invalid_settlement_value(yes_tokens: u64, no_tokens: u64) -> u64 {
+    yes_tokens / 2 + no_tokens / 2
+}
+
+// The YES price the pool quotes: the spot price while trading, 1 USD or nothing once
+// resolved. An empty pool has not moved from its initial even odds.
+pub fn yes_price(pool: &PoolState, resolved_outcome: Option<Outcome>) -> MathResult<u64> {
+    match resolved_outcome {
+        Some(Outcome::No) => Ok(0),
+        Some(Outcome::Yes) => Ok(SCALE as u64),
+        None if pool.yes_liquidity == 0 && pool.no_liquidity == 0 => Ok(SCALE as u64 / 2),
+        None => Ok(prices(pool.yes_liquidity, pool.no_liquidity)?.0),
+    }
+}
+
+// Adds `yes_price` held since `last_update` to the running sum of price * seconds. A clock
+// that did not move (or moved back) adds nothing, so several updates in the same second
+// count once.
+pub fn accumulate_price(
+    price_cumulative: u128,
+    last_update: i64,
+    yes_price: u64,
+    now: i64,
+) -> MathResult<u128> {
+    if now <= last_update {
+        return Ok(price_cumulative);
+    }
+    let elapsed = (now - last_update) as u128;
+    price_cumulative
+        .checked_add(yes_price as u128 * elapsed)
+        .ok_or(MathError::Overflow)
+}
+
+// The time weighted YES price between two observations of the running sum
+pub fn twap(
+    start_cumulative: u128,
+    start_time: i64,
+    end_cumulative: u128,
+    end_time: i64,
+) -> MathResult<u64> {
+    if end_time <= start_time || end_cumulative < start_cumulative {
+        return Err(MathError::InvalidWindow);
+    }
+    to_u64((end_cumulative - start_cumulative) / (end_time - start_time) as u128)
+}
@@ -0,0 +1,277 @@
+// `migrate_market`, upgrading the markets and pools stored in an older layout. The fixtures
+// are a market and its pool as they were stored before the accounts were versioned, after
+// a deposit, a purchase and new risk limits.
+mod harness;
+
+use anchor_lang::error::ErrorCode;
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::system_program;
+use harness::market::{MarketEnv, USD};
+use harness::{assert_error, StoredAccount, Svm};
+use market_client::MarketAddresses;
+use solana_bet_placing_market::legacy::{MarketPoolV0, MarketPoolV1, MarketV0};
+use solana_bet_placing_market::{
+    accounts, event_accounts, instruction, Market, MarketError, MarketMigratedEvent, MarketPool,
+    MarketPrices, MarketStatus, RiskLimits,
+};
+
+const YES: u8 = 1;
+
+const MARKET_V0: &[u8] = include_bytes!("fixtures/market_v0.bin");
+const MARKET_POOL_V0: &[u8] = include_bytes!("fixtures/market_pool_v0.bin");
+
+fn program_account(svm: &Svm, data: &[u8]) -> StoredAccount {
+    StoredAccount {
+        lamports: svm.rent.minimum_balance(data.len()),
+        data: data.to_vec(),
+        owner: solana_bet_placing_market::ID,
+        executable: false,
+    }
+}
+
+// Cuts the versioned fields off an account. They come last, so this gives back the old layout.
+fn downgrade(svm: &mut Svm, key: Pubkey, len: usize) {
+    let mut account = svm.account(&key).unwrap().clone();
+    account.data.truncate(8 + len);
+    account.lamports = svm.rent.minimum_balance(8 + len);
+    svm.set_account(key, account);
+}
+
+// Puts a market and its pool back in version 1: no bumps stored and a Borsh pool
+fn downgrade_to_v1(env: &mut MarketEnv) {
+    let mut market = env.market();
+    market.version = 1;
+    market.vault_bump = 0;
+    market.yes_mint_bump = 0;
+    market.no_mint_bump = 0;
+    market.lp_share_mint_bump = 0;
+    let mut data = Vec::new();
+    market.try_serialize(&mut data).unwrap();
+    data.resize(8 + Market::LEN, 0);
+    let account = program_account(&env.svm, &data);
+    env.svm.set_account(env.market, account);
+
+    let pool = env.pool();
+    let pool = MarketPoolV1 {
+        liquidity_yes_tokens_account: pool.liquidity_yes_tokens_account,
+        liquidity_no_tokens_account: pool.liquidity_no_tokens_account,
+        yes_liquidity: pool.yes_liquidity,
+        no_liquidity: pool.no_liquidity,
+        liquidity_value: pool.liquidity_value,
+        liquidity_shares: pool.liquidity_shares,
+        usd_collateral: pool.usd_collateral,
+        total_yes_mints: pool.total_yes_mints,
+        total_no_mints: pool.total_no_mints,
+        bump: pool.bump,
+        yes_price_cumulative: pool.yes_price_cumulative.get(),
+        last_price_update: pool.last_price_update,
+        fees_collected: pool.fees_collected,
+        fee_per_share: pool.fee_per_share.get(),
+        version: 1,
+        reserved: [0; 64],
+    };
+    let mut data = MarketPool::DISCRIMINATOR.to_vec();
+    pool.serialize(&mut data).unwrap();
+    data.resize(8 + MarketPoolV1::LEN, 0);
+    let account = program_account(&env.svm, &data);
+    env.svm.set_account(env.pool, account);
+}
+
+fn get_prices(svm: &mut Svm, market: Pubkey, pool: Pubkey) -> harness::TransactionResult {
+    svm.simulate(
+        accounts::QuoteMarket { market, pool },
+        instruction::GetPrices {},
+        &[],
+    )
+}
+
+#[test]
+fn a_fixture_market_is_migrated_in_place() {
+    let mut svm = Svm::new();
+    let mut legacy_market = MarketV0::deserialize(&mut &MARKET_V0[8..]).unwrap();
+    let legacy_pool = MarketPoolV0::deserialize(&mut &MARKET_POOL_V0[8..]).unwrap();
+    assert_eq!(MARKET_V0.len(), 8 + MarketV0::LEN);
+    assert_eq!(MARKET_POOL_V0.len(), 8 + MarketPoolV0::LEN);
+    assert_eq!(legacy_market.risk_limits.max_trade_size, 50 * USD);
+    assert_eq!(legacy_market.status, MarketStatus::Open);
+
+    // Nobody holds the key of the fixture's authority, a wallet of the test takes its place
+    // along with the market address and bump that follow from it
+    let authority = svm.new_wallet();
+    let (market, bump) = Pubkey::find_program_address(
+        &[
+            b"market",
+            authority.as_ref(),
+            &legacy_market.market_number.to_le_bytes(),
+        ],
+        &solana_bet_placing_market::ID,
+    );
+    legacy_market.authority = authority;
+    legacy_market.bump = bump;
+    let mut market_data = MARKET_V0[..8].to_vec();
+    legacy_market.serialize(&mut market_data).unwrap();
+    market_data.resize(MARKET_V0.len(), 0);
+
+    let pool = MarketAddresses::new(market).pool;
+    let account = program_account(&svm, &market_data);
+    svm.set_account(market, account);
+    let account = program_account(&svm, MARKET_POOL_V0);
+    svm.set_account(pool, account);
+
+    // Nothing reads the old layout but the migration
+    assert_error(
+        get_prices(&mut svm, market, pool),
+        ErrorCode::AccountDidNotDeserialize,
+    );
+
+    let migrate = |svm: &mut Svm, authority: Pubkey| {
+        svm.call(
+            event_accounts!(accounts::MigrateMarket {
+                market,
+                pool,
+                authority,
+                system_program: system_program::ID,
+            }),
+            instruction::MigrateMarket {},
+            &[authority],
+        )
+    };
+    let mallory = svm.new_wallet();
+    assert_error(migrate(&mut svm, mallory), ErrorCode::ConstraintHasOne);
+
+    let migrated: MarketMigratedEvent = migrate(&mut svm, authority).unwrap().event();
+    assert_eq!(migrated.market, market);
+    assert_eq!(migrated.version, Market::VERSION);
+    assert_eq!(migrated.header.event_seq, legacy_market.event_seq + 1);
+
+    // Every field carried over, the bumps found and the accounts grown to their new size
+    // and rent exempt
+    let mut expected = Market::from(legacy_market.clone());
+    expected.find_bumps(&market);
+    expected.version = Market::VERSION;
+    expected.event_seq += 1;
+    let migrated_market: Market = svm.anchor_account(&market);
+    assert_eq!(
+        migrated_market.try_to_vec().unwrap(),
+        expected.try_to_vec().unwrap()
+    );
+    let addresses = MarketAddresses::new(market);
+    for (seed, bump, address) in [
+        (&b"vault"[..], migrated_market.vault_bump, addresses.vault),
+        (
+            b"yes_mint",
+            migrated_market.yes_mint_bump,
+            addresses.yes_mint,
+        ),
+        (b"no_mint", migrated_market.no_mint_bump, addresses.no_mint),
+        (
+            b"lp_share_mint",
+            migrated_market.lp_share_mint_bump,
+            addresses.lp_share_mint,
+        ),
+    ] {
+        let derived = Pubkey::create_program_address(
+            &[seed, market.as_ref(), &[bump]],
+            &solana_bet_placing_market::ID,
+        );
+        assert_eq!(derived, Ok(address));
+    }
+    let migrated_pool: MarketPool = svm.zero_copy_account(&pool);
+    let expected_pool = MarketPoolV1::from(legacy_pool.clone()).migrate(market);
+    assert_eq!(
+        bytemuck::bytes_of(&migrated_pool),
+        bytemuck::bytes_of(&expected_pool)
+    );
+    assert_eq!(migrated_pool.market, market);
+    assert_eq!(migrated_pool.version, MarketPool::VERSION);
+    for (key, len) in [(market, 8 + Market::LEN), (pool, MarketPool::LEN)] {
+        let account = svm.account(&key).unwrap();
+        assert_eq!(account.data.len(), len);
+        assert_eq!(account.lamports, svm.rent.minimum_balance(len));
+    }
+
+    let prices: MarketPrices = get_prices(&mut svm, market, pool).unwrap().return_value();
+    assert_eq!(prices.yes_liquidity, legacy_pool.yes_liquidity);
+    assert_eq!(prices.no_liquidity, legacy_pool.no_liquidity);
+    assert_eq!(prices.status, MarketStatus::Open);
+
+    assert_error(
+        migrate(&mut svm, authority),
+        MarketError::AccountAlreadyMigrated,
+    );
+}
+
+#[test]
+fn a_migrated_market_keeps_trading() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.purchase(&alice, 10 * USD, env.yes_mint).unwrap();
+    env.set_risk_limits(RiskLimits {
+        max_trade_size: 50 * USD,
+        ..RiskLimits::default()
+    })
+    .unwrap();
+    assert_error(env.migrate(), MarketError::AccountAlreadyMigrated);
+
+    // Nothing but the event count differs from before the round trip
+    let market = env.market();
+    let pool = env.pool();
+    downgrade_to_v1(&mut env);
+    assert_error(
+        env.purchase(&alice, 10 * USD, env.yes_mint),
+        ErrorCode::ConstraintHasOne,
+    );
+    env.migrate().unwrap();
+    let mut expected = market;
+    expected.event_seq += 1;
+    assert_eq!(
+        env.market().try_to_vec().unwrap(),
+        expected.try_to_vec().unwrap()
+    );
+    assert_eq!(bytemuck::bytes_of(&env.pool()), bytemuck::bytes_of(&pool));
+    assert_eq!(env.market().risk_limits.max_trade_size, 50 * USD);
+
+    env.purchase(&alice, 10 * USD, env.yes_mint).unwrap();
+    env.settle(&env.oracle.clone(), YES);
+    env.claim(&alice).unwrap();
+    env.remove_liquidity(&lp, 100 * USD).unwrap();
+    assert_eq!(env.pool().liquidity_shares, 0);
+}
+
+#[test]
+fn a_draft_market_is_migrated_before_its_pool_exists() {
+    let env = MarketEnv::new();
+    let market_number = env.market().market_number + 1;
+    let mut draft = MarketEnv::for_market(
+        env.svm,
+        env.authority,
+        env.oracle,
+        env.usd_mint,
+        env.usd_mint_authority,
+        market_number,
+    );
+    draft.create_market().unwrap();
+    downgrade(&mut draft.svm, draft.market, MarketV0::LEN);
+
+    draft
+        .svm
+        .call(
+            event_accounts!(accounts::MigrateMarket {
+                market: draft.market,
+                pool: draft.pool,
+                authority: draft.authority,
+                system_program: system_program::ID,
+            }),
+            instruction::MigrateMarket {},
+            &[draft.authority],
+        )
+        .unwrap();
+    assert_eq!(draft.market().status, MarketStatus::Draft);
+    assert_eq!(draft.market().version, Market::VERSION);
+
+    draft.initialize_pool().unwrap();
+    assert_eq!(draft.pool().version, MarketPool::VERSION);
+    assert_eq!(draft.market().status, MarketStatus::Open);
+}
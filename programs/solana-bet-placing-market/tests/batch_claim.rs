@@ -0,0 +1,100 @@
+// Claiming the winnings of several markets in one `batch_claim_winnings`.
+mod harness;
+
+use harness::assert_error;
+use harness::market::{MarketEnv, USD};
+use solana_bet_placing_market::{
+    BatchClaimEvent, MarketError, MarketPool, ResolveUserWinningsEvent,
+};
+
+const NO: u8 = 0;
+const YES: u8 = 1;
+
+#[test]
+fn one_transaction_claims_every_resolved_market() {
+    // Bob wins 150 on the first market and 60 on the second one, which both get resolved
+    let mut first = MarketEnv::new();
+    let lp = first.new_user(1_000 * USD);
+    let bob = first.new_user(1_000 * USD);
+    first.add_liquidity(&lp, 100 * USD).unwrap();
+    first.purchase(&bob, 100 * USD, first.yes_mint).unwrap();
+    first.settle(&first.oracle.clone(), YES);
+    let mut groups = first.batch_claim_group(&bob);
+    let (first_market, first_pool) = (first.market, first.pool);
+
+    let mut second = first.next_market();
+    let lp = second.join(lp.wallet, lp.usd);
+    let bob_second = second.join(bob.wallet, bob.usd);
+    second.add_liquidity(&lp, 40 * USD).unwrap();
+    second
+        .purchase(&bob_second, 40 * USD, second.no_mint)
+        .unwrap();
+    second.settle(&second.oracle.clone(), NO);
+    groups.extend(second.batch_claim_group(&bob_second));
+    let second_market = second.market;
+
+    // The third one is still open, and gets skipped
+    let mut third = second.next_market();
+    let lp = third.join(lp.wallet, lp.usd);
+    let bob_third = third.join(bob.wallet, bob.usd);
+    third.add_liquidity(&lp, 10 * USD).unwrap();
+    third
+        .purchase(&bob_third, 10 * USD, third.yes_mint)
+        .unwrap();
+    groups.extend(third.batch_claim_group(&bob_third));
+
+    let usd_before = third.balance(&bob.usd);
+    let result = third.batch_claim(bob.wallet, groups).unwrap();
+    let claims: Vec<ResolveUserWinningsEvent> = result.events();
+    let batch: BatchClaimEvent = result.event();
+    assert_eq!(claims.len(), 2);
+    assert_eq!(claims[0].winning_amount, 150 * USD);
+    assert_eq!(claims[1].winning_amount, 60 * USD);
+    assert_eq!(batch.user, bob.wallet);
+    assert_eq!(batch.claimed_markets, vec![first_market, second_market]);
+    assert_eq!(batch.skipped_markets, vec![third.market]);
+    assert_eq!(batch.total_winnings, 210 * USD);
+
+    assert_eq!(third.balance(&bob.usd), usd_before + 210 * USD);
+    assert_eq!(third.balance(&bob.yes), 0);
+    assert_eq!(third.balance(&bob_second.no), 0);
+    assert!(third.balance(&bob_third.yes) > 0);
+    let first_pool: MarketPool = third.svm.zero_copy_account(&first_pool);
+    assert_eq!(first_pool.total_yes_mints, 50 * USD);
+}
+
+#[test]
+fn a_batch_must_be_made_of_whole_market_groups() {
+    let mut first = MarketEnv::new();
+    let lp = first.new_user(1_000 * USD);
+    let bob = first.new_user(1_000 * USD);
+    first.add_liquidity(&lp, 100 * USD).unwrap();
+    first.purchase(&bob, 100 * USD, first.yes_mint).unwrap();
+    first.settle(&first.oracle.clone(), YES);
+    let first_group = first.batch_claim_group(&bob);
+
+    let mut second = first.next_market();
+    let bob_second = second.join(bob.wallet, bob.usd);
+
+    // Paying out of another market's vault
+    let mut groups = first_group.clone();
+    groups[2] = second.batch_claim_group(&bob_second)[2].clone();
+    assert_error(
+        second.batch_claim(bob.wallet, groups),
+        MarketError::InvalidBatch,
+    );
+
+    let mut groups = first_group.clone();
+    groups.pop();
+    assert_error(
+        second.batch_claim(bob.wallet, groups),
+        MarketError::InvalidBatch,
+    );
+    assert_error(
+        second.batch_claim(bob.wallet, Vec::new()),
+        MarketError::InvalidBatch,
+    );
+
+    second.batch_claim(bob.wallet, first_group).unwrap();
+    assert_eq!(second.balance(&bob.yes), 0);
+}
@@ -0,0 +1,165 @@
+// The event log an indexer rebuilds the markets from: a header on every event, numbered
+// per market and per factory.
+mod harness;
+
+use harness::market::{MarketEnv, USD};
+use harness::{TransactionMeta, TransactionResult};
+use solana_bet_placing_market::math::SCALE;
+use solana_bet_placing_market::{
+    EventHeader, FactoryFeesSetEvent, MarketCreatedEvent, MarketStatus, PoolInitializedEvent,
+    PurchasedOutcomeSharesEvent, SoldOutcomeSharesEvent, EVENT_SCHEMA_VERSION,
+};
+
+const YES: u8 = 1;
+
+// The events of the given transactions numbered after `last_seq`, in order and without gaps
+#[track_caller]
+fn assert_sequenced(env: &MarketEnv, last_seq: u64, results: &[TransactionMeta]) -> u64 {
+    let headers: Vec<EventHeader> = results
+        .iter()
+        .flat_map(TransactionMeta::event_headers)
+        .collect();
+    for (header, event_seq) in headers.iter().zip(last_seq + 1..) {
+        assert_eq!(header.version, EVENT_SCHEMA_VERSION);
+        assert_eq!(header.event_seq, event_seq);
+        assert_eq!(header.slot, env.svm.clock.slot);
+        assert_eq!(header.unix_timestamp, env.svm.clock.unix_timestamp);
+    }
+    last_seq + headers.len() as u64
+}
+
+fn ok(result: TransactionResult) -> TransactionMeta {
+    result.expect("the transaction failed")
+}
+
+#[test]
+fn every_market_event_is_numbered_without_gaps() {
+    let mut env = MarketEnv::with_fees(100, 2_000);
+    // Created, the pool initialized, then the market opened
+    assert_eq!(env.market().event_seq, 3);
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    let partner = env.new_user(0);
+    let keeper = env.new_user(0);
+
+    let results = vec![
+        ok(env.add_liquidity(&lp, 100 * USD)),
+        ok(env.open_position(&alice)),
+        ok(env.purchase(&alice, 10 * USD, env.yes_mint)),
+        ok(env.purchase(&bob, 10 * USD, env.yes_mint)),
+        ok(env.approve_settlement(&bob)),
+        ok(env.fund_settlement_budget(USD, USD / 10)),
+        ok(env.set_risk_limits(Default::default())),
+    ];
+    let last_seq = assert_sequenced(&env, 3, &results);
+
+    // The fees of a referred trade come with their own events, while the referrer and
+    // its stats are outside of any market
+    let registered = ok(env.register_referrer(&partner));
+    assert_eq!(registered.event_headers()[0].event_seq, 0);
+    let shares = env.balance(&alice.yes) / 2;
+    let results = vec![
+        ok(env.sell_referred(&alice, shares, env.yes_mint, &partner)),
+        ok(env.resolve(&env.oracle.clone(), YES)),
+        ok(env.set_status(MarketStatus::Finalized)),
+        ok(env.claim(&alice)),
+        ok(env.settle_holders(&keeper, &[(bob.yes, bob.usd)])),
+        ok(env.remove_liquidity(&lp, 50 * USD)),
+    ];
+    assert_eq!(results[0].events.len(), 2);
+    // The resolution changes the status, then reports the outcome
+    assert_eq!(results[1].events.len(), 2);
+    // The settlement pays a holder, then reports
+    assert_eq!(results[4].events.len(), 2);
+    let last_seq = assert_sequenced(&env, last_seq, &results);
+    assert_eq!(env.market().event_seq, last_seq);
+}
+
+#[test]
+fn trades_report_the_prices_after_them() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+
+    let purchase: PurchasedOutcomeSharesEvent = env
+        .purchase(&alice, 50 * USD, env.yes_mint)
+        .unwrap()
+        .event();
+    assert!(purchase.yes_price_after_purchase > purchase.yes_price_before_purchase);
+    assert_eq!(
+        purchase.yes_price_after_purchase + purchase.no_price_after_purchase,
+        SCALE as u64
+    );
+
+    let shares = env.balance(&alice.yes);
+    let sale: SoldOutcomeSharesEvent = env.sell(&alice, shares, env.yes_mint).unwrap().event();
+    assert_eq!(
+        sale.yes_price_before_sale,
+        purchase.yes_price_after_purchase
+    );
+    assert!(sale.yes_price_after_sale < sale.yes_price_before_sale);
+}
+
+#[test]
+fn the_factory_and_the_market_setup_have_events() {
+    let mut env = MarketEnv::new();
+    // Initialized only
+    assert_eq!(env.market_factory().event_seq, 1);
+
+    let fees: FactoryFeesSetEvent = env.set_relay_fee(USD / 10).unwrap().event();
+    assert_eq!(fees.header.event_seq, 2);
+    assert_eq!(fees.market_factory, env.market_factory);
+    assert_eq!(fees.relay_fee, USD / 10);
+    assert_eq!(fees.trading_fee_bps, 0);
+
+    let market_number = env.market().market_number + 1;
+    let mut next = MarketEnv::for_market(
+        env.svm,
+        env.authority,
+        env.oracle,
+        env.usd_mint,
+        env.usd_mint_authority,
+        market_number,
+    );
+    let created: MarketCreatedEvent = next.create_market().unwrap().event();
+    assert_eq!(created.header.event_seq, 1);
+    assert_eq!(created.market, next.market);
+    assert_eq!(created.market_number, market_number);
+    assert_eq!(created.yes_mint, next.yes_mint);
+    assert_eq!(created.vault, next.vault);
+    assert_eq!(created.relay_fee, USD / 10);
+
+    let pool: PoolInitializedEvent = next.initialize_pool().unwrap().event();
+    assert_eq!(pool.header.event_seq, 2);
+    assert_eq!(pool.pool, next.pool);
+    assert_eq!(pool.price_history, next.price_history);
+}
+
+// With the `event-cpi` feature the events are the data of self-CPIs, which the indexer
+// decodes from the inner instructions, and none of them is left in the logs
+#[cfg(feature = "event-cpi")]
+#[test]
+fn events_come_back_from_the_inner_instructions() {
+    use market_client::events::{cpi_event, log_events};
+    use solana_bet_placing_market::ID;
+
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    ok(env.add_liquidity(&lp, 100 * USD));
+    let result = ok(env.purchase(&alice, 10 * USD, env.yes_mint));
+
+    let inner_events: Vec<Vec<u8>> = result
+        .inner_instructions
+        .iter()
+        .filter(|(program_id, _)| *program_id == ID)
+        .filter_map(|(_, data)| cpi_event(data).map(<[u8]>::to_vec))
+        .collect();
+    assert!(!inner_events.is_empty());
+    assert_eq!(inner_events, result.events);
+    assert!(log_events(&result.logs, &ID).is_empty());
+    let purchase: PurchasedOutcomeSharesEvent = result.event();
+    assert_eq!(purchase.user, alice.wallet);
+}
@@ -0,0 +1,1074 @@
+// A deployed market with its factory, pool and fake USD mint, plus helpers to drive it.
+//
+// Every successful step is followed by `audit_market`, which has to succeed and report
+// that the pool accounting matches the token program.
+#![allow(dead_code)]
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::{system_program, sysvar};
+use anchor_lang::InstructionData;
+use anchor_spl::associated_token::get_associated_token_address;
+use anchor_spl::token::spl_token;
+use market_client::{pda, MarketAddresses};
+use solana_bet_placing_market::{
+    accounts, event_accounts, instruction, LiquidityNav, LiquidityPosition, Market,
+    MarketAuditEvent, MarketFactory, MarketGroup, MarketPool, MarketStatus, MarketTwap,
+    PriceHistory, PriceObservation, ReferralStats, RiskLimits, SessionKey, UserPosition,
+};
+
+use super::{Svm, TransactionResult};
+
+pub const USD: u64 = 1_000_000_000;
+
+pub struct User {
+    pub wallet: Pubkey,
+    pub usd: Pubkey,
+    pub yes: Pubkey,
+    pub no: Pubkey,
+    pub lp: Pubkey,
+    // Only passed to the instructions once `open_position` created it
+    pub position: Pubkey,
+    // The same with `open_liquidity_position`
+    pub lp_position: Pubkey,
+    // The same with `register_referrer`, shared by all the markets
+    pub referral: Pubkey,
+    // Who signs the trades and claims: the wallet, or a session key with its session
+    pub signer: Pubkey,
+    pub session: Option<Pubkey>,
+}
+
+impl User {
+    // The same user, trading through a session of `session_key`
+    pub fn with_session(&self, session_key: Pubkey) -> User {
+        User {
+            signer: session_key,
+            session: Some(pda::session(&self.wallet, &session_key)),
+            ..*self
+        }
+    }
+}
+
+pub struct MarketEnv {
+    pub svm: Svm,
+    pub authority: Pubkey,
+    pub oracle: Pubkey,
+    pub usd_mint: Pubkey,
+    pub usd_mint_authority: Pubkey,
+    pub market_factory: Pubkey,
+    pub market: Pubkey,
+    pub yes_mint: Pubkey,
+    pub no_mint: Pubkey,
+    pub lp_share_mint: Pubkey,
+    pub vault: Pubkey,
+    pub pool: Pubkey,
+    pub liquidity_yes_tokens_account: Pubkey,
+    pub liquidity_no_tokens_account: Pubkey,
+    pub price_history: Pubkey,
+}
+
+// The user signs a trade, and so does its relayer if any
+fn signers(user: &User, relayer: Option<&User>) -> Vec<Pubkey> {
+    let mut signers = vec![user.signer];
+    signers.extend(relayer.map(|relayer| relayer.wallet));
+    signers
+}
+
+impl MarketEnv {
+    // A factory, a first market and its pool, without any liquidity
+    pub fn new() -> Self {
+        Self::with_trading_fee(0)
+    }
+
+    // The same, with a factory fee set before the market is created
+    pub fn with_trading_fee(trading_fee_bps: u16) -> Self {
+        Self::with_fees(trading_fee_bps, 0)
+    }
+
+    // And with the referrers' share of that fee
+    pub fn with_fees(trading_fee_bps: u16, referral_share_bps: u16) -> Self {
+        let mut svm = Svm::new();
+        let authority = svm.new_wallet();
+        let oracle = svm.new_wallet();
+        let usd_mint_authority = svm.new_wallet();
+        let usd_mint = svm.create_mint(&authority, &usd_mint_authority, 9);
+
+        let market_factory = pda::market_factory(&authority);
+        svm.call(
+            event_accounts!(accounts::InitializeMarketFactory {
+                market_factory,
+                authority,
+                system_program: system_program::ID,
+            }),
+            instruction::InitializeMarketFactory {},
+            &[authority],
+        )
+        .expect("cannot initialize the factory");
+
+        let mut env = Self::for_market(svm, authority, oracle, usd_mint, usd_mint_authority, 0);
+        if trading_fee_bps > 0 {
+            env.set_trading_fee(trading_fee_bps)
+                .expect("cannot set the trading fee");
+        }
+        if referral_share_bps > 0 {
+            env.set_referral_share(referral_share_bps)
+                .expect("cannot set the referral share");
+        }
+        env.create_market().expect("cannot create the market");
+        env.initialize_pool().expect("cannot initialize the pool");
+        env
+    }
+
+    // Creates the next market of the factory, with its pool, on the same chain
+    pub fn next_market(self) -> Self {
+        let market_number = self.market().market_number + 1;
+        let mut env = Self::for_market(
+            self.svm,
+            self.authority,
+            self.oracle,
+            self.usd_mint,
+            self.usd_mint_authority,
+            market_number,
+        );
+        env.create_market().expect("cannot create the market");
+        env.initialize_pool().expect("cannot initialize the pool");
+        env
+    }
+
+    // Goes back to another market of the factory, on the same chain
+    pub fn switch_to(self, market_number: u64) -> Self {
+        Self::for_market(
+            self.svm,
+            self.authority,
+            self.oracle,
+            self.usd_mint,
+            self.usd_mint_authority,
+            market_number,
+        )
+    }
+
+    // The addresses of the market with the given number, which may not exist yet
+    pub fn for_market(
+        svm: Svm,
+        authority: Pubkey,
+        oracle: Pubkey,
+        usd_mint: Pubkey,
+        usd_mint_authority: Pubkey,
+        market_number: u64,
+    ) -> Self {
+        let addresses = MarketAddresses::new(pda::market(&authority, market_number));
+
+        MarketEnv {
+            svm,
+            authority,
+            oracle,
+            usd_mint,
+            usd_mint_authority,
+            market_factory: pda::market_factory(&authority),
+            market: addresses.market,
+            yes_mint: addresses.yes_mint,
+            no_mint: addresses.no_mint,
+            lp_share_mint: addresses.lp_share_mint,
+            vault: addresses.vault,
+            pool: addresses.pool,
+            liquidity_yes_tokens_account: addresses.yes_liquidity_pool,
+            liquidity_no_tokens_account: addresses.no_liquidity_pool,
+            price_history: addresses.price_history,
+        }
+    }
+
+    pub fn create_market(&mut self) -> TransactionResult {
+        self.svm.call(
+            event_accounts!(accounts::InitializeMarket {
+                market: self.market,
+                yes_mint: self.yes_mint,
+                no_mint: self.no_mint,
+                lp_share_mint: self.lp_share_mint,
+                usd_mint: self.usd_mint,
+                market_factory: self.market_factory,
+                vault: self.vault,
+                authority: self.authority,
+                system_program: system_program::ID,
+                token_program: spl_token::ID,
+                rent: sysvar::rent::ID,
+            }),
+            instruction::CreateNewMarket {
+                oracle_key: self.oracle,
+            },
+            &[self.authority],
+        )
+    }
+
+    pub fn initialize_pool(&mut self) -> TransactionResult {
+        self.svm.call(
+            event_accounts!(accounts::InitializePool {
+                pool: self.pool,
+                liquidity_yes_tokens_account: self.liquidity_yes_tokens_account,
+                liquidity_no_tokens_account: self.liquidity_no_tokens_account,
+                price_history: self.price_history,
+                market: self.market,
+                authority: self.authority,
+                yes_mint: self.yes_mint,
+                no_mint: self.no_mint,
+                system_program: system_program::ID,
+                token_program: spl_token::ID,
+            }),
+            instruction::InitializePool {},
+            &[self.authority],
+        )
+    }
+
+    // A wallet with its token accounts, funded with `usd_amount` of the fake USD
+    pub fn new_user(&mut self, usd_amount: u64) -> User {
+        let wallet = self.svm.new_wallet();
+        let usd = self
+            .svm
+            .create_associated_token_account(&wallet, &self.usd_mint);
+        if usd_amount > 0 {
+            self.svm
+                .mint_to(&self.usd_mint, &self.usd_mint_authority, &usd, usd_amount);
+        }
+        self.join(wallet, usd)
+    }
+
+    // The token accounts of this market for a wallet that already has a USD account
+    pub fn join(&mut self, wallet: Pubkey, usd: Pubkey) -> User {
+        let yes = self
+            .svm
+            .create_token_account(&wallet, &wallet, &self.yes_mint);
+        let no = self
+            .svm
+            .create_token_account(&wallet, &wallet, &self.no_mint);
+        let lp = self
+            .svm
+            .create_token_account(&wallet, &wallet, &self.lp_share_mint);
+
+        User {
+            wallet,
+            usd,
+            yes,
+            no,
+            lp,
+            position: pda::position(&self.market, &wallet),
+            lp_position: pda::lp_position(&self.market, &wallet),
+            referral: pda::referral(&wallet),
+            signer: wallet,
+            session: None,
+        }
+    }
+
+    pub fn market_factory(&self) -> MarketFactory {
+        self.svm.anchor_account(&self.market_factory)
+    }
+
+    pub fn market(&self) -> Market {
+        self.svm.anchor_account(&self.market)
+    }
+
+    pub fn pool(&self) -> MarketPool {
+        self.svm.zero_copy_account(&self.pool)
+    }
+
+    pub fn price_history(&self) -> PriceHistory {
+        self.svm.zero_copy_account(&self.price_history)
+    }
+
+    pub fn position(&self, user: &User) -> UserPosition {
+        self.svm.anchor_account(&user.position)
+    }
+
+    fn position_of(&self, user: &User) -> Option<Pubkey> {
+        self.svm.account(&user.position).map(|_| user.position)
+    }
+
+    pub fn lp_position(&self, user: &User) -> LiquidityPosition {
+        self.svm.anchor_account(&user.lp_position)
+    }
+
+    fn lp_position_of(&self, user: &User) -> Option<Pubkey> {
+        self.svm
+            .account(&user.lp_position)
+            .map(|_| user.lp_position)
+    }
+
+    pub fn open_liquidity_position(&mut self, user: &User) -> TransactionResult {
+        self.svm.call(
+            event_accounts!(accounts::OpenLiquidityPosition {
+                lp_position: user.lp_position,
+                market: self.market,
+                pool: self.pool,
+                user: user.wallet,
+                payer: user.wallet,
+                system_program: system_program::ID,
+            }),
+            instruction::OpenLiquidityPosition {},
+            &[user.wallet],
+        )
+    }
+
+    pub fn liquidity_nav(&mut self, user: &User) -> LiquidityNav {
+        self.svm
+            .simulate(
+                accounts::QuoteLiquidityPosition {
+                    market: self.market,
+                    pool: self.pool,
+                    lp_position: user.lp_position,
+                },
+                instruction::GetLiquidityNav {},
+                &[],
+            )
+            .expect("cannot compute the NAV")
+            .return_value()
+    }
+
+    // Applies to the markets created afterwards
+    pub fn set_trading_fee(&mut self, trading_fee_bps: u16) -> TransactionResult {
+        self.svm.call(
+            event_accounts!(accounts::SetTradingFee {
+                market_factory: self.market_factory,
+                authority: self.authority,
+            }),
+            instruction::SetTradingFee { trading_fee_bps },
+            &[self.authority],
+        )
+    }
+
+    // Applies to the markets created afterwards
+    pub fn set_relay_fee(&mut self, relay_fee: u64) -> TransactionResult {
+        self.svm.call(
+            event_accounts!(accounts::SetRelayFee {
+                market_factory: self.market_factory,
+                authority: self.authority,
+            }),
+            instruction::SetRelayFee { relay_fee },
+            &[self.authority],
+        )
+    }
+
+    // Applies to the markets created afterwards
+    pub fn set_referral_share(&mut self, referral_share_bps: u16) -> TransactionResult {
+        self.svm.call(
+            event_accounts!(accounts::SetReferralShare {
+                market_factory: self.market_factory,
+                authority: self.authority,
+            }),
+            instruction::SetReferralShare { referral_share_bps },
+            &[self.authority],
+        )
+    }
+
+    // Fees are paid to the referrer's USD account
+    pub fn register_referrer(&mut self, referrer: &User) -> TransactionResult {
+        self.svm.call(
+            event_accounts!(accounts::RegisterReferrer {
+                referral: referrer.referral,
+                usd_account: referrer.usd,
+                referrer: referrer.wallet,
+                payer: referrer.wallet,
+                system_program: system_program::ID,
+            }),
+            instruction::RegisterReferrer {},
+            &[referrer.wallet],
+        )
+    }
+
+    pub fn referral_stats(&self, referrer: &User) -> ReferralStats {
+        self.svm.anchor_account(&referrer.referral)
+    }
+
+    pub fn set_risk_limits(&mut self, risk_limits: RiskLimits) -> TransactionResult {
+        self.svm.call(
+            event_accounts!(accounts::SetRiskLimits {
+                market: self.market,
+                authority: self.authority,
+            }),
+            instruction::SetRiskLimits { risk_limits },
+            &[self.authority],
+        )
+    }
+
+    pub fn open_position(&mut self, user: &User) -> TransactionResult {
+        self.open_position_paid_by(user, user.wallet)
+    }
+
+    pub fn open_position_paid_by(&mut self, user: &User, payer: Pubkey) -> TransactionResult {
+        self.svm.call(
+            event_accounts!(accounts::OpenPosition {
+                position: user.position,
+                market: self.market,
+                user: user.wallet,
+                payer,
+                system_program: system_program::ID,
+            }),
+            instruction::OpenPosition {},
+            &[user.wallet, payer],
+        )
+    }
+
+    // A session of `session_key` for `user`, over this market only when `scoped`
+    pub fn create_session(
+        &mut self,
+        user: &User,
+        session_key: Pubkey,
+        scoped: bool,
+        expires_at: i64,
+        spend_cap: u64,
+    ) -> TransactionResult {
+        self.svm.call(
+            event_accounts!(accounts::CreateSession {
+                session: pda::session(&user.wallet, &session_key),
+                owner_usd_account: user.usd,
+                owner: user.wallet,
+                payer: user.wallet,
+                system_program: system_program::ID,
+                token_program: spl_token::ID,
+            }),
+            instruction::CreateSession {
+                session_key,
+                market: if scoped {
+                    self.market
+                } else {
+                    Pubkey::default()
+                },
+                expires_at,
+                spend_cap,
+            },
+            &[user.wallet],
+        )
+    }
+
+    pub fn delegate_to_session(
+        &mut self,
+        user: &User,
+        session_key: Pubkey,
+        token_account: Pubkey,
+    ) -> TransactionResult {
+        self.svm.call(
+            event_accounts!(accounts::DelegateToSession {
+                session: pda::session(&user.wallet, &session_key),
+                token_account,
+                owner: user.wallet,
+                token_program: spl_token::ID,
+            }),
+            instruction::DelegateToSession {},
+            &[user.wallet],
+        )
+    }
+
+    // Also revokes the delegation of the `delegated` outcome token accounts
+    pub fn revoke_session(
+        &mut self,
+        user: &User,
+        session_key: Pubkey,
+        delegated: &[Pubkey],
+    ) -> TransactionResult {
+        let mut accounts = event_accounts!(accounts::RevokeSession {
+            session: pda::session(&user.wallet, &session_key),
+            owner_usd_account: user.usd,
+            owner: user.wallet,
+            token_program: spl_token::ID,
+        })
+        .to_account_metas(None);
+        accounts.extend(
+            delegated
+                .iter()
+                .map(|token_account| AccountMeta::new(*token_account, false)),
+        );
+        let instruction = Instruction {
+            program_id: solana_bet_placing_market::ID,
+            accounts,
+            data: instruction::RevokeSession {}.data(),
+        };
+        self.svm.process(&[instruction], &[user.wallet])
+    }
+
+    pub fn session(&self, user: &User, session_key: &Pubkey) -> SessionKey {
+        self.svm
+            .anchor_account(&pda::session(&user.wallet, session_key))
+    }
+
+    pub fn balance(&self, token_account: &Pubkey) -> u64 {
+        self.svm.token_balance(token_account)
+    }
+
+    fn pool_liquidity_accounts(&self, user: &User) -> accounts::PoolLiquidity {
+        event_accounts!(accounts::PoolLiquidity {
+            pool: self.pool,
+            market: self.market,
+            vault: self.vault,
+            yes_mint: self.yes_mint,
+            no_mint: self.no_mint,
+            lp_share_mint: self.lp_share_mint,
+            user_usd_account: user.usd,
+            user_yes_account: user.yes,
+            user_no_account: user.no,
+            user_lp_share_account: user.lp,
+            liquidity_yes_tokens_account: self.liquidity_yes_tokens_account,
+            liquidity_no_tokens_account: self.liquidity_no_tokens_account,
+            position: self.position_of(user),
+            lp_position: self.lp_position_of(user),
+            user: user.wallet,
+            token_program: spl_token::ID,
+        })
+    }
+
+    pub fn add_liquidity(&mut self, user: &User, usd_amount: u64) -> TransactionResult {
+        let result = self.svm.call(
+            self.pool_liquidity_accounts(user),
+            instruction::AddLiquidity { usd_amount },
+            &[user.wallet],
+        );
+        self.audit_after(result)
+    }
+
+    pub fn remove_liquidity(&mut self, user: &User, shares: u64) -> TransactionResult {
+        let result = self.svm.call(
+            self.pool_liquidity_accounts(user),
+            instruction::RemoveLiquidity { shares },
+            &[user.wallet],
+        );
+        self.audit_after(result)
+    }
+
+    pub fn purchase(
+        &mut self,
+        user: &User,
+        usd_amount: u64,
+        outcome_mint: Pubkey,
+    ) -> TransactionResult {
+        self.purchase_with(user, usd_amount, outcome_mint, None, None)
+    }
+
+    pub fn purchase_referred(
+        &mut self,
+        user: &User,
+        usd_amount: u64,
+        outcome_mint: Pubkey,
+        referrer: &User,
+    ) -> TransactionResult {
+        self.purchase_with(user, usd_amount, outcome_mint, Some(referrer), None)
+    }
+
+    // A purchase whose transaction `relayer` pays for
+    pub fn purchase_relayed(
+        &mut self,
+        user: &User,
+        usd_amount: u64,
+        outcome_mint: Pubkey,
+        relayer: &User,
+    ) -> TransactionResult {
+        self.purchase_with(user, usd_amount, outcome_mint, None, Some(relayer))
+    }
+
+    fn purchase_with(
+        &mut self,
+        user: &User,
+        usd_amount: u64,
+        outcome_mint: Pubkey,
+        referrer: Option<&User>,
+        relayer: Option<&User>,
+    ) -> TransactionResult {
+        let user_outcome_mint_account = if outcome_mint == self.no_mint {
+            user.no
+        } else {
+            user.yes
+        };
+        let result = self.svm.call(
+            event_accounts!(accounts::PurchaseOutcomeShares {
+                market: self.market,
+                pool: self.pool,
+                vault: self.vault,
+                yes_mint: self.yes_mint,
+                no_mint: self.no_mint,
+                user_usd_account: user.usd,
+                user_outcome_mint_account,
+                liquidity_yes_tokens_account: self.liquidity_yes_tokens_account,
+                liquidity_no_tokens_account: self.liquidity_no_tokens_account,
+                price_history: self.price_history,
+                position: self.position_of(user),
+                referral: referrer.map(|referrer| referrer.referral),
+                referrer_usd_account: referrer.map(|referrer| referrer.usd),
+                session: user.session,
+                relayer: relayer.map(|relayer| relayer.wallet),
+                relayer_usd_account: relayer.map(|relayer| relayer.usd),
+                user: user.signer,
+                token_program: spl_token::ID,
+            }),
+            instruction::PurchaseOutcomeShares {
+                usd_amount,
+                purchased_outcome_mint_pubkey: outcome_mint,
+            },
+            &signers(user, relayer),
+        );
+        self.audit_after(result)
+    }
+
+    pub fn sell(&mut self, user: &User, shares: u64, outcome_mint: Pubkey) -> TransactionResult {
+        self.sell_with(user, shares, outcome_mint, None, None)
+    }
+
+    pub fn sell_referred(
+        &mut self,
+        user: &User,
+        shares: u64,
+        outcome_mint: Pubkey,
+        referrer: &User,
+    ) -> TransactionResult {
+        self.sell_with(user, shares, outcome_mint, Some(referrer), None)
+    }
+
+    pub fn sell_relayed(
+        &mut self,
+        user: &User,
+        shares: u64,
+        outcome_mint: Pubkey,
+        relayer: &User,
+    ) -> TransactionResult {
+        self.sell_with(user, shares, outcome_mint, None, Some(relayer))
+    }
+
+    fn sell_with(
+        &mut self,
+        user: &User,
+        shares: u64,
+        outcome_mint: Pubkey,
+        referrer: Option<&User>,
+        relayer: Option<&User>,
+    ) -> TransactionResult {
+        let user_outcome_mint_account = if outcome_mint == self.no_mint {
+            user.no
+        } else {
+            user.yes
+        };
+        let result = self.svm.call(
+            event_accounts!(accounts::SellOutcomeShares {
+                market: self.market,
+                pool: self.pool,
+                vault: self.vault,
+                yes_mint: self.yes_mint,
+                no_mint: self.no_mint,
+                user_usd_account: user.usd,
+                user_outcome_mint_account,
+                liquidity_yes_tokens_account: self.liquidity_yes_tokens_account,
+                liquidity_no_tokens_account: self.liquidity_no_tokens_account,
+                price_history: self.price_history,
+                position: self.position_of(user),
+                referral: referrer.map(|referrer| referrer.referral),
+                referrer_usd_account: referrer.map(|referrer| referrer.usd),
+                session: user.session,
+                relayer: relayer.map(|relayer| relayer.wallet),
+                relayer_usd_account: relayer.map(|relayer| relayer.usd),
+                user: user.signer,
+                token_program: spl_token::ID,
+            }),
+            instruction::SellOutcomeShares {
+                shares,
+                sold_outcome_mint_pubkey: outcome_mint,
+            },
+            &signers(user, relayer),
+        );
+        self.audit_after(result)
+    }
+
+    // The accounts `batch_claim_winnings` expects for this market
+    pub fn batch_claim_group(&self, user: &User) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(self.market, false),
+            AccountMeta::new(self.pool, false),
+            AccountMeta::new(self.vault, false),
+            AccountMeta::new(self.yes_mint, false),
+            AccountMeta::new(self.no_mint, false),
+            AccountMeta::new(user.usd, false),
+            AccountMeta::new(user.yes, false),
+            AccountMeta::new(user.no, false),
+        ]
+    }
+
+    pub fn batch_claim(&mut self, wallet: Pubkey, groups: Vec<AccountMeta>) -> TransactionResult {
+        let mut accounts = event_accounts!(accounts::BatchClaimWinnings {
+            user: wallet,
+            token_program: spl_token::ID,
+        })
+        .to_account_metas(None);
+        accounts.extend(groups);
+        let instruction = Instruction {
+            program_id: solana_bet_placing_market::ID,
+            accounts,
+            data: instruction::BatchClaimWinnings {}.data(),
+        };
+        let result = self.svm.process(&[instruction], &[wallet]);
+        self.audit_after(result)
+    }
+
+    pub fn approve_settlement(&mut self, user: &User) -> TransactionResult {
+        self.svm.call(
+            event_accounts!(accounts::ApproveSettlement {
+                market: self.market,
+                user_yes_account: user.yes,
+                user_no_account: user.no,
+                user: user.wallet,
+                token_program: spl_token::ID,
+            }),
+            instruction::ApproveSettlement {},
+            &[user.wallet],
+        )
+    }
+
+    // Mints the budget to the authority first
+    pub fn fund_settlement_budget(&mut self, budget: u64, keeper_reward: u64) -> TransactionResult {
+        let authority_usd_account = get_associated_token_address(&self.authority, &self.usd_mint);
+        if self.svm.account(&authority_usd_account).is_none() {
+            self.svm
+                .create_associated_token_account(&self.authority, &self.usd_mint);
+        }
+        self.svm.mint_to(
+            &self.usd_mint,
+            &self.usd_mint_authority,
+            &authority_usd_account,
+            budget,
+        );
+        let result = self.svm.call(
+            event_accounts!(accounts::FundSettlementBudget {
+                market: self.market,
+                vault: self.vault,
+                authority_usd_account,
+                authority: self.authority,
+                token_program: spl_token::ID,
+            }),
+            instruction::FundSettlementBudget {
+                budget,
+                keeper_reward,
+            },
+            &[self.authority],
+        );
+        self.audit_after(result)
+    }
+
+    pub fn reclaim_settlement_budget(&mut self) -> TransactionResult {
+        let authority_usd_account = get_associated_token_address(&self.authority, &self.usd_mint);
+        let result = self.svm.call(
+            event_accounts!(accounts::ReclaimSettlementBudget {
+                market: self.market,
+                pool: self.pool,
+                vault: self.vault,
+                authority_usd_account,
+                authority: self.authority,
+                token_program: spl_token::ID,
+            }),
+            instruction::ReclaimSettlementBudget {},
+            &[self.authority],
+        );
+        self.audit_after(result)
+    }
+
+    // `holders` are (outcome token account, USD account) pairs
+    pub fn settle_holders(
+        &mut self,
+        keeper: &User,
+        holders: &[(Pubkey, Pubkey)],
+    ) -> TransactionResult {
+        let mut accounts = event_accounts!(accounts::SettleHolders {
+            market: self.market,
+            pool: self.pool,
+            vault: self.vault,
+            yes_mint: self.yes_mint,
+            no_mint: self.no_mint,
+            keeper_usd_account: keeper.usd,
+            keeper: keeper.wallet,
+            token_program: spl_token::ID,
+        })
+        .to_account_metas(None);
+        for (outcome_account, usd_account) in holders {
+            accounts.push(AccountMeta::new(*outcome_account, false));
+            accounts.push(AccountMeta::new(*usd_account, false));
+        }
+        let instruction = Instruction {
+            program_id: solana_bet_placing_market::ID,
+            accounts,
+            data: instruction::SettleHolders {}.data(),
+        };
+        let result = self.svm.process(&[instruction], &[keeper.wallet]);
+        self.audit_after(result)
+    }
+
+    pub fn resolve(&mut self, oracle: &Pubkey, outcome: u8) -> TransactionResult {
+        let result = self.svm.call(
+            event_accounts!(accounts::ResolveMarket {
+                market: self.market,
+                pool: self.pool,
+                oracle: *oracle,
+            }),
+            instruction::ResolveMarket { outcome },
+            &[*oracle],
+        );
+        self.audit_after(result)
+    }
+
+    pub fn invalidate(&mut self, oracle: &Pubkey) -> TransactionResult {
+        let result = self.svm.call(
+            event_accounts!(accounts::ResolveMarket {
+                market: self.market,
+                pool: self.pool,
+                oracle: *oracle,
+            }),
+            instruction::InvalidateMarket {},
+            &[*oracle],
+        );
+        self.audit_after(result)
+    }
+
+    pub fn migrate(&mut self) -> TransactionResult {
+        let result = self.svm.call(
+            event_accounts!(accounts::MigrateMarket {
+                market: self.market,
+                pool: self.pool,
+                authority: self.authority,
+                system_program: system_program::ID,
+            }),
+            instruction::MigrateMarket {},
+            &[self.authority],
+        );
+        self.audit_after(result)
+    }
+
+    pub fn set_status(&mut self, status: MarketStatus) -> TransactionResult {
+        self.svm.call(
+            event_accounts!(accounts::SetMarketStatus {
+                market: self.market,
+                authority: self.authority,
+            }),
+            instruction::SetMarketStatus { status },
+            &[self.authority],
+        )
+    }
+
+    // Anyone's call once the dispute window is over, see `settle`
+    pub fn finalize(&mut self, payer: Pubkey) -> TransactionResult {
+        self.svm.call(
+            event_accounts!(accounts::FinalizeMarket {
+                market: self.market,
+                payer,
+            }),
+            instruction::FinalizeMarket {},
+            &[payer],
+        )
+    }
+
+    // A group of the factory's markets, see `create_market_group`
+    pub fn create_market_group(&mut self, group_id: u64, markets: &[Pubkey]) -> TransactionResult {
+        let group = pda::market_group(&self.authority, group_id);
+        let mut accounts = event_accounts!(accounts::CreateMarketGroup {
+            group,
+            vault: pda::group_vault(&group),
+            usd_mint: self.usd_mint,
+            authority: self.authority,
+            system_program: system_program::ID,
+            token_program: spl_token::ID,
+        })
+        .to_account_metas(None);
+        accounts.extend(
+            markets
+                .iter()
+                .map(|market| AccountMeta::new(*market, false)),
+        );
+        let instruction = Instruction {
+            program_id: solana_bet_placing_market::ID,
+            accounts,
+            data: instruction::CreateMarketGroup { group_id }.data(),
+        };
+        self.svm.process(&[instruction], &[self.authority])
+    }
+
+    pub fn market_group(&self, group: &Pubkey) -> MarketGroup {
+        self.svm.anchor_account(group)
+    }
+
+    pub fn resolve_market_group(
+        &mut self,
+        oracle: &Pubkey,
+        group: &Pubkey,
+        winner: u8,
+    ) -> TransactionResult {
+        let market_group = self.market_group(group);
+        let mut accounts = event_accounts!(accounts::ResolveMarketGroup {
+            group: *group,
+            vault: market_group.vault,
+            oracle: *oracle,
+            token_program: spl_token::ID,
+        })
+        .to_account_metas(None);
+        for market in &market_group.markets {
+            let addresses = MarketAddresses::new(*market);
+            accounts.push(AccountMeta::new(addresses.market, false));
+            accounts.push(AccountMeta::new(addresses.pool, false));
+            accounts.push(AccountMeta::new(addresses.vault, false));
+        }
+        let instruction = Instruction {
+            program_id: solana_bet_placing_market::ID,
+            accounts,
+            data: instruction::ResolveMarketGroup { winner }.data(),
+        };
+        self.svm.process(&[instruction], &[*oracle])
+    }
+
+    // `no_accounts` are the user's NO accounts of every member, in the group's order
+    pub fn convert_no_tokens(
+        &mut self,
+        user: &User,
+        group: &Pubkey,
+        no_accounts: &[Pubkey],
+        amount: u64,
+    ) -> TransactionResult {
+        let market_group = self.market_group(group);
+        let mut accounts = event_accounts!(accounts::ConvertNoTokens {
+            group: *group,
+            vault: market_group.vault,
+            user_usd_account: user.usd,
+            user: user.wallet,
+            token_program: spl_token::ID,
+        })
+        .to_account_metas(None);
+        for (market, no_account) in market_group.markets.iter().zip(no_accounts) {
+            let addresses = MarketAddresses::new(*market);
+            accounts.push(AccountMeta::new_readonly(addresses.market, false));
+            accounts.push(AccountMeta::new(addresses.pool, false));
+            accounts.push(AccountMeta::new(addresses.vault, false));
+            accounts.push(AccountMeta::new(addresses.no_mint, false));
+            accounts.push(AccountMeta::new(*no_account, false));
+        }
+        let instruction = Instruction {
+            program_id: solana_bet_placing_market::ID,
+            accounts,
+            data: instruction::ConvertNoTokens { amount }.data(),
+        };
+        self.svm.process(&[instruction], &[user.wallet])
+    }
+
+    // Resolves the market, lets the dispute window pass and has a bystander finalize the
+    // resolution, so that it pays out
+    pub fn settle(&mut self, oracle: &Pubkey, outcome: u8) {
+        self.resolve(oracle, outcome).unwrap();
+        self.pass_dispute_window();
+        let bystander = self.svm.new_wallet();
+        self.finalize(bystander).unwrap();
+    }
+
+    pub fn pass_dispute_window(&mut self) {
+        // A slot every 400ms
+        let slots = Market::DISPUTE_WINDOW as u64 * 5 / 2;
+        self.svm.warp(Market::DISPUTE_WINDOW, slots);
+    }
+
+    pub fn claim(&mut self, user: &User) -> TransactionResult {
+        let result = self.svm.call(
+            event_accounts!(accounts::ResolveUserWinnings {
+                market: self.market,
+                pool: self.pool,
+                vault: self.vault,
+                yes_mint: self.yes_mint,
+                no_mint: self.no_mint,
+                lp_share_mint: self.lp_share_mint,
+                user_usd_account: user.usd,
+                user_yes_account: user.yes,
+                user_no_account: user.no,
+                position: self.position_of(user),
+                session: user.session,
+                user: user.signer,
+                token_program: spl_token::ID,
+            }),
+            instruction::ResolveUserWinnings {},
+            &[user.signer],
+        );
+        self.audit_after(result)
+    }
+
+    pub fn audit_accounts(&self) -> accounts::AuditMarket {
+        event_accounts!(accounts::AuditMarket {
+            market: self.market,
+            pool: self.pool,
+            vault: self.vault,
+            yes_mint: self.yes_mint,
+            no_mint: self.no_mint,
+            lp_share_mint: self.lp_share_mint,
+            liquidity_yes_tokens_account: self.liquidity_yes_tokens_account,
+            liquidity_no_tokens_account: self.liquidity_no_tokens_account,
+        })
+    }
+
+    pub fn quote_accounts(&self) -> accounts::QuoteMarket {
+        accounts::QuoteMarket {
+            market: self.market,
+            pool: self.pool,
+        }
+    }
+
+    pub fn observe_price(&mut self) -> PriceObservation {
+        self.svm
+            .simulate(self.quote_accounts(), instruction::ObservePrice {}, &[])
+            .expect("cannot observe the price")
+            .return_value()
+    }
+
+    pub fn get_twap(&mut self, since: PriceObservation) -> TransactionResult {
+        self.svm
+            .simulate(self.quote_accounts(), instruction::GetTwap { since }, &[])
+    }
+
+    pub fn twap(&mut self, since: PriceObservation) -> MarketTwap {
+        self.get_twap(since)
+            .expect("cannot compute the TWAP")
+            .return_value()
+    }
+
+    // Runs the permissionless solvency check and asserts the pool is consistent
+    #[track_caller]
+    pub fn audit(&mut self) -> MarketAuditEvent {
+        let report: MarketAuditEvent = self
+            .svm
+            .call(self.audit_accounts(), instruction::AuditMarket {}, &[])
+            .unwrap_or_else(|error| panic!("audit failed: {error:#?}"))
+            .event();
+
+        let pool = self.pool();
+        assert!(
+            report.collateral_covered,
+            "vault {} below the collateral {}",
+            report.vault_balance, report.usd_collateral
+        );
+        assert!(
+            report.supplies_match,
+            "supplies YES {} / NO {} / LP {} but pool YES {} / NO {} / LP {}",
+            report.yes_supply,
+            report.no_supply,
+            report.lp_share_supply,
+            pool.total_yes_mints,
+            pool.total_no_mints,
+            pool.liquidity_shares
+        );
+        assert!(
+            report.liquidity_matches,
+            "pool liquidity YES {} / NO {} but accounts hold YES {} / NO {}",
+            pool.yes_liquidity,
+            pool.no_liquidity,
+            self.balance(&self.liquidity_yes_tokens_account),
+            self.balance(&self.liquidity_no_tokens_account)
+        );
+        assert!(report.vault_surplus >= 0);
+        assert_eq!(report.vault_balance, self.balance(&self.vault));
+        report
+    }
+
+    #[track_caller]
+    fn audit_after(&mut self, result: TransactionResult) -> TransactionResult {
+        if result.is_ok() {
+            self.audit();
+        }
+        result
+    }
+}
+
+impl Default for MarketEnv {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,599 @@
+// The integration tests run on `solana-program-test`: a real bank with the SPL token and
+// associated token programs, where signatures, writable and owner checks, rent and compute
+// limits are enforced by the runtime itself.
+//
+// `cargo test-sbf` builds `solana_bet_placing_market.so` and points `SBF_OUT_DIR` at it, the
+// tests then load the program as deployed. A plain `cargo test` runs the same tests on the
+// program compiled for the host, registered as a builtin. Nothing talks to the network.
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::Once;
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::clock::Clock;
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::instruction::{Instruction, InstructionError};
+use anchor_lang::solana_program::program_pack::Pack;
+use anchor_lang::solana_program::program_stubs::{self, SyscallStubs};
+use anchor_lang::solana_program::rent::Rent;
+use anchor_lang::solana_program::{system_instruction, system_program};
+use anchor_lang::{Discriminator, InstructionData, ToAccountMetas, ZeroCopy};
+use anchor_spl::associated_token::{get_associated_token_address, spl_associated_token_account};
+use anchor_spl::token::spl_token;
+use market_client::events::{cpi_event, log_events};
+use solana_bet_placing_market::EventHeader;
+use solana_program_test::tokio::runtime::Runtime;
+use solana_program_test::{processor, BanksClientError, ProgramTest, ProgramTestContext};
+use solana_sdk::account::{Account, AccountSharedData};
+use solana_sdk::signature::{Keypair, Signature, Signer};
+use solana_sdk::transaction::Transaction;
+
+pub mod market;
+
+pub const LAMPORTS_PER_SOL: u64 = 1_000_000_000;
+
+// What a single transaction may spend, the highest limit a compute budget instruction can ask for
+const COMPUTE_UNIT_LIMIT: u64 = 1_400_000;
+
+#[derive(Clone, Debug, Default)]
+pub struct StoredAccount {
+    pub lamports: u64,
+    pub data: Vec<u8>,
+    pub owner: Pubkey,
+    pub executable: bool,
+}
+
+// What a successful transaction left behind
+#[derive(Clone, Debug, Default)]
+pub struct TransactionMeta {
+    pub logs: Vec<String>,
+    // The instructions invoked through CPIs, with the program that ran them
+    pub inner_instructions: Vec<(Pubkey, Vec<u8>)>,
+    pub events: Vec<Vec<u8>>,
+    pub return_data: Option<(Pubkey, Vec<u8>)>,
+    pub compute_units: u64,
+}
+
+impl TransactionMeta {
+    // Decodes every event of the given type, in emission order
+    pub fn events<T: AnchorDeserialize + Discriminator>(&self) -> Vec<T> {
+        self.events
+            .iter()
+            .filter_map(|data| decode_event::<T>(data))
+            .collect()
+    }
+
+    // The header every event of the program starts with, in emission order
+    pub fn event_headers(&self) -> Vec<EventHeader> {
+        self.events
+            .iter()
+            .map(|data| EventHeader::deserialize(&mut &data[8..]).expect("the event has no header"))
+            .collect()
+    }
+
+    pub fn event<T: AnchorDeserialize + Discriminator>(&self) -> T {
+        let mut events = self.events::<T>();
+        assert_eq!(events.len(), 1, "expected exactly one event");
+        events.remove(0)
+    }
+
+    pub fn return_value<T: AnchorDeserialize>(&self) -> T {
+        let (_, data) = self.return_data.as_ref().expect("no return data");
+        T::try_from_slice(data).expect("invalid return data")
+    }
+}
+
+pub fn decode_event<T: AnchorDeserialize + Discriminator>(data: &[u8]) -> Option<T> {
+    let body = data.strip_prefix(T::DISCRIMINATOR)?;
+    T::try_from_slice(body).ok()
+}
+
+#[derive(Clone, Debug)]
+pub struct TransactionError {
+    pub error: solana_sdk::transaction::TransactionError,
+    pub logs: Vec<String>,
+}
+
+impl TransactionError {
+    // The Anchor error code, if the program failed with one
+    pub fn code(&self) -> Option<u32> {
+        match self.error {
+            solana_sdk::transaction::TransactionError::InstructionError(
+                _,
+                InstructionError::Custom(code),
+            ) => Some(code),
+            _ => None,
+        }
+    }
+}
+
+pub type TransactionResult = std::result::Result<TransactionMeta, TransactionError>;
+
+// Anchor error codes as the runtime reports them
+pub fn error_code(error: impl Into<anchor_lang::error::Error>) -> u32 {
+    match ProgramError::from(error.into()) {
+        ProgramError::Custom(code) => code,
+        other => panic!("not a custom error: {other:?}"),
+    }
+}
+
+#[track_caller]
+pub fn assert_error(result: TransactionResult, expected: impl Into<anchor_lang::error::Error>) {
+    let expected = error_code(expected);
+    match result {
+        Ok(_) => panic!("expected error {expected}, the transaction succeeded"),
+        Err(error) => assert_eq!(
+            error.code(),
+            Some(expected),
+            "unexpected error {:?}, logs: {:#?}",
+            error.error,
+            error.logs
+        ),
+    }
+}
+
+// The builtin entry point of the host build. Anchor ties the account infos to the lifetime
+// of the slice holding them, which the builtin signature cannot express, so the slice is
+// leaked: it lives until the end of the test.
+fn market_processor(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let accounts = Box::leak(accounts.to_vec().into_boxed_slice());
+    solana_bet_placing_market::entry(program_id, accounts, data)
+}
+
+// On the host build `sol_log_data` ends up in the syscall stubs, which `solana-program-test`
+// leaves to the default that prints it. The data is kept here instead, on the thread that
+// runs the bank, until the transaction is over.
+thread_local! {
+    static LOGGED_DATA: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+static INSTALL_STUBS: Once = Once::new();
+
+struct NoStubs;
+
+impl SyscallStubs for NoStubs {}
+
+// The stubs of `solana-program-test`, only catching the logged data
+struct LogDataStubs(Box<dyn SyscallStubs>);
+
+impl SyscallStubs for LogDataStubs {
+    fn sol_log_data(&self, fields: &[&[u8]]) {
+        LOGGED_DATA.with(|data| {
+            let mut data = data.borrow_mut();
+            for field in fields {
+                data.push(field.to_vec());
+            }
+        });
+    }
+
+    fn sol_log(&self, message: &str) {
+        self.0.sol_log(message)
+    }
+
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        self.0
+            .sol_invoke_signed(instruction, account_infos, signers_seeds)
+    }
+
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_clock_sysvar(var_addr)
+    }
+
+    fn sol_get_epoch_schedule_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_epoch_schedule_sysvar(var_addr)
+    }
+
+    fn sol_get_epoch_rewards_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_epoch_rewards_sysvar(var_addr)
+    }
+
+    fn sol_get_fees_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_fees_sysvar(var_addr)
+    }
+
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_rent_sysvar(var_addr)
+    }
+
+    fn sol_get_last_restart_slot(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_last_restart_slot(var_addr)
+    }
+
+    fn sol_get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+        self.0.sol_get_return_data()
+    }
+
+    fn sol_set_return_data(&self, data: &[u8]) {
+        self.0.sol_set_return_data(data)
+    }
+
+    fn sol_get_stack_height(&self) -> u64 {
+        self.0.sol_get_stack_height()
+    }
+}
+
+pub struct Svm {
+    runtime: Runtime,
+    context: ProgramTestContext,
+    // Every key a test can sign with
+    keypairs: HashMap<Pubkey, Keypair>,
+    signatures: HashSet<Signature>,
+    pub clock: Clock,
+    pub rent: Rent,
+}
+
+impl Svm {
+    pub fn new() -> Self {
+        let mut program_test = ProgramTest::new(
+            "solana_bet_placing_market",
+            solana_bet_placing_market::ID,
+            processor!(market_processor),
+        );
+        program_test.set_compute_max_units(COMPUTE_UNIT_LIMIT);
+
+        let runtime = solana_program_test::tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("cannot start the runtime");
+        let context = runtime.block_on(program_test.start_with_context());
+        // `solana-program-test` installs its stubs when the first bank starts, and every test
+        // waits here before sending anything
+        INSTALL_STUBS.call_once(|| {
+            let program_test_stubs = program_stubs::set_syscall_stubs(Box::new(NoStubs));
+            program_stubs::set_syscall_stubs(Box::new(LogDataStubs(program_test_stubs)));
+        });
+        let rent = runtime
+            .block_on(context.banks_client.get_rent())
+            .expect("cannot read the rent");
+
+        Svm {
+            runtime,
+            context,
+            keypairs: HashMap::new(),
+            signatures: HashSet::new(),
+            clock: Clock {
+                slot: 1,
+                unix_timestamp: 1_700_000_000,
+                ..Clock::default()
+            },
+            rent,
+        }
+    }
+
+    pub fn account(&self, key: &Pubkey) -> Option<StoredAccount> {
+        let account = self
+            .runtime
+            .block_on(self.context.banks_client.get_account(*key))
+            .expect("cannot read the account")?;
+        Some(StoredAccount {
+            lamports: account.lamports,
+            data: account.data,
+            owner: account.owner,
+            executable: account.executable,
+        })
+    }
+
+    // Writes an account as is, bypassing the runtime
+    pub fn set_account(&mut self, key: Pubkey, account: StoredAccount) {
+        let account = Account {
+            lamports: account.lamports,
+            data: account.data,
+            owner: account.owner,
+            executable: account.executable,
+            rent_epoch: u64::MAX,
+        };
+        self.context
+            .set_account(&key, &AccountSharedData::from(account));
+    }
+
+    pub fn lamports(&self, key: &Pubkey) -> u64 {
+        self.account(key).map_or(0, |account| account.lamports)
+    }
+
+    // A key the tests can sign with, without any account behind it
+    pub fn new_signer(&mut self) -> Pubkey {
+        let keypair = Keypair::new();
+        let key = keypair.pubkey();
+        self.keypairs.insert(key, keypair);
+        key
+    }
+
+    // Creates a system account holding some SOL, ready to sign and pay
+    pub fn new_wallet(&mut self) -> Pubkey {
+        let key = self.new_signer();
+        self.airdrop(&key, 100 * LAMPORTS_PER_SOL);
+        key
+    }
+
+    pub fn airdrop(&mut self, key: &Pubkey, lamports: u64) {
+        let mut account = self.account(key).unwrap_or_else(|| StoredAccount {
+            owner: system_program::ID,
+            ..StoredAccount::default()
+        });
+        account.lamports += lamports;
+        self.set_account(*key, account);
+    }
+
+    pub fn warp(&mut self, seconds: i64, slots: u64) {
+        self.clock.unix_timestamp += seconds;
+        self.clock.slot += slots;
+    }
+
+    // Deserializes an Anchor account owned by the market program
+    pub fn anchor_account<T: AccountDeserialize>(&self, key: &Pubkey) -> T {
+        let account = self.account(key).expect("missing account");
+        T::try_deserialize(&mut account.data.as_slice()).expect("invalid account")
+    }
+
+    // Copies a zero-copy account owned by the market program out of its data
+    pub fn zero_copy_account<T: ZeroCopy>(&self, key: &Pubkey) -> T {
+        let account = self.account(key).expect("missing account");
+        assert_eq!(&account.data[..8], T::DISCRIMINATOR, "invalid account");
+        bytemuck::pod_read_unaligned(&account.data[8..8 + std::mem::size_of::<T>()])
+    }
+
+    pub fn token_account(&self, key: &Pubkey) -> spl_token::state::Account {
+        let account = self.account(key).expect("missing token account");
+        spl_token::state::Account::unpack(&account.data).expect("invalid token account")
+    }
+
+    pub fn token_balance(&self, key: &Pubkey) -> u64 {
+        self.token_account(key).amount
+    }
+
+    pub fn mint(&self, key: &Pubkey) -> spl_token::state::Mint {
+        let account = self.account(key).expect("missing mint");
+        spl_token::state::Mint::unpack(&account.data).expect("invalid mint")
+    }
+
+    pub fn create_mint(&mut self, payer: &Pubkey, authority: &Pubkey, decimals: u8) -> Pubkey {
+        let mint = self.new_signer();
+        let lamports = self.rent.minimum_balance(spl_token::state::Mint::LEN);
+        self.process(
+            &[
+                system_instruction::create_account(
+                    payer,
+                    &mint,
+                    lamports,
+                    spl_token::state::Mint::LEN as u64,
+                    &spl_token::ID,
+                ),
+                spl_token::instruction::initialize_mint2(
+                    &spl_token::ID,
+                    &mint,
+                    authority,
+                    None,
+                    decimals,
+                )
+                .unwrap(),
+            ],
+            &[*payer, mint],
+        )
+        .expect("cannot create mint");
+        mint
+    }
+
+    pub fn create_token_account(
+        &mut self,
+        payer: &Pubkey,
+        owner: &Pubkey,
+        mint: &Pubkey,
+    ) -> Pubkey {
+        let account = self.new_signer();
+        let lamports = self.rent.minimum_balance(spl_token::state::Account::LEN);
+        self.process(
+            &[
+                system_instruction::create_account(
+                    payer,
+                    &account,
+                    lamports,
+                    spl_token::state::Account::LEN as u64,
+                    &spl_token::ID,
+                ),
+                spl_token::instruction::initialize_account3(&spl_token::ID, &account, mint, owner)
+                    .unwrap(),
+            ],
+            &[*payer, account],
+        )
+        .expect("cannot create token account");
+        account
+    }
+
+    // The owner's associated token account, paid by the owner
+    pub fn create_associated_token_account(&mut self, owner: &Pubkey, mint: &Pubkey) -> Pubkey {
+        self.process(
+            &[
+                spl_associated_token_account::instruction::create_associated_token_account(
+                    owner,
+                    owner,
+                    mint,
+                    &spl_token::ID,
+                ),
+            ],
+            &[*owner],
+        )
+        .expect("cannot create the associated token account");
+        get_associated_token_address(owner, mint)
+    }
+
+    pub fn mint_to(&mut self, mint: &Pubkey, authority: &Pubkey, to: &Pubkey, amount: u64) {
+        self.process(
+            &[
+                spl_token::instruction::mint_to(&spl_token::ID, mint, to, authority, &[], amount)
+                    .unwrap(),
+            ],
+            &[*authority],
+        )
+        .expect("cannot mint");
+    }
+
+    // Builds and runs a market program instruction
+    pub fn call(
+        &mut self,
+        accounts: impl ToAccountMetas,
+        data: impl InstructionData,
+        signers: &[Pubkey],
+    ) -> TransactionResult {
+        let instruction = Instruction {
+            program_id: solana_bet_placing_market::ID,
+            accounts: accounts.to_account_metas(None),
+            data: data.data(),
+        };
+        self.process(&[instruction], signers)
+    }
+
+    // Runs an instruction without committing anything, like `simulateTransaction`
+    pub fn simulate(
+        &mut self,
+        accounts: impl ToAccountMetas,
+        data: impl InstructionData,
+        signers: &[Pubkey],
+    ) -> TransactionResult {
+        let instruction = Instruction {
+            program_id: solana_bet_placing_market::ID,
+            accounts: accounts.to_account_metas(None),
+            data: data.data(),
+        };
+        let transaction = self.transaction(&[instruction], signers);
+        self.run_simulation(&transaction)
+    }
+
+    // Runs all the instructions in one transaction paid by the test payer, `signers` being
+    // the keys that signed it as well
+    pub fn process(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[Pubkey],
+    ) -> TransactionResult {
+        let transaction = self.transaction(instructions, signers);
+
+        // Only a simulation hands the inner instructions back, so the transaction runs twice
+        // against the same bank: once to see what it does, then for real
+        let meta = self.run_simulation(&transaction)?;
+        self.runtime
+            .block_on(
+                self.context
+                    .banks_client
+                    .process_transaction(transaction.clone()),
+            )
+            .map_err(|error| match error {
+                BanksClientError::TransactionError(error) => TransactionError {
+                    error,
+                    logs: meta.logs.clone(),
+                },
+                other => panic!("cannot process the transaction: {other}"),
+            })?;
+        self.signatures.insert(transaction.signatures[0]);
+        Ok(meta)
+    }
+
+    fn run_simulation(&mut self, transaction: &Transaction) -> TransactionResult {
+        LOGGED_DATA.with(|data| data.borrow_mut().clear());
+        let simulation = self
+            .runtime
+            .block_on(
+                self.context
+                    .banks_client
+                    .simulate_transaction(transaction.clone()),
+            )
+            .expect("cannot simulate the transaction");
+        let details = simulation
+            .simulation_details
+            .expect("the simulation has no details");
+        if let Some(Err(error)) = simulation.result {
+            return Err(TransactionError {
+                error,
+                logs: details.logs,
+            });
+        }
+
+        let account_keys = &transaction.message.account_keys;
+        let inner_instructions: Vec<(Pubkey, Vec<u8>)> = details
+            .inner_instructions
+            .unwrap_or_default()
+            .into_iter()
+            .flatten()
+            .map(|inner| {
+                let program_id = account_keys[inner.instruction.program_id_index as usize];
+                (program_id, inner.instruction.data)
+            })
+            .collect();
+
+        // The same sources as the indexer: the program's own `Program data:` logs and, in
+        // the `event-cpi` build, the self-CPIs
+        let program_id = solana_bet_placing_market::ID;
+        let mut events = log_events(&details.logs, &program_id);
+        events.extend(LOGGED_DATA.with(|data| data.take()));
+        for (instruction_program, data) in &inner_instructions {
+            if *instruction_program == program_id {
+                if let Some(event) = cpi_event(data) {
+                    events.push(event.to_vec());
+                }
+            }
+        }
+
+        Ok(TransactionMeta {
+            logs: details.logs,
+            inner_instructions,
+            events,
+            return_data: details
+                .return_data
+                .map(|return_data| (return_data.program_id, return_data.data)),
+            compute_units: details.units_consumed,
+        })
+    }
+
+    // Signs the instructions with the payer and every listed key the message asks for. The
+    // clock of the harness becomes the bank's before anything runs.
+    fn transaction(&mut self, instructions: &[Instruction], signers: &[Pubkey]) -> Transaction {
+        self.context.set_sysvar(&self.clock);
+
+        let payer = self.context.payer.insecure_clone();
+        let mut transaction = Transaction::new_with_payer(instructions, Some(&payer.pubkey()));
+        let required = transaction.message.account_keys
+            [..transaction.message.header.num_required_signatures as usize]
+            .to_vec();
+        let mut keypairs: Vec<&Keypair> = vec![&payer];
+        for signer in signers {
+            if required.contains(signer)
+                && !keypairs.iter().any(|keypair| keypair.pubkey() == *signer)
+            {
+                keypairs.push(
+                    self.keypairs
+                        .get(signer)
+                        .unwrap_or_else(|| panic!("{signer} is not a key of the tests")),
+                );
+            }
+        }
+
+        // Identical transactions would share their signature, and the bank refuses to process
+        // the same one twice
+        let mut blockhash = self.context.last_blockhash;
+        loop {
+            transaction
+                .try_sign(&keypairs, blockhash)
+                .expect("a required signer is missing");
+            if !self.signatures.contains(&transaction.signatures[0]) {
+                break;
+            }
+            blockhash = self
+                .runtime
+                .block_on(self.context.get_new_latest_blockhash())
+                .expect("no new blockhash");
+        }
+        transaction
+    }
+}
+
+impl Default for Svm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
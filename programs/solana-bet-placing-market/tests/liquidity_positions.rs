@@ -0,0 +1,114 @@
+// The trading fee, the LP position record and the NAV view built on it.
+mod harness;
+
+use anchor_lang::error::ErrorCode;
+use harness::assert_error;
+use harness::market::{MarketEnv, USD};
+use solana_bet_placing_market::{
+    accounts, event_accounts, instruction, MarketError, MarketFactory, PurchasedOutcomeSharesEvent,
+};
+
+#[test]
+fn the_trading_fee_goes_to_the_lps() {
+    let mut env = MarketEnv::with_trading_fee(100);
+    assert_eq!(env.market().trading_fee_bps, 100);
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    env.open_liquidity_position(&alice).unwrap();
+    env.add_liquidity(&alice, 100 * USD).unwrap();
+
+    // 1% of the purchase stays in the pool
+    let event: PurchasedOutcomeSharesEvent =
+        env.purchase(&bob, 100 * USD, env.yes_mint).unwrap().event();
+    assert_eq!(event.fee, USD);
+    assert_eq!(env.pool().fees_collected, USD);
+    assert_eq!(env.liquidity_nav(&alice).accrued_fees, USD);
+
+    // So does 1% of the sale, and the round trip leaves the LP better off
+    let shares = env.balance(&bob.yes);
+    env.sell(&bob, shares, env.yes_mint).unwrap();
+    assert!(env.balance(&bob.usd) < 998 * USD);
+    let fees = env.pool().fees_collected;
+    assert!(fees > 19 * USD / 10);
+
+    let nav = env.liquidity_nav(&alice);
+    // Less the rounding of the per-share accumulator
+    assert!(fees - nav.accrued_fees < 100);
+    assert!(nav.net_asset_value > 101 * USD);
+    assert!(nav.profit_and_loss > 0);
+
+    env.remove_liquidity(&alice, 100 * USD).unwrap();
+    let lp_position = env.lp_position(&alice);
+    assert_eq!(lp_position.lp_shares, 0);
+    assert_eq!(lp_position.deposited_usd, 100 * USD);
+    assert_eq!(
+        lp_position.withdrawn_usd,
+        env.balance(&alice.usd) - 900 * USD
+    );
+    assert_eq!(lp_position.accrued_fees, nav.accrued_fees);
+    assert_eq!(
+        lp_position.withdrawn_usd + lp_position.yes_received + lp_position.no_received,
+        nav.redeemable_usd + nav.redeemable_outcome_tokens
+    );
+}
+
+#[test]
+fn the_nav_shows_what_the_traders_took() {
+    let mut env = MarketEnv::new();
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    let carol = env.new_user(1_000 * USD);
+    env.open_liquidity_position(&alice).unwrap();
+    env.add_liquidity(&alice, 100 * USD).unwrap();
+    env.purchase(&bob, 100 * USD, env.yes_mint).unwrap();
+
+    // The pool holds 50 YES and 200 NO, with YES at 0.8
+    let nav = env.liquidity_nav(&alice);
+    assert_eq!(nav.lp_shares, 100 * USD);
+    assert_eq!(nav.liquidity_value_share, 100 * USD);
+    assert_eq!(nav.redeemable_usd, 50 * USD);
+    assert_eq!(nav.redeemable_outcome_tokens, 150 * USD);
+    assert_eq!(nav.redeemable_outcome_mint, env.no_mint);
+    assert_eq!(nav.net_asset_value, 80 * USD);
+    assert_eq!(nav.profit_and_loss, -20 * USD as i64);
+    assert_eq!(nav.impermanent_loss, nav.profit_and_loss);
+    assert_eq!(nav.accrued_fees, 0);
+
+    // A deposit into the unbalanced pool hands back YES, valued at the current price
+    env.open_liquidity_position(&carol).unwrap();
+    env.add_liquidity(&carol, 100 * USD).unwrap();
+    let lp_position = env.lp_position(&carol);
+    assert_eq!(lp_position.yes_received, env.balance(&carol.yes));
+    assert_eq!(lp_position.yes_received, 75 * USD);
+    let nav = env.liquidity_nav(&carol);
+    assert_eq!(nav.received_tokens_value, 60 * USD);
+    assert!(nav.profit_and_loss.abs() <= 1);
+}
+
+#[test]
+fn only_the_factory_authority_sets_the_fee() {
+    let mut env = MarketEnv::new();
+    assert_error(
+        env.set_trading_fee(MarketFactory::MAX_TRADING_FEE_BPS + 1),
+        MarketError::FeeTooHigh,
+    );
+
+    let mallory = env.svm.new_wallet();
+    assert_error(
+        env.svm.call(
+            event_accounts!(accounts::SetTradingFee {
+                market_factory: env.market_factory,
+                authority: mallory,
+            }),
+            instruction::SetTradingFee {
+                trading_fee_bps: 500,
+            },
+            &[mallory],
+        ),
+        ErrorCode::ConstraintSeeds,
+    );
+
+    // The existing market keeps the fee it was created with
+    env.set_trading_fee(500).unwrap();
+    assert_eq!(env.market().trading_fee_bps, 0);
+}
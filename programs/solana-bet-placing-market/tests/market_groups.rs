@@ -0,0 +1,275 @@
+// Markets linked in a group of which exactly one resolves YES: group resolution and the
+// conversion of NO tokens into USD.
+mod harness;
+
+use anchor_lang::prelude::Pubkey;
+use harness::assert_error;
+use harness::market::{MarketEnv, User, USD};
+use market_client::pda;
+use solana_bet_placing_market::{
+    MarketError, MarketGroupCreatedEvent, MarketGroupResolvedEvent, MarketJoinedGroupEvent,
+    MarketResolvedEvent, MarketStatus, NoTokensConvertedEvent,
+};
+
+const GROUP_ID: u64 = 7;
+const MEMBERS: u64 = 3;
+
+// Three markets with 100 USD of liquidity each, in a group, and Alice's accounts on each
+// after she bought 10 USD of NO tokens of every one. Ends on the last market.
+fn group() -> (MarketEnv, Pubkey, Vec<User>) {
+    let mut env = MarketEnv::new().next_market().next_market();
+    let markets: Vec<Pubkey> = (0..MEMBERS)
+        .map(|number| pda::market(&env.authority, number))
+        .collect();
+    let result = env.create_market_group(GROUP_ID, &markets).unwrap();
+    let group = pda::market_group(&env.authority, GROUP_ID);
+    let created: MarketGroupCreatedEvent = result.event();
+    assert_eq!(created.group, group);
+    assert_eq!(created.markets, markets);
+    // Each member records joining the group in its own event sequence
+    let joined: Vec<MarketJoinedGroupEvent> = result.events();
+    assert_eq!(
+        joined.iter().map(|event| event.market).collect::<Vec<_>>(),
+        markets
+    );
+
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    let mut alice_members = Vec::new();
+    for number in 0..MEMBERS {
+        env = env.switch_to(number);
+        assert_eq!(env.market().group, group);
+        assert_eq!(
+            env.market().event_seq,
+            joined[number as usize].header.event_seq
+        );
+        let lp = env.join(lp.wallet, lp.usd);
+        env.add_liquidity(&lp, 100 * USD).unwrap();
+        let alice = env.join(alice.wallet, alice.usd);
+        env.purchase(&alice, 10 * USD, env.no_mint).unwrap();
+        alice_members.push(alice);
+    }
+    (env, group, alice_members)
+}
+
+// Audits every member, then comes back to the last one
+fn audit_members(mut env: MarketEnv) -> MarketEnv {
+    for number in 0..MEMBERS {
+        env = env.switch_to(number);
+        env.audit();
+    }
+    env
+}
+
+fn no_accounts(members: &[User]) -> Vec<Pubkey> {
+    members.iter().map(|member| member.no).collect()
+}
+
+#[test]
+fn no_tokens_of_every_member_convert_into_usd() {
+    let (mut env, group, alice) = group();
+    let no_before: Vec<u64> = alice.iter().map(|member| env.balance(&member.no)).collect();
+    let usd_before = env.balance(&alice[0].usd);
+
+    // One NO of each of the three members pays 2 USD whichever one wins
+    let result = env
+        .convert_no_tokens(&alice[0], &group, &no_accounts(&alice), 10 * USD)
+        .unwrap();
+    let converted: NoTokensConvertedEvent = result.event();
+    assert_eq!(converted.amount, 10 * USD);
+    assert_eq!(converted.usd_received, 20 * USD);
+    assert_eq!(env.balance(&alice[0].usd), usd_before + 20 * USD);
+    for (member, before) in alice.iter().zip(no_before) {
+        assert_eq!(env.balance(&member.no), before - 10 * USD);
+    }
+
+    // The group keeps the USD of the YES tokens left without their NO
+    let market_group = env.market_group(&group);
+    assert_eq!(market_group.converted, 10 * USD);
+    assert_eq!(env.balance(&market_group.vault), 10 * USD);
+    let mut env = audit_members(env);
+    for number in 0..MEMBERS {
+        env = env.switch_to(number);
+        let pool = env.pool();
+        assert_eq!(pool.group_collateral, 10 * USD);
+        assert_eq!(pool.total_yes_mints, pool.total_no_mints + 10 * USD);
+    }
+}
+
+#[test]
+fn the_group_resolves_every_member_and_funds_the_winner() {
+    let (mut env, group, alice) = group();
+    env.convert_no_tokens(&alice[0], &group, &no_accounts(&alice), 10 * USD)
+        .unwrap();
+    let mut env = env.switch_to(1);
+    let bob = env.new_user(100 * USD);
+    env.purchase(&bob, 20 * USD, env.yes_mint).unwrap();
+
+    // Members are only resolved together
+    let oracle = env.oracle;
+    assert_error(env.resolve(&oracle, 1), MarketError::MarketInGroup);
+    assert_error(env.invalidate(&oracle), MarketError::MarketInGroup);
+    assert_error(
+        env.resolve_market_group(&env.authority.clone(), &group, 1),
+        anchor_lang::error::ErrorCode::ConstraintHasOne,
+    );
+
+    let winner_vault_before = env.balance(&env.vault);
+    let result = env.resolve_market_group(&oracle, &group, 1).unwrap();
+    let resolutions: Vec<MarketResolvedEvent> = result.events();
+    assert_eq!(
+        resolutions
+            .iter()
+            .map(|resolution| resolution.outcome)
+            .collect::<Vec<_>>(),
+        vec![0, 1, 0]
+    );
+    let resolved: MarketGroupResolvedEvent = result.event();
+    assert_eq!(resolved.winner, env.market);
+    assert_eq!(resolved.converted, 10 * USD);
+    assert_eq!(env.market_group(&group).winner, Some(1));
+    assert_eq!(env.balance(&env.vault), winner_vault_before + 10 * USD);
+    assert_eq!(env.balance(&env.market_group(&group).vault), 0);
+    assert_eq!(env.pool().group_collateral, 0);
+    let mut env = audit_members(env);
+
+    // Every YES token of the winner and every NO token of the others is paid in full, once
+    // the resolution of each member is finalized
+    env.pass_dispute_window();
+    for (number, member) in alice.iter().enumerate() {
+        env = env.switch_to(number as u64);
+        assert_eq!(env.market().status, MarketStatus::Resolved);
+        env.finalize(member.wallet).unwrap();
+        let usd_before = env.balance(&member.usd);
+        let no_tokens = env.balance(&member.no);
+        env.claim(member).unwrap();
+        let paid = if number == 1 { 0 } else { no_tokens };
+        assert_eq!(env.balance(&member.usd), usd_before + paid);
+    }
+    let mut env = env.switch_to(1);
+    let yes_tokens = env.balance(&bob.yes);
+    let usd_before = env.balance(&bob.usd);
+    env.claim(&bob).unwrap();
+    assert_eq!(env.balance(&bob.usd), usd_before + yes_tokens);
+}
+
+#[test]
+fn a_disputed_group_resolution_moves_the_converted_usd() {
+    let (mut env, group, alice) = group();
+    env.convert_no_tokens(&alice[0], &group, &no_accounts(&alice), 10 * USD)
+        .unwrap();
+    let oracle = env.oracle;
+    env.resolve_market_group(&oracle, &group, 0).unwrap();
+
+    // Nothing to resolve again until a member is disputed, then the whole group is
+    assert_error(
+        env.resolve_market_group(&oracle, &group, 2),
+        MarketError::MarketResolved,
+    );
+    let mut env = env.switch_to(0);
+    let first_vault_before = env.balance(&env.vault);
+    env.set_status(MarketStatus::Disputed).unwrap();
+    let mut env = env.switch_to(MEMBERS - 1);
+    let last_vault_before = env.balance(&env.vault);
+    env.resolve_market_group(&oracle, &group, 2).unwrap();
+
+    assert_eq!(env.market().outcome, Some(1));
+    assert_eq!(env.balance(&env.vault), last_vault_before + 10 * USD);
+    assert_eq!(env.pool().group_collateral, 0);
+    let mut env = env.switch_to(0);
+    assert_eq!(env.market().outcome, Some(0));
+    assert_eq!(env.balance(&env.vault), first_vault_before - 10 * USD);
+    assert_eq!(env.pool().group_collateral, 10 * USD);
+    for number in 0..MEMBERS {
+        env = env.switch_to(number);
+        assert_eq!(env.market().status, MarketStatus::Resolved);
+    }
+    audit_members(env);
+}
+
+#[test]
+fn a_disputed_member_is_resolved_again_with_the_finalized_winner() {
+    let (mut env, group, alice) = group();
+    env.convert_no_tokens(&alice[0], &group, &no_accounts(&alice), 10 * USD)
+        .unwrap();
+    let oracle = env.oracle;
+    env.resolve_market_group(&oracle, &group, 0).unwrap();
+    let mut env = env.switch_to(0);
+    env.set_status(MarketStatus::Disputed).unwrap();
+
+    // The members nobody disputed are finalized once the window is over
+    env.pass_dispute_window();
+    for number in 1..MEMBERS {
+        env = env.switch_to(number);
+        env.finalize(alice[number as usize].wallet).unwrap();
+    }
+
+    // Which fixes the winner of the disputed one
+    assert_error(
+        env.resolve_market_group(&oracle, &group, 2),
+        MarketError::MarketResolved,
+    );
+    let vault_before = env.balance(&env.vault);
+    let result = env.resolve_market_group(&oracle, &group, 0).unwrap();
+    let resolutions: Vec<MarketResolvedEvent> = result.events();
+    assert_eq!(resolutions.len(), 1);
+    assert_eq!(resolutions[0].outcome, 1);
+    assert_eq!(env.balance(&env.vault), vault_before);
+
+    // And its winnings are paid once its new resolution is final
+    let mut env = env.switch_to(0);
+    assert_eq!(env.market().status, MarketStatus::Resolved);
+    assert_eq!(env.market().outcome, Some(1));
+    env.pass_dispute_window();
+    env.finalize(alice[0].wallet).unwrap();
+    env.claim(&alice[0]).unwrap();
+    audit_members(env);
+}
+
+#[test]
+fn groups_check_their_members() {
+    let mut env = MarketEnv::new().next_market();
+    let first = pda::market(&env.authority, 0);
+    let second = env.market;
+    assert_error(
+        env.create_market_group(GROUP_ID, &[first]),
+        MarketError::InvalidGroup,
+    );
+    assert_error(
+        env.create_market_group(GROUP_ID, &[first, first]),
+        MarketError::InvalidGroup,
+    );
+
+    // A market resolved by another oracle
+    env.oracle = env.svm.new_wallet();
+    let mut env = env.next_market();
+    assert_error(
+        env.create_market_group(GROUP_ID, &[first, env.market]),
+        MarketError::InvalidGroup,
+    );
+
+    // A market already in a group
+    env.create_market_group(GROUP_ID, &[first, second]).unwrap();
+    let mut env = env.switch_to(0);
+    assert_error(
+        env.create_market_group(GROUP_ID + 1, &[first, pda::market(&env.authority, 2)]),
+        MarketError::MarketInGroup,
+    );
+
+    // Conversions wait for every member to trade
+    let group = pda::market_group(&env.authority, GROUP_ID);
+    let lp = env.new_user(1_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    let alice = env.new_user(100 * USD);
+    env.purchase(&alice, 10 * USD, env.no_mint).unwrap();
+    let mut env = env.switch_to(1);
+    let lp = env.join(lp.wallet, lp.usd);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    let alice_second = env.join(alice.wallet, alice.usd);
+    env.purchase(&alice_second, 10 * USD, env.no_mint).unwrap();
+    env.set_status(MarketStatus::Paused).unwrap();
+    assert_error(
+        env.convert_no_tokens(&alice, &group, &[alice.no, alice_second.no], USD),
+        MarketError::MarketPaused,
+    );
+}
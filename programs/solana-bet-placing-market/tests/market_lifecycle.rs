@@ -0,0 +1,489 @@
+// End-to-end runs of the program against the real SPL token program, in process.
+//
+// Every helper on `MarketEnv` audits the market after a successful instruction, so each
+// step below is also checked for vault solvency and pool/token supply consistency.
+mod harness;
+
+use anchor_lang::error::ErrorCode;
+use harness::assert_error;
+use harness::market::{MarketEnv, User, USD};
+use solana_bet_placing_market::{
+    accounts, instruction, AddLiquidityQuote, MarketError, MarketPrices, MarketStatus,
+    PurchaseQuote, RemoveLiquidityQuote, SaleQuote,
+};
+
+const YES: u8 = 1;
+const NO: u8 = 0;
+
+#[test]
+fn fresh_market_is_empty() {
+    let env = MarketEnv::new();
+
+    let market = env.market();
+    assert_eq!(market.authority, env.authority);
+    assert_eq!(market.oracle, env.oracle);
+    assert_eq!(market.usd_mint, env.usd_mint);
+    assert!(!market.resolved);
+    assert_eq!(market.outcome, None);
+    assert_eq!(market.status, MarketStatus::Open);
+
+    let pool = env.pool();
+    assert_eq!(pool.yes_liquidity, 0);
+    assert_eq!(pool.no_liquidity, 0);
+    assert_eq!(pool.liquidity_shares, 0);
+    assert_eq!(env.balance(&env.vault), 0);
+}
+
+#[test]
+fn full_lifecycle_resolving_yes() {
+    let mut env = MarketEnv::new();
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    let carol = env.new_user(1_000 * USD);
+    let dave = env.new_user(1_000 * USD);
+
+    // Alice seeds the pool at even odds
+    env.add_liquidity(&alice, 100 * USD).unwrap();
+    assert_eq!(env.balance(&alice.usd), 900 * USD);
+    assert_eq!(env.balance(&alice.lp), 100 * USD);
+    assert_eq!(env.balance(&alice.yes), 0);
+    assert_eq!(env.balance(&alice.no), 0);
+    let pool = env.pool();
+    assert_eq!(
+        (pool.yes_liquidity, pool.no_liquidity),
+        (100 * USD, 100 * USD)
+    );
+    assert_eq!(pool.liquidity_value, 100 * USD);
+    assert_eq!(env.balance(&env.vault), 100 * USD);
+
+    // Bob buys YES for 100, pushing the YES price to 0.8
+    env.purchase(&bob, 100 * USD, env.yes_mint).unwrap();
+    assert_eq!(env.balance(&bob.usd), 900 * USD);
+    assert_eq!(env.balance(&bob.yes), 150 * USD);
+    let pool = env.pool();
+    assert_eq!(
+        (pool.yes_liquidity, pool.no_liquidity),
+        (50 * USD, 200 * USD)
+    );
+    assert_eq!(env.balance(&env.liquidity_yes_tokens_account), 50 * USD);
+    assert_eq!(env.balance(&env.liquidity_no_tokens_account), 200 * USD);
+    assert_eq!(env.balance(&env.vault), 200 * USD);
+
+    // Carol adds liquidity at the unbalanced odds and keeps the surplus YES
+    env.add_liquidity(&carol, 100 * USD).unwrap();
+    assert_eq!(env.balance(&carol.usd), 900 * USD);
+    assert_eq!(env.balance(&carol.lp), 50 * USD);
+    assert_eq!(env.balance(&carol.yes), 75 * USD);
+    assert_eq!(env.balance(&carol.no), 0);
+    let pool = env.pool();
+    assert_eq!(
+        (pool.yes_liquidity, pool.no_liquidity),
+        (75 * USD, 300 * USD)
+    );
+    assert_eq!(pool.liquidity_value, 150 * USD);
+    assert_eq!(pool.liquidity_shares, 150 * USD);
+    assert_eq!(env.balance(&env.vault), 300 * USD);
+
+    // Dave takes the other side
+    env.purchase(&dave, 20 * USD, env.no_mint).unwrap();
+    assert_eq!(env.balance(&dave.usd), 980 * USD);
+    assert_eq!(env.balance(&dave.no), 83_157_894_736);
+    let pool = env.pool();
+    assert_eq!(pool.yes_liquidity, 95 * USD);
+    assert_eq!(pool.no_liquidity, 236_842_105_264);
+    assert_eq!(env.balance(&env.vault), 320 * USD);
+
+    // Alice pulls a third of the liquidity before the market resolves. She gets back USD
+    // for the balanced part of her share and the NO surplus as tokens.
+    env.remove_liquidity(&alice, 50 * USD).unwrap();
+    assert_eq!(env.balance(&alice.lp), 50 * USD);
+    assert_eq!(env.balance(&alice.usd), 931_666_666_666);
+    assert_eq!(env.balance(&alice.no), 47_280_701_753);
+    assert_eq!(env.balance(&alice.yes), 0);
+    let pool = env.pool();
+    assert_eq!(pool.yes_liquidity, 63_333_333_334);
+    assert_eq!(pool.no_liquidity, 157_894_736_845);
+    assert_eq!(pool.liquidity_shares, 100 * USD);
+    assert_eq!(env.balance(&env.vault), 288_333_333_334);
+
+    env.resolve(&env.oracle.clone(), YES).unwrap();
+    let market = env.market();
+    assert!(market.resolved);
+    assert_eq!(market.outcome, Some(YES));
+    // Nobody disputes the resolution, so anyone finalizes it after the window
+    env.pass_dispute_window();
+    env.finalize(carol.wallet).unwrap();
+    assert_eq!(env.market().status, MarketStatus::Finalized);
+
+    // Winners get one USD per YES, losers get nothing
+    env.claim(&bob).unwrap();
+    assert_eq!(env.balance(&bob.usd), 1_050 * USD);
+    assert_eq!(env.balance(&bob.yes), 0);
+    env.claim(&carol).unwrap();
+    assert_eq!(env.balance(&carol.usd), 975 * USD);
+    assert_eq!(env.balance(&carol.yes), 0);
+    env.claim(&dave).unwrap();
+    assert_eq!(env.balance(&dave.usd), 980 * USD);
+    assert_eq!(env.balance(&dave.no), 0);
+    env.claim(&alice).unwrap();
+    assert_eq!(env.balance(&alice.no), 0);
+
+    // The LPs split the remaining YES liquidity, rounded down to the share price
+    env.remove_liquidity(&alice, 50 * USD).unwrap();
+    assert_eq!(env.balance(&alice.usd), 931_666_666_666 + 31_666_666_650);
+    env.remove_liquidity(&carol, 50 * USD).unwrap();
+    assert_eq!(env.balance(&carol.usd), 975 * USD + 31_666_666_650);
+    assert_eq!(env.balance(&alice.lp), 0);
+    assert_eq!(env.balance(&carol.lp), 0);
+
+    // Nobody holds a claim any more. Only the rounding dust is left in the vault and
+    // every other USD is back with a user.
+    let pool = env.pool();
+    assert_eq!(pool.liquidity_shares, 0);
+    assert_eq!(pool.yes_liquidity, 34);
+    assert_eq!(pool.total_yes_mints, 34);
+    assert_eq!(pool.total_no_mints, pool.no_liquidity);
+    assert_eq!(env.balance(&env.vault), 34);
+    let total: u64 = [&alice, &bob, &carol, &dave]
+        .iter()
+        .map(|user| env.balance(&user.usd))
+        .sum();
+    assert_eq!(total + 34, 4_000 * USD);
+}
+
+#[test]
+fn resolving_no_pays_no_holders() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let yes_buyer = env.new_user(1_000 * USD);
+    let no_buyer = env.new_user(1_000 * USD);
+
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.purchase(&yes_buyer, 100 * USD, env.yes_mint).unwrap();
+    env.purchase(&no_buyer, 50 * USD, env.no_mint).unwrap();
+    let no_shares = env.balance(&no_buyer.no);
+    assert!(no_shares > 50 * USD);
+
+    env.settle(&env.oracle.clone(), NO);
+    env.claim(&yes_buyer).unwrap();
+    env.claim(&no_buyer).unwrap();
+    assert_eq!(env.balance(&yes_buyer.usd), 900 * USD);
+    assert_eq!(env.balance(&no_buyer.usd), 950 * USD + no_shares);
+
+    let no_left = env.pool().no_liquidity;
+    env.remove_liquidity(&lp, 100 * USD).unwrap();
+    assert_eq!(env.balance(&lp.usd), 900 * USD + no_left);
+    assert_eq!(env.balance(&env.vault), 0);
+}
+
+#[test]
+fn second_market_is_independent() {
+    let mut first = MarketEnv::new();
+    let user = first.new_user(1_000 * USD);
+    first.add_liquidity(&user, 100 * USD).unwrap();
+
+    let MarketEnv {
+        svm,
+        authority,
+        oracle,
+        usd_mint,
+        usd_mint_authority,
+        ..
+    } = first;
+    let mut second = MarketEnv::for_market(svm, authority, oracle, usd_mint, usd_mint_authority, 1);
+    second.create_market().unwrap();
+    second.initialize_pool().unwrap();
+    assert_eq!(second.market().market_number, 1);
+
+    let other = second.new_user(1_000 * USD);
+    second.add_liquidity(&other, 40 * USD).unwrap();
+    second.purchase(&other, 10 * USD, second.no_mint).unwrap();
+    assert_eq!(second.balance(&second.vault), 50 * USD);
+    assert_eq!(second.pool().liquidity_shares, 40 * USD);
+}
+
+#[test]
+fn only_the_market_authority_opens_its_pool() {
+    let env = MarketEnv::new();
+    let MarketEnv {
+        svm,
+        authority,
+        oracle,
+        usd_mint,
+        usd_mint_authority,
+        ..
+    } = env;
+    let mut second = MarketEnv::for_market(svm, authority, oracle, usd_mint, usd_mint_authority, 1);
+    second.create_market().unwrap();
+
+    second.authority = second.svm.new_wallet();
+    assert_error(second.initialize_pool(), ErrorCode::ConstraintHasOne);
+    second.authority = authority;
+    let yes_mint = second.yes_mint;
+    second.yes_mint = second.no_mint;
+    assert_error(second.initialize_pool(), ErrorCode::ConstraintHasOne);
+    assert_eq!(second.market().status, MarketStatus::Draft);
+
+    second.yes_mint = yes_mint;
+    second.initialize_pool().unwrap();
+    assert_eq!(second.market().status, MarketStatus::Open);
+}
+
+#[test]
+fn selling_undoes_a_purchase() {
+    let mut env = MarketEnv::new();
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    env.add_liquidity(&alice, 100 * USD).unwrap();
+    let pool_before = env.pool();
+
+    env.purchase(&bob, 100 * USD, env.yes_mint).unwrap();
+    let shares = env.balance(&bob.yes);
+    assert_eq!(shares, 150 * USD);
+
+    let quote: SaleQuote = env
+        .svm
+        .simulate(
+            env.quote_accounts(),
+            instruction::QuoteSell {
+                shares,
+                sold_outcome_mint_pubkey: env.yes_mint,
+            },
+            &[],
+        )
+        .unwrap()
+        .return_value();
+    env.sell(&bob, shares, env.yes_mint).unwrap();
+    assert_eq!(env.balance(&bob.usd), 900 * USD + quote.usd_received);
+    assert_eq!(env.balance(&bob.yes), 0);
+
+    // Back to where it started, give or take the rounding kept by the pool
+    assert!(100 * USD - quote.usd_received <= 1);
+    let pool = env.pool();
+    assert_eq!(pool.yes_liquidity, quote.pool_yes_liquidity_after);
+    assert_eq!(pool.no_liquidity, quote.pool_no_liquidity_after);
+    assert!(pool.yes_liquidity - pool_before.yes_liquidity <= 1);
+    assert!(pool.no_liquidity - pool_before.no_liquidity <= 1);
+    assert_eq!(env.market().market_volume, 100 * USD + quote.usd_received);
+}
+
+#[test]
+fn rejects_invalid_actions() {
+    let mut env = MarketEnv::new();
+    let alice = env.new_user(1_000 * USD);
+    let mallory = env.new_user(1_000 * USD);
+
+    assert_error(env.purchase(&alice, 0, env.yes_mint), MarketError::Zero);
+    assert_error(
+        env.purchase(&alice, 10 * USD, env.lp_share_mint),
+        ErrorCode::ConstraintRaw,
+    );
+
+    env.add_liquidity(&alice, 100 * USD).unwrap();
+    assert_error(
+        env.remove_liquidity(&alice, 101 * USD),
+        MarketError::InsufficientFunds,
+    );
+    assert_error(env.remove_liquidity(&alice, 0), MarketError::Zero);
+    assert_error(env.claim(&alice), MarketError::MarketNotResolved);
+    assert_error(env.sell(&alice, 0, env.yes_mint), MarketError::Zero);
+    assert_error(
+        env.sell(&mallory, USD, env.yes_mint),
+        MarketError::InsufficientFunds,
+    );
+
+    assert_error(
+        env.resolve(&mallory.wallet, YES),
+        ErrorCode::ConstraintHasOne,
+    );
+    assert_error(
+        env.resolve(&env.oracle.clone(), 2),
+        MarketError::InvalidOutcome,
+    );
+    env.resolve(&env.oracle.clone(), YES).unwrap();
+    assert_error(
+        env.resolve(&env.oracle.clone(), NO),
+        MarketError::MarketResolved,
+    );
+
+    assert_error(
+        env.purchase(&alice, 10 * USD, env.yes_mint),
+        MarketError::MarketResolved,
+    );
+    assert_error(env.claim(&alice), MarketError::ResolutionNotFinal);
+    assert_error(
+        env.finalize(mallory.wallet),
+        MarketError::ResolutionNotFinal,
+    );
+    env.pass_dispute_window();
+    env.finalize(mallory.wallet).unwrap();
+    assert_error(env.claim(&mallory), MarketError::InsufficientFunds);
+    assert_error(
+        env.sell(&alice, USD, env.yes_mint),
+        MarketError::MarketResolved,
+    );
+
+    // A failed transaction leaves no trace
+    assert_eq!(env.balance(&alice.usd), 900 * USD);
+    assert_eq!(env.balance(&env.vault), 100 * USD);
+}
+
+#[test]
+fn a_market_only_trades_against_its_own_pool() {
+    let mut first = MarketEnv::new();
+    let lp = first.new_user(1_000 * USD);
+    first.add_liquidity(&lp, 100 * USD).unwrap();
+    let first_pool = first.pool;
+
+    let mut second = first.next_market();
+    let lp_second = second.join(lp.wallet, lp.usd);
+    second.add_liquidity(&lp_second, 100 * USD).unwrap();
+    second.pool = first_pool;
+    assert_error(
+        second.purchase(&lp_second, 10 * USD, second.yes_mint),
+        ErrorCode::ConstraintHasOne,
+    );
+    assert_error(
+        second.add_liquidity(&lp_second, 10 * USD),
+        ErrorCode::ConstraintHasOne,
+    );
+}
+
+#[test]
+fn a_market_only_takes_payments_into_its_own_vault() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    let market_vault = env.vault;
+
+    let mallory = env.new_user(0);
+    env.vault = mallory.usd;
+    assert_error(
+        env.purchase(&alice, 10 * USD, env.yes_mint),
+        ErrorCode::ConstraintSeeds,
+    );
+    assert_eq!(env.balance(&mallory.usd), 0);
+
+    env.vault = market_vault;
+    env.purchase(&alice, 10 * USD, env.yes_mint).unwrap();
+    env.vault = mallory.usd;
+    assert_error(
+        env.sell(&alice, env.balance(&alice.yes), env.yes_mint),
+        ErrorCode::ConstraintSeeds,
+    );
+}
+
+#[test]
+fn winnings_are_only_paid_for_the_market_tokens() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    let mallory = env.new_user(0);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.purchase(&alice, 10 * USD, env.yes_mint).unwrap();
+    env.settle(&env.oracle.clone(), YES);
+    let vault_before = env.balance(&env.vault);
+
+    // Tokens of a mint of mallory's own, passed as the YES mint
+    let fake_mint = env.svm.create_mint(&mallory.wallet, &mallory.wallet, 9);
+    let fake_yes = env
+        .svm
+        .create_token_account(&mallory.wallet, &mallory.wallet, &fake_mint);
+    env.svm
+        .mint_to(&fake_mint, &mallory.wallet, &fake_yes, 1_000 * USD);
+    let market_yes_mint = env.yes_mint;
+    env.yes_mint = fake_mint;
+    let forger = User {
+        yes: fake_yes,
+        ..mallory
+    };
+    assert_error(env.claim(&forger), ErrorCode::ConstraintSeeds);
+
+    // Or as the YES account next to the real mint
+    env.yes_mint = market_yes_mint;
+    assert_error(env.claim(&forger), ErrorCode::ConstraintTokenMint);
+    assert_eq!(env.balance(&env.vault), vault_before);
+    assert_eq!(env.balance(&mallory.usd), 0);
+}
+
+#[test]
+fn quotes_match_execution() {
+    let mut env = MarketEnv::new();
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    env.add_liquidity(&alice, 100 * USD).unwrap();
+
+    let quote: PurchaseQuote = env
+        .svm
+        .simulate(
+            accounts::QuoteMarket {
+                market: env.market,
+                pool: env.pool,
+            },
+            instruction::QuotePurchase {
+                usd_amount: 30 * USD,
+                purchased_outcome_mint_pubkey: env.no_mint,
+            },
+            &[],
+        )
+        .unwrap()
+        .return_value();
+    env.purchase(&bob, 30 * USD, env.no_mint).unwrap();
+    assert_eq!(env.balance(&bob.no), quote.shares_purchased);
+    assert_eq!(env.pool().no_liquidity, quote.pool_no_liquidity_after);
+    assert_eq!(env.pool().yes_liquidity, quote.pool_yes_liquidity_after);
+
+    let prices: MarketPrices = env
+        .svm
+        .simulate(
+            accounts::QuoteMarket {
+                market: env.market,
+                pool: env.pool,
+            },
+            instruction::GetPrices {},
+            &[],
+        )
+        .unwrap()
+        .return_value();
+    assert_eq!(prices.yes_price, quote.yes_price_after);
+    assert_eq!(prices.no_price, quote.no_price_after);
+    assert!(USD - (prices.yes_price + prices.no_price) <= 1);
+
+    let add: AddLiquidityQuote = env
+        .svm
+        .simulate(
+            accounts::QuoteMarket {
+                market: env.market,
+                pool: env.pool,
+            },
+            instruction::QuoteAddLiquidity {
+                usd_amount: 60 * USD,
+            },
+            &[],
+        )
+        .unwrap()
+        .return_value();
+    env.add_liquidity(&bob, 60 * USD).unwrap();
+    assert_eq!(env.balance(&bob.lp), add.liquidity_shares_gained);
+    assert_eq!(env.balance(&bob.yes), add.yes_given_to_user);
+    assert_eq!(env.pool().liquidity_value, add.pool_liquidity_value_after);
+
+    let remove: RemoveLiquidityQuote = env
+        .svm
+        .simulate(
+            accounts::QuoteMarket {
+                market: env.market,
+                pool: env.pool,
+            },
+            instruction::QuoteRemoveLiquidity { shares: 40 * USD },
+            &[],
+        )
+        .unwrap()
+        .return_value();
+    let usd_before = env.balance(&alice.usd);
+    env.remove_liquidity(&alice, 40 * USD).unwrap();
+    assert_eq!(env.balance(&alice.usd) - usd_before, remove.usd_received);
+    assert_eq!(env.pool().yes_liquidity, remove.pool_yes_liquidity_after);
+    assert_eq!(env.pool().no_liquidity, remove.pool_no_liquidity_after);
+}
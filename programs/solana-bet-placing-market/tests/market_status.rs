@@ -0,0 +1,219 @@
+// The market status machine: what the authority and the oracle can move a market to, and
+// what each status lets users do.
+mod harness;
+
+use harness::assert_error;
+use harness::market::{MarketEnv, USD};
+use solana_bet_placing_market::{
+    instruction, Market, MarketError, MarketPrices, MarketStatus, MarketStatusChangedEvent,
+    ResolveUserWinningsEvent, RiskLimits,
+};
+
+const YES: u8 = 1;
+const NO: u8 = 0;
+
+fn prices(env: &mut MarketEnv) -> MarketPrices {
+    let accounts = env.quote_accounts();
+    env.svm
+        .simulate(accounts, instruction::GetPrices {}, &[])
+        .unwrap()
+        .return_value()
+}
+
+#[test]
+fn pausing_and_closing_stop_the_trading() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.purchase(&alice, 10 * USD, env.yes_mint).unwrap();
+
+    let paused: MarketStatusChangedEvent = env.set_status(MarketStatus::Paused).unwrap().event();
+    assert_eq!(paused.previous_status, MarketStatus::Open);
+    assert_eq!(paused.status, MarketStatus::Paused);
+    assert_eq!(paused.changed_by, env.authority);
+    assert_error(
+        env.purchase(&alice, 10 * USD, env.yes_mint),
+        MarketError::MarketPaused,
+    );
+    assert_error(
+        env.sell(&alice, USD, env.yes_mint),
+        MarketError::MarketPaused,
+    );
+    assert_error(env.add_liquidity(&lp, USD), MarketError::MarketPaused);
+    assert_error(env.remove_liquidity(&lp, USD), MarketError::MarketPaused);
+    assert_error(
+        env.set_status(MarketStatus::Paused),
+        MarketError::InvalidStatusTransition,
+    );
+
+    env.set_status(MarketStatus::Open).unwrap();
+    env.purchase(&alice, 10 * USD, env.yes_mint).unwrap();
+
+    // Once closed, trading is over for good but the LPs can leave
+    env.set_status(MarketStatus::Closed).unwrap();
+    assert_error(
+        env.purchase(&alice, 10 * USD, env.yes_mint),
+        MarketError::MarketClosed,
+    );
+    assert_error(
+        env.set_status(MarketStatus::Open),
+        MarketError::InvalidStatusTransition,
+    );
+    env.set_risk_limits(RiskLimits::default()).unwrap();
+    env.remove_liquidity(&lp, 10 * USD).unwrap();
+    assert_error(env.claim(&alice), MarketError::MarketNotResolved);
+
+    env.resolve(&env.oracle.clone(), YES).unwrap();
+    assert_eq!(env.market().status, MarketStatus::Resolved);
+}
+
+#[test]
+fn a_disputed_resolution_is_resolved_again() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.purchase(&alice, 10 * USD, env.yes_mint).unwrap();
+    env.purchase(&bob, 10 * USD, env.no_mint).unwrap();
+
+    env.resolve(&env.oracle.clone(), YES).unwrap();
+    assert_error(env.claim(&alice), MarketError::ResolutionNotFinal);
+    assert_error(
+        env.remove_liquidity(&lp, USD),
+        MarketError::ResolutionNotFinal,
+    );
+    assert_error(
+        env.set_status(MarketStatus::Closed),
+        MarketError::InvalidStatusTransition,
+    );
+
+    env.set_status(MarketStatus::Disputed).unwrap();
+    assert_error(env.claim(&alice), MarketError::MarketDisputed);
+    assert_error(
+        env.set_status(MarketStatus::Finalized),
+        MarketError::InvalidStatusTransition,
+    );
+
+    // The oracle changes its mind, and the NO holders win
+    env.resolve(&env.oracle.clone(), NO).unwrap();
+    assert_eq!(env.market().outcome, Some(NO));
+    assert_eq!(env.pool().liquidity_value, env.pool().no_liquidity);
+    env.set_status(MarketStatus::Finalized).unwrap();
+    assert_error(
+        env.set_status(MarketStatus::Disputed),
+        MarketError::InvalidStatusTransition,
+    );
+    assert_error(
+        env.resolve(&env.oracle.clone(), YES),
+        MarketError::MarketResolved,
+    );
+
+    let bob_no = env.balance(&bob.no);
+    let claim: ResolveUserWinningsEvent = env.claim(&bob).unwrap().event();
+    assert_eq!(claim.winning_amount, bob_no);
+    env.claim(&alice).unwrap();
+    assert_eq!(env.balance(&alice.usd), 990 * USD);
+    env.remove_liquidity(&lp, 100 * USD).unwrap();
+}
+
+#[test]
+fn an_undisputed_resolution_is_finalized_by_anyone() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    let bystander = env.new_user(0);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.purchase(&alice, 10 * USD, env.yes_mint).unwrap();
+
+    assert_error(
+        env.finalize(bystander.wallet),
+        MarketError::InvalidStatusTransition,
+    );
+    env.resolve(&env.oracle.clone(), YES).unwrap();
+    assert_eq!(env.market().resolved_at, env.svm.clock.unix_timestamp);
+    assert_error(
+        env.finalize(bystander.wallet),
+        MarketError::ResolutionNotFinal,
+    );
+
+    // A resolution resolved again after a dispute gets a window of its own
+    env.set_status(MarketStatus::Disputed).unwrap();
+    env.svm.warp(Market::DISPUTE_WINDOW / 2, 1);
+    env.resolve(&env.oracle.clone(), YES).unwrap();
+    env.svm.warp(Market::DISPUTE_WINDOW / 2, 1);
+    assert_error(
+        env.finalize(bystander.wallet),
+        MarketError::ResolutionNotFinal,
+    );
+
+    env.pass_dispute_window();
+    assert_error(
+        env.set_status(MarketStatus::Disputed),
+        MarketError::DisputeWindowElapsed,
+    );
+    let finalized: MarketStatusChangedEvent = env.finalize(bystander.wallet).unwrap().event();
+    assert_eq!(finalized.status, MarketStatus::Finalized);
+    assert_eq!(finalized.changed_by, bystander.wallet);
+    assert_error(
+        env.finalize(bystander.wallet),
+        MarketError::InvalidStatusTransition,
+    );
+    env.claim(&alice).unwrap();
+}
+
+#[test]
+fn an_invalid_market_pays_half_of_every_token() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    let keeper = env.new_user(0);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.purchase(&alice, 10 * USD, env.yes_mint).unwrap();
+    env.purchase(&bob, 20 * USD, env.no_mint).unwrap();
+    env.approve_settlement(&bob).unwrap();
+
+    // Invalidated after a disputed resolution
+    env.resolve(&env.oracle.clone(), YES).unwrap();
+    env.set_status(MarketStatus::Disputed).unwrap();
+    let invalidated: MarketStatusChangedEvent =
+        env.invalidate(&env.oracle.clone()).unwrap().event();
+    assert_eq!(invalidated.previous_status, MarketStatus::Disputed);
+    assert_eq!(invalidated.changed_by, env.oracle);
+    let market = env.market();
+    assert_eq!(market.status, MarketStatus::Invalid);
+    assert_eq!(market.outcome, None);
+    assert_error(
+        env.invalidate(&env.oracle.clone()),
+        MarketError::MarketInvalid,
+    );
+    assert_error(
+        env.purchase(&alice, USD, env.yes_mint),
+        MarketError::MarketInvalid,
+    );
+
+    let prices = prices(&mut env);
+    assert_eq!(prices.yes_price, USD / 2);
+    assert_eq!(prices.no_price, USD / 2);
+
+    let alice_yes = env.balance(&alice.yes);
+    env.claim(&alice).unwrap();
+    assert_eq!(env.balance(&alice.usd), 990 * USD + alice_yes / 2);
+
+    // The crank pays the losing side as well
+    let bob_no = env.balance(&bob.no);
+    let paid: ResolveUserWinningsEvent = env
+        .settle_holders(&keeper, &[(bob.no, bob.usd)])
+        .unwrap()
+        .event();
+    assert_eq!(paid.winning_amount, bob_no / 2);
+    assert_eq!(env.balance(&bob.usd), 980 * USD + bob_no / 2);
+
+    // The LPs take their part of the pool as it stood before the resolution
+    let pool = env.pool();
+    assert!(pool.liquidity_value > pool.yes_liquidity.min(pool.no_liquidity));
+    env.remove_liquidity(&lp, 100 * USD).unwrap();
+    assert_eq!(env.pool().liquidity_shares, 0);
+}
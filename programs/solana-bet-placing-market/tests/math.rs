@@ -0,0 +1,631 @@
+// Properties of the AMM math in `src/math.rs`. They live here rather than next to it, as
+// the pricing crate compiles that file too and would run them a second time.
+use proptest::prelude::*;
+use solana_bet_placing_market::math::*;
+
+const USD: u64 = SCALE as u64;
+
+fn balanced_pool(usd_amount: u64) -> PoolState {
+    PoolState {
+        yes_liquidity: usd_amount,
+        no_liquidity: usd_amount,
+        liquidity_value: usd_amount,
+        liquidity_shares: usd_amount,
+        fee_bps: 0,
+        referral_share_bps: 0,
+    }
+}
+
+fn apply_buy(pool: &PoolState, result: &BuyResult) -> PoolState {
+    PoolState {
+        yes_liquidity: result.yes_liquidity_after,
+        no_liquidity: result.no_liquidity_after,
+        liquidity_value: result.liquidity_value_after,
+        ..*pool
+    }
+}
+
+fn apply_add(pool: &PoolState, result: &AddLiquidityResult) -> PoolState {
+    PoolState {
+        yes_liquidity: pool.yes_liquidity + result.yes_to_pool,
+        no_liquidity: pool.no_liquidity + result.no_to_pool,
+        liquidity_value: result.liquidity_value_after,
+        liquidity_shares: result.liquidity_shares_after,
+        ..*pool
+    }
+}
+
+#[test]
+fn sqrt_of_small_numbers() {
+    let expected = [0, 1, 1, 1, 2, 2, 2, 2, 2, 3];
+    for (input, root) in expected.iter().enumerate() {
+        assert_eq!(sqrt_u128(input as u128), *root);
+    }
+    assert_eq!(
+        sqrt_u128(u64::MAX as u128 * u64::MAX as u128),
+        u64::MAX as u128
+    );
+    assert_eq!(sqrt_u128(u128::MAX), u64::MAX as u128);
+}
+
+#[test]
+fn prices_of_balanced_pool_are_even() {
+    assert_eq!(prices(100 * USD, 100 * USD), Ok((USD / 2, USD / 2)));
+}
+
+#[test]
+fn prices_follow_the_other_side_liquidity() {
+    // 3x more YES liquidity means YES is the unlikely outcome
+    assert_eq!(prices(300 * USD, 100 * USD), Ok((USD / 4, 3 * USD / 4)));
+}
+
+#[test]
+fn prices_of_empty_pool_fail() {
+    assert_eq!(prices(0, 0), Err(MathError::EmptyPool));
+}
+
+#[test]
+fn buy_yes_on_balanced_pool() {
+    let pool = balanced_pool(100 * USD);
+    let result = buy(&pool, 100 * USD, Outcome::Yes).unwrap();
+
+    // k = 100^2, NO goes to 200 so YES drops to 50
+    assert_eq!(result.yes_liquidity_after, 50 * USD);
+    assert_eq!(result.no_liquidity_after, 200 * USD);
+    assert_eq!(result.shares_from_pool, 50 * USD);
+    assert_eq!(result.shares_purchased, 150 * USD);
+    assert_eq!(result.yes_price_before, USD / 2);
+    assert_eq!(result.no_price_before, USD / 2);
+}
+
+#[test]
+fn buy_no_is_symmetric_to_buy_yes() {
+    let pool = balanced_pool(100 * USD);
+    let yes = buy(&pool, 37 * USD, Outcome::Yes).unwrap();
+    let no = buy(&pool, 37 * USD, Outcome::No).unwrap();
+
+    assert_eq!(yes.shares_purchased, no.shares_purchased);
+    assert_eq!(yes.yes_liquidity_after, no.no_liquidity_after);
+    assert_eq!(yes.no_liquidity_after, no.yes_liquidity_after);
+}
+
+#[test]
+fn buy_on_empty_pool_fails() {
+    assert_eq!(
+        buy(&PoolState::default(), USD, Outcome::Yes),
+        Err(MathError::EmptyPool)
+    );
+}
+
+#[test]
+fn sell_returns_what_was_paid() {
+    let pool = balanced_pool(100 * USD);
+    let bought = buy(&pool, 100 * USD, Outcome::Yes).unwrap();
+    let pool = apply_buy(&pool, &bought);
+
+    let sold = sell(&pool, bought.shares_purchased, Outcome::Yes).unwrap();
+    assert_eq!(sold.usd_returned, 100 * USD);
+    assert_eq!(sold.yes_liquidity_after, 100 * USD);
+    assert_eq!(sold.no_liquidity_after, 100 * USD);
+}
+
+#[test]
+fn the_fee_stays_in_the_pool() {
+    // 1% of 100 USD is kept, the 99 USD left trade as usual
+    let pool = PoolState {
+        fee_bps: 100,
+        ..balanced_pool(100 * USD)
+    };
+    let result = buy(&pool, 100 * USD, Outcome::Yes).unwrap();
+    let without_fee = buy(&balanced_pool(100 * USD), 99 * USD, Outcome::Yes).unwrap();
+
+    assert_eq!(result.fee, USD);
+    assert_eq!(result.shares_purchased, without_fee.shares_purchased);
+    assert_eq!(
+        result.yes_liquidity_after,
+        without_fee.yes_liquidity_after + USD
+    );
+    assert_eq!(
+        result.no_liquidity_after,
+        without_fee.no_liquidity_after + USD
+    );
+    assert!(result.liquidity_value_after > pool.liquidity_value);
+
+    let pool = apply_buy(&pool, &result);
+    let sold = sell(&pool, result.shares_purchased, Outcome::Yes).unwrap();
+    assert_eq!(
+        sold.fee,
+        trading_fee(sold.usd_returned + sold.fee, 100).unwrap()
+    );
+    assert!(sold.usd_returned < 99 * USD);
+}
+
+#[test]
+fn price_impact_is_relative_to_the_price_before() {
+    // 50% to 80% is a 60% move, 80% back to 50% a 37.5% one
+    assert_eq!(price_impact_bps(500_000_000, 800_000_000), Ok(6_000));
+    assert_eq!(price_impact_bps(800_000_000, 500_000_000), Ok(3_750));
+    assert_eq!(price_impact_bps(USD, USD), Ok(0));
+    assert_eq!(price_impact_bps(0, USD), Err(MathError::EmptyPool));
+}
+
+#[test]
+fn the_referrer_gets_a_cut_of_the_fee() {
+    // A fifth of the 1 USD fee goes to the referrer, the rest stays in the pool
+    let pool = PoolState {
+        fee_bps: 100,
+        ..balanced_pool(100 * USD)
+    };
+    let referred = PoolState {
+        referral_share_bps: 2_000,
+        ..pool
+    };
+    let result = buy(&referred, 100 * USD, Outcome::Yes).unwrap();
+    let unreferred = buy(&pool, 100 * USD, Outcome::Yes).unwrap();
+
+    assert_eq!(result.fee, USD);
+    assert_eq!(result.referral_fee, USD / 5);
+    assert_eq!(unreferred.referral_fee, 0);
+    assert_eq!(result.shares_purchased, unreferred.shares_purchased);
+    assert_eq!(
+        result.yes_liquidity_after,
+        unreferred.yes_liquidity_after - USD / 5
+    );
+    assert_eq!(
+        result.no_liquidity_after,
+        unreferred.no_liquidity_after - USD / 5
+    );
+
+    // The seller gets the same either way
+    let pool = apply_buy(&pool, &unreferred);
+    let sold = sell(
+        &PoolState {
+            referral_share_bps: 2_000,
+            ..pool
+        },
+        USD,
+        Outcome::No,
+    )
+    .unwrap();
+    let unreferred = sell(&pool, USD, Outcome::No).unwrap();
+    assert_eq!(sold.usd_returned, unreferred.usd_returned);
+    assert_eq!(sold.referral_fee, referral_fee(sold.fee, 2_000).unwrap());
+    assert_eq!(referral_fee(3, 5_000), Ok(1));
+}
+
+#[test]
+fn fees_are_earned_per_share() {
+    let fee_per_share = accrue_fee_per_share(0, 3 * USD, 100 * USD).unwrap();
+    let fee_per_share = accrue_fee_per_share(fee_per_share, 2 * USD, 50 * USD).unwrap();
+
+    // 10 shares held all along, then 40 more after the first fee
+    assert_eq!(fees_earned(10 * USD, fee_per_share, 0), Ok(700_000_000));
+    let checkpoint = accrue_fee_per_share(0, 3 * USD, 100 * USD).unwrap();
+    assert_eq!(
+        fees_earned(40 * USD, fee_per_share, checkpoint),
+        Ok(1_600_000_000)
+    );
+    assert_eq!(accrue_fee_per_share(42, USD, 0), Ok(42));
+}
+
+#[test]
+fn sell_of_nothing_returns_nothing() {
+    let pool = balanced_pool(100 * USD);
+    assert_eq!(sell(&pool, 0, Outcome::No).unwrap().usd_returned, 0);
+}
+
+#[test]
+fn add_liquidity_to_empty_pool() {
+    let result = add_liquidity(&PoolState::default(), 10 * USD).unwrap();
+
+    assert_eq!(result.liquidity_shares_gained, 10 * USD);
+    assert_eq!(result.yes_to_pool, 10 * USD);
+    assert_eq!(result.no_to_pool, 10 * USD);
+    assert_eq!(result.yes_to_user, 0);
+    assert_eq!(result.no_to_user, 0);
+    assert_eq!(result.liquidity_value_after, 10 * USD);
+    assert_eq!(result.liquidity_shares_after, 10 * USD);
+}
+
+#[test]
+fn add_liquidity_with_more_no_gives_back_yes() {
+    // YES is at 75%
+    let pool = PoolState {
+        yes_liquidity: 100 * USD,
+        no_liquidity: 300 * USD,
+        liquidity_value: 173_205_080_756,
+        liquidity_shares: 173_205_080_756,
+        fee_bps: 0,
+        referral_share_bps: 0,
+    };
+    let result = add_liquidity(&pool, 300 * USD).unwrap();
+
+    // The pool keeps all the NO tokens and a third of that in YES tokens
+    assert_eq!(result.no_to_pool, 300 * USD);
+    assert_eq!(result.yes_to_pool, 100 * USD);
+    assert_eq!(result.yes_to_user, 200 * USD);
+    assert_eq!(result.no_to_user, 0);
+    assert_eq!(result.liquidity_value_after, 346_410_161_513);
+    // The pool doubled, so did the shares
+    assert_eq!(result.liquidity_shares_gained, 173_205_080_756);
+}
+
+#[test]
+fn add_liquidity_with_more_yes_gives_back_no() {
+    let pool = PoolState {
+        yes_liquidity: 300 * USD,
+        no_liquidity: 100 * USD,
+        liquidity_value: 173_205_080_756,
+        liquidity_shares: 173_205_080_756,
+        fee_bps: 0,
+        referral_share_bps: 0,
+    };
+    let result = add_liquidity(&pool, 300 * USD).unwrap();
+
+    assert_eq!(result.yes_to_pool, 300 * USD);
+    assert_eq!(result.no_to_pool, 100 * USD);
+    assert_eq!(result.no_to_user, 200 * USD);
+    assert_eq!(result.yes_to_user, 0);
+}
+
+#[test]
+fn remove_all_liquidity_from_balanced_pool() {
+    let pool = balanced_pool(100 * USD);
+    let result = remove_liquidity(&pool, 100 * USD, None).unwrap();
+
+    assert_eq!(result.usd_to_user, 100 * USD);
+    assert_eq!(result.outcome_tokens_to_user, 0);
+    assert_eq!(result.yes_liquidity_after, 0);
+    assert_eq!(result.no_liquidity_after, 0);
+    assert_eq!(result.liquidity_value_after, 0);
+    assert_eq!(result.liquidity_shares_after, 0);
+}
+
+#[test]
+fn remove_liquidity_from_unbalanced_pool_gives_back_the_cheaper_outcome() {
+    let pool = balanced_pool(100 * USD);
+    let pool = apply_buy(&pool, &buy(&pool, 100 * USD, Outcome::Yes).unwrap());
+    // 50 YES / 200 NO, the LP owns everything
+    let result = remove_liquidity(&pool, 100 * USD, None).unwrap();
+
+    assert_eq!(result.usd_to_user, 50 * USD);
+    assert_eq!(result.outcome_given, Some(Outcome::No));
+    assert_eq!(result.outcome_tokens_to_user, 150 * USD);
+    assert_eq!(result.yes_liquidity_after, 0);
+    assert_eq!(result.no_liquidity_after, 0);
+}
+
+// Found by the pool_accounting fuzz target: the odds used to be rebuilt from the
+// truncated prices, which handed the leaving LP a few hundred NO too many
+#[test]
+fn remove_liquidity_never_exceeds_the_lp_share_of_a_side() {
+    let pool = PoolState {
+        yes_liquidity: 1_644_451_548_891,
+        no_liquidity: 3_137_658_813_114,
+        liquidity_value: 2_271_503_443_783,
+        liquidity_shares: 2_271_503_443_783,
+        fee_bps: 0,
+        referral_share_bps: 0,
+    };
+    let shares = 1_635_482_479_524;
+    let result = remove_liquidity(&pool, shares, None).unwrap();
+
+    let no_share =
+        (pool.no_liquidity as u128 * shares as u128 / pool.liquidity_shares as u128) as u64;
+    assert_eq!(result.outcome_given, Some(Outcome::No));
+    assert!(result.usd_to_user + result.outcome_tokens_to_user <= no_share + 1);
+}
+
+#[test]
+fn remove_liquidity_after_resolution_pays_winning_tokens() {
+    let pool = balanced_pool(100 * USD);
+    let pool = apply_buy(&pool, &buy(&pool, 100 * USD, Outcome::Yes).unwrap());
+    let pool = PoolState {
+        liquidity_value: resolved_liquidity_value(&pool, Outcome::No),
+        ..pool
+    };
+
+    let result = remove_liquidity(&pool, 50 * USD, Some(Outcome::No)).unwrap();
+    assert_eq!(result.usd_to_user, 100 * USD);
+    assert_eq!(result.outcome_given, None);
+    assert_eq!(result.no_liquidity_after, 100 * USD);
+    assert_eq!(result.yes_liquidity_after, 0);
+    assert_eq!(result.liquidity_shares_after, 50 * USD);
+}
+
+#[test]
+fn remove_liquidity_from_empty_pool_fails() {
+    assert_eq!(
+        remove_liquidity(&PoolState::default(), 1, None),
+        Err(MathError::EmptyPool)
+    );
+}
+
+#[test]
+fn remove_more_shares_than_exist_fails() {
+    let pool = balanced_pool(USD);
+    assert_eq!(
+        remove_liquidity(&pool, USD + 1, None),
+        Err(MathError::Overflow)
+    );
+}
+
+#[test]
+fn settlement_pays_only_the_winning_side() {
+    assert_eq!(settlement_value(7, 3, Outcome::Yes), 7);
+    assert_eq!(settlement_value(7, 3, Outcome::No), 3);
+    assert_eq!(Outcome::from_u8(0), Some(Outcome::No));
+    assert_eq!(Outcome::from_u8(1), Some(Outcome::Yes));
+    assert_eq!(Outcome::from_u8(2), None);
+}
+
+#[test]
+fn an_invalid_market_pays_half_of_every_token() {
+    assert_eq!(invalid_settlement_value(8, 4), 6);
+    assert_eq!(invalid_settlement_value(7, 3), 4);
+    assert_eq!(invalid_settlement_value(u64::MAX, u64::MAX), u64::MAX - 1);
+}
+
+#[test]
+fn yes_price_follows_the_pool_then_the_resolution() {
+    let pool = balanced_pool(100 * USD);
+    let pool = apply_buy(&pool, &buy(&pool, 100 * USD, Outcome::Yes).unwrap());
+
+    assert_eq!(yes_price(&PoolState::default(), None), Ok(USD / 2));
+    assert_eq!(yes_price(&pool, None), Ok(800_000_000));
+    assert_eq!(yes_price(&pool, Some(Outcome::Yes)), Ok(USD));
+    assert_eq!(yes_price(&pool, Some(Outcome::No)), Ok(0));
+}
+
+#[test]
+fn twap_weights_prices_by_time() {
+    // 0.5 for 90 seconds, then 0.8 for 10 seconds
+    let start = accumulate_price(0, 0, USD / 2, 0).unwrap();
+    let middle = accumulate_price(start, 0, USD / 2, 90).unwrap();
+    let end = accumulate_price(middle, 90, 800_000_000, 100).unwrap();
+
+    assert_eq!(twap(start, 0, end, 100), Ok(530_000_000));
+    assert_eq!(twap(middle, 90, end, 100), Ok(800_000_000));
+}
+
+#[test]
+fn accumulate_price_ignores_a_clock_that_did_not_move() {
+    assert_eq!(accumulate_price(42, 100, USD, 100), Ok(42));
+    assert_eq!(accumulate_price(42, 100, USD, 99), Ok(42));
+}
+
+#[test]
+fn twap_needs_a_window() {
+    assert_eq!(twap(0, 100, 0, 100), Err(MathError::InvalidWindow));
+    assert_eq!(twap(10, 100, 5, 200), Err(MathError::InvalidWindow));
+}
+
+fn outcome() -> impl Strategy<Value = Outcome> {
+    prop_oneof![Just(Outcome::Yes), Just(Outcome::No)]
+}
+
+// Pools reached by a deposit followed by a trade, like the real ones
+fn traded_pool() -> impl Strategy<Value = PoolState> {
+    (USD..1_000_000 * USD, 0..1_000_000 * USD, outcome()).prop_map(|(liquidity, traded, side)| {
+        let pool = balanced_pool(liquidity);
+        if traded == 0 {
+            return pool;
+        }
+        apply_buy(&pool, &buy(&pool, traded, side).unwrap())
+    })
+}
+
+proptest! {
+    #[test]
+    fn sqrt_is_the_floor_root(input in any::<u128>()) {
+        let root = sqrt_u128(input);
+        prop_assert!(root * root <= input);
+        prop_assert!((root + 1).checked_mul(root + 1).is_none_or(|next| next > input));
+    }
+
+    #[test]
+    fn prices_add_up_to_one(yes in 1..u64::MAX, no in 1..u64::MAX) {
+        let (yes_price, no_price) = prices(yes, no).unwrap();
+        let total = yes_price as u128 + no_price as u128;
+        prop_assert!((SCALE - 1..=SCALE).contains(&total));
+    }
+
+    #[test]
+    fn buy_never_gives_less_than_one_share_per_usd(
+        pool in traded_pool(),
+        usd_amount in 1..1_000_000 * USD,
+        side in outcome(),
+    ) {
+        let result = buy(&pool, usd_amount, side).unwrap();
+        prop_assert!(result.shares_purchased >= usd_amount);
+        prop_assert_eq!(result.shares_purchased, usd_amount + result.shares_from_pool);
+    }
+
+    #[test]
+    fn buy_keeps_the_constant_product(
+        pool in traded_pool(),
+        usd_amount in 1..1_000_000 * USD,
+        side in outcome(),
+    ) {
+        let result = buy(&pool, usd_amount, side).unwrap();
+        let invariant = (pool.liquidity_value as u128).pow(2);
+        let product = result.yes_liquidity_after as u128 * result.no_liquidity_after as u128;
+        let other_liquidity = match side {
+            Outcome::Yes => result.no_liquidity_after,
+            Outcome::No => result.yes_liquidity_after,
+        };
+
+        // Only the rounded up division can push the product above the invariant
+        prop_assert!(product >= invariant);
+        prop_assert!(product - invariant < other_liquidity as u128);
+    }
+
+    #[test]
+    fn buy_moves_the_price_towards_the_bought_outcome(
+        pool in traded_pool(),
+        usd_amount in USD..1_000_000 * USD,
+        side in outcome(),
+    ) {
+        let result = buy(&pool, usd_amount, side).unwrap();
+        let (yes_after, no_after) =
+            prices(result.yes_liquidity_after, result.no_liquidity_after).unwrap();
+        match side {
+            Outcome::Yes => prop_assert!(yes_after >= result.yes_price_before),
+            Outcome::No => prop_assert!(no_after >= result.no_price_before),
+        }
+    }
+
+    #[test]
+    fn selling_back_never_returns_more_than_paid(
+        pool in traded_pool(),
+        usd_amount in 1..1_000_000 * USD,
+        side in outcome(),
+    ) {
+        let bought = buy(&pool, usd_amount, side).unwrap();
+        let sold = sell(&apply_buy(&pool, &bought), bought.shares_purchased, side).unwrap();
+        prop_assert!(sold.usd_returned <= usd_amount);
+    }
+
+    #[test]
+    fn fees_only_grow_the_pool(
+        pool in traded_pool(),
+        amount in 1..1_000_000 * USD,
+        side in outcome(),
+        fee_bps in 0..=1_000u16,
+        referral_share_bps in 0..=10_000u16,
+    ) {
+        let with_fee = PoolState { fee_bps, referral_share_bps, ..pool };
+        let invariant = (pool.liquidity_value as u128).pow(2);
+
+        let bought = buy(&with_fee, amount, side).unwrap();
+        let product = bought.yes_liquidity_after as u128 * bought.no_liquidity_after as u128;
+        prop_assert!(product >= invariant);
+        prop_assert!(bought.liquidity_value_after >= pool.liquidity_value);
+        prop_assert!(bought.shares_purchased <= buy(&pool, amount, side).unwrap().shares_purchased);
+
+        let sold = sell(&with_fee, amount, side).unwrap();
+        let before = pool.yes_liquidity as u128 * pool.no_liquidity as u128;
+        let product = sold.yes_liquidity_after as u128 * sold.no_liquidity_after as u128;
+        prop_assert!(product >= invariant.min(before));
+        prop_assert_eq!(sold.usd_returned + sold.fee, sell(&pool, amount, side).unwrap().usd_returned);
+    }
+
+    #[test]
+    fn sell_keeps_the_constant_product(
+        pool in traded_pool(),
+        shares in 0..1_000_000 * USD,
+        side in outcome(),
+    ) {
+        let result = sell(&pool, shares, side).unwrap();
+        let invariant = (pool.liquidity_value as u128).pow(2);
+        let product = result.yes_liquidity_after as u128 * result.no_liquidity_after as u128;
+        let before = pool.yes_liquidity as u128 * pool.no_liquidity as u128;
+
+        // The payout rounds down, so the pool never ends below its invariant
+        // unless it already was there before the trade
+        prop_assert!(product >= invariant.min(before));
+    }
+
+    #[test]
+    fn add_liquidity_mints_complete_sets(
+        pool in traded_pool(),
+        usd_amount in 1..1_000_000 * USD,
+    ) {
+        let result = add_liquidity(&pool, usd_amount).unwrap();
+        prop_assert_eq!(result.yes_to_pool + result.yes_to_user, usd_amount);
+        prop_assert_eq!(result.no_to_pool + result.no_to_user, usd_amount);
+        prop_assert!(result.yes_to_user == 0 || result.no_to_user == 0);
+    }
+
+    #[test]
+    fn add_liquidity_keeps_the_odds(
+        pool in traded_pool(),
+        usd_amount in USD..1_000_000 * USD,
+    ) {
+        let result = add_liquidity(&pool, usd_amount).unwrap();
+        let after = apply_add(&pool, &result);
+        let (yes_before, _) = prices(pool.yes_liquidity, pool.no_liquidity).unwrap();
+        let (yes_after, _) = prices(after.yes_liquidity, after.no_liquidity).unwrap();
+
+        // A few units of rounding, no more
+        prop_assert!((yes_before as i128 - yes_after as i128).abs() <= 2);
+    }
+
+    #[test]
+    fn add_liquidity_never_dilutes_the_existing_shares(
+        pool in traded_pool(),
+        usd_amount in 1..1_000_000 * USD,
+    ) {
+        let result = add_liquidity(&pool, usd_amount).unwrap();
+        let after = apply_add(&pool, &result);
+
+        // Each share is backed by at least as many tokens of either side as before
+        for (before, after_liquidity) in [
+            (pool.yes_liquidity, after.yes_liquidity),
+            (pool.no_liquidity, after.no_liquidity),
+        ] {
+            prop_assert!(
+                after_liquidity as u128 * pool.liquidity_shares as u128
+                    >= before as u128 * after.liquidity_shares as u128
+            );
+        }
+    }
+
+    #[test]
+    fn balanced_add_then_remove_round_trips(
+        liquidity in USD..1_000_000 * USD,
+        usd_amount in 1..1_000_000 * USD,
+    ) {
+        let pool = balanced_pool(liquidity);
+        let added = add_liquidity(&pool, usd_amount).unwrap();
+        let pool = apply_add(&pool, &added);
+        let removed = remove_liquidity(&pool, added.liquidity_shares_gained, None).unwrap();
+
+        prop_assert_eq!(removed.usd_to_user, usd_amount);
+        prop_assert_eq!(removed.outcome_tokens_to_user, 0);
+        prop_assert_eq!(removed.yes_liquidity_after, liquidity);
+        prop_assert_eq!(removed.no_liquidity_after, liquidity);
+    }
+
+    #[test]
+    fn remove_liquidity_never_takes_more_than_the_pool_holds(
+        pool in traded_pool(),
+        shares_bps in 1..=10_000u64,
+    ) {
+        let shares = ((pool.liquidity_shares as u128 * shares_bps as u128) / 10_000) as u64;
+        prop_assume!(shares > 0);
+        let result = remove_liquidity(&pool, shares, None).unwrap();
+
+        // Every USD paid out burns a complete set held by the pool
+        prop_assert!(result.usd_to_user <= pool.yes_liquidity.min(pool.no_liquidity) + 1);
+        prop_assert!(result.yes_burnt <= pool.yes_liquidity);
+        prop_assert!(result.no_burnt <= pool.no_liquidity);
+
+        // And the tokens handed back come out of what is left in the pool
+        let handed_back = match result.outcome_given {
+            Some(Outcome::Yes) => pool.yes_liquidity - result.yes_burnt - result.yes_liquidity_after,
+            Some(Outcome::No) => pool.no_liquidity - result.no_burnt - result.no_liquidity_after,
+            None => 0,
+        };
+        prop_assert_eq!(handed_back, result.outcome_tokens_to_user);
+    }
+
+    #[test]
+    fn resolved_remove_liquidity_pays_a_fair_share(
+        pool in traded_pool(),
+        shares_bps in 1..=10_000u64,
+        side in outcome(),
+    ) {
+        let pool = PoolState {
+            liquidity_value: resolved_liquidity_value(&pool, side),
+            ..pool
+        };
+        prop_assume!(pool.liquidity_value > 0);
+        let shares = ((pool.liquidity_shares as u128 * shares_bps as u128) / 10_000) as u64;
+        let result = remove_liquidity(&pool, shares, Some(side)).unwrap();
+
+        let winning = resolved_liquidity_value(&pool, side) as u128;
+        let fair = winning * shares as u128 / pool.liquidity_shares as u128;
+        prop_assert!(result.usd_to_user as u128 <= fair);
+    }
+}
@@ -0,0 +1,89 @@
+// The opt-in user position account kept up to date by the trade and claim instructions.
+mod harness;
+
+use harness::market::{MarketEnv, USD};
+use solana_bet_placing_market::math::{self, Outcome};
+
+const YES: u8 = 1;
+
+#[test]
+fn a_position_follows_purchases_sales_and_the_claim() {
+    let mut env = MarketEnv::new();
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    env.add_liquidity(&alice, 100 * USD).unwrap();
+    env.open_position(&bob).unwrap();
+
+    let position = env.position(&bob);
+    assert_eq!(position.market, env.market);
+    assert_eq!(position.owner, bob.wallet);
+    assert_eq!(position.yes_shares, 0);
+
+    env.purchase(&bob, 100 * USD, env.yes_mint).unwrap();
+    let position = env.position(&bob);
+    assert_eq!(position.yes_shares, 150 * USD);
+    assert_eq!(position.yes_cost_basis, 100 * USD);
+    assert_eq!(position.total_spent, 100 * USD);
+    assert_eq!(position.average_entry_price(Outcome::Yes), 666_666_666);
+    assert_eq!(position.realized_pnl, 0);
+
+    // Half of the shares leave with half of the cost
+    env.sell(&bob, 75 * USD, env.yes_mint).unwrap();
+    let received = env.balance(&bob.usd) - 900 * USD;
+    let position = env.position(&bob);
+    assert_eq!(position.yes_shares, 75 * USD);
+    assert_eq!(position.yes_cost_basis, 50 * USD);
+    assert_eq!(position.realized_pnl, received as i64 - 50 * USD as i64);
+
+    env.settle(&env.oracle.clone(), YES);
+    env.claim(&bob).unwrap();
+    let position = env.position(&bob);
+    assert_eq!(position.yes_shares, 0);
+    assert_eq!(position.yes_cost_basis, 0);
+    assert_eq!(position.total_spent, 100 * USD);
+    assert_eq!(
+        position.realized_pnl,
+        (env.balance(&bob.usd) - 1_000 * USD) as i64
+    );
+}
+
+#[test]
+fn tokens_from_the_pool_enter_at_the_pool_price() {
+    let mut env = MarketEnv::new();
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    env.open_position(&alice).unwrap();
+    env.add_liquidity(&alice, 100 * USD).unwrap();
+    env.purchase(&bob, 100 * USD, env.yes_mint).unwrap();
+
+    let pool = env.pool();
+    let (_, no_price) = math::prices(pool.yes_liquidity, pool.no_liquidity).unwrap();
+    env.remove_liquidity(&alice, 100 * USD).unwrap();
+
+    let position = env.position(&alice);
+    let no_tokens = env.balance(&alice.no);
+    assert!(no_tokens > 0);
+    assert_eq!(position.no_shares, no_tokens);
+    assert_eq!(
+        position.no_cost_basis,
+        (no_tokens as u128 * no_price as u128 / USD as u128) as u64
+    );
+    assert_eq!(position.yes_shares, 0);
+    assert_eq!(position.total_spent, 0);
+}
+
+#[test]
+fn trading_without_a_position_still_works() {
+    let mut env = MarketEnv::new();
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    env.add_liquidity(&alice, 100 * USD).unwrap();
+    env.purchase(&bob, 10 * USD, env.no_mint).unwrap();
+    env.sell(&bob, 5 * USD, env.no_mint).unwrap();
+    assert!(env.svm.account(&bob.position).is_none());
+
+    // And opening one later only tracks what comes next
+    env.open_position(&bob).unwrap();
+    env.purchase(&bob, 10 * USD, env.no_mint).unwrap();
+    assert_eq!(env.position(&bob).total_spent, 10 * USD);
+}
@@ -0,0 +1,86 @@
+// The on-chain candle history the trades write into.
+mod harness;
+
+use harness::market::{MarketEnv, USD};
+use solana_bet_placing_market::{math, Candle, PriceHistory, PRICE_HISTORY_CANDLES};
+
+const HOUR: i64 = PriceHistory::INTERVAL;
+
+fn yes_price(env: &MarketEnv) -> u64 {
+    let pool = env.pool();
+    math::prices(pool.yes_liquidity, pool.no_liquidity)
+        .unwrap()
+        .0
+}
+
+#[test]
+fn trades_build_hourly_candles() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let trader = env.new_user(1_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+
+    let history = env.price_history();
+    assert_eq!(history.market, env.market);
+    assert_eq!(history.interval, HOUR);
+    assert_eq!(history.candles().count(), 0);
+
+    // Two trades in the same hour share a candle
+    env.purchase(&trader, 100 * USD, env.yes_mint).unwrap();
+    let high = yes_price(&env);
+    assert_eq!(high, 800_000_000);
+    env.svm.warp(60, 150);
+    env.purchase(&trader, 50 * USD, env.no_mint).unwrap();
+    let close = yes_price(&env);
+
+    let now = env.svm.clock.unix_timestamp;
+    let hour_start = now - now.rem_euclid(HOUR);
+    let candles: Vec<Candle> = env.price_history().candles().copied().collect();
+    assert_eq!(
+        candles,
+        vec![Candle {
+            start: hour_start,
+            open: USD / 2,
+            high,
+            low: USD / 2,
+            close,
+            volume: 150 * USD,
+        }]
+    );
+
+    // The next hour opens where the previous one closed
+    env.svm.warp(HOUR, 9_000);
+    env.purchase(&trader, 10 * USD, env.no_mint).unwrap();
+    let history = env.price_history();
+    let latest = history.candles().last().unwrap();
+    assert_eq!(latest.start, hour_start + HOUR);
+    assert_eq!(latest.open, close);
+    assert_eq!(latest.high, close);
+    assert!(latest.low < close && latest.close == latest.low);
+    assert_eq!(latest.volume, 10 * USD);
+}
+
+#[test]
+fn the_oldest_candles_are_overwritten() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let trader = env.new_user(1_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+
+    let first_hour = {
+        let now = env.svm.clock.unix_timestamp;
+        now - now.rem_euclid(HOUR)
+    };
+    let trades = PRICE_HISTORY_CANDLES as i64 + 5;
+    for _ in 0..trades {
+        env.purchase(&trader, USD, env.yes_mint).unwrap();
+        env.svm.warp(HOUR, 9_000);
+    }
+
+    let history = env.price_history();
+    assert_eq!(history.count as usize, PRICE_HISTORY_CANDLES);
+    let starts: Vec<i64> = history.candles().map(|candle| candle.start).collect();
+    let expected: Vec<i64> = (5..trades).map(|hour| first_hour + hour * HOUR).collect();
+    assert_eq!(starts, expected);
+    assert!(history.candles().all(|candle| candle.volume == USD));
+}
@@ -0,0 +1,70 @@
+// The TWAP accumulator on the pool, observed through `observe_price` and `get_twap`.
+mod harness;
+
+use harness::assert_error;
+use harness::market::{MarketEnv, USD};
+use solana_bet_placing_market::MarketError;
+
+const YES: u8 = 1;
+
+#[test]
+fn twap_weights_each_price_by_the_time_it_stood() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let trader = env.new_user(1_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    let start = env.observe_price();
+
+    // Even odds for 100 seconds, then YES at 0.8 for 25 seconds
+    env.svm.warp(100, 250);
+    env.purchase(&trader, 100 * USD, env.yes_mint).unwrap();
+    assert_eq!(env.pool().last_price_update, start.timestamp + 100);
+    env.svm.warp(25, 60);
+
+    let twap = env.twap(start);
+    assert_eq!(twap.start, start.timestamp);
+    assert_eq!(twap.end, start.timestamp + 125);
+    assert_eq!(twap.yes_price, (USD / 2 * 100 + 800_000_000 * 25) / 125);
+    assert_eq!(twap.no_price, USD - twap.yes_price);
+}
+
+#[test]
+fn a_trade_reverted_within_the_same_second_does_not_move_the_twap() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let whale = env.new_user(100_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    let start = env.observe_price();
+    env.svm.warp(600, 1_500);
+
+    // The whale pushes YES to almost 1 and lets it fall back right away
+    env.purchase(&whale, 10_000 * USD, env.yes_mint).unwrap();
+    assert!(env.pool().no_liquidity > 99 * env.pool().yes_liquidity);
+    env.purchase(&whale, 10_000 * USD, env.no_mint).unwrap();
+
+    assert_eq!(env.twap(start).yes_price, USD / 2);
+}
+
+#[test]
+fn the_resolved_price_counts_after_resolution() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.svm.warp(50, 125);
+    env.resolve(&env.oracle.clone(), YES).unwrap();
+    let resolved = env.observe_price();
+
+    env.svm.warp(50, 125);
+    assert_eq!(env.twap(resolved).yes_price, USD);
+}
+
+#[test]
+fn twap_needs_time_to_pass() {
+    let mut env = MarketEnv::new();
+    let start = env.observe_price();
+    assert_error(env.get_twap(start), MarketError::InvalidTwapWindow);
+
+    // An empty pool quotes even odds
+    env.svm.warp(10, 25);
+    assert_eq!(env.twap(start).yes_price, USD / 2);
+}
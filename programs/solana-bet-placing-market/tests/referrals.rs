@@ -0,0 +1,130 @@
+// Referrers registered by partner frontends and their share of the trading fee.
+mod harness;
+
+use anchor_lang::error::ErrorCode;
+use anchor_spl::token::spl_token;
+use harness::assert_error;
+use harness::market::{MarketEnv, User, USD};
+use solana_bet_placing_market::{
+    accounts, event_accounts, instruction, MarketError, MarketFactory, PurchasedOutcomeSharesEvent,
+    ReferralFeePaidEvent, SoldOutcomeSharesEvent,
+};
+
+#[test]
+fn a_referred_trade_pays_the_referrer() {
+    // 1% fee, a fifth of which goes to the referrer
+    let mut env = MarketEnv::with_fees(100, 2_000);
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    let partner = env.new_user(0);
+    env.add_liquidity(&alice, 100 * USD).unwrap();
+    env.register_referrer(&partner).unwrap();
+
+    // Without a referrer the whole fee stays in the pool
+    env.purchase(&bob, 10 * USD, env.no_mint).unwrap();
+    assert_eq!(env.pool().fees_collected, USD / 10);
+
+    let result = env
+        .purchase_referred(&bob, 100 * USD, env.yes_mint, &partner)
+        .unwrap();
+    let purchase: PurchasedOutcomeSharesEvent = result.event();
+    let referral: ReferralFeePaidEvent = result.event();
+    assert_eq!(purchase.fee, USD);
+    assert_eq!(referral.market, env.market);
+    assert_eq!(referral.referrer, partner.wallet);
+    assert_eq!(referral.user, bob.wallet);
+    assert_eq!(referral.volume, 100 * USD);
+    assert_eq!(referral.referral_fee, USD / 5);
+    assert_eq!(referral.total_volume, 100 * USD);
+    assert_eq!(referral.total_earnings, USD / 5);
+    assert_eq!(env.balance(&partner.usd), USD / 5);
+    assert_eq!(env.balance(&bob.usd), 890 * USD);
+    assert_eq!(env.balance(&bob.yes), purchase.wanted_shares_purchased);
+    assert_eq!(env.pool().fees_collected, USD / 10 + 4 * USD / 5);
+
+    // On a sale the referrer is paid out of the vault, next to the seller
+    let shares = env.balance(&bob.yes);
+    let result = env
+        .sell_referred(&bob, shares, env.yes_mint, &partner)
+        .unwrap();
+    let sale: SoldOutcomeSharesEvent = result.event();
+    let referral: ReferralFeePaidEvent = result.event();
+    assert_eq!(referral.referral_fee, sale.fee / 5);
+    assert_eq!(env.balance(&partner.usd), USD / 5 + referral.referral_fee);
+
+    let stats = env.referral_stats(&partner);
+    assert_eq!(stats.referrer, partner.wallet);
+    assert_eq!(stats.usd_account, partner.usd);
+    assert_eq!(stats.trades, 2);
+    assert_eq!(stats.volume, 100 * USD + sale.usd_received);
+    assert_eq!(stats.earnings, env.balance(&partner.usd));
+}
+
+#[test]
+fn referrals_are_checked() {
+    let mut env = MarketEnv::with_fees(100, 5_000);
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    let partner = env.new_user(0);
+    let mallory = env.new_user(0);
+    env.add_liquidity(&alice, 100 * USD).unwrap();
+    env.register_referrer(&partner).unwrap();
+
+    // The fee is paid to the account the referrer registered with
+    let redirected = User {
+        usd: mallory.usd,
+        ..partner
+    };
+    assert_error(
+        env.purchase_referred(&bob, 10 * USD, env.yes_mint, &redirected),
+        MarketError::InvalidReferrer,
+    );
+    // Nobody refers their own trades
+    assert_error(
+        env.purchase_referred(&partner, 10 * USD, env.yes_mint, &partner),
+        MarketError::InvalidReferrer,
+    );
+    // And only registered referrers get paid
+    assert_error(
+        env.purchase_referred(&bob, 10 * USD, env.yes_mint, &mallory),
+        ErrorCode::AccountNotInitialized,
+    );
+
+    // The stats never come without the account they pay to
+    assert_error(
+        env.svm.call(
+            event_accounts!(accounts::PurchaseOutcomeShares {
+                market: env.market,
+                pool: env.pool,
+                vault: env.vault,
+                yes_mint: env.yes_mint,
+                no_mint: env.no_mint,
+                user_usd_account: bob.usd,
+                user_outcome_mint_account: bob.yes,
+                liquidity_yes_tokens_account: env.liquidity_yes_tokens_account,
+                liquidity_no_tokens_account: env.liquidity_no_tokens_account,
+                price_history: env.price_history,
+                position: None,
+                referral: Some(partner.referral),
+                referrer_usd_account: None,
+                session: None,
+                relayer: None,
+                relayer_usd_account: None,
+                user: bob.wallet,
+                token_program: spl_token::ID,
+            }),
+            instruction::PurchaseOutcomeShares {
+                usd_amount: 10 * USD,
+                purchased_outcome_mint_pubkey: env.yes_mint,
+            },
+            &[bob.wallet],
+        ),
+        MarketError::InvalidReferrer,
+    );
+    assert_eq!(env.referral_stats(&partner).trades, 0);
+
+    assert_error(
+        env.set_referral_share(MarketFactory::MAX_REFERRAL_SHARE_BPS + 1),
+        MarketError::ReferralShareTooHigh,
+    );
+}
@@ -0,0 +1,116 @@
+// Trades whose transaction a relayer pays for, in exchange for a USD fee.
+mod harness;
+
+use anchor_lang::error::ErrorCode;
+use harness::assert_error;
+use harness::market::{MarketEnv, User, USD};
+use solana_bet_placing_market::{
+    accounts, event_accounts, instruction, MarketError, MarketFactory, PurchasedOutcomeSharesEvent,
+    RelayFeePaidEvent, SoldOutcomeSharesEvent,
+};
+
+const RELAY_FEE: u64 = USD / 10;
+
+// A market created after the relay fee was set
+fn relayed_market() -> MarketEnv {
+    let mut env = MarketEnv::new();
+    env.set_relay_fee(RELAY_FEE).unwrap();
+    assert_eq!(env.market().relay_fee, 0);
+    let env = env.next_market();
+    assert_eq!(env.market().relay_fee, RELAY_FEE);
+    env
+}
+
+#[test]
+fn the_relayer_is_paid_out_of_the_trade() {
+    let mut env = relayed_market();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    let relayer = env.new_user(0);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+
+    // The relayer pays the rent of the accounts the user opens
+    let lamports = env.svm.lamports(&alice.wallet);
+    env.open_position_paid_by(&alice, relayer.wallet).unwrap();
+    assert_eq!(env.svm.lamports(&alice.wallet), lamports);
+
+    let result = env
+        .purchase_relayed(&alice, 10 * USD, env.yes_mint, &relayer)
+        .unwrap();
+    let purchase: PurchasedOutcomeSharesEvent = result.event();
+    let relay: RelayFeePaidEvent = result.event();
+    assert_eq!(purchase.amount, 10 * USD - RELAY_FEE);
+    assert_eq!(relay.market, env.market);
+    assert_eq!(relay.relayer, relayer.wallet);
+    assert_eq!(relay.user, alice.wallet);
+    assert_eq!(relay.relay_fee, RELAY_FEE);
+    assert_eq!(env.balance(&alice.usd), 990 * USD);
+    assert_eq!(env.balance(&relayer.usd), RELAY_FEE);
+    assert_eq!(env.position(&alice).total_spent, 10 * USD - RELAY_FEE);
+
+    // On a sale the fee comes out of what the user gets
+    let shares = env.balance(&alice.yes);
+    let sale: SoldOutcomeSharesEvent = env
+        .sell_relayed(&alice, shares, env.yes_mint, &relayer)
+        .unwrap()
+        .event();
+    assert_eq!(env.balance(&alice.usd), 990 * USD + sale.usd_received);
+    assert_eq!(env.balance(&relayer.usd), 2 * RELAY_FEE);
+
+    assert_error(
+        env.purchase_relayed(&alice, RELAY_FEE, env.yes_mint, &relayer),
+        MarketError::RelayFeeNotCovered,
+    );
+    // Without a relayer there is no fee
+    env.purchase(&alice, RELAY_FEE, env.yes_mint).unwrap();
+    assert_eq!(env.balance(&relayer.usd), 2 * RELAY_FEE);
+}
+
+#[test]
+fn relayers_are_checked() {
+    let mut env = relayed_market();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    let relayer = env.new_user(0);
+    let mallory = env.new_user(0);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+
+    // The fee goes to the relayer's own account
+    let redirected = User {
+        usd: mallory.usd,
+        ..relayer
+    };
+    assert_error(
+        env.purchase_relayed(&alice, 10 * USD, env.yes_mint, &redirected),
+        MarketError::InvalidRelayer,
+    );
+    // And in USD
+    let paid_in_yes = User {
+        usd: relayer.yes,
+        ..relayer
+    };
+    assert_error(
+        env.purchase_relayed(&alice, 10 * USD, env.yes_mint, &paid_in_yes),
+        MarketError::InvalidRelayer,
+    );
+
+    // Within the cap
+    assert_error(
+        env.set_relay_fee(MarketFactory::MAX_RELAY_FEE + 1),
+        MarketError::RelayFeeTooHigh,
+    );
+    env.set_relay_fee(MarketFactory::MAX_RELAY_FEE).unwrap();
+
+    // Only the factory authority sets the fee
+    assert_error(
+        env.svm.call(
+            event_accounts!(accounts::SetRelayFee {
+                market_factory: env.market_factory,
+                authority: mallory.wallet,
+            }),
+            instruction::SetRelayFee { relay_fee: USD },
+            &[mallory.wallet],
+        ),
+        ErrorCode::ConstraintSeeds,
+    );
+}
@@ -0,0 +1,117 @@
+// The per-market bounds on the size and the price impact of a trade.
+mod harness;
+
+use anchor_lang::error::ErrorCode;
+use harness::assert_error;
+use harness::market::{MarketEnv, USD};
+use solana_bet_placing_market::{accounts, event_accounts, instruction, MarketError, RiskLimits};
+
+const YES: u8 = 1;
+
+#[test]
+fn a_trade_cannot_move_the_price_too_far() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let whale = env.new_user(1_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.set_risk_limits(RiskLimits {
+        max_price_impact_bps: 5_000,
+        ..RiskLimits::default()
+    })
+    .unwrap();
+    assert_eq!(env.market().risk_limits.max_price_impact_bps, 5_000);
+
+    // 0.5 to 0.8 is a 60% move, 0.5 to about 0.69 is fine
+    assert_error(
+        env.purchase(&whale, 100 * USD, env.yes_mint),
+        MarketError::PriceImpactTooHigh,
+    );
+    env.purchase(&whale, 50 * USD, env.yes_mint).unwrap();
+
+    // Selling is bound the same way, on the price of what is sold: all of it would take
+    // YES back to 0.5
+    env.set_risk_limits(RiskLimits {
+        max_price_impact_bps: 2_000,
+        ..RiskLimits::default()
+    })
+    .unwrap();
+    let shares = env.balance(&whale.yes);
+    assert_error(
+        env.sell(&whale, shares, env.yes_mint),
+        MarketError::PriceImpactTooHigh,
+    );
+    env.sell(&whale, shares / 2, env.yes_mint).unwrap();
+
+    // Back to no limit
+    env.set_risk_limits(RiskLimits::default()).unwrap();
+    let shares = env.balance(&whale.yes);
+    env.sell(&whale, shares, env.yes_mint).unwrap();
+}
+
+#[test]
+fn trades_are_bounded_in_size_and_by_the_reserve() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let trader = env.new_user(1_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.set_risk_limits(RiskLimits {
+        max_trade_size: 10 * USD,
+        ..RiskLimits::default()
+    })
+    .unwrap();
+
+    assert_error(
+        env.purchase(&trader, 10 * USD + 1, env.yes_mint),
+        MarketError::TradeTooLarge,
+    );
+    env.purchase(&trader, 10 * USD, env.yes_mint).unwrap();
+    env.purchase(&trader, 10 * USD, env.yes_mint).unwrap();
+    // The shares of both purchases are worth more than 10 USD
+    let shares = env.balance(&trader.yes);
+    assert_error(
+        env.sell(&trader, shares, env.yes_mint),
+        MarketError::TradeTooLarge,
+    );
+    env.sell(&trader, shares / 3, env.yes_mint).unwrap();
+
+    // The pool has about 90 YES left, and has to keep 80
+    env.set_risk_limits(RiskLimits {
+        min_pool_reserve: 80 * USD,
+        ..RiskLimits::default()
+    })
+    .unwrap();
+    assert_error(
+        env.purchase(&trader, 20 * USD, env.yes_mint),
+        MarketError::PoolReserveTooLow,
+    );
+    env.purchase(&trader, 5 * USD, env.yes_mint).unwrap();
+    assert!(env.pool().yes_liquidity >= 80 * USD);
+}
+
+#[test]
+fn only_the_authority_sets_the_limits_before_resolution() {
+    let mut env = MarketEnv::new();
+    let mallory = env.svm.new_wallet();
+    let limits = RiskLimits {
+        max_price_impact_bps: 1,
+        max_trade_size: 1,
+        min_pool_reserve: 1,
+    };
+    assert_error(
+        env.svm.call(
+            event_accounts!(accounts::SetRiskLimits {
+                market: env.market,
+                authority: mallory,
+            }),
+            instruction::SetRiskLimits {
+                risk_limits: limits,
+            },
+            &[mallory],
+        ),
+        ErrorCode::ConstraintHasOne,
+    );
+    assert_eq!(env.market().risk_limits, RiskLimits::default());
+
+    env.resolve(&env.oracle.clone(), YES).unwrap();
+    assert_error(env.set_risk_limits(limits), MarketError::MarketResolved);
+}
@@ -0,0 +1,199 @@
+// Trading and claiming through session keys, without the owner signing every trade.
+mod harness;
+
+use anchor_lang::error::ErrorCode;
+use harness::assert_error;
+use harness::market::{MarketEnv, User, USD};
+use solana_bet_placing_market::{
+    Market, MarketError, PurchasedOutcomeSharesEvent, ResolveUserWinningsEvent,
+    SoldOutcomeSharesEvent,
+};
+
+const YES: u8 = 1;
+const HOUR: i64 = 3_600;
+
+#[test]
+fn a_session_key_trades_and_claims_for_its_owner() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.open_position(&alice).unwrap();
+
+    // Long enough to claim once the dispute window is over
+    let key = env.svm.new_signer();
+    let expires_at = env.svm.clock.unix_timestamp + Market::DISPUTE_WINDOW + HOUR;
+    env.create_session(&alice, key, true, expires_at, 50 * USD)
+        .unwrap();
+    env.delegate_to_session(&alice, key, alice.yes).unwrap();
+    let session = env.session(&alice, &key);
+    assert_eq!(session.owner, alice.wallet);
+    assert_eq!(session.market, env.market);
+    assert_eq!(session.spend_cap, 50 * USD);
+
+    // The key signs, the owner's accounts and position move
+    let through_key = alice.with_session(key);
+    let purchase: PurchasedOutcomeSharesEvent = env
+        .purchase(&through_key, 20 * USD, env.yes_mint)
+        .unwrap()
+        .event();
+    assert_eq!(purchase.user, alice.wallet);
+    assert_eq!(env.balance(&alice.usd), 980 * USD);
+    assert_eq!(env.balance(&alice.yes), purchase.wanted_shares_purchased);
+    assert_eq!(env.position(&alice).total_spent, 20 * USD);
+    assert_eq!(env.session(&alice, &key).spent, 20 * USD);
+
+    let shares = env.balance(&alice.yes) / 2;
+    let sale: SoldOutcomeSharesEvent = env
+        .sell(&through_key, shares, env.yes_mint)
+        .unwrap()
+        .event();
+    assert_eq!(sale.user, alice.wallet);
+    assert_eq!(env.balance(&alice.usd), 980 * USD + sale.usd_received);
+
+    // Sales do not give back any of the cap
+    assert_error(
+        env.purchase(&through_key, 30 * USD + 1, env.yes_mint),
+        MarketError::SessionSpendCapExceeded,
+    );
+    env.purchase(&through_key, 30 * USD, env.yes_mint).unwrap();
+
+    env.settle(&env.oracle.clone(), YES);
+    let yes = env.balance(&alice.yes);
+    let claim: ResolveUserWinningsEvent = env.claim(&through_key).unwrap().event();
+    assert_eq!(claim.user, alice.wallet);
+    assert_eq!(claim.winning_amount, yes);
+    assert_eq!(env.balance(&alice.yes), 0);
+    assert_eq!(env.position(&alice).yes_shares, 0);
+}
+
+#[test]
+fn sessions_are_bounded() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    let mallory = env.new_user(0);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    let now = env.svm.clock.unix_timestamp;
+    let key = env.svm.new_signer();
+
+    assert_error(
+        env.create_session(&alice, key, false, now, 100 * USD),
+        MarketError::SessionExpired,
+    );
+    env.create_session(&alice, key, true, now + HOUR, 100 * USD)
+        .unwrap();
+    env.delegate_to_session(&alice, key, alice.yes).unwrap();
+    let through_key = alice.with_session(key);
+    env.purchase(&through_key, 10 * USD, env.yes_mint).unwrap();
+
+    // The key cannot pay the owner's funds anywhere but into the market's vault
+    let market_vault = env.vault;
+    env.vault = mallory.usd;
+    assert_error(
+        env.purchase(&through_key, 10 * USD, env.yes_mint),
+        ErrorCode::ConstraintSeeds,
+    );
+    env.vault = market_vault;
+    assert_eq!(env.balance(&mallory.usd), 0);
+
+    // Only the key signs for the session
+    let impostor = User {
+        signer: mallory.wallet,
+        ..alice.with_session(key)
+    };
+    assert_error(
+        env.purchase(&impostor, 10 * USD, env.yes_mint),
+        MarketError::InvalidSession,
+    );
+    // And the proceeds only go to the owner
+    let redirected = User {
+        usd: mallory.usd,
+        ..alice.with_session(key)
+    };
+    assert_error(
+        env.sell(&redirected, USD, env.yes_mint),
+        MarketError::InvalidSession,
+    );
+
+    // A session scoped to the first market cannot trade on the next one
+    let first_yes = alice.yes;
+    let mut other = env.next_market();
+    let lp = other.join(lp.wallet, lp.usd);
+    let alice = other.join(alice.wallet, alice.usd);
+    other.add_liquidity(&lp, 100 * USD).unwrap();
+    assert_error(
+        other.purchase(&alice.with_session(key), 10 * USD, other.yes_mint),
+        MarketError::SessionMarketNotAllowed,
+    );
+
+    // Unless it is not scoped at all, which takes over the USD account once the first
+    // session is revoked
+    let roaming_key = other.svm.new_signer();
+    assert_error(
+        other.create_session(&alice, roaming_key, false, now + HOUR, 100 * USD),
+        MarketError::AlreadyDelegated,
+    );
+    other.revoke_session(&alice, key, &[first_yes]).unwrap();
+    other
+        .create_session(&alice, roaming_key, false, now + HOUR, 100 * USD)
+        .unwrap();
+    let roaming = alice.with_session(roaming_key);
+    other.purchase(&roaming, 10 * USD, other.yes_mint).unwrap();
+
+    other.svm.warp(HOUR, 9_000);
+    assert_error(
+        other.purchase(&roaming, 10 * USD, other.yes_mint),
+        MarketError::SessionExpired,
+    );
+
+    // The owner closes the session whenever they want
+    other.revoke_session(&alice, roaming_key, &[]).unwrap();
+    assert_error(
+        other.purchase(&roaming, 10 * USD, other.yes_mint),
+        ErrorCode::AccountNotInitialized,
+    );
+}
+
+#[test]
+fn revoking_a_session_lifts_its_delegations() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.purchase(&alice, 10 * USD, env.yes_mint).unwrap();
+
+    let key = env.svm.new_signer();
+    let expires_at = env.svm.clock.unix_timestamp + HOUR;
+    env.create_session(&alice, key, true, expires_at, 50 * USD)
+        .unwrap();
+    env.delegate_to_session(&alice, key, alice.yes).unwrap();
+
+    // The market cannot take over the account delegated to the session
+    assert_error(
+        env.approve_settlement(&alice),
+        MarketError::AlreadyDelegated,
+    );
+
+    // Only the accounts delegated to the session are revoked through it
+    assert_error(
+        env.revoke_session(&alice, key, &[alice.no]),
+        MarketError::InvalidSession,
+    );
+    env.revoke_session(&alice, key, &[alice.yes]).unwrap();
+    for account in [alice.usd, alice.yes] {
+        let account = env.svm.token_account(&account);
+        assert!(account.delegate.is_none());
+        assert_eq!(account.delegated_amount, 0);
+    }
+
+    // Then the settlement can be approved, and a session cannot take it over
+    env.approve_settlement(&alice).unwrap();
+    let key = env.svm.new_signer();
+    env.create_session(&alice, key, true, expires_at, 50 * USD)
+        .unwrap();
+    assert_error(
+        env.delegate_to_session(&alice, key, alice.yes),
+        MarketError::AlreadyDelegated,
+    );
+}
@@ -0,0 +1,197 @@
+// The permissionless `settle_holders` crank and the keeper rewards it pays.
+mod harness;
+
+use anchor_spl::associated_token::get_associated_token_address;
+use harness::assert_error;
+use harness::market::{MarketEnv, USD};
+use solana_bet_placing_market::{
+    HoldersSettledEvent, MarketError, ResolveUserWinningsEvent, SettlementBudgetReclaimedEvent,
+};
+
+const YES: u8 = 1;
+
+#[test]
+fn a_keeper_pays_the_winners_who_approved_it() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    let carol = env.new_user(1_000 * USD);
+    let keeper = env.new_user(0);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.purchase(&alice, 10 * USD, env.yes_mint).unwrap();
+    env.purchase(&bob, 10 * USD, env.yes_mint).unwrap();
+    env.purchase(&carol, 10 * USD, env.no_mint).unwrap();
+    for user in [&alice, &bob, &carol] {
+        env.approve_settlement(user).unwrap();
+    }
+    env.fund_settlement_budget(USD, USD / 10).unwrap();
+    assert_eq!(env.market().settlement_budget, USD);
+
+    // Nothing to settle before the resolution
+    let holders = [
+        (alice.yes, alice.usd),
+        (bob.yes, bob.usd),
+        (carol.no, carol.usd),
+        (lp.yes, lp.usd),
+    ];
+    assert_error(
+        env.settle_holders(&keeper, &holders),
+        MarketError::MarketNotResolved,
+    );
+
+    env.settle(&env.oracle.clone(), YES);
+    let alice_yes = env.balance(&alice.yes);
+    let bob_yes = env.balance(&bob.yes);
+    let result = env.settle_holders(&keeper, &holders).unwrap();
+
+    let paid: Vec<ResolveUserWinningsEvent> = result.events();
+    assert_eq!(paid.len(), 2);
+    assert_eq!(paid[0].user, alice.wallet);
+    assert_eq!(paid[0].winning_amount, alice_yes);
+    let settled: HoldersSettledEvent = result.event();
+    assert_eq!(settled.settled_holders, 2);
+    assert_eq!(settled.paid_out, alice_yes + bob_yes);
+    assert_eq!(settled.keeper_reward, USD / 5);
+    // Carol lost, the LP never approved the crank
+    assert_eq!(settled.skipped_accounts, vec![carol.no, lp.yes]);
+
+    assert_eq!(env.balance(&alice.yes), 0);
+    assert_eq!(env.balance(&alice.usd), 990 * USD + alice_yes);
+    assert_eq!(env.balance(&bob.usd), 990 * USD + bob_yes);
+    assert_eq!(env.balance(&keeper.usd), USD / 5);
+    assert_eq!(env.market().settlement_budget, 4 * USD / 5);
+
+    // Settling twice pays nothing
+    let result = env.settle_holders(&keeper, &holders[..2]).unwrap();
+    assert_eq!(result.event::<HoldersSettledEvent>().settled_holders, 0);
+    assert_eq!(env.balance(&keeper.usd), USD / 5);
+}
+
+#[test]
+fn the_rewards_stop_with_the_budget() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    let keeper = env.new_user(0);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.purchase(&alice, 10 * USD, env.yes_mint).unwrap();
+    env.purchase(&bob, 10 * USD, env.yes_mint).unwrap();
+    env.approve_settlement(&alice).unwrap();
+    env.approve_settlement(&bob).unwrap();
+    env.fund_settlement_budget(USD / 10, USD / 10).unwrap();
+    env.settle(&env.oracle.clone(), YES);
+
+    // Payouts only go to the holder's associated USD account
+    assert_error(
+        env.settle_holders(&keeper, &[(alice.yes, keeper.usd)]),
+        MarketError::InvalidHolderAccount,
+    );
+
+    env.settle_holders(&keeper, &[(alice.yes, alice.usd)])
+        .unwrap();
+    env.settle_holders(&keeper, &[(bob.yes, bob.usd)]).unwrap();
+    assert_eq!(env.balance(&keeper.usd), USD / 10);
+    assert_eq!(env.balance(&bob.yes), 0);
+    assert_eq!(env.market().settlement_budget, 0);
+}
+
+#[test]
+fn dust_holders_are_paid_without_rewarding_the_keeper() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    let keeper = env.new_user(0);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    let dust: Vec<_> = (0..3)
+        .map(|_| {
+            let holder = env.new_user(USD);
+            env.purchase(&holder, USD / 100, env.yes_mint).unwrap();
+            env.approve_settlement(&holder).unwrap();
+            holder
+        })
+        .collect();
+    env.purchase(&alice, 10 * USD, env.yes_mint).unwrap();
+    env.approve_settlement(&alice).unwrap();
+    env.fund_settlement_budget(USD, USD / 10).unwrap();
+    env.settle(&env.oracle.clone(), YES);
+
+    // Each dust account is paid out, but only Alice's payout is worth a reward
+    let mut holders: Vec<_> = dust.iter().map(|holder| (holder.yes, holder.usd)).collect();
+    holders.push((alice.yes, alice.usd));
+    let result = env.settle_holders(&keeper, &holders).unwrap();
+    let settled: HoldersSettledEvent = result.event();
+    assert_eq!(settled.settled_holders, 4);
+    assert_eq!(settled.keeper_reward, USD / 10);
+    for holder in &dust {
+        assert_eq!(env.balance(&holder.yes), 0);
+    }
+    assert_eq!(env.balance(&keeper.usd), USD / 10);
+    assert_eq!(env.market().settlement_budget, 9 * USD / 10);
+}
+
+#[test]
+fn the_budget_waits_for_the_market_to_open() {
+    let MarketEnv {
+        svm,
+        authority,
+        oracle,
+        usd_mint,
+        usd_mint_authority,
+        ..
+    } = MarketEnv::new();
+    let mut draft = MarketEnv::for_market(svm, authority, oracle, usd_mint, usd_mint_authority, 1);
+    draft.create_market().unwrap();
+    assert_error(
+        draft.fund_settlement_budget(USD, USD / 10),
+        MarketError::MarketNotInitialized,
+    );
+}
+
+#[test]
+fn the_budget_left_goes_back_to_the_authority_once_every_holder_is_paid() {
+    let mut env = MarketEnv::new();
+    let lp = env.new_user(1_000 * USD);
+    let alice = env.new_user(1_000 * USD);
+    let bob = env.new_user(1_000 * USD);
+    let keeper = env.new_user(0);
+    env.add_liquidity(&lp, 100 * USD).unwrap();
+    env.purchase(&alice, 10 * USD, env.yes_mint).unwrap();
+    env.purchase(&bob, 10 * USD, env.no_mint).unwrap();
+    env.approve_settlement(&alice).unwrap();
+    env.fund_settlement_budget(USD, USD / 10).unwrap();
+    assert_error(
+        env.reclaim_settlement_budget(),
+        MarketError::MarketNotResolved,
+    );
+
+    // Alice's winnings are still to be paid, Bob's losing tokens are not
+    env.settle(&env.oracle.clone(), YES);
+    assert_error(
+        env.reclaim_settlement_budget(),
+        MarketError::HoldersNotSettled,
+    );
+    env.settle_holders(&keeper, &[(alice.yes, alice.usd)])
+        .unwrap();
+
+    // The reward the keepers started on stays, the budget can still grow
+    assert_error(
+        env.fund_settlement_budget(USD, USD / 5),
+        MarketError::SettlementStarted,
+    );
+    env.fund_settlement_budget(USD, USD / 10).unwrap();
+    assert_eq!(env.market().settlement_budget, 19 * USD / 10);
+
+    let authority_usd_account = get_associated_token_address(&env.authority, &env.usd_mint);
+    let authority_usd_before = env.balance(&authority_usd_account);
+    let result = env.reclaim_settlement_budget().unwrap();
+    let reclaimed: SettlementBudgetReclaimedEvent = result.event();
+    assert_eq!(reclaimed.budget, 19 * USD / 10);
+    assert_eq!(env.market().settlement_budget, 0);
+    assert_eq!(
+        env.balance(&authority_usd_account),
+        authority_usd_before + 19 * USD / 10
+    );
+    assert_error(env.reclaim_settlement_budget(), MarketError::Zero);
+}